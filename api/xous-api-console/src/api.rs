@@ -0,0 +1,62 @@
+/// The name that the console shell registers with the name server
+pub const SERVER_NAME: &str = "_console_";
+
+#[derive(num_derive::FromPrimitive, num_derive::ToPrimitive, Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Opcode {
+    /// Characters typed on the console, sent by the log server as described in
+    /// `xous_api_log::api::InputListener`
+    Input = 0,
+
+    /// Add a command to the shell. This is a mutable lend of a `CommandRegistration`.
+    RegisterCommand = 1,
+
+    /// Remove a command that the calling process added. This is a mutable lend of a
+    /// `CommandRegistration`, of which only the name is used.
+    UnregisterCommand = 2,
+}
+
+/// The longest command name, in bytes
+pub const NAME_LEN: usize = 16;
+
+/// The longest line of help for a command, in bytes
+pub const HELP_LEN: usize = 80;
+
+/// The most commands that services may add
+pub const MAX_COMMANDS: usize = 32;
+
+/// Sent as a mutable lend with `Opcode::RegisterCommand` and `Opcode::UnregisterCommand`
+#[repr(C)]
+pub struct CommandRegistration {
+    /// The word that runs the command. It may not contain whitespace.
+    pub name: [u8; NAME_LEN],
+    pub name_length: u32,
+    /// One line describing the command, shown by `help`
+    pub help: [u8; HELP_LEN],
+    pub help_length: u32,
+    /// The server that runs the command
+    pub sid: [u32; 4],
+    /// The ID of the `Invocation` that is lent to the server
+    pub opcode: u32,
+    /// Filled in with 0 on success, or an `xous::Error` as a `u32`. Registering fails
+    /// with `ServerExists` if the name is taken, and unregistering fails with
+    /// `ServerNotFound` if the caller has no command with that name.
+    pub error: u32,
+}
+
+/// The longest argument string passed to a command, in bytes
+pub const ARGS_LEN: usize = 256;
+
+/// The most output that a command can return, in bytes
+pub const OUTPUT_LEN: usize = 3584;
+
+/// Lent mutably to the server that added a command, with the command's `opcode`, each
+/// time that the command is run. The server writes what the command has to say to
+/// `output` and replies.
+#[repr(C)]
+pub struct Invocation {
+    /// Everything after the command name, with the surrounding whitespace removed
+    pub args: [u8; ARGS_LEN],
+    pub args_length: u32,
+    pub output: [u8; OUTPUT_LEN],
+    pub output_length: u32,
+}
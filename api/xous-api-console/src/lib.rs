@@ -0,0 +1,147 @@
+pub mod api;
+
+use core::fmt::Write;
+use num_traits::ToPrimitive;
+
+#[repr(C, align(4096))]
+struct RegistrationPage(api::CommandRegistration);
+
+/// Copy as much of `s` into `dest` as fits, stopping at a character boundary, and
+/// return the number of bytes copied
+fn copy_truncated(dest: &mut [u8], s: &str) -> u32 {
+    let mut len = s.len().min(dest.len());
+    while !s.is_char_boundary(len) {
+        len -= 1;
+    }
+    dest[..len].copy_from_slice(&s.as_bytes()[..len]);
+    len as u32
+}
+
+pub struct Console {
+    conn: xous::CID,
+}
+
+impl Console {
+    /// Connect to the console shell, waiting for it to start if necessary
+    pub fn new(xns: &xous_api_names::XousNames) -> Result<Self, xous::Error> {
+        let conn = xns.request_connection_blocking(api::SERVER_NAME)?;
+        Ok(Console { conn })
+    }
+
+    fn send_registration(
+        &self,
+        opcode: api::Opcode,
+        registration: api::CommandRegistration,
+    ) -> Result<(), xous::Error> {
+        let mut page = Box::new(RegistrationPage(registration));
+        let buf = unsafe {
+            xous::MemoryRange::new(
+                &mut *page as *mut RegistrationPage as usize,
+                core::mem::size_of::<RegistrationPage>(),
+            )?
+        };
+        xous::send_message(
+            self.conn,
+            xous::Message::new_lend_mut(opcode.to_usize().unwrap(), buf, None, None),
+        )?;
+        match page.0.error {
+            0 => Ok(()),
+            e => Err(xous::Error::from_usize(e as usize)),
+        }
+    }
+
+    /// Add a command to the shell. Each time it is run, an `api::Invocation` is lent to
+    /// `sid` with the ID `opcode`; see `handle_invocation()`.
+    ///
+    /// # Arguments:
+    ///
+    ///     * name: The word that runs the command, up to `api::NAME_LEN` bytes
+    ///     * help: One line describing the command, which is cut short after
+    ///       `api::HELP_LEN` bytes
+    ///     * sid: The server that runs the command
+    ///     * opcode: The ID of the message that the server receives
+    ///
+    /// # Errors:
+    ///
+    ///     * InvalidString: the name is empty, too long or contains whitespace
+    ///     * ServerExists: a command with that name already exists
+    ///     * OutOfMemory: `api::MAX_COMMANDS` commands have already been added
+    pub fn register_command(
+        &self,
+        name: &str,
+        help: &str,
+        sid: xous::SID,
+        opcode: u32,
+    ) -> Result<(), xous::Error> {
+        if name.is_empty() || name.len() > api::NAME_LEN || name.contains(char::is_whitespace) {
+            return Err(xous::Error::InvalidString);
+        }
+        let mut registration = api::CommandRegistration {
+            name: [0u8; api::NAME_LEN],
+            name_length: 0,
+            help: [0u8; api::HELP_LEN],
+            help_length: 0,
+            sid: sid.to_array(),
+            opcode,
+            error: 0,
+        };
+        registration.name_length = copy_truncated(&mut registration.name, name);
+        registration.help_length = copy_truncated(&mut registration.help, help);
+        self.send_registration(api::Opcode::RegisterCommand, registration)
+    }
+
+    /// Remove a command that this process added
+    pub fn unregister_command(&self, name: &str) -> Result<(), xous::Error> {
+        let mut registration = api::CommandRegistration {
+            name: [0u8; api::NAME_LEN],
+            name_length: 0,
+            help: [0u8; api::HELP_LEN],
+            help_length: 0,
+            sid: [0; 4],
+            opcode: 0,
+            error: 0,
+        };
+        registration.name_length = copy_truncated(&mut registration.name, name);
+        self.send_registration(api::Opcode::UnregisterCommand, registration)
+    }
+}
+
+/// The output of a command, which is sent back to the shell. Text that doesn't fit is
+/// dropped.
+pub struct Output<'a> {
+    invocation: &'a mut api::Invocation,
+}
+
+impl Write for Output<'_> {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        let start = self.invocation.output_length as usize;
+        let written = copy_truncated(&mut self.invocation.output[start..], s);
+        self.invocation.output_length += written;
+        Ok(())
+    }
+}
+
+/// Run a command that was added with `Console::register_command()`. `run` is called
+/// with the arguments of the command and the output to write to. Messages that aren't
+/// an `api::Invocation` are ignored.
+pub fn handle_invocation<F>(envelope: &mut xous::MessageEnvelope, run: F)
+where
+    F: FnOnce(&str, &mut Output),
+{
+    if !matches!(envelope.body, xous::Message::MutableBorrow(_)) {
+        return;
+    }
+    let mem = match envelope.body.memory_message_mut() {
+        Some(mem) if mem.buf.len() >= core::mem::size_of::<api::Invocation>() => mem,
+        _ => return,
+    };
+    let invocation = unsafe { &mut *(mem.buf.as_mut_ptr() as *mut api::Invocation) };
+    let args = invocation
+        .args
+        .get(..invocation.args_length as usize)
+        .and_then(|args| core::str::from_utf8(args).ok())
+        .unwrap_or("")
+        .to_owned();
+    invocation.output_length = 0;
+    run(&args, &mut Output { invocation });
+}
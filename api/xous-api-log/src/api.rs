@@ -0,0 +1,394 @@
+use core::convert::TryInto;
+
+/// The address of the log server. It is well-known so that processes can log before
+/// the name server is running.
+pub const SERVER_NAME: &[u8; 16] = b"xous-log-server ";
+
+/// Set in the `offset` field of a `StandardOutput` or `StandardError` lend when more
+/// chunks of the same text follow. The log server holds on to the chunks until the last
+/// one arrives. This is the same as `xous::STRING_STREAM_CONTINUES`, so text sent with
+/// `StringBuffer::lend_streamed()` is understood as well.
+pub const STREAM_CONTINUES: usize = 1;
+
+/// Do not modify the discriminants in this structure. `StandardOutput` and
+/// `StandardError` are used in `libstd` directly.
+#[derive(num_derive::FromPrimitive, num_derive::ToPrimitive, Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Opcode {
+    /// A `LogRecord`, lent as a memory message
+    LogRecord = 0,
+
+    /// Text written to stdout, lent as a memory message
+    StandardOutput = 1,
+
+    /// Text written to stderr, lent as a memory message
+    StandardError = 2,
+
+    /// Fetch the records held in RAM. This is a mutable lend of a `RecordQuery`.
+    QueryRecords = 3,
+
+    /// Set or remove a level filter. This is a mutable lend of a `LevelFilterRule`.
+    SetLevelFilter = 4,
+
+    /// Fetch the level filters that apply to the calling process. This is a mutable
+    /// lend of a `LevelFilters`.
+    GetLevelFilters = 5,
+
+    /// A compact record whose arguments fit in a scalar message. `arg1` is the address of
+    /// the interned entry, and `arg2` to `arg4` hold the length of the encoded arguments in
+    /// the low four bits of the first byte and the `log::Level` in the high four bits,
+    /// followed by the arguments, in little-endian order.
+    CompactScalar = 6,
+
+    /// A compact record with longer arguments, lent as a `CompactRecord`
+    CompactRecord = 7,
+
+    /// Add a sink. This is a mutable lend of a `SinkRequest`, and `id` is filled in.
+    AddSink = 8,
+
+    /// Remove the sink `id`. This is a mutable lend of a `SinkRequest`.
+    RemoveSink = 9,
+
+    /// Set the level filter of the sink `id` to `level`. This is a mutable lend of a
+    /// `SinkRequest`.
+    SetSinkLevel = 10,
+
+    /// Set or remove the rate limit of a process. This is a mutable lend of a
+    /// `RateLimit`.
+    SetRateLimit = 11,
+
+    /// Send the characters that arrive on the console to a server. This is a mutable
+    /// lend of an `InputListener`.
+    ListenInput = 12,
+
+    /// A process has panicked, and the panic message follows
+    BeginPanic = 1000,
+
+    /// A piece of a panic message. The number of bytes is added to `PanicMessage0`,
+    /// and the bytes are packed into the four arguments.
+    PanicMessage0 = 1100,
+    PanicMessage1 = 1101,
+    PanicMessage2 = 1102,
+    PanicMessage3 = 1103,
+    PanicMessage4 = 1104,
+    PanicMessage5 = 1105,
+    PanicMessage6 = 1106,
+    PanicMessage7 = 1107,
+    PanicMessage8 = 1108,
+    PanicMessage9 = 1109,
+    PanicMessage10 = 1110,
+    PanicMessage11 = 1111,
+    PanicMessage12 = 1112,
+    PanicMessage13 = 1113,
+    PanicMessage14 = 1114,
+    PanicMessage15 = 1115,
+    PanicMessage16 = 1116,
+    PanicMessage17 = 1117,
+    PanicMessage18 = 1118,
+    PanicMessage19 = 1119,
+    PanicMessage20 = 1120,
+    PanicMessage21 = 1121,
+    PanicMessage22 = 1122,
+    PanicMessage23 = 1123,
+    PanicMessage24 = 1124,
+    PanicMessage25 = 1125,
+    PanicMessage26 = 1126,
+    PanicMessage27 = 1127,
+    PanicMessage28 = 1128,
+    PanicMessage29 = 1129,
+    PanicMessage30 = 1130,
+    PanicMessage31 = 1131,
+    PanicMessage32 = 1132,
+
+    /// The panicking process is terminating
+    PanicFinished = 1200,
+
+    /// Re-enable the log UART after a resume
+    ResumeLogger = 2000,
+}
+
+/// A single log message, lent to the log server with `Opcode::LogRecord`. Each string
+/// is followed by its length, and strings that don't fit are truncated.
+#[repr(C)]
+pub struct LogRecord {
+    pub file: [u8; 128],
+    pub file_length: u32,
+    pub line: Option<core::num::NonZeroU32>,
+    pub module: [u8; 128],
+    pub module_length: u32,
+    /// The `log::Level` of the record, as a `u32`
+    pub level: u32,
+    pub args: [u8; 2048],
+    pub args_length: u32,
+    /// Filled in by the log server with the generation of its level filters, if the
+    /// record was lent mutably. Clients fetch the filters again when it changes.
+    pub filter_generation: u32,
+}
+
+/// Used as the `records_per_second` of a `RateLimit` to remove the limit of a process,
+/// so that the default applies to it again
+pub const REMOVE_RATE_LIMIT: u32 = u32::MAX;
+
+/// Sent as a mutable lend with `Opcode::SetRateLimit`. Each process may send `burst` log
+/// records at once, and `records_per_second` after that. Records beyond that are
+/// dropped, and the number dropped is logged every few seconds. Output to stdout and
+/// stderr isn't limited. Only trusted processes may change the limits of other
+/// processes, or the default.
+#[repr(C)]
+pub struct RateLimit {
+    /// The process to limit, or 0 to set the default for processes without a limit
+    pub pid: u32,
+    /// 0 for no limit, or `REMOVE_RATE_LIMIT`
+    pub records_per_second: u32,
+    pub burst: u32,
+    /// Filled in with 1 if the limit was set or removed, or 0 if there was no limit to
+    /// remove or the caller isn't trusted to change `pid`
+    pub accepted: u32,
+}
+
+/// The most characters that are sent to an input listener in one message
+pub const INPUT_CHARS: usize = 8;
+
+/// Sent as a mutable lend with `Opcode::ListenInput`. Characters that arrive on the
+/// console are sent to the server `sid` as non-blocking scalar messages with the ID
+/// `opcode`. `arg1` holds the number of characters, up to `INPUT_CHARS`, and `arg2` and
+/// `arg3` hold the characters in little-endian order. There is only one listener, which
+/// must be a trusted process, and characters are dropped if its queue is full.
+#[repr(C)]
+pub struct InputListener {
+    pub sid: [u32; 4],
+    pub opcode: u32,
+    /// Filled in with 0 on success, or an `xous::Error` as a `u32`: `AccessDenied` if the
+    /// caller isn't trusted, or `ServerExists` if another process is already listening.
+    pub error: u32,
+}
+
+/// The sink ID of the console, which can't be removed
+pub const CONSOLE_SINK: u32 = 0;
+
+/// The sink ID of the RAM ring that serves `QueryRecords`, which can't be removed
+pub const RING_SINK: u32 = 1;
+
+#[derive(num_derive::FromPrimitive, num_derive::ToPrimitive, Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SinkKind {
+    /// A UART with the same register layout as the console, at `address`
+    Uart = 0,
+    /// A server that is sent a copy of the output. Each piece of output is moved to it
+    /// as a page with the ID `opcode`, and the number of bytes in `valid`. The bytes are
+    /// those that the console would show, with records ending in `\n`.
+    Forward = 1,
+    /// A file at `path`, which is only available in hosted mode
+    File = 2,
+}
+
+/// Sent as a mutable lend with `Opcode::AddSink`, `Opcode::RemoveSink` and
+/// `Opcode::SetSinkLevel`. These are refused with `AccessDenied` unless the caller is
+/// trusted.
+#[repr(C)]
+pub struct SinkRequest {
+    /// A `SinkKind` as a `u32`
+    pub kind: u32,
+    pub id: u32,
+    /// The most verbose `log::LevelFilter` to write to the sink, as a `u32`
+    pub level: u32,
+    pub address: u32,
+    pub sid: [u32; 4],
+    pub opcode: u32,
+    pub path: [u8; 256],
+    pub path_length: u32,
+    /// Filled in with 0 on success, or an `xous::Error` as a `u32`
+    pub error: u32,
+}
+
+/// The most bytes of encoded arguments in a compact record
+pub const COMPACT_ARGS_LEN: usize = 256;
+
+/// A compact record, lent to the log server with `Opcode::CompactRecord`. See
+/// `crate::compact` for the format.
+#[repr(C)]
+pub struct CompactRecord {
+    /// The address of the interned entry in the sending process
+    pub entry: u32,
+    /// The `log::Level` of the record, as a `u32`
+    pub level: u32,
+    pub args_length: u32,
+    pub args: [u8; COMPACT_ARGS_LEN],
+}
+
+/// Used as the `level` of a `LevelFilterRule` to remove the rule
+pub const REMOVE_FILTER: u32 = u32::MAX;
+
+/// Sent as a mutable lend with `Opcode::SetLevelFilter`. Records whose module path
+/// starts with `module` are limited to `level`. When several rules match a record, the
+/// one with the longest module prefix wins, and a rule for a PID wins over a rule for
+/// every process with the same prefix. An empty module matches every record. Only
+/// trusted processes may set rules for other processes, or for every process.
+#[repr(C)]
+pub struct LevelFilterRule {
+    pub module: [u8; 128],
+    pub module_length: u32,
+    /// The process that the rule applies to, or 0 for every process
+    pub pid: u32,
+    /// A `log::LevelFilter` as a `u32`, or `REMOVE_FILTER`
+    pub level: u32,
+    /// Filled in with 1 if the rule was set or removed. Setting fails once there are
+    /// `MAX_LEVEL_FILTERS` rules, removing fails if there was no such rule, and both
+    /// fail if the caller isn't trusted to change `pid`.
+    pub accepted: u32,
+}
+
+/// The most level filters that the log server holds
+pub const MAX_LEVEL_FILTERS: usize = 28;
+
+/// A level filter, as it applies to one process
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct ModuleLevel {
+    pub module: [u8; 128],
+    pub module_length: u32,
+    /// A `log::LevelFilter` as a `u32`
+    pub level: u32,
+}
+
+/// Sent as a mutable lend with `Opcode::GetLevelFilters`, and filled in with the
+/// filters that apply to the calling process. A module without a matching filter is
+/// limited by `log::max_level()` as set by the process itself.
+#[repr(C)]
+pub struct LevelFilters {
+    pub generation: u32,
+    pub count: u32,
+    pub filters: [ModuleLevel; MAX_LEVEL_FILTERS],
+}
+
+/// The number of bytes of encoded records that fit in a `RecordQuery`
+pub const QUERY_DATA_LEN: usize = 8192 - 160;
+
+/// Sent as a mutable lend with `Opcode::QueryRecords`. The log server fills `data` with
+/// as many matching records as fit, oldest first, each encoded by `Record::encode()`.
+#[repr(C)]
+pub struct RecordQuery {
+    /// Only return records with a sequence number greater than this, so that a client
+    /// can page through the buffer
+    pub after: u64,
+    /// The most verbose `log::Level` to return, as a `u32`, or 0 for all levels
+    pub level: u32,
+    /// Only return records from this PID, or 0 for all processes
+    pub pid: u32,
+    /// Only return records whose module path starts with this prefix
+    pub module: [u8; 128],
+    pub module_length: u32,
+    /// Filled in with the number of records in `data`
+    pub count: u32,
+    /// Filled in with 1 if more matching records follow the last one in `data`
+    pub more: u32,
+    /// Filled in with the number of bytes used in `data`
+    pub data_length: u32,
+    pub data: [u8; QUERY_DATA_LEN],
+}
+
+/// A log record that was kept in RAM by the log server
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    /// Increases by one for every record that the log server receives, starting at 1
+    pub sequence: u64,
+    /// The ticktimer's elapsed time in milliseconds, read just after the record arrived.
+    /// The log server asks for it without waiting, so a record can only be read out once
+    /// the time has come back, usually a moment after it was logged.
+    pub timestamp_ms: u64,
+    /// The process that logged the record, or 0 if unknown
+    pub pid: u32,
+    pub level: log::Level,
+    pub module: String,
+    pub file: String,
+    pub line: Option<u32>,
+    pub args: String,
+}
+
+/// The fixed-size part of an encoded `Record`
+const RECORD_HEADER_LEN: usize = 34;
+
+impl Record {
+    /// Append this record to `buf` in the format used by `RecordQuery::data`. Returns
+    /// the number of bytes written, or `None` if it doesn't fit.
+    pub fn encode(&self, buf: &mut [u8]) -> Option<usize> {
+        let strings = [
+            self.module.as_bytes(),
+            self.file.as_bytes(),
+            self.args.as_bytes(),
+        ];
+        let len = RECORD_HEADER_LEN + strings.iter().map(|s| s.len()).sum::<usize>();
+        if len > buf.len() || strings.iter().any(|s| s.len() > u16::MAX as usize) {
+            return None;
+        }
+        buf[0..8].copy_from_slice(&self.sequence.to_le_bytes());
+        buf[8..16].copy_from_slice(&self.timestamp_ms.to_le_bytes());
+        buf[16..20].copy_from_slice(&self.pid.to_le_bytes());
+        buf[20..24].copy_from_slice(&(self.level as u32).to_le_bytes());
+        buf[24..28].copy_from_slice(&self.line.unwrap_or(0).to_le_bytes());
+        let mut offset = 28;
+        for s in strings.iter() {
+            buf[offset..offset + 2].copy_from_slice(&(s.len() as u16).to_le_bytes());
+            offset += 2;
+        }
+        for s in strings.iter() {
+            buf[offset..offset + s.len()].copy_from_slice(s);
+            offset += s.len();
+        }
+        Some(len)
+    }
+
+    /// Decode a record from the start of `buf`, returning it and the number of bytes
+    /// that it used
+    pub fn decode(buf: &[u8]) -> Option<(Record, usize)> {
+        if buf.len() < RECORD_HEADER_LEN {
+            return None;
+        }
+        let u32_at =
+            |offset: usize| u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap());
+        let u64_at =
+            |offset: usize| u64::from_le_bytes(buf[offset..offset + 8].try_into().unwrap());
+        let len_at = |offset: usize| u16::from_le_bytes([buf[offset], buf[offset + 1]]) as usize;
+
+        let mut strings = [String::new(), String::new(), String::new()];
+        let mut offset = RECORD_HEADER_LEN;
+        for (i, s) in strings.iter_mut().enumerate() {
+            let len = len_at(28 + i * 2);
+            *s = String::from_utf8_lossy(buf.get(offset..offset + len)?).into_owned();
+            offset += len;
+        }
+        let [module, file, args] = strings;
+        let record = Record {
+            sequence: u64_at(0),
+            timestamp_ms: u64_at(8),
+            pid: u32_at(16),
+            level: level_from_u32(u32_at(20))?,
+            module,
+            file,
+            line: match u32_at(24) {
+                0 => None,
+                line => Some(line),
+            },
+            args,
+        };
+        Some((record, offset))
+    }
+}
+
+/// Convert a `log::LevelFilter` that was sent as a `u32` back
+pub fn level_filter_from_u32(level: u32) -> Option<log::LevelFilter> {
+    match level {
+        0 => Some(log::LevelFilter::Off),
+        _ => level_from_u32(level).map(|l| l.to_level_filter()),
+    }
+}
+
+/// Convert the `level` field of a `LogRecord` back into a `log::Level`
+pub fn level_from_u32(level: u32) -> Option<log::Level> {
+    match level {
+        1 => Some(log::Level::Error),
+        2 => Some(log::Level::Warn),
+        3 => Some(log::Level::Info),
+        4 => Some(log::Level::Debug),
+        5 => Some(log::Level::Trace),
+        _ => None,
+    }
+}
@@ -0,0 +1,340 @@
+//! Compact log records, which are formatted on the host rather than on the device.
+//!
+//! `compact_info!()` and its siblings take the same arguments as `log::info!()`, but the
+//! format string, level, module, file and line never leave the process. They are
+//! interned at build time as the name of a one-byte static, and only the address of that
+//! static and the raw arguments are sent to the log server. The log server writes these
+//! to the console as
+//!
+//! ```text
+//! XLOG <pid> <address in hex> <encoded arguments in hex>
+//! ```
+//!
+//! and `tools/src/bin/decode-log.rs` turns them back into text using the symbol table of
+//! the process's ELF file. The symbol names are
+//!
+//! ```text
+//! xous_log;<level>;<module>;<file>;<line>;<column>;<names>;<format string>
+//! ```
+//!
+//! where `<names>` lists the names of the named arguments, each followed by a comma.
+//! Named arguments are sent first, in that order, followed by the positional ones.
+//! Variables that the format string captures by name, as in `"{count}"`, can't be sent
+//! and are shown as `{?}`; pass them as `count = count` instead.
+//!
+//! Each argument is encoded as a tag byte followed by its value:
+//!
+//! | Tag | Value                                           |
+//! |-----|-------------------------------------------------|
+//! | 0   | unsigned integer, LEB128                        |
+//! | 1   | signed integer, zigzag and then LEB128          |
+//! | 2   | `false`                                         |
+//! | 3   | `true`                                          |
+//! | 4   | `char`, as an unsigned LEB128 code point        |
+//! | 5   | string, as an unsigned LEB128 length and UTF-8  |
+//!
+//! Arguments that don't fit in `api::COMPACT_ARGS_LEN` bytes are dropped, and strings
+//! are cut short to fit. Only the types above can be logged this way. Outside of Xous
+//! there is nothing to decode the records with, so the macros log text as usual.
+
+use crate::api;
+use core::convert::TryInto;
+use core::sync::atomic::Ordering;
+use num_traits::ToPrimitive;
+
+#[doc(hidden)]
+pub use log;
+
+const TAG_UNSIGNED: u8 = 0;
+const TAG_SIGNED: u8 = 1;
+const TAG_FALSE: u8 = 2;
+const TAG_TRUE: u8 = 3;
+const TAG_CHAR: u8 = 4;
+const TAG_STR: u8 = 5;
+
+/// The most argument bytes that are sent in a scalar message rather than a lend
+const SCALAR_ARGS_LEN: usize = 11;
+
+/// Collects encoded arguments, dropping those that don't fit
+pub struct Encoder {
+    buf: [u8; api::COMPACT_ARGS_LEN],
+    len: usize,
+    full: bool,
+}
+
+impl Encoder {
+    fn new() -> Self {
+        Encoder {
+            buf: [0u8; api::COMPACT_ARGS_LEN],
+            len: 0,
+            full: false,
+        }
+    }
+
+    fn push_leb128(&mut self, mut value: u64, start: usize) -> Option<usize> {
+        let mut offset = start;
+        loop {
+            let byte = (value & 0x7f) as u8;
+            value >>= 7;
+            *self.buf.get_mut(offset)? = if value == 0 { byte } else { byte | 0x80 };
+            offset += 1;
+            if value == 0 {
+                return Some(offset);
+            }
+        }
+    }
+
+    /// Append a tag, returning `false` if there is no room for it
+    fn push_tag(&mut self, tag: u8) -> bool {
+        if self.full || self.len >= self.buf.len() {
+            self.full = true;
+            return false;
+        }
+        self.buf[self.len] = tag;
+        true
+    }
+
+    fn push_tagged(&mut self, tag: u8, value: u64) {
+        if !self.push_tag(tag) {
+            return;
+        }
+        match self.push_leb128(value, self.len + 1) {
+            Some(end) => self.len = end,
+            None => self.full = true,
+        }
+    }
+
+    fn push_unsigned(&mut self, value: u64) {
+        self.push_tagged(TAG_UNSIGNED, value);
+    }
+
+    fn push_signed(&mut self, value: i64) {
+        self.push_tagged(TAG_SIGNED, ((value << 1) ^ (value >> 63)) as u64);
+    }
+
+    fn push_str(&mut self, s: &str) {
+        if !self.push_tag(TAG_STR) {
+            return;
+        }
+        // Leave room for the tag and a length of up to two bytes, and cut the string at a
+        // character boundary
+        let room = self.buf.len().saturating_sub(self.len + 3);
+        let mut len = s.len().min(room);
+        while !s.is_char_boundary(len) {
+            len -= 1;
+        }
+        let start = match self.push_leb128(len as u64, self.len + 1) {
+            Some(start) => start,
+            None => {
+                self.full = true;
+                return;
+            }
+        };
+        self.buf[start..start + len].copy_from_slice(&s.as_bytes()[..len]);
+        self.len = start + len;
+        if len < s.len() {
+            self.full = true;
+        }
+    }
+}
+
+/// A value that can be an argument of a compact record
+pub trait CompactArg {
+    fn encode(&self, encoder: &mut Encoder);
+}
+
+macro_rules! compact_unsigned {
+    ($($t:ty),*) => {
+        $(impl CompactArg for $t {
+            fn encode(&self, encoder: &mut Encoder) {
+                encoder.push_unsigned(*self as u64);
+            }
+        })*
+    };
+}
+
+macro_rules! compact_signed {
+    ($($t:ty),*) => {
+        $(impl CompactArg for $t {
+            fn encode(&self, encoder: &mut Encoder) {
+                encoder.push_signed(*self as i64);
+            }
+        })*
+    };
+}
+
+compact_unsigned!(u8, u16, u32, u64, usize);
+compact_signed!(i8, i16, i32, i64, isize);
+
+impl CompactArg for bool {
+    fn encode(&self, encoder: &mut Encoder) {
+        if encoder.push_tag(if *self { TAG_TRUE } else { TAG_FALSE }) {
+            encoder.len += 1;
+        }
+    }
+}
+
+impl CompactArg for char {
+    fn encode(&self, encoder: &mut Encoder) {
+        encoder.push_tagged(TAG_CHAR, *self as u64);
+    }
+}
+
+impl CompactArg for str {
+    fn encode(&self, encoder: &mut Encoder) {
+        encoder.push_str(self);
+    }
+}
+
+impl CompactArg for String {
+    fn encode(&self, encoder: &mut Encoder) {
+        encoder.push_str(self);
+    }
+}
+
+impl<T: CompactArg + ?Sized> CompactArg for &T {
+    fn encode(&self, encoder: &mut Encoder) {
+        (**self).encode(encoder);
+    }
+}
+
+#[repr(C, align(4096))]
+struct CompactPage(api::CompactRecord);
+
+/// Send a compact record. This is called by the macros, with `entry` being the address
+/// of the interned static.
+#[doc(hidden)]
+pub fn log_compact(level: log::Level, module: &str, entry: usize, args: &[&dyn CompactArg]) {
+    let conn = crate::LOG_CONNECTION.load(Ordering::Relaxed);
+    if conn == 0 || !crate::filters_allow(conn, level, module) {
+        return;
+    }
+
+    let mut encoder = Encoder::new();
+    for arg in args {
+        arg.encode(&mut encoder);
+    }
+    let args = &encoder.buf[..encoder.len];
+
+    if args.len() <= SCALAR_ARGS_LEN {
+        let mut words = [0u8; 12];
+        words[0] = args.len() as u8 | (level as u8) << 4;
+        words[1..1 + args.len()].copy_from_slice(args);
+        let word =
+            |i: usize| u32::from_le_bytes(words[i * 4..i * 4 + 4].try_into().unwrap()) as usize;
+        xous::send_message(
+            conn,
+            xous::Message::new_scalar(
+                api::Opcode::CompactScalar.to_usize().unwrap(),
+                entry,
+                word(0),
+                word(1),
+                word(2),
+            ),
+        )
+        .ok();
+        return;
+    }
+
+    let mut page = CompactPage(api::CompactRecord {
+        entry: entry as u32,
+        level: level as u32,
+        args_length: args.len() as u32,
+        args: [0u8; api::COMPACT_ARGS_LEN],
+    });
+    page.0.args[..args.len()].copy_from_slice(args);
+    let buf = unsafe {
+        xous::MemoryRange::new(
+            &mut page as *mut CompactPage as usize,
+            core::mem::size_of::<CompactPage>(),
+        )
+        .unwrap()
+    };
+    xous::send_message(
+        conn,
+        xous::Message::new_lend(
+            api::Opcode::CompactRecord.to_usize().unwrap(),
+            buf,
+            None,
+            None,
+        ),
+    )
+    .ok();
+}
+
+/// Log a compact record at the given level, which must be a literal from 1 (`Error`) to 5
+/// (`Trace`). Use `compact_error!()` and the like instead.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! compact_log {
+    ($level_num:literal, $level:expr, $fmt:literal $(, $($args:tt)*)?) => {
+        $crate::compact_log!(@args $level_num, $level, $fmt, [] [] $(, $($args)*)?)
+    };
+    // Sort the arguments into named and positional ones, one at a time
+    (@args $level_num:literal, $level:expr, $fmt:literal, [$($pos:expr),*]
+        [$($name:ident = $named:expr),*], $next:ident = $value:expr $(, $($rest:tt)*)?) => {
+        $crate::compact_log!(@args $level_num, $level, $fmt, [$($pos),*]
+            [$($name = $named,)* $next = $value] $(, $($rest)*)?)
+    };
+    (@args $level_num:literal, $level:expr, $fmt:literal, [$($pos:expr),*]
+        [$($name:ident = $named:expr),*], $next:expr $(, $($rest:tt)*)?) => {
+        $crate::compact_log!(@args $level_num, $level, $fmt, [$($pos,)* $next]
+            [$($name = $named),*] $(, $($rest)*)?)
+    };
+    (@args $level_num:literal, $level:expr, $fmt:literal, [$($pos:expr),*]
+        [$($name:ident = $named:expr),*] $(,)?) => {{
+        #[cfg(target_os = "xous")]
+        {
+            // Check the format string and arguments, as `log::log!()` would
+            if false {
+                let _ = ::core::format_args!($fmt $(, $pos)* $(, $name = $named)*);
+            }
+            #[export_name = concat!(
+                "xous_log;", $level_num, ";", module_path!(), ";", file!(), ";", line!(), ";",
+                column!(), ";", $(stringify!($name), ",",)* ";", $fmt
+            )]
+            static ENTRY: u8 = 0;
+            $crate::compact::log_compact(
+                $level,
+                module_path!(),
+                &ENTRY as *const u8 as usize,
+                &[
+                    $(&$named as &dyn $crate::compact::CompactArg,)*
+                    $(&$pos as &dyn $crate::compact::CompactArg,)*
+                ],
+            );
+        }
+        #[cfg(not(target_os = "xous"))]
+        $crate::compact::log::log!($level, $fmt $(, $pos)* $(, $name = $named)*);
+    }};
+}
+
+/// Like `log::error!()`, but formatted on the host. See `xous_api_log::compact`.
+#[macro_export]
+macro_rules! compact_error {
+    ($($args:tt)+) => { $crate::compact_log!(1, $crate::compact::log::Level::Error, $($args)+) };
+}
+
+/// Like `log::warn!()`, but formatted on the host. See `xous_api_log::compact`.
+#[macro_export]
+macro_rules! compact_warn {
+    ($($args:tt)+) => { $crate::compact_log!(2, $crate::compact::log::Level::Warn, $($args)+) };
+}
+
+/// Like `log::info!()`, but formatted on the host. See `xous_api_log::compact`.
+#[macro_export]
+macro_rules! compact_info {
+    ($($args:tt)+) => { $crate::compact_log!(3, $crate::compact::log::Level::Info, $($args)+) };
+}
+
+/// Like `log::debug!()`, but formatted on the host. See `xous_api_log::compact`.
+#[macro_export]
+macro_rules! compact_debug {
+    ($($args:tt)+) => { $crate::compact_log!(4, $crate::compact::log::Level::Debug, $($args)+) };
+}
+
+/// Like `log::trace!()`, but formatted on the host. See `xous_api_log::compact`.
+#[macro_export]
+macro_rules! compact_trace {
+    ($($args:tt)+) => { $crate::compact_log!(5, $crate::compact::log::Level::Trace, $($args)+) };
+}
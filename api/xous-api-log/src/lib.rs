@@ -0,0 +1,648 @@
+#![cfg_attr(target_os = "none", no_std)]
+
+pub mod api;
+pub mod compact;
+mod cursor;
+
+use core::cell::UnsafeCell;
+use core::fmt::Write;
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use num_traits::ToPrimitive;
+
+#[derive(Debug)]
+pub enum LogError {
+    LoggerExists,
+    NoConnection,
+}
+
+/// The connection to the log server. Connection IDs start at 2, so 0 means that
+/// `init()` hasn't been called yet.
+static LOG_CONNECTION: AtomicU32 = AtomicU32::new(0);
+
+struct XousLogger;
+static XOUS_LOGGER: XousLogger = XousLogger;
+
+/// `LogRecord`s are lent to the log server, so they need a page of their own
+#[repr(C, align(4096))]
+struct RecordPage(api::LogRecord);
+
+/// A `RecordQuery` spans two pages
+#[repr(C, align(4096))]
+struct QueryPages(api::RecordQuery);
+
+#[repr(C, align(4096))]
+struct FilterPage(api::LevelFilters);
+
+#[repr(C, align(4096))]
+struct RulePage(api::LevelFilterRule);
+
+#[repr(C, align(4096))]
+struct SinkPage(api::SinkRequest);
+
+#[repr(C, align(4096))]
+struct RateLimitPage(api::RateLimit);
+
+#[repr(C, align(4096))]
+struct ListenerPage(api::InputListener);
+
+/// Text written with `print()` is lent a page at a time
+#[repr(C, align(4096))]
+struct TextPage([u8; 4096]);
+
+/// The level filters that apply to this process, as fetched from the log server
+struct Filters {
+    /// The generation of the filters held here, or 0 if they were never fetched
+    generation: u32,
+    modules: Vec<(String, log::LevelFilter)>,
+    /// `log::max_level()` as the process set it, from before the filters raised it
+    base: Option<log::LevelFilter>,
+}
+
+impl Filters {
+    /// The most verbose level allowed for `module`, or `None` if nothing limits it
+    /// beyond `log::max_level()`
+    fn level_for(&self, module: &str) -> Option<log::LevelFilter> {
+        self.modules
+            .iter()
+            .filter(|(prefix, _)| module.starts_with(prefix.as_str()))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map(|(_, level)| *level)
+            .or(self.base)
+    }
+
+    /// Fetch the filters from the log server, and raise `log::max_level()` so that
+    /// records that they allow reach `XousLogger::log()` at all
+    fn refresh(&mut self, conn: xous::CID) {
+        // Don't ask again until the generation changes, even if the server is too old
+        // to answer
+        self.generation = SERVER_GENERATION.load(Ordering::Relaxed);
+
+        let mut page = Box::new(FilterPage(api::LevelFilters {
+            generation: 0,
+            count: 0,
+            filters: [api::ModuleLevel {
+                module: [0u8; 128],
+                module_length: 0,
+                level: 0,
+            }; api::MAX_LEVEL_FILTERS],
+        }));
+        let buf = match unsafe {
+            xous::MemoryRange::new(
+                &mut *page as *mut FilterPage as usize,
+                core::mem::size_of::<FilterPage>(),
+            )
+        } {
+            Ok(buf) => buf,
+            Err(_) => return,
+        };
+        let opcode = api::Opcode::GetLevelFilters.to_usize().unwrap();
+        if xous::send_message(conn, xous::Message::new_lend_mut(opcode, buf, None, None)).is_err() {
+            return;
+        }
+
+        let reply = &page.0;
+        if reply.generation != 0 {
+            self.generation = reply.generation;
+        }
+        let count = (reply.count as usize).min(api::MAX_LEVEL_FILTERS);
+        self.modules = reply.filters[..count]
+            .iter()
+            .filter_map(|f| {
+                let module =
+                    core::str::from_utf8(f.module.get(..f.module_length as usize)?).ok()?;
+                Some((module.to_owned(), api::level_filter_from_u32(f.level)?))
+            })
+            .collect();
+
+        match self.modules.iter().map(|(_, level)| *level).max() {
+            Some(most_verbose) => {
+                let base = *self.base.get_or_insert_with(log::max_level);
+                log::set_max_level(base.max(most_verbose));
+            }
+            None => {
+                if let Some(base) = self.base.take() {
+                    log::set_max_level(base);
+                }
+            }
+        }
+    }
+}
+
+/// `Filters` are shared by every thread that logs. A `Mutex` can't be used, because the
+/// ticktimer that implements it also logs through this crate.
+struct FilterCache {
+    locked: AtomicBool,
+    filters: UnsafeCell<Filters>,
+}
+
+unsafe impl Sync for FilterCache {}
+
+impl FilterCache {
+    fn with<R>(&self, f: impl FnOnce(&mut Filters) -> R) -> R {
+        while self.locked.swap(true, Ordering::Acquire) {
+            xous::yield_slice();
+        }
+        let result = f(unsafe { &mut *self.filters.get() });
+        self.locked.store(false, Ordering::Release);
+        result
+    }
+}
+
+static FILTERS: FilterCache = FilterCache {
+    locked: AtomicBool::new(false),
+    filters: UnsafeCell::new(Filters {
+        generation: 0,
+        modules: Vec::new(),
+        base: None,
+    }),
+};
+
+/// `true` if a record at `level` from `module` passes the level filters, fetching them
+/// again first if they have changed
+fn filters_allow(conn: xous::CID, level: log::Level, module: &str) -> bool {
+    level <= log::max_level()
+        && FILTERS.with(|filters| {
+            if filters.generation != SERVER_GENERATION.load(Ordering::Relaxed) {
+                filters.refresh(conn);
+            }
+            filters
+                .level_for(module)
+                .map(|allowed| level <= allowed)
+                .unwrap_or(true)
+        })
+}
+
+/// The generation of the log server's filters, as of the last record that was sent. The
+/// server starts at 1, so the filters are fetched with the first record.
+static SERVER_GENERATION: AtomicU32 = AtomicU32::new(1);
+
+/// Copy as much of `src` as fits into `dest`, returning the number of bytes copied
+fn copy_truncated(dest: &mut [u8], src: &str) -> u32 {
+    let len = src.len().min(dest.len());
+    dest[..len].copy_from_slice(&src.as_bytes()[..len]);
+    len as u32
+}
+
+impl log::Log for XousLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= log::max_level()
+            && FILTERS.with(|filters| {
+                filters
+                    .level_for(metadata.target())
+                    .map(|level| metadata.level() <= level)
+                    .unwrap_or(true)
+            })
+    }
+
+    fn log(&self, record: &log::Record) {
+        let conn = LOG_CONNECTION.load(Ordering::Relaxed);
+        let module = record.module_path().unwrap_or(record.target());
+        if conn == 0 || !filters_allow(conn, record.level(), module) {
+            return;
+        }
+
+        let mut page = RecordPage(api::LogRecord {
+            file: [0u8; 128],
+            file_length: 0,
+            line: record.line().and_then(core::num::NonZeroU32::new),
+            module: [0u8; 128],
+            module_length: 0,
+            level: record.level() as u32,
+            args: [0u8; 2048],
+            args_length: 0,
+            filter_generation: 0,
+        });
+        let lr = &mut page.0;
+        lr.file_length = copy_truncated(&mut lr.file, record.file().unwrap_or_default());
+        lr.module_length = copy_truncated(&mut lr.module, record.module_path().unwrap_or_default());
+        let mut args = cursor::BufferWrapper::new(&mut lr.args);
+        // A message that doesn't fit is cut off where the buffer ends
+        write!(args, "{}", record.args()).ok();
+        lr.args_length = args.len() as u32;
+
+        let buf = unsafe {
+            xous::MemoryRange::new(
+                &mut page as *mut RecordPage as usize,
+                core::mem::size_of::<RecordPage>(),
+            )
+            .unwrap()
+        };
+        let opcode = api::Opcode::LogRecord.to_usize().unwrap();
+        if xous::send_message(conn, xous::Message::new_lend_mut(opcode, buf, None, None)).is_ok() {
+            // Older log servers leave this at 0
+            let generation = page.0.filter_generation;
+            if generation != 0 {
+                SERVER_GENERATION.store(generation, Ordering::Relaxed);
+            }
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+fn install(conn: xous::CID) -> Result<(), LogError> {
+    LOG_CONNECTION.store(conn, Ordering::Relaxed);
+    log::set_logger(&XOUS_LOGGER).map_err(|_| LogError::LoggerExists)?;
+    log::set_max_level(log::LevelFilter::Info);
+    Ok(())
+}
+
+/// Connect to the log server and install it as the `log` backend. Fails if the log
+/// server isn't running yet.
+pub fn init() -> Result<(), LogError> {
+    let conn = xous::try_connect(xous::SID::from_bytes(api::SERVER_NAME).unwrap())
+        .map_err(|_| LogError::NoConnection)?;
+    install(conn)
+}
+
+/// Like `init()`, but waits for the log server to start
+pub fn init_wait() -> Result<(), LogError> {
+    let sid = xous::SID::from_bytes(api::SERVER_NAME).unwrap();
+    loop {
+        match xous::try_connect(sid) {
+            Ok(conn) => return install(conn),
+            Err(xous::Error::ServerNotFound) => xous::yield_slice(),
+            Err(_) => return Err(LogError::NoConnection),
+        }
+    }
+}
+
+/// Re-enable the log UART after a resume
+pub fn resume() {
+    let conn = LOG_CONNECTION.load(Ordering::Relaxed);
+    if conn != 0 {
+        xous::send_message(
+            conn,
+            xous::Message::new_scalar(api::Opcode::ResumeLogger.to_usize().unwrap(), 0, 0, 0, 0),
+        )
+        .ok();
+    }
+}
+
+/// Write `text` to the log server's standard output. Text that is longer than a page is
+/// sent in several chunks, which the log server joins back together, so that output from
+/// other processes doesn't end up in the middle of it.
+pub fn print(text: &str) -> Result<(), xous::Error> {
+    if text.is_empty() {
+        return Ok(());
+    }
+    let conn = LOG_CONNECTION.load(Ordering::Relaxed);
+    if conn == 0 {
+        return Err(xous::Error::ServerNotFound);
+    }
+    let mut page = Box::new(TextPage([0; 4096]));
+    let buf = unsafe {
+        xous::MemoryRange::new(
+            &mut *page as *mut TextPage as usize,
+            core::mem::size_of::<TextPage>(),
+        )?
+    };
+    let mut chunks = text.as_bytes().chunks(page.0.len()).peekable();
+    while let Some(chunk) = chunks.next() {
+        page.0[..chunk.len()].copy_from_slice(chunk);
+        let offset = if chunks.peek().is_some() {
+            xous::MemoryAddress::new(api::STREAM_CONTINUES)
+        } else {
+            None
+        };
+        xous::send_message(
+            conn,
+            xous::Message::new_lend(
+                api::Opcode::StandardOutput.to_usize().unwrap(),
+                buf,
+                offset,
+                xous::MemorySize::new(chunk.len()),
+            ),
+        )?;
+    }
+    Ok(())
+}
+
+/// Select the records returned by `query_records()`. The default matches every record.
+#[derive(Debug, Default, Clone)]
+pub struct RecordFilter<'a> {
+    /// The most verbose level to return
+    pub level: Option<log::Level>,
+    /// Only return records whose module path starts with this
+    pub module: Option<&'a str>,
+    /// Only return records from this process
+    pub pid: Option<u32>,
+}
+
+/// Fetch records that the log server has kept in RAM. Only the most recent records are
+/// kept, so older ones may already be gone.
+///
+/// # Arguments:
+///
+///     * filter: Which records to return
+///     * after: Only return records with a sequence number greater than this. Pass 0 to
+///       start from the oldest record, and then the sequence number of the last record
+///       returned to fetch the next batch.
+///
+/// # Returns:
+///
+///     * The records, oldest first, and whether more records match after the last one
+pub fn query_records(
+    filter: &RecordFilter,
+    after: u64,
+) -> Result<(Vec<api::Record>, bool), xous::Error> {
+    let conn = xous::connect(xous::SID::from_bytes(api::SERVER_NAME).unwrap())?;
+    let mut pages = Box::new(QueryPages(api::RecordQuery {
+        after,
+        level: filter.level.map(|l| l as u32).unwrap_or(0),
+        pid: filter.pid.unwrap_or(0),
+        module: [0u8; 128],
+        module_length: 0,
+        count: 0,
+        more: 0,
+        data_length: 0,
+        data: [0u8; api::QUERY_DATA_LEN],
+    }));
+    let query = &mut pages.0;
+    query.module_length = copy_truncated(&mut query.module, filter.module.unwrap_or_default());
+
+    let buf = unsafe {
+        xous::MemoryRange::new(
+            &mut *pages as *mut QueryPages as usize,
+            core::mem::size_of::<QueryPages>(),
+        )?
+    };
+    xous::send_message(
+        conn,
+        xous::Message::new_lend_mut(
+            api::Opcode::QueryRecords.to_usize().unwrap(),
+            buf,
+            None,
+            None,
+        ),
+    )?;
+
+    let query = &pages.0;
+    let data = &query.data[..(query.data_length as usize).min(api::QUERY_DATA_LEN)];
+    let mut records = Vec::with_capacity(query.count as usize);
+    let mut offset = 0;
+    while records.len() < query.count as usize {
+        let (record, len) =
+            api::Record::decode(&data[offset..]).ok_or(xous::Error::InternalError)?;
+        records.push(record);
+        offset += len;
+    }
+    Ok((records, query.more != 0))
+}
+
+fn send_filter_rule(module: &str, pid: Option<u32>, level: u32) -> Result<bool, xous::Error> {
+    let conn = xous::connect(xous::SID::from_bytes(api::SERVER_NAME).unwrap())?;
+    let mut page = Box::new(RulePage(api::LevelFilterRule {
+        module: [0u8; 128],
+        module_length: 0,
+        pid: pid.unwrap_or(0),
+        level,
+        accepted: 0,
+    }));
+    if module.len() > page.0.module.len() {
+        return Err(xous::Error::InvalidString);
+    }
+    page.0.module_length = copy_truncated(&mut page.0.module, module);
+
+    let buf = unsafe {
+        xous::MemoryRange::new(
+            &mut *page as *mut RulePage as usize,
+            core::mem::size_of::<RulePage>(),
+        )?
+    };
+    xous::send_message(
+        conn,
+        xous::Message::new_lend_mut(
+            api::Opcode::SetLevelFilter.to_usize().unwrap(),
+            buf,
+            None,
+            None,
+        ),
+    )?;
+    Ok(page.0.accepted != 0)
+}
+
+/// Limit the records that are logged from modules whose path starts with `module` to
+/// `level`, without rebuilding anything. This can make a process more verbose than it
+/// asked for with `log::set_max_level()`, as well as quieter.
+///
+/// Processes pick up the change the next time that they send a record, and drop the
+/// records that it filters out before they are sent.
+///
+/// # Arguments:
+///
+///     * module: A module path prefix, such as `"mbox2"` or `"xous_ticktimer::heartbeat"`.
+///       An empty prefix matches every module.
+///     * pid: The process to apply the filter to, or `None` for every process. Only
+///       trusted processes may name a process other than their own.
+///     * level: The most verbose level to log
+///
+/// # Returns:
+///
+///     * `false` if the log server already holds `api::MAX_LEVEL_FILTERS` filters, or
+///       if the calling process isn't trusted to change `pid`
+pub fn set_level_filter(
+    module: &str,
+    pid: Option<u32>,
+    level: log::LevelFilter,
+) -> Result<bool, xous::Error> {
+    send_filter_rule(module, pid, level as u32)
+}
+
+/// Remove a filter that was set by `set_level_filter()`, returning `false` if there was
+/// no such filter or the calling process isn't trusted to remove it
+pub fn remove_level_filter(module: &str, pid: Option<u32>) -> Result<bool, xous::Error> {
+    send_filter_rule(module, pid, api::REMOVE_FILTER)
+}
+
+/// A place for the log server to write output to, in addition to the console
+#[derive(Debug, Clone, Copy)]
+pub enum Sink<'a> {
+    /// A UART with the same register layout as the console, at this physical address.
+    /// Only the UARTs that the log server was built to know about are accepted.
+    Uart(usize),
+    /// A server that is sent a copy of all output. See `api::SinkKind::Forward`.
+    Forward { sid: xous::SID, opcode: usize },
+    /// A file on the host, in hosted mode
+    File(&'a str),
+}
+
+fn send_sink_request(opcode: api::Opcode, request: api::SinkRequest) -> Result<u32, xous::Error> {
+    let conn = xous::connect(xous::SID::from_bytes(api::SERVER_NAME).unwrap())?;
+    let mut page = Box::new(SinkPage(request));
+    let buf = unsafe {
+        xous::MemoryRange::new(
+            &mut *page as *mut SinkPage as usize,
+            core::mem::size_of::<SinkPage>(),
+        )?
+    };
+    xous::send_message(
+        conn,
+        xous::Message::new_lend_mut(opcode.to_usize().unwrap(), buf, None, None),
+    )?;
+    match page.0.error {
+        0 => Ok(page.0.id),
+        e => Err(xous::Error::from_usize(e as usize)),
+    }
+}
+
+fn sink_request(id: u32, level: log::LevelFilter) -> api::SinkRequest {
+    api::SinkRequest {
+        kind: 0,
+        id,
+        level: level as u32,
+        address: 0,
+        sid: [0; 4],
+        opcode: 0,
+        path: [0u8; 256],
+        path_length: 0,
+        error: 0,
+    }
+}
+
+/// Have the log server write records at `level` and below, and all other output, to
+/// `sink` as well as to the console. Returns an ID for `remove_sink()` and
+/// `set_sink_level()`.
+///
+/// Sinks can only be added, removed or changed by trusted processes. The others get
+/// `AccessDenied`.
+pub fn add_sink(sink: &Sink, level: log::LevelFilter) -> Result<u32, xous::Error> {
+    let mut request = sink_request(0, level);
+    match sink {
+        Sink::Uart(address) => {
+            request.kind = api::SinkKind::Uart as u32;
+            request.address = *address as u32;
+        }
+        Sink::Forward { sid, opcode } => {
+            request.kind = api::SinkKind::Forward as u32;
+            request.sid = sid.to_array();
+            request.opcode = *opcode as u32;
+        }
+        Sink::File(path) => {
+            if path.len() > request.path.len() {
+                return Err(xous::Error::InvalidString);
+            }
+            request.kind = api::SinkKind::File as u32;
+            request.path_length = copy_truncated(&mut request.path, path);
+        }
+    }
+    send_sink_request(api::Opcode::AddSink, request)
+}
+
+/// Stop writing to a sink that was added with `add_sink()`
+pub fn remove_sink(id: u32) -> Result<(), xous::Error> {
+    send_sink_request(
+        api::Opcode::RemoveSink,
+        sink_request(id, log::LevelFilter::Off),
+    )
+    .map(|_| ())
+}
+
+/// Change the level filter of a sink. This also works for `api::CONSOLE_SINK` and
+/// `api::RING_SINK`. Setting it to `Off` stops all output to the sink.
+pub fn set_sink_level(id: u32, level: log::LevelFilter) -> Result<(), xous::Error> {
+    send_sink_request(api::Opcode::SetSinkLevel, sink_request(id, level)).map(|_| ())
+}
+
+fn send_rate_limit(pid: u32, records_per_second: u32, burst: u32) -> Result<bool, xous::Error> {
+    let conn = xous::connect(xous::SID::from_bytes(api::SERVER_NAME).unwrap())?;
+    let mut page = Box::new(RateLimitPage(api::RateLimit {
+        pid,
+        records_per_second,
+        burst,
+        accepted: 0,
+    }));
+    let buf = unsafe {
+        xous::MemoryRange::new(
+            &mut *page as *mut RateLimitPage as usize,
+            core::mem::size_of::<RateLimitPage>(),
+        )?
+    };
+    xous::send_message(
+        conn,
+        xous::Message::new_lend_mut(
+            api::Opcode::SetRateLimit.to_usize().unwrap(),
+            buf,
+            None,
+            None,
+        ),
+    )?;
+    Ok(page.0.accepted != 0)
+}
+
+/// Limit how many log records a process may send. Records beyond the limit are dropped
+/// by the log server, which reports how many were dropped every few seconds.
+///
+/// # Arguments:
+///
+///     * pid: The process to limit, or `None` to set the default for every process that
+///       doesn't have a limit of its own. Only trusted processes may name a process other
+///       than their own.
+///     * records_per_second: The sustained rate, or 0 to remove any limit
+///     * burst: How many records may be sent at once
+///
+/// # Errors:
+///
+///     * AccessDenied: The calling process isn't trusted to change the limit of `pid`
+pub fn set_rate_limit(
+    pid: Option<u32>,
+    records_per_second: u32,
+    burst: u32,
+) -> Result<(), xous::Error> {
+    if records_per_second == api::REMOVE_RATE_LIMIT {
+        return Err(xous::Error::InvalidLimit);
+    }
+    match send_rate_limit(pid.unwrap_or(0), records_per_second, burst)? {
+        true => Ok(()),
+        false => Err(xous::Error::AccessDenied),
+    }
+}
+
+/// Return a process to the default rate limit, returning `false` if it didn't have a
+/// limit of its own or the calling process isn't trusted to change it
+pub fn remove_rate_limit(pid: u32) -> Result<bool, xous::Error> {
+    send_rate_limit(pid, api::REMOVE_RATE_LIMIT, 0)
+}
+
+/// Have the characters that arrive on the console sent to `sid` as scalar messages with
+/// the ID `opcode`. See `api::InputListener` for the format. Only one process may listen,
+/// it must be trusted, and the log server only reads the console if it was built with
+/// input enabled. The log server learns which processes are trusted shortly after it
+/// starts, and refuses everyone with `AccessDenied` until then.
+pub fn listen_input(sid: xous::SID, opcode: u32) -> Result<(), xous::Error> {
+    let conn = xous::connect(xous::SID::from_bytes(api::SERVER_NAME).unwrap())?;
+    let mut page = Box::new(ListenerPage(api::InputListener {
+        sid: sid.to_array(),
+        opcode,
+        error: 0,
+    }));
+    let buf = unsafe {
+        xous::MemoryRange::new(
+            &mut *page as *mut ListenerPage as usize,
+            core::mem::size_of::<ListenerPage>(),
+        )?
+    };
+    xous::send_message(
+        conn,
+        xous::Message::new_lend_mut(
+            api::Opcode::ListenInput.to_usize().unwrap(),
+            buf,
+            None,
+            None,
+        ),
+    )?;
+    match page.0.error {
+        0 => Ok(()),
+        e => Err(xous::Error::from_usize(e as usize)),
+    }
+}
+
+/// The characters in an input message sent to a listener registered with
+/// `listen_input()`
+pub fn input_chars(scalar: &xous::ScalarMessage) -> Vec<u8> {
+    let mut chars = Vec::with_capacity(api::INPUT_CHARS);
+    chars.extend_from_slice(&(scalar.arg2 as u32).to_le_bytes());
+    chars.extend_from_slice(&(scalar.arg3 as u32).to_le_bytes());
+    chars.truncate(scalar.arg1);
+    chars
+}
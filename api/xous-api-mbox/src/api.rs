@@ -0,0 +1,29 @@
+/// The name that the mailbox server registers with the name server
+pub const SERVER_NAME: &str = "_mbox_";
+
+#[derive(num_derive::FromPrimitive, num_derive::ToPrimitive, Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Opcode {
+    /// Send a request to the other core and wait for its response. This is a mutable
+    /// lend of a `Call`, which is answered once the response arrives.
+    Call = 0,
+}
+
+/// The longest request or response, in words
+pub const MAX_PAYLOAD_WORDS: usize = xous_mbox_rpc::frame::MAX_MESSAGE_WORDS;
+
+/// Sent as a mutable lend with `Opcode::Call`
+#[repr(C)]
+pub struct Call {
+    /// The opcode of the request, which must fit into 16 bits
+    pub opcode: u32,
+    /// The number of valid words in `payload`
+    pub length: u32,
+    /// The request, which is replaced by the response
+    pub payload: [u32; MAX_PAYLOAD_WORDS],
+    /// Filled in with 0 on success, or an `xous::Error` as a `u32`. `Timeout` means
+    /// that the other core didn't answer, even after the request was retried.
+    pub error: u32,
+}
+
+/// The size of the memory that is lent with a `Call`, rounded up to whole pages
+pub const CALL_LEN: usize = (core::mem::size_of::<Call>() + 4095) & !4095;
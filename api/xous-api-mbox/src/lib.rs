@@ -0,0 +1,53 @@
+pub mod api;
+
+use num_traits::ToPrimitive;
+
+pub struct Mailbox {
+    conn: xous::CID,
+}
+
+impl Mailbox {
+    /// Connect to the mailbox server, waiting for it to start if necessary
+    pub fn new(xns: &xous_api_names::XousNames) -> Result<Self, xous::Error> {
+        let conn = xns.request_connection_blocking(api::SERVER_NAME)?;
+        Ok(Mailbox { conn })
+    }
+
+    /// Send a request to the other core, and wait for its response. Requests from
+    /// several callers may be in flight at once, and are queued while the mailbox
+    /// recovers from an abort.
+    ///
+    /// # Errors:
+    ///
+    ///     * OutOfMemory: `payload` is longer than `api::MAX_PAYLOAD_WORDS`
+    ///     * Timeout: the other core didn't answer, even after the request was retried
+    pub fn call(&self, opcode: u16, payload: &[u32]) -> Result<Vec<u32>, xous::Error> {
+        if payload.len() > api::MAX_PAYLOAD_WORDS {
+            return Err(xous::Error::OutOfMemory);
+        }
+        let buf = xous::map_memory(
+            None,
+            None,
+            api::CALL_LEN,
+            xous::MemoryFlags::R | xous::MemoryFlags::W,
+        )?;
+        let call = unsafe { &mut *(buf.as_mut_ptr() as *mut api::Call) };
+        call.opcode = opcode as u32;
+        call.length = payload.len() as u32;
+        call.payload[..payload.len()].copy_from_slice(payload);
+
+        let result = xous::send_message(
+            self.conn,
+            xous::Message::new_lend_mut(api::Opcode::Call.to_usize().unwrap(), buf, None, None),
+        )
+        .and_then(|_| match call.error {
+            0 => {
+                let length = (call.length as usize).min(api::MAX_PAYLOAD_WORDS);
+                Ok(call.payload[..length].to_vec())
+            }
+            e => Err(xous::Error::from_usize(e as usize)),
+        });
+        xous::unmap_memory(buf)?;
+        result
+    }
+}
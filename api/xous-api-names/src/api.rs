@@ -0,0 +1,501 @@
+use core::fmt;
+use xous_ipc::String;
+
+#[allow(dead_code)]
+pub(crate) const SERVER_NAME_NAME: &str = "xous-name-server";
+
+/// The longest name that a server may register.
+pub const NAME_MAX_LENGTH: usize = 64;
+
+/// Number of bytes of a public key that are used to identify it in an
+/// `AuthenticateRequest`.
+pub const PUBKEY_ID_LEN: usize = 20;
+
+/// Length of the message that a client signs in response to an
+/// `AuthenticateRequest`: the challenge, the client's nonce, and the
+/// zero-padded server name.
+pub const AUTHENTICATION_MESSAGE_LEN: usize = 16 + 32 + NAME_MAX_LENGTH;
+
+/// The ID of the `Scalar` message that the kernel sends to each server that a process
+/// was connected to when that process terminates. The sender is the kernel, PID 1, and
+/// `arg1` holds the PID of the process that terminated. This is the same as
+/// `xous::PROCESS_TERMINATED_ID` in the kernel's `xous` crate, which is newer than the
+/// one that services link.
+pub const PROCESS_TERMINATED_ID: usize = 0xffff_fffe;
+
+/// Returns the PID of the process that terminated, if `msg` is the kernel's
+/// notice that a process terminated.
+pub fn terminated_pid(msg: &xous::MessageEnvelope) -> Option<xous::PID> {
+    if msg.body.id() != PROCESS_TERMINATED_ID || msg.sender.pid().map(|p| p.get()) != Some(1) {
+        return None;
+    }
+    msg.body
+        .scalar_message()
+        .and_then(|scalar| xous::PID::new(scalar.arg1 as u8))
+}
+
+/// The ID of the `Scalar` messages that the kernel sends to the name server when it is
+/// created, naming the processes in the boot image. The sender is the kernel, PID 1.
+/// `arg1` holds the PID in bits 0-7, the offset of this part of the name in bits 8-15
+/// and the length of the whole name in bits 16-23, and `arg2` to `arg4` hold up to 12
+/// bytes of the name, little-endian. This is the same as `xous::PROCESS_NAME_ID` in
+/// the kernel's `xous` crate.
+pub const PROCESS_NAME_ID: usize = 0xffff_fffd;
+
+/// A set of PIDs, as returned by `TrustedProcesses`.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct ProcessSet(u64);
+
+impl ProcessSet {
+    /// The set held in the two words of a `Scalar2` response, low PIDs first
+    pub fn from_words(lo: usize, hi: usize) -> Self {
+        ProcessSet((lo as u32) as u64 | ((hi as u32) as u64) << 32)
+    }
+
+    /// The two words that `from_words()` takes
+    pub fn to_words(&self) -> (usize, usize) {
+        (self.0 as u32 as usize, (self.0 >> 32) as u32 as usize)
+    }
+
+    pub fn insert(&mut self, pid: xous::PID) {
+        if let Some(bit) = Self::bit(pid) {
+            self.0 |= bit;
+        }
+    }
+
+    pub fn remove(&mut self, pid: xous::PID) {
+        if let Some(bit) = Self::bit(pid) {
+            self.0 &= !bit;
+        }
+    }
+
+    pub fn contains(&self, pid: xous::PID) -> bool {
+        Self::bit(pid).map(|bit| self.0 & bit != 0).unwrap_or(false)
+    }
+
+    /// The PIDs in the set, lowest first
+    pub fn iter(&self) -> impl Iterator<Item = xous::PID> + '_ {
+        (1..64u8)
+            .filter_map(xous::PID::new)
+            .filter(move |pid| self.contains(*pid))
+    }
+
+    fn bit(pid: xous::PID) -> Option<u64> {
+        (pid.get() < 64).then_some(1u64 << pid.get())
+    }
+}
+
+#[derive(num_derive::FromPrimitive, num_derive::ToPrimitive, Debug)]
+pub enum Opcode {
+    /// Create a new server with the given name and return its SID.
+    Register = 0,
+
+    /// Create a connection to the target server.
+    Lookup = 1,
+
+    /// Create an authenticated connection to the target server.
+    AuthenticatedLookup = 2,
+
+    /// unregister a server, given its cryptographically unique SID.
+    Unregister = 3,
+
+    /// disconnect, given a server name and a cryptographically unique, one-time use token
+    Disconnect = 4,
+
+    /// indicates if all inherentely trusted slots have been occupied. Should not run untrusted code until this is the case.
+    TrustedInitDone = 5,
+
+    /// Connect to a Server, blocking if the Server does not exist. When the Server is started,
+    /// return with either the CID or an AuthenticationRequest
+    ///
+    /// # Message Types
+    ///
+    ///     * MutableLend
+    ///
+    /// # Arguments
+    ///
+    /// The memory being pointed to should be a &str, and the length of the string should
+    /// be specified in the `valid` field.
+    ///
+    /// # Return Values
+    ///
+    /// Memory is overwritten to contain a return value.  This return value can be defined
+    /// as the following enum:
+    ///
+    /// ```rust
+    /// #[repr(C)]
+    /// #[non_exhaustive]
+    /// enum ConnectResult {
+    ///     Success(xous::CID /* connection ID */, [u32; 4] /* Disconnection token */),
+    ///     Error(u32 /* error code */),
+    ///     Unhandled, /* Catchall for future Results */
+    /// }
+    /// ```
+    ///
+    /// Only `Lookup` issues authentication challenges. If the server has used up its
+    /// connections and was registered with a public key, this fails with `AccessDenied`
+    /// straight away, and the client has to connect with `Lookup` and
+    /// `AuthenticatedLookup` instead.
+    BlockingConnect = 6,
+
+    /// Connect to a Server, returning the connection ID or an authentication request if
+    /// it exists, and returning ServerNotFound if it does not exist.
+    ///
+    /// # Message Types
+    ///
+    ///     * MutableLend
+    ///
+    /// # Arguments
+    ///
+    /// The memory being pointed to should be a &str, and the length of the string should
+    /// be specified in the `valid` field.
+    ///
+    /// # Return Values
+    ///
+    /// Memory is overwritten to contain a return value.  This return value can be defined
+    /// as the following enum:
+    ///
+    /// ```rust
+    /// #[repr(C)]
+    /// #[non_exhaustive]
+    /// enum ConnectResult {
+    ///     Success(xous::CID /* connection ID */, [u32; 4] /* Disconnection token */),
+    ///     Error(u32 /* error code */),
+    ///     Unhandled, /* Catchall for future Results */
+    /// }
+    /// ```
+    ///
+    /// Only `Lookup` issues authentication challenges. If the server has used up its
+    /// connections and was registered with a public key, this fails with `AccessDenied`
+    /// straight away, and the client has to connect with `Lookup` and
+    /// `AuthenticatedLookup` instead.
+    TryConnect = 7,
+
+    /// Claim a process name, which is used to look up the caller in the name server's
+    /// access policy. A process that was loaded from the boot image may only claim the
+    /// name that the loader gave it, and nobody else may claim that name.
+    ///
+    /// # Message Types
+    ///
+    ///     * MutableLend
+    ///
+    /// # Arguments
+    ///
+    /// A `String<64>` containing the name of the calling process.
+    ///
+    /// # Return Values
+    ///
+    /// `Return::Success` if the name was claimed, `Return::Failure` otherwise.
+    Identify = 8,
+
+    /// List the servers that are registered, one page at a time.
+    ///
+    /// # Message Types
+    ///
+    ///     * MutableLend
+    ///
+    /// # Arguments
+    ///
+    /// A `ServerList` with `start` set to the index of the first server to return.
+    ///
+    /// # Return Values
+    ///
+    /// The `ServerList` is filled in with up to `SERVER_LIST_PAGE_LEN` servers,
+    /// sorted by name.
+    ListServers = 9,
+
+    /// Find the process that claimed a name with `Identify`.
+    ///
+    /// # Message Types
+    ///
+    ///     * MutableLend
+    ///
+    /// # Arguments
+    ///
+    /// A `String<64>` containing the name of the process.
+    ///
+    /// # Return Values
+    ///
+    /// `Return::PID` with the process' PID, or `Return::Failure` if no process has
+    /// claimed the name yet.
+    ResolveProcess = 10,
+
+    /// List the processes that are trusted with privileged requests, such as setting
+    /// the time or another process' log level. These are the processes whose image
+    /// names, as given by the loader, appear in `trusted.conf`.
+    ///
+    /// # Message Types
+    ///
+    ///     * BlockingScalar
+    ///
+    /// # Return Values
+    ///
+    /// `Scalar2` with the `ProcessSet` of trusted PIDs, in the form taken by
+    /// `ProcessSet::from_words()`.
+    TrustedProcesses = 11,
+
+    /// Find the name of a process: the name that the loader gave it, or else the name
+    /// that it claimed with `Identify`.
+    ///
+    /// # Message Types
+    ///
+    ///     * MutableLend
+    ///
+    /// # Arguments
+    ///
+    /// A `ProcessName` with `pid` set.
+    ///
+    /// # Return Values
+    ///
+    /// The `ProcessName` with `name` filled in, or `None` if the process has no name.
+    ProcessName = 12,
+
+    /// Find the number of bytes of RAM that a process owns. Only trusted processes may
+    /// ask about processes other than themselves.
+    ///
+    /// # Message Types
+    ///
+    ///     * BlockingScalar
+    ///
+    /// # Arguments
+    ///
+    /// `arg1` is the PID of the process.
+    ///
+    /// # Return Values
+    ///
+    /// `Scalar2` with 0 and the number of bytes, or with a `xous::Error` code and 0:
+    /// `ProcessNotFound` if the process isn't running, or `AccessDenied`.
+    ProcessRam = 13,
+}
+
+#[derive(Debug, rkyv::Archive, rkyv::Serialize, rkyv::Deserialize)]
+pub struct Registration {
+    pub name: String<64>,
+    pub conn_limit: Option<u32>,
+    /// An ed25519 public key. Clients holding the matching private key may
+    /// connect even after `conn_limit` has been reached.
+    pub pubkey: Option<[u8; 32]>,
+    /// Issue a restart token, which reserves the name for a new instance of the
+    /// server if this one terminates
+    pub restartable: bool,
+    /// The restart token of a server that terminated, when registering its replacement
+    pub restart_token: Option<[u32; 4]>,
+}
+
+#[derive(Debug, rkyv::Archive, rkyv::Serialize, rkyv::Deserialize)]
+pub struct Disconnect {
+    pub name: String<64>,
+    pub token: [u32; 4],
+}
+
+/// The response to an `AuthenticateRequest`.
+#[derive(Debug, rkyv::Archive, rkyv::Serialize, rkyv::Deserialize)]
+pub struct AuthenticatedLookup {
+    pub name: String<64>,
+    /// The `pubkey_id` from the `AuthenticateRequest`
+    pub pubkey_id: [u8; PUBKEY_ID_LEN],
+    /// A random number picked by the client, which is mixed into the signed message
+    pub response_nonce: [u32; 8],
+    /// The ed25519 signature of `authentication_message()`
+    pub response: [u32; 16],
+}
+
+/// Sent in response to a `Lookup` when the server's connection limit has been
+/// reached, but it has registered a public key.
+#[derive(Debug, rkyv::Archive, rkyv::Serialize, rkyv::Deserialize)]
+pub struct AuthenticateRequest {
+    pub name: String<64>,
+    /// Identifies the public key that the challenge must be signed with
+    pub pubkey_id: [u8; PUBKEY_ID_LEN],
+    /// A one-time challenge issued to the requesting process
+    pub challenge: [u32; 4],
+}
+
+#[derive(Debug, rkyv::Archive, rkyv::Serialize, rkyv::Deserialize)]
+pub enum Return {
+    /// The caller must perform an AuthenticatedLookup using this challenge
+    AuthenticateRequest(AuthenticateRequest),
+
+    /// The connection was successful, and here is your CID and an optional disconnect token
+    CID((xous::CID, Option<[u32; 4]>)),
+
+    /// The registration was successful, and here is your SID
+    SID([u32; 4]),
+
+    /// The registration was successful, and here is your SID and restart token
+    RestartableSID(([u32; 4], [u32; 4])),
+
+    /// The operation failed for some reason
+    Failure,
+
+    /// The operation was successful
+    Success,
+
+    /// The PID of the process that was looked up
+    PID(u8),
+}
+
+/// A process and its name, as returned by `ProcessName`.
+#[derive(Debug, rkyv::Archive, rkyv::Serialize, rkyv::Deserialize)]
+pub struct ProcessName {
+    pub pid: u8,
+    pub name: Option<String<64>>,
+}
+
+/// Number of servers that are returned by each `ListServers` request.
+pub const SERVER_LIST_PAGE_LEN: usize = 16;
+
+/// A description of a registered server, as returned by `ListServers`.
+#[derive(Debug, Copy, Clone, rkyv::Archive, rkyv::Serialize, rkyv::Deserialize)]
+pub struct ServerInfo {
+    pub name: String<64>,
+    /// The PID of the process that registered the server
+    pub owner: u8,
+    /// Number of connections made without authentication
+    pub current_conns: u32,
+    /// Number of connections made with `AuthenticatedLookup`
+    pub auth_conns: u32,
+    /// The limit on unauthenticated connections, if any
+    pub max_conns: Option<u32>,
+    /// `true` if the server registered a public key for authenticated connections
+    pub authenticated: bool,
+    /// `false` if the server terminated and its name is waiting for a restart
+    pub running: bool,
+}
+
+impl Default for ServerInfo {
+    fn default() -> Self {
+        ServerInfo {
+            name: String::new(),
+            owner: 0,
+            current_conns: 0,
+            auth_conns: 0,
+            max_conns: None,
+            authenticated: false,
+            running: false,
+        }
+    }
+}
+
+#[derive(Debug, rkyv::Archive, rkyv::Serialize, rkyv::Deserialize)]
+pub struct ServerList {
+    /// The index of the first server in this page
+    pub start: u32,
+    /// The total number of registered servers
+    pub total: u32,
+    /// The number of valid entries in `servers`
+    pub count: u32,
+    pub servers: [ServerInfo; SERVER_LIST_PAGE_LEN],
+}
+
+impl ServerList {
+    pub fn new(start: u32) -> Self {
+        ServerList {
+            start,
+            total: 0,
+            count: 0,
+            servers: [ServerInfo::default(); SERVER_LIST_PAGE_LEN],
+        }
+    }
+}
+
+/// Identify a public key by its leading bytes.
+pub fn pubkey_id(pubkey: &[u8; 32]) -> [u8; PUBKEY_ID_LEN] {
+    let mut id = [0u8; PUBKEY_ID_LEN];
+    id.copy_from_slice(&pubkey[..PUBKEY_ID_LEN]);
+    id
+}
+
+/// Build the message that a client signs to answer an `AuthenticateRequest`.
+/// Both the client and `xous-names` must construct this identically.
+pub fn authentication_message(
+    name: &str,
+    challenge: &[u32; 4],
+    nonce: &[u32; 8],
+) -> [u8; AUTHENTICATION_MESSAGE_LEN] {
+    let mut message = [0u8; AUTHENTICATION_MESSAGE_LEN];
+    let (challenge_bytes, rest) = message.split_at_mut(16);
+    let (nonce_bytes, name_bytes) = rest.split_at_mut(32);
+    for (dest, word) in challenge_bytes.chunks_exact_mut(4).zip(challenge.iter()) {
+        dest.copy_from_slice(&word.to_le_bytes());
+    }
+    for (dest, word) in nonce_bytes.chunks_exact_mut(4).zip(nonce.iter()) {
+        dest.copy_from_slice(&word.to_le_bytes());
+    }
+    let len = name.len().min(NAME_MAX_LENGTH);
+    name_bytes[..len].copy_from_slice(&name.as_bytes()[..len]);
+    message
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Hash)]
+pub struct XousServerName {
+    value: [u8; NAME_MAX_LENGTH],
+    length: usize,
+}
+
+impl Default for XousServerName {
+    fn default() -> Self {
+        XousServerName {
+            value: [0u8; NAME_MAX_LENGTH],
+            length: 0,
+        }
+    }
+}
+
+impl XousServerName {
+    /// Create a name from `input`, truncating it to `NAME_MAX_LENGTH` bytes on a
+    /// character boundary.
+    pub fn from_str(input: &str) -> XousServerName {
+        let mut output = Self::default();
+        let mut length = input.len().min(NAME_MAX_LENGTH);
+        while !input.is_char_boundary(length) {
+            length -= 1;
+        }
+        output.value[..length].copy_from_slice(&input.as_bytes()[..length]);
+        output.length = length;
+        output
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.value[..self.length]
+    }
+
+    pub fn to_str(&self) -> &str {
+        // Safe because `from_str()` only ever stores whole characters
+        unsafe { core::str::from_utf8_unchecked(self.as_bytes()) }
+    }
+}
+
+impl fmt::Display for XousServerName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.to_str())
+    }
+}
+
+impl fmt::Debug for XousServerName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "XousServerName({:?})", self.to_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pid(pid: u8) -> xous::PID {
+        xous::PID::new(pid).unwrap()
+    }
+
+    #[test]
+    fn process_sets_survive_the_trip() {
+        let mut set = ProcessSet::default();
+        set.insert(pid(2));
+        set.insert(pid(40));
+        set.insert(pid(200));
+        let (lo, hi) = set.to_words();
+        let set = ProcessSet::from_words(lo, hi);
+        assert!(set.contains(pid(2)) && set.contains(pid(40)));
+        assert!(!set.contains(pid(3)) && !set.contains(pid(200)));
+        assert_eq!(set.iter().map(|p| p.get()).collect::<Vec<_>>(), vec![2, 40]);
+    }
+}
@@ -0,0 +1,435 @@
+#![cfg_attr(target_os = "none", no_std)]
+
+extern crate alloc;
+
+pub mod api;
+pub use api::*;
+pub mod service_error;
+pub use service_error::*;
+
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU32, Ordering};
+use num_traits::ToPrimitive;
+use xous::{send_message, Error, CID};
+use xous_ipc::{Buffer, String};
+
+// Define a struct that's 4096 bytes of `u8` and ensure it's page-aligned
+#[derive(Debug)]
+#[repr(C, align(4096))]
+struct ConnectRequest([u8; 4096]);
+
+#[derive(Debug)]
+pub struct XousNames {
+    conn: CID,
+}
+impl XousNames {
+    pub fn new() -> Result<Self, Error> {
+        REFCOUNT.fetch_add(1, Ordering::Relaxed);
+        let conn = xous::connect(xous::SID::from_bytes(b"xous-name-server").unwrap())
+            .expect("Couldn't connect to XousNames");
+        Ok(XousNames { conn })
+    }
+
+    pub fn unregister_server(&self, sid: xous::SID) -> Result<(), Error> {
+        let s = sid.to_u32();
+        let response = send_message(
+            self.conn,
+            xous::Message::new_blocking_scalar(
+                api::Opcode::Unregister.to_usize().unwrap(),
+                s.0 as usize,
+                s.1 as usize,
+                s.2 as usize,
+                s.3 as usize,
+            ),
+        )
+        .expect("unregistration failed");
+        if let xous::Result::Scalar1(result) = response {
+            if result != 0 {
+                Ok(())
+            } else {
+                Err(xous::Error::ServerNotFound)
+            }
+        } else {
+            Err(Error::InternalError)
+        }
+    }
+
+    /// Register a server with the given name, and create it. `max_conns` limits the
+    /// number of clients that may connect; `None` allows any number of connections.
+    pub fn register_name(&self, name: &str, max_conns: Option<u32>) -> Result<xous::SID, Error> {
+        self.register(name, max_conns, None, false, None)
+            .map(|(sid, _)| sid)
+    }
+
+    /// Register a server that can be restarted. If the server's process terminates, its
+    /// name is held until a new instance calls `reregister_name()` with the returned
+    /// restart token, and clients that are blocked connecting to it are then connected
+    /// to the new instance.
+    pub fn register_name_restartable(
+        &self,
+        name: &str,
+        max_conns: Option<u32>,
+    ) -> Result<(xous::SID, [u32; 4]), Error> {
+        match self.register(name, max_conns, None, true, None)? {
+            (sid, Some(token)) => Ok((sid, token)),
+            (_, None) => Err(xous::Error::InternalError),
+        }
+    }
+
+    /// Take over the name of a restartable server whose process has terminated.
+    /// `token` is the restart token that was returned by `register_name_restartable()`.
+    pub fn reregister_name(
+        &self,
+        name: &str,
+        max_conns: Option<u32>,
+        token: [u32; 4],
+    ) -> Result<xous::SID, Error> {
+        self.register(name, max_conns, None, true, Some(token))
+            .map(|(sid, _)| sid)
+    }
+
+    /// Register a server along with an ed25519 public key. Once `max_conns` has been
+    /// reached, clients may still connect with `request_authenticated_connection()` if
+    /// they hold the matching private key.
+    pub fn register_name_with_key(
+        &self,
+        name: &str,
+        max_conns: Option<u32>,
+        pubkey: [u8; 32],
+    ) -> Result<xous::SID, Error> {
+        self.register(name, max_conns, Some(pubkey), false, None)
+            .map(|(sid, _)| sid)
+    }
+
+    fn register(
+        &self,
+        name: &str,
+        max_conns: Option<u32>,
+        pubkey: Option<[u8; 32]>,
+        restartable: bool,
+        restart_token: Option<[u32; 4]>,
+    ) -> Result<(xous::SID, Option<[u32; 4]>), Error> {
+        let registration = api::Registration {
+            name: String::<64>::from_str(name),
+            conn_limit: max_conns,
+            pubkey,
+            restartable,
+            restart_token,
+        };
+        let mut buf = Buffer::into_buf(registration).or(Err(xous::Error::InternalError))?;
+        buf.lend_mut(self.conn, api::Opcode::Register.to_u32().unwrap())
+            .or(Err(xous::Error::InternalError))?;
+
+        match buf.to_original().unwrap() {
+            api::Return::SID(sid_raw) => {
+                let sid = sid_raw.into();
+                xous::create_server_with_sid(sid).expect("can't auto-register server");
+                Ok((sid, None))
+            }
+            api::Return::RestartableSID((sid_raw, token)) => {
+                let sid = sid_raw.into();
+                xous::create_server_with_sid(sid).expect("can't auto-register server");
+                Ok((sid, Some(token)))
+            }
+            _ => Err(xous::Error::InternalError),
+        }
+    }
+
+    pub fn request_connection_with_token(
+        &self,
+        name: &str,
+    ) -> Result<(CID, Option<[u32; 4]>), Error> {
+        let lookup_name = xous_ipc::String::<64>::from_str(name);
+        let mut buf = Buffer::into_buf(lookup_name).or(Err(xous::Error::InternalError))?;
+        buf.lend_mut(self.conn, api::Opcode::Lookup.to_u32().unwrap())
+            .or(Err(xous::Error::InternalError))?;
+
+        match buf.to_original().unwrap() {
+            api::Return::CID((cid, token)) => Ok((cid, token)),
+            api::Return::AuthenticateRequest(_) => Err(xous::Error::AccessDenied),
+            _ => Err(xous::Error::ServerNotFound),
+        }
+    }
+
+    /// Connect to a server that was registered with `register_name_with_key()`. If the
+    /// server has no free connections, `xous-names` issues a challenge, which is signed
+    /// by calling `sign` with the message to be signed and must return an ed25519
+    /// signature made with the private key matching `pubkey`.
+    ///
+    /// The disconnect token is only returned if one of the server's regular
+    /// connections was free. Connections made by authenticating don't count
+    /// against the limit, so they have no token to free one up.
+    ///
+    /// # Errors
+    ///
+    /// * **AccessDenied**: The server does not accept `pubkey`, or the signature
+    ///   was rejected
+    /// * **ServerNotFound**: The server does not exist
+    pub fn request_authenticated_connection<F>(
+        &self,
+        name: &str,
+        pubkey: &[u8; 32],
+        sign: F,
+    ) -> Result<(CID, Option<[u32; 4]>), Error>
+    where
+        F: FnOnce(&[u8]) -> [u8; 64],
+    {
+        let lookup_name = xous_ipc::String::<64>::from_str(name);
+        let mut buf = Buffer::into_buf(lookup_name).or(Err(xous::Error::InternalError))?;
+        buf.lend_mut(self.conn, api::Opcode::Lookup.to_u32().unwrap())
+            .or(Err(xous::Error::InternalError))?;
+
+        let request = match buf.to_original().unwrap() {
+            api::Return::CID((cid, token)) => return Ok((cid, token)),
+            api::Return::AuthenticateRequest(request) => request,
+            _ => return Err(xous::Error::ServerNotFound),
+        };
+        if request.pubkey_id != api::pubkey_id(pubkey) {
+            return Err(xous::Error::AccessDenied);
+        }
+
+        // Mix in a nonce of our own, so that we never sign a message chosen
+        // entirely by someone else.
+        let (n0, n1, n2, n3) = xous::create_server_id()?.to_u32();
+        let (n4, n5, n6, n7) = xous::create_server_id()?.to_u32();
+        let nonce = [n0, n1, n2, n3, n4, n5, n6, n7];
+        let message = api::authentication_message(name, &request.challenge, &nonce);
+        let signature = sign(&message);
+        let mut response = [0u32; 16];
+        for (word, src) in response.iter_mut().zip(signature.chunks_exact(4)) {
+            *word = u32::from_le_bytes([src[0], src[1], src[2], src[3]]);
+        }
+
+        let lookup = api::AuthenticatedLookup {
+            name: String::<64>::from_str(name),
+            pubkey_id: request.pubkey_id,
+            response_nonce: nonce,
+            response,
+        };
+        let mut buf = Buffer::into_buf(lookup).or(Err(xous::Error::InternalError))?;
+        buf.lend_mut(
+            self.conn,
+            api::Opcode::AuthenticatedLookup.to_u32().unwrap(),
+        )
+        .or(Err(xous::Error::InternalError))?;
+        match buf.to_original().unwrap() {
+            api::Return::CID((cid, token)) => Ok((cid, token)),
+            _ => Err(xous::Error::AccessDenied),
+        }
+    }
+
+    pub fn disconnect_with_token(&self, name: &str, token: [u32; 4]) -> Result<(), Error> {
+        let disconnect = api::Disconnect {
+            name: String::<64>::from_str(name),
+            token,
+        };
+        let mut buf = Buffer::into_buf(disconnect).or(Err(xous::Error::InternalError))?;
+        buf.lend_mut(self.conn, api::Opcode::Disconnect.to_u32().unwrap())
+            .or(Err(xous::Error::InternalError))?;
+
+        match buf.to_original().unwrap() {
+            api::Return::Success => Ok(()),
+            _ => Err(xous::Error::ServerNotFound),
+        }
+    }
+
+    pub fn request_connection(&self, name: &str) -> Result<CID, Error> {
+        self.request_connection_with_token(name).map(|(cid, _)| cid)
+    }
+
+    /// Connect to the named server, blocking until it has been registered.
+    pub fn request_connection_blocking(&self, name: &str) -> Result<CID, Error> {
+        self.connect(name, api::Opcode::BlockingConnect)
+            .map(|(cid, _)| cid)
+    }
+
+    pub fn request_connection_with_token_blocking(
+        &self,
+        name: &str,
+    ) -> Result<(CID, Option<[u32; 4]>), Error> {
+        self.connect(name, api::Opcode::BlockingConnect)
+    }
+
+    /// Connect to the named server, returning `ServerNotFound` if it has not been
+    /// registered yet.
+    pub fn try_request_connection(&self, name: &str) -> Result<CID, Error> {
+        self.connect(name, api::Opcode::TryConnect)
+            .map(|(cid, _)| cid)
+    }
+
+    fn connect(&self, name: &str, opcode: api::Opcode) -> Result<(CID, Option<[u32; 4]>), Error> {
+        let mut request = ConnectRequest([0u8; 4096]);
+        let name_bytes = name.as_bytes();
+        if name_bytes.len() > api::NAME_MAX_LENGTH {
+            return Err(xous::Error::InvalidString);
+        }
+        request.0[..name_bytes.len()].copy_from_slice(name_bytes);
+
+        let msg = xous::MemoryMessage {
+            id: opcode.to_usize().unwrap(),
+            buf: unsafe {
+                xous::MemoryRange::new(request.0.as_mut_ptr() as usize, request.0.len())
+            }?,
+            offset: None,
+            valid: xous::MemorySize::new(name_bytes.len()),
+        };
+        let result = send_message(self.conn, xous::Message::MutableBorrow(msg));
+        CallError::check_response(result, &request.0)?;
+
+        let mut response = [0u32; 6];
+        for (word, src) in response.iter_mut().zip(request.0.chunks_exact(4)) {
+            *word = u32::from_le_bytes([src[0], src[1], src[2], src[3]]);
+        }
+        let token = [response[2], response[3], response[4], response[5]];
+        let token = if token == [0u32; 4] {
+            None
+        } else {
+            Some(token)
+        };
+        Ok((response[1] as CID, token))
+    }
+
+    /// Tell the name server which process this is, so that its access policy can be
+    /// applied. This should be called once, early in boot. Returns `AccessDenied` if
+    /// the name is not in the policy, or has already been claimed.
+    pub fn identify(&self, process_name: &str) -> Result<(), Error> {
+        let name = String::<64>::from_str(process_name);
+        let mut buf = Buffer::into_buf(name).or(Err(xous::Error::InternalError))?;
+        buf.lend_mut(self.conn, api::Opcode::Identify.to_u32().unwrap())
+            .or(Err(xous::Error::InternalError))?;
+
+        match buf.to_original().unwrap() {
+            api::Return::Success => Ok(()),
+            _ => Err(xous::Error::AccessDenied),
+        }
+    }
+
+    /// Find the process that identified itself as `process_name`. Returns
+    /// `ProcessNotFound` if no process has claimed that name yet.
+    pub fn resolve_process(&self, process_name: &str) -> Result<xous::PID, Error> {
+        let name = String::<64>::from_str(process_name);
+        let mut buf = Buffer::into_buf(name).or(Err(xous::Error::InternalError))?;
+        buf.lend_mut(self.conn, api::Opcode::ResolveProcess.to_u32().unwrap())
+            .or(Err(xous::Error::InternalError))?;
+
+        match buf.to_original().unwrap() {
+            api::Return::PID(pid) => xous::PID::new(pid).ok_or(xous::Error::ProcessNotFound),
+            _ => Err(xous::Error::ProcessNotFound),
+        }
+    }
+
+    /// The name of `pid`, as given by the loader or claimed with `identify()`, or
+    /// `None` if the process isn't known by any name.
+    pub fn process_name(&self, pid: xous::PID) -> Result<Option<alloc::string::String>, Error> {
+        let request = api::ProcessName {
+            pid: pid.get(),
+            name: None,
+        };
+        let mut buf = Buffer::into_buf(request).or(Err(xous::Error::InternalError))?;
+        buf.lend_mut(self.conn, api::Opcode::ProcessName.to_u32().unwrap())
+            .or(Err(xous::Error::InternalError))?;
+        let process = buf.to_original::<api::ProcessName, _>().unwrap();
+        Ok(process
+            .name
+            .map(|name| name.as_str().unwrap_or("<invalid>").to_owned()))
+    }
+
+    /// The number of bytes of RAM owned by `pid`, or `None` if it isn't running. Only
+    /// trusted processes may ask about other processes.
+    pub fn process_ram(&self, pid: xous::PID) -> Result<Option<usize>, Error> {
+        let response = send_message(
+            self.conn,
+            xous::Message::new_blocking_scalar(
+                api::Opcode::ProcessRam.to_usize().unwrap(),
+                pid.get() as usize,
+                0,
+                0,
+                0,
+            ),
+        )?;
+        match response {
+            xous::Result::Scalar2(0, bytes) => Ok(Some(bytes)),
+            xous::Result::Scalar2(code, _) => match xous::Error::from_usize(code) {
+                xous::Error::ProcessNotFound => Ok(None),
+                e => Err(e),
+            },
+            _ => Err(xous::Error::InternalError),
+        }
+    }
+
+    /// Return a description of every server that is registered, sorted by name.
+    pub fn list_servers(&self) -> Result<Vec<api::ServerInfo>, Error> {
+        let mut servers = Vec::new();
+        loop {
+            let list = api::ServerList::new(servers.len() as u32);
+            let mut buf = Buffer::into_buf(list).or(Err(xous::Error::InternalError))?;
+            buf.lend_mut(self.conn, api::Opcode::ListServers.to_u32().unwrap())
+                .or(Err(xous::Error::InternalError))?;
+            let list = buf.to_original::<api::ServerList, _>().unwrap();
+
+            let count = (list.count as usize).min(api::SERVER_LIST_PAGE_LEN);
+            servers.extend_from_slice(&list.servers[..count]);
+            // Stop once everything has been read, or if the table shrank
+            // underneath us.
+            if count == 0 || servers.len() >= list.total as usize {
+                return Ok(servers);
+            }
+        }
+    }
+
+    /// The processes that are trusted with privileged requests. The set is fixed by
+    /// the boot image, but processes that terminate should be dropped from it, as
+    /// their PIDs may be reused.
+    pub fn trusted_processes(&self) -> Result<api::ProcessSet, Error> {
+        let response = send_message(
+            self.conn,
+            xous::Message::new_blocking_scalar(
+                api::Opcode::TrustedProcesses.to_usize().unwrap(),
+                0,
+                0,
+                0,
+                0,
+            ),
+        )?;
+        if let xous::Result::Scalar2(lo, hi) = response {
+            Ok(api::ProcessSet::from_words(lo, hi))
+        } else {
+            Err(xous::Error::InternalError)
+        }
+    }
+
+    pub fn trusted_init_done(&self) -> Result<bool, Error> {
+        let response = send_message(
+            self.conn,
+            xous::Message::new_blocking_scalar(
+                api::Opcode::TrustedInitDone.to_usize().unwrap(),
+                0,
+                0,
+                0,
+                0,
+            ),
+        )
+        .expect("couldn't query trusted_init_done");
+        if let xous::Result::Scalar1(result) = response {
+            if result == 1 {
+                Ok(true)
+            } else {
+                Ok(false)
+            }
+        } else {
+            Err(xous::Error::InternalError)
+        }
+    }
+}
+
+static REFCOUNT: AtomicU32 = AtomicU32::new(0);
+impl Drop for XousNames {
+    fn drop(&mut self) {
+        // de-allocate myself. It's unsafe because we are responsible to make sure nobody else is using the connection.
+        if REFCOUNT.fetch_sub(1, Ordering::Relaxed) == 1 {
+            unsafe {
+                xous::disconnect(self.conn).unwrap();
+            }
+        }
+    }
+}
@@ -0,0 +1,273 @@
+//! A standard envelope for errors that a server returns in response to a
+//! lent memory message. It lives here rather than in `xous` because services
+//! link a `xous` from crates.io, which doesn't have it.
+//!
+//! The server marks the response as an error out of band, by returning the
+//! memory with its `offset` set to [`SERVICE_ERROR_OFFSET`]. Nothing that a
+//! successful response writes into the buffer can be mistaken for an error, as
+//! long as the service never returns that offset on success.
+//!
+//! The details of the error are written over the start of the lent buffer as a
+//! sequence of little-endian `u32` words:
+//!
+//! | Word | Contents                                                     |
+//! |------|--------------------------------------------------------------|
+//! | 0    | Status: `0` on success, [`SERVICE_ERROR_STATUS`] on failure  |
+//! | 1    | Service-specific error code                                  |
+//! | 2    | Kernel `Error` that best describes the failure               |
+//! | 3    | Length of the message, in bytes                              |
+//! | 4..  | Optional UTF-8 message                                       |
+//!
+//! On success, the buffer holds the service-specific reply.
+
+use alloc::string::String;
+
+/// Value of the status word when the server rejected a request.
+pub const SERVICE_ERROR_STATUS: u32 = 1;
+
+/// The `offset` that a server returns the memory with when it rejected a request.
+pub const SERVICE_ERROR_OFFSET: usize = usize::MAX;
+
+/// The longest message that can be attached to a `ServiceError`.
+pub const SERVICE_ERROR_MESSAGE_LEN: usize = 64;
+
+const HEADER_WORDS: usize = 4;
+
+/// An error reported by a server, as opposed to a failure to deliver
+/// the message in the first place.
+#[derive(Debug, PartialEq)]
+pub struct ServiceError {
+    /// A code whose meaning is defined by the service
+    pub code: u32,
+
+    /// The kernel error that most closely describes this failure
+    pub kernel: xous::Error,
+
+    /// An optional human-readable description
+    pub message: Option<String>,
+}
+
+impl ServiceError {
+    pub fn new(code: u32, kernel: xous::Error) -> Self {
+        ServiceError {
+            code,
+            kernel,
+            message: None,
+        }
+    }
+
+    /// Attach a message to this error. Messages longer than
+    /// `SERVICE_ERROR_MESSAGE_LEN` bytes are truncated.
+    pub fn with_message(mut self, message: &str) -> Self {
+        let mut end = message.len().min(SERVICE_ERROR_MESSAGE_LEN);
+        while !message.is_char_boundary(end) {
+            end -= 1;
+        }
+        self.message = Some(String::from(&message[..end]));
+        self
+    }
+
+    /// Write this error into the response buffer of `mem`, and mark it as an
+    /// error for when the memory is returned with `mem.offset`. Returns
+    /// `Error::OutOfMemory` if the buffer is too small to hold the header.
+    /// The message is truncated if it does not fit.
+    pub fn respond(&self, mem: &mut xous::MemoryMessage) -> Result<(), xous::Error> {
+        let buf = mem.buf.as_slice_mut::<u8>();
+        if buf.len() < HEADER_WORDS * 4 {
+            return Err(xous::Error::OutOfMemory);
+        }
+        let message = self.message.as_deref().unwrap_or("");
+        let message_len = message.len().min(buf.len() - HEADER_WORDS * 4);
+
+        let header = [
+            SERVICE_ERROR_STATUS,
+            self.code,
+            self.kernel.to_usize() as u32,
+            message_len as u32,
+        ];
+        for (dest, word) in buf.chunks_exact_mut(4).zip(header.iter()) {
+            dest.copy_from_slice(&word.to_le_bytes());
+        }
+        buf[HEADER_WORDS * 4..HEADER_WORDS * 4 + message_len]
+            .copy_from_slice(&message.as_bytes()[..message_len]);
+
+        mem.valid = None;
+        mem.offset = xous::MemorySize::new(SERVICE_ERROR_OFFSET);
+        Ok(())
+    }
+
+    /// Decode the error envelope from a returned buffer, given the `result` of
+    /// lending it. Returns `None` if the server indicated success, or if the
+    /// buffer is too small to hold an envelope.
+    pub fn from_response(result: &xous::Result, buf: &[u8]) -> Option<ServiceError> {
+        match result {
+            xous::Result::MemoryReturned(Some(offset), _)
+                if offset.get() == SERVICE_ERROR_OFFSET => {}
+            _ => return None,
+        }
+        if buf.len() < HEADER_WORDS * 4 {
+            return None;
+        }
+        let mut header = [0u32; HEADER_WORDS];
+        for (word, src) in header.iter_mut().zip(buf.chunks_exact(4)) {
+            *word = u32::from_le_bytes([src[0], src[1], src[2], src[3]]);
+        }
+        if header[0] != SERVICE_ERROR_STATUS {
+            return None;
+        }
+
+        let message_len = (header[3] as usize)
+            .min(SERVICE_ERROR_MESSAGE_LEN)
+            .min(buf.len() - HEADER_WORDS * 4);
+        let message = if message_len > 0 {
+            let raw = &buf[HEADER_WORDS * 4..HEADER_WORDS * 4 + message_len];
+            Some(String::from_utf8_lossy(raw).into_owned())
+        } else {
+            None
+        };
+
+        Some(ServiceError {
+            code: header[1],
+            kernel: xous::Error::from_usize(header[2] as usize),
+            message,
+        })
+    }
+}
+
+impl core::fmt::Display for ServiceError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "service error {} ({:?})", self.code, self.kernel)?;
+        if let Some(message) = &self.message {
+            write!(f, ": {}", message)?;
+        }
+        Ok(())
+    }
+}
+
+/// The ways in which a request to a server can fail.
+#[derive(Debug, PartialEq)]
+pub enum CallError {
+    /// The message could not be delivered, or the kernel rejected it
+    Transport(xous::Error),
+
+    /// The server received the message and rejected it
+    Service(ServiceError),
+}
+
+impl CallError {
+    /// Turn the result of lending `buf` to a server into a typed `Result`,
+    /// separating transport failures from errors reported by the server.
+    pub fn check_response(
+        result: Result<xous::Result, xous::Error>,
+        buf: &[u8],
+    ) -> Result<xous::Result, CallError> {
+        let result = result.map_err(CallError::Transport)?;
+        match ServiceError::from_response(&result, buf) {
+            Some(e) => Err(CallError::Service(e)),
+            None => Ok(result),
+        }
+    }
+
+    /// The kernel error that best describes this failure.
+    pub fn kernel_error(&self) -> &xous::Error {
+        match self {
+            CallError::Transport(e) => e,
+            CallError::Service(e) => &e.kernel,
+        }
+    }
+}
+
+impl From<xous::Error> for CallError {
+    fn from(e: xous::Error) -> Self {
+        CallError::Transport(e)
+    }
+}
+
+impl From<ServiceError> for CallError {
+    fn from(e: ServiceError) -> Self {
+        CallError::Service(e)
+    }
+}
+
+impl From<CallError> for xous::Error {
+    fn from(e: CallError) -> Self {
+        match e {
+            CallError::Transport(e) => e,
+            CallError::Service(e) => e.kernel,
+        }
+    }
+}
+
+impl core::fmt::Display for CallError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            CallError::Transport(e) => write!(f, "transport error: {:?}", e),
+            CallError::Service(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn envelope(code: u32, kernel: xous::Error, message: &str) -> Vec<u8> {
+        let mut buf = Vec::new();
+        for word in [
+            SERVICE_ERROR_STATUS,
+            code,
+            kernel.to_usize() as u32,
+            message.len() as u32,
+        ] {
+            buf.extend_from_slice(&word.to_le_bytes());
+        }
+        buf.extend_from_slice(message.as_bytes());
+        buf.resize(4096, 0);
+        buf
+    }
+
+    fn returned(offset: Option<usize>) -> Result<xous::Result, xous::Error> {
+        Ok(xous::Result::MemoryReturned(
+            offset.and_then(xous::MemorySize::new),
+            None,
+        ))
+    }
+
+    #[test]
+    fn errors_are_marked_by_the_offset() {
+        let buf = envelope(5, xous::Error::ServerNotFound, "no such server");
+        let err =
+            CallError::check_response(returned(Some(SERVICE_ERROR_OFFSET)), &buf).unwrap_err();
+        assert_eq!(
+            err,
+            CallError::Service(
+                ServiceError::new(5, xous::Error::ServerNotFound).with_message("no such server")
+            )
+        );
+    }
+
+    #[test]
+    fn replies_that_look_like_an_envelope_are_replies() {
+        let buf = envelope(5, xous::Error::ServerNotFound, "");
+        assert!(CallError::check_response(returned(None), &buf).is_ok());
+        assert!(CallError::check_response(returned(Some(4)), &buf).is_ok());
+    }
+
+    #[test]
+    fn undelivered_messages_are_transport_errors() {
+        let buf = envelope(5, xous::Error::ServerNotFound, "");
+        assert_eq!(
+            CallError::check_response(Err(xous::Error::ServerNotFound), &buf),
+            Err(CallError::Transport(xous::Error::ServerNotFound))
+        );
+    }
+
+    #[test]
+    fn long_messages_are_truncated() {
+        let long = "é".repeat(SERVICE_ERROR_MESSAGE_LEN);
+        let err = ServiceError::new(1, xous::Error::InternalError).with_message(&long);
+        let message = err.message.unwrap();
+        assert!(message.len() <= SERVICE_ERROR_MESSAGE_LEN);
+        assert!(message.chars().all(|c| c == 'é'));
+    }
+}
@@ -1,38 +1,54 @@
 /// Do not modify the discriminants in this structure. They are used in `libstd` directly.
-#[repr(usize)]
-#[derive(num_derive::FromPrimitive, num_derive::ToPrimitive, Debug)]
-pub enum Opcode {
+///
+/// The `Opcode` enum, the `TicktimerClient` and `dispatch_ticktimer_api()` are generated
+/// from this trait, which the server implements. Opcodes marked `#[manual]` have
+/// hand-written clients in `Ticktimer`, and are handled in the server's main loop.
+#[xous_api_derive::service(opcode = Opcode, client = TicktimerClient)]
+pub trait TicktimerApi {
     /// Get the elapsed time in milliseconds
-    ElapsedMs = 0,
+    #[opcode = 0]
+    fn elapsed_ms(&mut self) -> u64;
 
     /// Sleep for the specified numer of milliseconds
-    SleepMs = 1,
+    #[opcode = 1]
+    #[deferred]
+    fn sleep_ms(&mut self, ms: usize);
 
     /// Recalculate the sleep time
-    RecalculateSleep = 2,
+    #[opcode = 2]
+    #[manual]
+    fn recalculate_sleep(&mut self);
 
     /// Suspend/resume callback
-    SuspendResume = 3,
+    #[opcode = 3]
+    #[manual]
+    fn suspend_resume(&mut self);
 
     /// force a WDT update
-    PingWdt = 4,
+    #[opcode = 4]
+    fn ping_wdt(&mut self);
 
     /// Return the version string of Xous. We bury it here because this is a small, lightweight server we can rebuild on every run.
-    GetVersion = 5,
+    #[opcode = 5]
+    #[manual]
+    fn get_version(&mut self);
 
     /// Lock the given Mutex, blocking if it is already locked.
     ///
     /// # Arguments
     ///
     /// *arg1*: An integer of some sort, such as the address of the Mutex
-    LockMutex = 6,
+    #[opcode = 6]
+    #[deferred]
+    fn lock_mutex(&mut self, mtx: usize);
 
     /// Unlock the given Mutex
     ///
     /// # Arguments
     ///
     /// *arg1*: An integer of some sort, such as the address of the Mutex
-    UnlockMutex = 7,
+    #[opcode = 7]
+    fn unlock_mutex(&mut self, mtx: usize);
 
     /// Wait for a given condition to be signalled
     ///
@@ -40,7 +56,9 @@ pub enum Opcode {
     ///
     /// *arg1*: An integer of some sort, such as the address of the Condvar
     /// *arg2*: The number of milliseconds to wait, or 0 to wait forever
-    WaitForCondition = 8,
+    #[opcode = 8]
+    #[deferred]
+    fn wait_for_condition(&mut self, condvar: usize, ms: usize) -> usize;
 
     /// Notify a condition
     ///
@@ -48,13 +66,267 @@ pub enum Opcode {
     ///
     /// *arg1*: An integer of some sort, such as the address of the Condvar
     /// *arg2*: The number of conditions to notify
-    NotifyCondition = 9,
+    #[opcode = 9]
+    fn notify_condition(&mut self, condvar: usize, count: usize);
 
-    /// Invalid call -- an error occurred decoding the opcode
-    InvalidCall = u32::MAX as usize,
+    /// Create a timer that sends a message to a server when it expires. The
+    /// subscription is filled in with the ID of the new timer.
+    #[opcode = 10]
+    fn subscribe_timer(&mut self, subscription: &mut TimerSubscription);
+
+    /// Cancel a timer that was created by the calling process
+    ///
+    /// # Arguments
+    ///
+    /// *arg1*: The ID of the timer
+    #[opcode = 11]
+    fn cancel_timer(&mut self, id: u32) -> bool;
+
+    /// Restart a timer that was created by the calling process, so that it next
+    /// expires after the given number of milliseconds. This also becomes the
+    /// period of a periodic timer.
+    ///
+    /// # Arguments
+    ///
+    /// *arg1*: The ID of the timer
+    /// *arg2*, *arg3*: The number of milliseconds, low word first
+    #[opcode = 12]
+    fn reschedule_timer(&mut self, id: u32, ms: u64) -> bool;
+
+    /// Get the elapsed time in microseconds. The resolution is one tick of the hardware
+    /// timer: a microsecond on Cramium, but a millisecond on Precursor, where this is
+    /// always a multiple of 1000 and `sleep_us()` is rounded up to whole milliseconds.
+    #[opcode = 13]
+    fn elapsed_us(&mut self) -> u64;
+
+    /// Sleep for the specified number of microseconds
+    #[opcode = 14]
+    #[deferred]
+    fn sleep_us(&mut self, us: usize);
+
+    /// Take one unit from a counting semaphore, blocking until one is available.
+    /// Returns 0 if the unit was taken, or 1 if the operation timed out.
+    ///
+    /// # Arguments
+    ///
+    /// *arg1*: An integer of some sort, such as the address of the semaphore
+    /// *arg2*: The number of milliseconds to wait, or 0 to wait forever
+    #[opcode = 15]
+    #[deferred]
+    fn acquire_semaphore(&mut self, sem: usize, ms: usize) -> usize;
+
+    /// Return units to a counting semaphore, waking waiters in the order that they arrived
+    ///
+    /// # Arguments
+    ///
+    /// *arg1*: An integer of some sort, such as the address of the semaphore
+    /// *arg2*: The number of units to return
+    #[opcode = 16]
+    fn release_semaphore(&mut self, sem: usize, count: usize);
+
+    /// Take a shared read lock on a reader-writer lock. Returns 0 if the lock was
+    /// taken, or 1 if the operation timed out.
+    ///
+    /// # Arguments
+    ///
+    /// *arg1*: An integer of some sort, such as the address of the lock
+    /// *arg2*: The number of milliseconds to wait, or 0 to wait forever
+    #[opcode = 17]
+    #[deferred]
+    fn read_lock(&mut self, lock: usize, ms: usize) -> usize;
+
+    /// Take the exclusive write lock on a reader-writer lock. Returns 0 if the lock
+    /// was taken, or 1 if the operation timed out.
+    ///
+    /// # Arguments
+    ///
+    /// *arg1*: An integer of some sort, such as the address of the lock
+    /// *arg2*: The number of milliseconds to wait, or 0 to wait forever
+    #[opcode = 18]
+    #[deferred]
+    fn write_lock(&mut self, lock: usize, ms: usize) -> usize;
+
+    /// Release a read lock
+    ///
+    /// # Arguments
+    ///
+    /// *arg1*: An integer of some sort, such as the address of the lock
+    #[opcode = 19]
+    fn read_unlock(&mut self, lock: usize);
+
+    /// Release the write lock
+    ///
+    /// # Arguments
+    ///
+    /// *arg1*: An integer of some sort, such as the address of the lock
+    #[opcode = 20]
+    fn write_unlock(&mut self, lock: usize);
+
+    /// Describe the owners of contended Mutexes and the threads that are waiting on
+    /// Mutexes and condvars, along with any cycles of threads waiting on each other
+    #[opcode = 21]
+    fn lock_diagnostics(&mut self, report: &mut LockReport);
+
+    /// Get the wall-clock time in milliseconds since the Unix epoch, in UTC, or 0 if the
+    /// time has never been set
+    #[opcode = 22]
+    fn utc_ms(&mut self) -> u64;
+
+    /// Set the wall-clock time. Only trusted processes may do this.
+    ///
+    /// # Arguments
+    ///
+    /// *arg1*: The low word of the time in milliseconds since the Unix epoch, in UTC
+    /// *arg2*: The high word of the time
+    ///
+    /// Returns `xous::Error::NoError`, or `AccessDenied` if the caller isn't trusted,
+    /// as a `usize`
+    #[opcode = 23]
+    fn set_utc_ms(&mut self, utc_ms: u64) -> usize;
+
+    /// Whether the wall-clock time was set and the system hasn't been suspended since,
+    /// which makes the time lag behind by the duration of the suspend
+    #[opcode = 24]
+    fn wall_clock_synchronized(&mut self) -> bool;
+
+    /// Get the system time zone, as an index into `tz::ZONES`
+    #[opcode = 25]
+    fn time_zone(&mut self) -> usize;
+
+    /// Set the system time zone. Only trusted processes may do this.
+    ///
+    /// # Arguments
+    ///
+    /// *arg1*: An index into `tz::ZONES`
+    ///
+    /// Returns `xous::Error::NoError`, `AccessDenied` if the caller isn't trusted, or
+    /// `InvalidString` if the zone doesn't exist, as a `usize`
+    #[opcode = 26]
+    fn set_time_zone(&mut self, zone: usize) -> usize;
+
+    /// Register the caller as a critical service, which must send a heartbeat within
+    /// every `deadline_ms`. The watchdog stops being pet when a critical service misses
+    /// its deadline. Only trusted processes may register, and others are given the ID 0.
+    #[opcode = 27]
+    fn register_heartbeat(&mut self, registration: &mut HeartbeatRegistration);
+
+    /// Send a heartbeat for a critical service
+    ///
+    /// # Arguments
+    ///
+    /// *arg1*: The ID returned by `RegisterHeartbeat`
+    #[opcode = 28]
+    fn heartbeat(&mut self, id: u32);
+
+    /// Stop supervising a critical service. Returns `false` if no service with that ID
+    /// belongs to the caller, unless the caller is the watchdog supervisor and the
+    /// service has missed its deadline.
+    #[opcode = 29]
+    fn unregister_heartbeat(&mut self, id: u32) -> bool;
+
+    /// Ask to be told when a critical service misses its deadline, before the watchdog
+    /// stops being pet. Only trusted processes may be the supervisor.
+    #[opcode = 30]
+    fn supervise_watchdog(&mut self, supervisor: &mut WatchdogSupervisor);
+
+    /// Count the requests that the ticktimer is keeping track of, for diagnostics
+    #[opcode = 31]
+    fn stats(&mut self, stats: &mut TicktimerStats);
+
+    /// Hand the set of trusted processes from the name server to the main loop. This
+    /// is only accepted from the ticktimer itself.
+    ///
+    /// # Arguments
+    ///
+    /// *arg1*, *arg2*: The `xous_names::ProcessSet`, low PIDs first
+    #[opcode = 32]
+    #[manual]
+    fn trusted_processes(&mut self);
 }
 
 #[derive(rkyv::Archive, rkyv::Serialize, rkyv::Deserialize)]
 pub struct VersionString {
     pub version: xous_ipc::String<512>,
 }
+
+/// A request to send a message to a server when a timer expires.
+///
+/// The message is a non-blocking `Scalar` with `opcode` as its ID, the timer ID in
+/// `arg1`, and the time of expiry in milliseconds in `arg2` (low word) and `arg3`
+/// (high word).
+#[derive(Debug, Clone, rkyv::Archive, rkyv::Serialize, rkyv::Deserialize)]
+pub struct TimerSubscription {
+    /// The server that the message is sent to
+    pub sid: [u32; 4],
+    pub opcode: u32,
+    /// The number of milliseconds until the timer expires
+    pub ms: u64,
+    /// If `true`, the timer expires again every `ms` milliseconds until it is cancelled
+    pub periodic: bool,
+    /// Filled in by the ticktimer with the ID of the new timer, or 0 if the timer
+    /// couldn't be created
+    pub id: u32,
+}
+
+/// The longest report that `LockDiagnostics` can return, in bytes
+pub const LOCK_REPORT_LEN: usize = 3072;
+
+/// The report returned by `LockDiagnostics`
+#[derive(Debug, Clone, rkyv::Archive, rkyv::Serialize, rkyv::Deserialize)]
+pub struct LockReport {
+    /// One line per contended Mutex, waiting thread and deadlock. Truncated if it
+    /// doesn't fit.
+    pub text: xous_ipc::String<LOCK_REPORT_LEN>,
+    /// The number of cycles of threads waiting on each other
+    pub deadlocks: u32,
+}
+
+/// A request to register the caller as a critical service
+#[derive(Debug, Clone, rkyv::Archive, rkyv::Serialize, rkyv::Deserialize)]
+pub struct HeartbeatRegistration {
+    /// The name that the service is reported under
+    pub name: xous_ipc::String<64>,
+    /// The longest time allowed between heartbeats
+    pub deadline_ms: u32,
+    /// Filled in by the ticktimer with the ID to send heartbeats with, or 0 if the caller
+    /// isn't trusted
+    pub id: u32,
+}
+
+/// A request to be told about critical services that miss their deadline.
+///
+/// The message is a non-blocking `Scalar` with `opcode` as its ID, the ID of the service
+/// in `arg1`, its PID in `arg2`, and the number of milliseconds left before the watchdog
+/// stops being pet in `arg3`. The supervisor may prevent the reset by getting the service
+/// to send a heartbeat again, or by unregistering it on its behalf. The supervisor
+/// registration ends when its process terminates.
+#[derive(Debug, Clone, rkyv::Archive, rkyv::Serialize, rkyv::Deserialize)]
+pub struct WatchdogSupervisor {
+    /// The server that the message is sent to
+    pub sid: [u32; 4],
+    pub opcode: u32,
+    /// Filled in by the ticktimer: `false` if the caller isn't trusted, if another process
+    /// is already the supervisor, or if the ticktimer couldn't connect to the server
+    pub accepted: bool,
+}
+
+/// What the ticktimer is keeping track of, returned by `Stats`
+#[derive(Debug, Clone, Default, rkyv::Archive, rkyv::Serialize, rkyv::Deserialize)]
+pub struct TicktimerStats {
+    pub elapsed_ms: u64,
+    /// Messages handled since boot
+    pub messages: u64,
+    /// Threads blocked in `sleep_ms()` or `sleep_us()`
+    pub sleepers: u32,
+    /// Threads waiting on a condvar, semaphore or lock with a timeout
+    pub timeouts: u32,
+    /// Threads waiting to lock a Mutex
+    pub mutex_waiters: u32,
+    /// Threads waiting on a condvar
+    pub condvar_waiters: u32,
+    pub semaphores: u32,
+    pub rwlocks: u32,
+    pub timers: u32,
+    /// Critical services with a heartbeat deadline
+    pub heartbeats: u32,
+}
@@ -1,9 +1,9 @@
 #![cfg_attr(target_os = "none", no_std)]
 
 pub mod api;
+pub mod tz;
 
-use num_traits::ToPrimitive;
-use xous::{send_message, Error, CID};
+use xous::{send_message, Error};
 use xous_semver::SemVer;
 use core::cell::UnsafeCell;
 
@@ -14,7 +14,7 @@ struct StringRequest([u8; 4096]);
 
 #[derive(Debug)]
 pub struct Ticktimer {
-    conn: CID,
+    client: api::TicktimerClient,
     request: UnsafeCell::<StringRequest>,
 }
 impl Ticktimer {
@@ -22,7 +22,7 @@ impl Ticktimer {
         REFCOUNT.fetch_add(1, Ordering::Relaxed);
         let conn = xous::connect(xous::SID::from_bytes(b"ticktimer-server").unwrap())?;
         Ok(Ticktimer {
-            conn,
+            client: api::TicktimerClient::new(conn),
             request: UnsafeCell::new(StringRequest([0u8; 4096])),
         })
     }
@@ -40,24 +40,9 @@ impl Ticktimer {
     ///
     ///     * A `u64` that is the number of milliseconds elapsed since boot.
     pub fn elapsed_ms(&self) -> u64 {
-        let response = send_message(
-            self.conn,
-            xous::Message::new_blocking_scalar(
-                api::Opcode::ElapsedMs.to_usize().unwrap(),
-                0,
-                0,
-                0,
-                0,
-            ),
-        )
-        .expect("Ticktimer: failure to send message to Ticktimer");
-        if let xous::Result::Scalar2(upper, lower) = response {
-            upper as u64 | ((lower as u64) << 32)
-        } else {
-            panic!(
-                "Ticktimer elapsed_ms(): unexpected return value."
-            );
-        }
+        self.client
+            .elapsed_ms()
+            .expect("Ticktimer: failure to send message to Ticktimer")
     }
 
     /// Sleep for at least `ms` milliseconds. Blocks until the requested time has passed.
@@ -66,28 +51,45 @@ impl Ticktimer {
     ///
     ///     * ms: A `usize` specifying how many milliseconds to sleep for
     pub fn sleep_ms(&self, ms: usize) -> Result<(), Error> {
-        send_message(
-            self.conn,
-            xous::Message::new_blocking_scalar(
-                api::Opcode::SleepMs.to_usize().unwrap(),
-                ms,
-                0,
-                0,
-                0,
-            ),
-        )
-        .map(|_| ())
+        self.client.sleep_ms(ms)
+    }
+
+    /// Return the number of microseconds that have elapsed since boot. This follows the
+    /// same rules as `elapsed_ms()`, and its resolution is limited by the tick of the
+    /// hardware timer.
+    ///
+    /// On Cramium the timer ticks once per microsecond. On Precursor it ticks once per
+    /// millisecond, so there the result is always a multiple of 1000 and is no more
+    /// precise than `elapsed_ms()`. Hosted mode reads the host's clock.
+    ///
+    /// # Returns:
+    ///
+    ///     * A `u64` that is the number of microseconds elapsed since boot.
+    pub fn elapsed_us(&self) -> u64 {
+        self.client
+            .elapsed_us()
+            .expect("Ticktimer: failure to send message to Ticktimer")
+    }
+
+    /// Sleep for at least `us` microseconds. Blocks until the requested time has passed.
+    ///
+    /// The sleep ends on a tick of the hardware timer, so a sleep that is shorter than
+    /// a tick lasts until the next tick. On Precursor that is once per millisecond, so
+    /// `us` is in effect rounded up to whole milliseconds. Sleepers that become due on
+    /// the same tick are woken together.
+    ///
+    /// # Arguments:
+    ///
+    ///     * us: A `usize` specifying how many microseconds to sleep for
+    pub fn sleep_us(&self, us: usize) -> Result<(), Error> {
+        self.client.sleep_us(us)
     }
 
     /// Ping the watchdog timer. Processes may use this to periodically ping the WDT to prevent
     /// the system from resetting itself. Note that every call to `sleep_ms()` also implicitly
     /// pings the WDT, so in more complicated systems an explicit call is not needed.
     pub fn ping_wdt(&self) {
-        send_message(
-            self.conn,
-            xous::Message::new_scalar(api::Opcode::PingWdt.to_usize().unwrap(), 0, 0, 0, 0),
-        )
-        .expect("Couldn't send WDT ping");
+        self.client.ping_wdt().expect("Couldn't send WDT ping");
     }
 
     /// Query version information embedded in this implementation crate by the build system.
@@ -108,7 +110,7 @@ impl Ticktimer {
             .unwrap()
         };
         let result = send_message(
-            self.conn,
+            self.client.conn(),
             xous::Message::new_lend_mut(
                 api::Opcode::GetVersion as _,
                 memory_range,
@@ -150,17 +152,7 @@ impl Ticktimer {
     ///
     ///     * mtx: A `usize` referring to the Mutex. This is probably a pointer, but can be any `usize`
     pub fn lock_mutex(&self, mtx: usize) {
-        send_message(
-            self.conn,
-            xous::Message::new_blocking_scalar(
-                api::Opcode::LockMutex.to_usize().unwrap(),
-                mtx,
-                0,
-                0,
-                0,
-            ),
-        )
-        .expect("couldn't lock mutex");
+        self.client.lock_mutex(mtx).expect("couldn't lock mutex");
     }
 
     /// Unlock the given Mutex. Does not block. If the Mutex is not locked, then it will be
@@ -171,11 +163,7 @@ impl Ticktimer {
     ///
     ///     * mtx: A `usize` referring to the Mutex. This is probably a pointer, but can be any `usize`
     pub fn unlock_mutex(&self, mtx: usize) {
-        send_message(
-            self.conn,
-            xous::Message::new_scalar(api::Opcode::UnlockMutex.to_usize().unwrap(), mtx, 0, 0, 0),
-        )
-        .expect("couldn't unlock mutex");
+        self.client.unlock_mutex(mtx).expect("couldn't unlock mutex");
     }
 
     /// Wait for a Condition on the given condvar, with an optional Duration
@@ -189,19 +177,18 @@ impl Ticktimer {
     ///
     ///     * true: the condition was successfully received
     ///     * false: the condition was not received and the operation itmed out
+    ///
+    /// This is sent as a `BlockingScalar`, as libstd does. Earlier versions of this crate
+    /// sent a non-blocking `Scalar`, which the server can't respond to, so the call
+    /// returned `false` straight away instead of waiting.
     pub fn wait_condition(&self, condvar: usize, duration: Option<core::time::Duration>) -> bool {
-        send_message(
-            self.conn,
-            xous::Message::new_scalar(
-                api::Opcode::WaitForCondition.to_usize().unwrap(),
+        self.client
+            .wait_for_condition(
                 condvar,
                 duration.map(|d| d.as_millis() as usize).unwrap_or(0),
-                0,
-                0,
-            ),
-        )
-        .map(|r| r == xous::Result::Scalar1(0))
-        .expect("couldn't wait for condition")
+            )
+            .map(|r| r == 0)
+            .expect("couldn't wait for condition")
     }
 
     /// Notify a condition to one or more Waiters
@@ -212,19 +199,346 @@ impl Ticktimer {
     ///     * count: The number of Waiters to wake up
     ///
     pub fn notify_condition(&self, condvar: usize, count: usize) {
-        send_message(
-            self.conn,
-            xous::Message::new_scalar(
-                api::Opcode::NotifyCondition.to_usize().unwrap(),
-                condvar,
-                count,
-                0,
-                0,
-            ),
-        )
-        .map(|r| r == xous::Result::Scalar1(0))
-        .expect("couldn't notify condition");
+        self.client
+            .notify_condition(condvar, count)
+            .expect("couldn't notify condition");
     }
+
+    /// Take one unit from a counting semaphore, blocking until one is available or the
+    /// timeout expires. Waiters are served in the order that they arrived.
+    ///
+    /// Like Mutexes, semaphores start out empty. Call `release_semaphore()` to make
+    /// units available.
+    ///
+    /// # Arguments:
+    ///
+    ///     * sem: A `usize` referring to the semaphore. This is probably a pointer, but can be any `usize`
+    ///     * timeout: The amount of time to wait for a unit, if any
+    ///
+    /// # Returns:
+    ///
+    ///     * true: a unit was taken
+    ///     * false: the operation timed out
+    pub fn acquire_semaphore(&self, sem: usize, timeout: Option<core::time::Duration>) -> bool {
+        self.client
+            .acquire_semaphore(sem, timeout_ms(timeout))
+            .map(|r| r == 0)
+            .expect("couldn't acquire semaphore")
+    }
+
+    /// Return `count` units to a counting semaphore. Does not block.
+    ///
+    /// # Arguments:
+    ///
+    ///     * sem: A `usize` referring to the semaphore. This is probably a pointer, but can be any `usize`
+    ///     * count: The number of units to return
+    pub fn release_semaphore(&self, sem: usize, count: usize) {
+        self.client
+            .release_semaphore(sem, count)
+            .expect("couldn't release semaphore");
+    }
+
+    /// Take a shared read lock, blocking until no writer holds or is waiting for the
+    /// lock, or until the timeout expires. Reader-writer locks start out unlocked.
+    ///
+    /// # Arguments:
+    ///
+    ///     * lock: A `usize` referring to the lock. This is probably a pointer, but can be any `usize`
+    ///     * timeout: The amount of time to wait for the lock, if any
+    ///
+    /// # Returns:
+    ///
+    ///     * true: the lock was taken
+    ///     * false: the operation timed out
+    pub fn read_lock(&self, lock: usize, timeout: Option<core::time::Duration>) -> bool {
+        self.client
+            .read_lock(lock, timeout_ms(timeout))
+            .map(|r| r == 0)
+            .expect("couldn't take read lock")
+    }
+
+    /// Take the exclusive write lock, blocking until the lock is free and every thread
+    /// that was waiting before this one has been served, or until the timeout expires.
+    ///
+    /// # Arguments:
+    ///
+    ///     * lock: A `usize` referring to the lock. This is probably a pointer, but can be any `usize`
+    ///     * timeout: The amount of time to wait for the lock, if any
+    ///
+    /// # Returns:
+    ///
+    ///     * true: the lock was taken
+    ///     * false: the operation timed out
+    pub fn write_lock(&self, lock: usize, timeout: Option<core::time::Duration>) -> bool {
+        self.client
+            .write_lock(lock, timeout_ms(timeout))
+            .map(|r| r == 0)
+            .expect("couldn't take write lock")
+    }
+
+    /// Release a read lock that was taken with `read_lock()`. Does not block.
+    pub fn read_unlock(&self, lock: usize) {
+        self.client
+            .read_unlock(lock)
+            .expect("couldn't release read lock");
+    }
+
+    /// Release the write lock that was taken with `write_lock()`. Does not block.
+    pub fn write_unlock(&self, lock: usize) {
+        self.client
+            .write_unlock(lock)
+            .expect("couldn't release write lock");
+    }
+
+    /// Describe the Mutexes that threads are waiting on, who holds them, and which threads
+    /// are waiting on each other in a cycle. This is meant for debugging hangs.
+    ///
+    /// Only contended Mutexes are tracked, so the owner of a Mutex that was taken without
+    /// contention is unknown. A cycle is only found when every Mutex in it was handed
+    /// over by the ticktimer, so two threads that each took a Mutex without contention
+    /// and then wait on each other's are shown waiting, but aren't counted as a deadlock.
+    /// Threads are identified by the kernel, so this covers Mutexes and condvars used
+    /// through libstd as well as through this crate.
+    ///
+    /// # Returns:
+    ///
+    ///     * The report, and the number of deadlocks that were found
+    pub fn lock_diagnostics(&self) -> Result<(String, u32), Error> {
+        let mut report = api::LockReport {
+            text: xous_ipc::String::new(),
+            deadlocks: 0,
+        };
+        self.client.lock_diagnostics(&mut report)?;
+        Ok((
+            report.text.as_str().unwrap_or("").to_owned(),
+            report.deadlocks,
+        ))
+    }
+
+    /// Return the wall-clock time in milliseconds since the Unix epoch, in UTC. The time
+    /// keeps counting from the last call to `set_utc_ms()`, and is `None` if it was never set.
+    ///
+    /// Time spent in suspend is missing from the wall clock until it is set again, see
+    /// `wall_clock_synchronized()`.
+    pub fn utc_ms(&self) -> Option<u64> {
+        match self
+            .client
+            .utc_ms()
+            .expect("Ticktimer: failure to send message to Ticktimer")
+        {
+            0 => None,
+            ms => Some(ms),
+        }
+    }
+
+    /// Set the wall-clock time, for example from the RTC or from the network. Only
+    /// trusted processes may set the time, see `XousNames::trusted_processes()`.
+    ///
+    /// # Arguments:
+    ///
+    ///     * utc_ms: The time in milliseconds since the Unix epoch, in UTC
+    ///
+    /// # Returns:
+    ///
+    ///     * Error::AccessDenied if the calling process isn't trusted
+    pub fn set_utc_ms(&self, utc_ms: u64) -> Result<(), Error> {
+        match Error::from_usize(self.client.set_utc_ms(utc_ms)?) {
+            Error::NoError => Ok(()),
+            e => Err(e),
+        }
+    }
+
+    /// Check whether the wall clock can be trusted.
+    ///
+    /// # Returns:
+    ///
+    ///     * true: the time was set, and the system hasn't been suspended since
+    ///     * false: the time was never set, or the time spent in a suspend is missing from it
+    pub fn wall_clock_synchronized(&self) -> bool {
+        self.client
+            .wall_clock_synchronized()
+            .expect("Ticktimer: failure to send message to Ticktimer")
+    }
+
+    /// Return the current local time in the system time zone, or `None` if the wall-clock
+    /// time was never set
+    pub fn local_time(&self) -> Option<tz::DateTime> {
+        let utc_ms = self.utc_ms()?;
+        Some(self.time_zone().local_time(utc_ms as i64))
+    }
+
+    /// Return the system time zone, which starts out as UTC
+    pub fn time_zone(&self) -> &'static tz::Zone {
+        let zone = self
+            .client
+            .time_zone()
+            .expect("Ticktimer: failure to send message to Ticktimer");
+        tz::ZONES.get(zone).unwrap_or(&tz::ZONES[tz::UTC])
+    }
+
+    /// Set the system time zone. Only trusted processes may change it.
+    ///
+    /// # Arguments:
+    ///
+    ///     * name: The IANA name of a zone in `tz::ZONES`, such as "Europe/Berlin"
+    ///
+    /// # Returns:
+    ///
+    ///     * Error::InvalidString if the zone isn't in the table
+    ///     * Error::AccessDenied if the calling process isn't trusted
+    pub fn set_time_zone(&self, name: &str) -> Result<(), Error> {
+        let zone = tz::find(name).ok_or(Error::InvalidString)?;
+        // InvalidString means that the Ticktimer was built with an older table
+        match Error::from_usize(self.client.set_time_zone(zone)?) {
+            Error::NoError => Ok(()),
+            e => Err(e),
+        }
+    }
+
+    /// Register the calling process as a critical service. It must then call `heartbeat()`
+    /// at least once every `deadline_ms`, or the watchdog stops being pet and the system
+    /// resets. If a supervisor is registered with `supervise_watchdog()`, it is told
+    /// about the missed deadline first. Only trusted processes may register, see
+    /// `XousNames::trusted_processes()`. The registration ends when the process terminates.
+    ///
+    /// # Arguments:
+    ///
+    ///     * name: The name of the service, which is used in the logs and by the supervisor
+    ///     * deadline_ms: The longest time allowed between heartbeats
+    ///
+    /// # Returns:
+    ///
+    ///     * The ID to pass to `heartbeat()` and `unregister_heartbeat()`
+    ///     * Error::AccessDenied if the calling process isn't trusted
+    pub fn register_heartbeat(&self, name: &str, deadline_ms: u32) -> Result<u32, Error> {
+        let mut registration = api::HeartbeatRegistration {
+            name: xous_ipc::String::from_str(name),
+            deadline_ms,
+            id: 0,
+        };
+        self.client.register_heartbeat(&mut registration)?;
+        match registration.id {
+            0 => Err(Error::AccessDenied),
+            id => Ok(id),
+        }
+    }
+
+    /// Tell the Ticktimer that a critical service is still alive. This does not block.
+    pub fn heartbeat(&self, id: u32) -> Result<(), Error> {
+        self.client.heartbeat(id)
+    }
+
+    /// Stop supervising a critical service, for example before it exits on purpose.
+    ///
+    /// # Returns:
+    ///
+    ///     * true: the service is no longer critical
+    ///     * false: no service with that ID belongs to this process, and this process
+    ///       isn't the watchdog supervisor or the service hasn't missed its deadline
+    pub fn unregister_heartbeat(&self, id: u32) -> Result<bool, Error> {
+        self.client.unregister_heartbeat(id)
+    }
+
+    /// Ask to be told when a critical service misses its deadline. There is only one
+    /// supervisor, which must be a trusted process, and only it may replace its own
+    /// registration until it terminates.
+    ///
+    /// The message is a non-blocking `Scalar` with `opcode` as its ID, the ID of the
+    /// service in `arg1`, its PID in `arg2`, and the number of milliseconds left before
+    /// the watchdog stops being pet in `arg3`.
+    ///
+    /// # Returns:
+    ///
+    ///     * true: this process is now the supervisor
+    ///     * false: this process isn't trusted, another process is already the supervisor,
+    ///       or the server couldn't be reached
+    pub fn supervise_watchdog(&self, sid: xous::SID, opcode: u32) -> Result<bool, Error> {
+        let mut supervisor = api::WatchdogSupervisor {
+            sid: sid.to_array(),
+            opcode,
+            accepted: false,
+        };
+        self.client.supervise_watchdog(&mut supervisor)?;
+        Ok(supervisor.accepted)
+    }
+
+    /// Count the sleepers, waiters, timers and critical services that the Ticktimer is
+    /// keeping track of. This is meant for diagnostics, such as a console command.
+    pub fn stats(&self) -> Result<api::TicktimerStats, Error> {
+        let mut stats = api::TicktimerStats::default();
+        self.client.stats(&mut stats)?;
+        Ok(stats)
+    }
+
+    /// Ask the Ticktimer to send a message to a server after `ms` milliseconds, and then
+    /// every `ms` milliseconds if `periodic` is set. This lets a server run periodic work
+    /// from its own message loop instead of dedicating a thread to sleeping.
+    ///
+    /// The message is a non-blocking `Scalar` with `opcode` as its ID, the timer ID in
+    /// `arg1`, and the time of expiry in milliseconds in `arg2` (low word) and `arg3`
+    /// (high word). Expirations that arrive while the server's queue is full are dropped.
+    ///
+    /// # Arguments:
+    ///
+    ///     * sid: The server to send the message to. This is usually the caller's own server.
+    ///     * opcode: The ID of the message
+    ///     * ms: The number of milliseconds until the timer expires
+    ///     * periodic: Whether the timer repeats until it is cancelled
+    ///
+    /// # Returns:
+    ///
+    ///     * The ID of the timer, which may be passed to `cancel_timer()` and `reschedule_timer()`
+    pub fn subscribe_timer(
+        &self,
+        sid: xous::SID,
+        opcode: u32,
+        ms: u64,
+        periodic: bool,
+    ) -> Result<u32, Error> {
+        let mut subscription = api::TimerSubscription {
+            sid: sid.to_array(),
+            opcode,
+            ms,
+            periodic,
+            id: 0,
+        };
+        self.client.subscribe_timer(&mut subscription)?;
+        match subscription.id {
+            // the Ticktimer couldn't connect to the server
+            0 => Err(Error::ServerNotFound),
+            id => Ok(id),
+        }
+    }
+
+    /// Cancel a timer that was created with `subscribe_timer()`. A message for an
+    /// expiry that happened just before the timer was cancelled may still arrive.
+    ///
+    /// # Returns:
+    ///
+    ///     * true: the timer was cancelled
+    ///     * false: no timer with that ID belongs to this process
+    pub fn cancel_timer(&self, id: u32) -> Result<bool, Error> {
+        self.client.cancel_timer(id)
+    }
+
+    /// Restart a timer that was created with `subscribe_timer()`, so that it next
+    /// expires `ms` milliseconds from now. A periodic timer takes `ms` as its new period.
+    ///
+    /// # Returns:
+    ///
+    ///     * true: the timer was rescheduled
+    ///     * false: no timer with that ID belongs to this process
+    pub fn reschedule_timer(&self, id: u32, ms: u64) -> Result<bool, Error> {
+        self.client.reschedule_timer(id, ms)
+    }
+}
+
+/// Convert an optional timeout into the milliseconds argument of a blocking call, where
+/// 0 means "wait forever". Timeouts that round down to 0 are rounded up instead.
+fn timeout_ms(timeout: Option<core::time::Duration>) -> usize {
+    timeout
+        .map(|d| (d.as_millis() as usize).max(1))
+        .unwrap_or(0)
 }
 
 use core::sync::atomic::{AtomicU32, Ordering};
@@ -234,7 +548,7 @@ impl Drop for Ticktimer {
         // de-allocate myself. It's unsafe because we are responsible to make sure nobody else is using the connection.
         if REFCOUNT.fetch_sub(1, Ordering::Relaxed) == 1 {
             unsafe {
-                xous::disconnect(self.conn).unwrap();
+                xous::disconnect(self.client.conn()).unwrap();
             }
         }
     }
@@ -0,0 +1,359 @@
+//! A compiled-in table of time zones, and conversion from UTC to local civil time.
+//!
+//! Daylight saving rules follow the POSIX `TZ` model: an offset from UTC, and an optional
+//! pair of yearly transitions on the nth (or last) Sunday of a month. Only the current
+//! rules of each zone are known, so local times before the most recent rule change are
+//! not historically accurate.
+
+/// The ticktimer identifies zones by their index in `ZONES`, so zones may only ever be
+/// appended to this table.
+#[rustfmt::skip]
+pub const ZONES: &[Zone] = &[
+    Zone { name: "UTC", offset_min: 0, dst: None },
+    Zone { name: "Europe/London", offset_min: 0, dst: Some(eu(60)) },
+    Zone { name: "Europe/Berlin", offset_min: 60, dst: Some(eu(120)) },
+    Zone { name: "Europe/Paris", offset_min: 60, dst: Some(eu(120)) },
+    Zone { name: "Europe/Helsinki", offset_min: 120, dst: Some(eu(180)) },
+    Zone { name: "Europe/Moscow", offset_min: 180, dst: None },
+    Zone { name: "Asia/Kolkata", offset_min: 330, dst: None },
+    Zone { name: "Asia/Singapore", offset_min: 480, dst: None },
+    Zone { name: "Asia/Shanghai", offset_min: 480, dst: None },
+    Zone { name: "Asia/Tokyo", offset_min: 540, dst: None },
+    Zone { name: "Australia/Sydney", offset_min: 600, dst: Some(AUSTRALIA) },
+    Zone { name: "Pacific/Auckland", offset_min: 720, dst: Some(NEW_ZEALAND) },
+    Zone { name: "Pacific/Honolulu", offset_min: -600, dst: None },
+    Zone { name: "America/Anchorage", offset_min: -540, dst: Some(US) },
+    Zone { name: "America/Los_Angeles", offset_min: -480, dst: Some(US) },
+    Zone { name: "America/Denver", offset_min: -420, dst: Some(US) },
+    Zone { name: "America/Phoenix", offset_min: -420, dst: None },
+    Zone { name: "America/Chicago", offset_min: -360, dst: Some(US) },
+    Zone { name: "America/New_York", offset_min: -300, dst: Some(US) },
+    Zone { name: "America/Sao_Paulo", offset_min: -180, dst: None },
+];
+
+/// The index of `UTC` in `ZONES`, which is the zone that the ticktimer starts out in
+pub const UTC: usize = 0;
+
+const LAST: u8 = 5;
+
+/// The European Union switches at 01:00 UTC everywhere, which is `minute` in local
+/// standard time
+const fn eu(minute: u16) -> DstRule {
+    DstRule {
+        start: sunday(3, LAST, minute),
+        end: sunday(10, LAST, minute),
+    }
+}
+const US: DstRule = DstRule {
+    start: sunday(3, 2, 120),
+    end: sunday(11, 1, 60),
+};
+const AUSTRALIA: DstRule = DstRule {
+    start: sunday(10, 1, 120),
+    end: sunday(4, 1, 120),
+};
+const NEW_ZEALAND: DstRule = DstRule {
+    start: sunday(9, LAST, 120),
+    end: sunday(4, 1, 120),
+};
+
+const fn sunday(month: u8, week: u8, minute: u16) -> Transition {
+    Transition {
+        month,
+        week,
+        minute,
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Zone {
+    /// The IANA name of the zone
+    pub name: &'static str,
+    /// The offset of standard time from UTC, in minutes
+    pub offset_min: i16,
+    /// When daylight saving time applies, if ever
+    pub dst: Option<DstRule>,
+}
+
+/// Daylight saving time, which is always one hour ahead of standard time. In the southern
+/// hemisphere `start` comes later in the year than `end`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct DstRule {
+    pub start: Transition,
+    pub end: Transition,
+}
+
+/// A switch between standard and daylight saving time, which happens on a Sunday
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Transition {
+    /// 1 for January through 12 for December
+    pub month: u8,
+    /// Which Sunday of the month: 1 through 4, or 5 for the last one
+    pub week: u8,
+    /// The time of day of the switch in minutes, in local standard time
+    pub minute: u16,
+}
+
+/// A broken-down local time
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct DateTime {
+    pub year: i32,
+    /// 1 through 12
+    pub month: u8,
+    /// 1 through 31
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+    pub millisecond: u16,
+    /// 0 for Sunday through 6 for Saturday
+    pub weekday: u8,
+    /// The offset from UTC that this time is in, including daylight saving time
+    pub offset_min: i32,
+}
+
+const MS_PER_MIN: i64 = 60 * 1000;
+const MS_PER_DAY: i64 = 24 * 60 * MS_PER_MIN;
+
+/// Look up a zone by its name, returning its index in `ZONES`
+pub fn find(name: &str) -> Option<usize> {
+    ZONES.iter().position(|zone| zone.name == name)
+}
+
+impl Zone {
+    /// The offset from UTC in minutes at the instant `utc_ms`, in milliseconds since the
+    /// Unix epoch
+    pub fn offset_at(&self, utc_ms: i64) -> i32 {
+        let standard = self.offset_min as i32;
+        let rule = match self.dst {
+            Some(rule) => rule,
+            None => return standard,
+        };
+        let local_ms = utc_ms + standard as i64 * MS_PER_MIN;
+        let (year, _, _) = civil_from_days(local_ms.div_euclid(MS_PER_DAY));
+        let start = rule.start.local_ms(year);
+        let end = rule.end.local_ms(year);
+        let dst = if start < end {
+            local_ms >= start && local_ms < end
+        } else {
+            local_ms >= start || local_ms < end
+        };
+        if dst {
+            standard + 60
+        } else {
+            standard
+        }
+    }
+
+    /// Convert `utc_ms`, in milliseconds since the Unix epoch, to local time in this zone
+    pub fn local_time(&self, utc_ms: i64) -> DateTime {
+        DateTime::new(utc_ms, self.offset_at(utc_ms))
+    }
+}
+
+impl Transition {
+    /// The time of this transition in `year`, in milliseconds since the Unix epoch in
+    /// local standard time
+    fn local_ms(&self, year: i32) -> i64 {
+        let day = if self.week >= LAST {
+            // Go back from the last day of the month to a Sunday
+            let (next_year, next_month) = if self.month == 12 {
+                (year + 1, 1)
+            } else {
+                (year, self.month + 1)
+            };
+            let last = days_from_civil(next_year, next_month, 1) - 1;
+            last - weekday(last) as i64
+        } else {
+            let first = days_from_civil(year, self.month, 1);
+            let first_sunday = first + (7 - weekday(first) as i64) % 7;
+            first_sunday + 7 * (self.week as i64 - 1)
+        };
+        day * MS_PER_DAY + self.minute as i64 * MS_PER_MIN
+    }
+}
+
+impl DateTime {
+    /// Break down `utc_ms`, in milliseconds since the Unix epoch, into the local time at
+    /// `offset_min` minutes from UTC
+    pub fn new(utc_ms: i64, offset_min: i32) -> DateTime {
+        let local_ms = utc_ms + offset_min as i64 * MS_PER_MIN;
+        let days = local_ms.div_euclid(MS_PER_DAY);
+        let ms = local_ms.rem_euclid(MS_PER_DAY);
+        let (year, month, day) = civil_from_days(days);
+        DateTime {
+            year,
+            month,
+            day,
+            hour: (ms / (60 * MS_PER_MIN)) as u8,
+            minute: (ms / MS_PER_MIN % 60) as u8,
+            second: (ms / 1000 % 60) as u8,
+            millisecond: (ms % 1000) as u16,
+            weekday: weekday(days),
+            offset_min,
+        }
+    }
+}
+
+impl core::fmt::Display for DateTime {
+    /// Formats as RFC 3339, for example `2024-03-31T03:00:00.000+02:00`
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let sign = if self.offset_min < 0 { '-' } else { '+' };
+        let offset = self.offset_min.unsigned_abs();
+        write!(
+            f,
+            "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}{}{:02}:{:02}",
+            self.year,
+            self.month,
+            self.day,
+            self.hour,
+            self.minute,
+            self.second,
+            self.millisecond,
+            sign,
+            offset / 60,
+            offset % 60
+        )
+    }
+}
+
+/// The day of the week of a day counted from the Unix epoch, with 0 for Sunday. The
+/// epoch was a Thursday.
+fn weekday(days: i64) -> u8 {
+    (days + 4).rem_euclid(7) as u8
+}
+
+/// The number of days from the Unix epoch to a date in the proleptic Gregorian calendar.
+/// This is Howard Hinnant's `days_from_civil()`.
+fn days_from_civil(year: i32, month: u8, day: u8) -> i64 {
+    let year = if month <= 2 {
+        year as i64 - 1
+    } else {
+        year as i64
+    };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let month = month as i64;
+    let day_of_year =
+        (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day as i64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+/// The inverse of `days_from_civil()`
+fn civil_from_days(days: i64) -> (i32, u8, u8) {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days - era * 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * mp + 2) / 5 + 1) as u8;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u8;
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year as i32, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Milliseconds since the Unix epoch of a time in UTC
+    fn utc(year: i32, month: u8, day: u8, hour: i64, minute: i64) -> i64 {
+        days_from_civil(year, month, day) * MS_PER_DAY + (hour * 60 + minute) * MS_PER_MIN
+    }
+
+    fn zone(name: &str) -> &'static Zone {
+        &ZONES[find(name).unwrap()]
+    }
+
+    /// Check that `zone` switches from `before` to `after` minutes from UTC at `at_ms`
+    fn switches(zone: &Zone, at_ms: i64, before: i32, after: i32) {
+        assert_eq!(
+            zone.offset_at(at_ms - 1),
+            before,
+            "{} before {}",
+            zone.name,
+            at_ms
+        );
+        assert_eq!(zone.offset_at(at_ms), after, "{} at {}", zone.name, at_ms);
+    }
+
+    #[test]
+    fn united_states() {
+        let new_york = zone("America/New_York");
+        // 02:00 EST on the second Sunday in March, and 02:00 EDT on the first Sunday
+        // in November
+        switches(new_york, utc(2024, 3, 10, 7, 0), -300, -240);
+        switches(new_york, utc(2024, 11, 3, 6, 0), -240, -300);
+        let los_angeles = zone("America/Los_Angeles");
+        switches(los_angeles, utc(2025, 3, 9, 10, 0), -480, -420);
+        switches(los_angeles, utc(2025, 11, 2, 9, 0), -420, -480);
+        // Arizona doesn't observe daylight saving time
+        assert_eq!(
+            zone("America/Phoenix").offset_at(utc(2024, 7, 1, 0, 0)),
+            -420
+        );
+    }
+
+    #[test]
+    fn european_union() {
+        // Everyone switches at 01:00 UTC on the last Sundays of March and October
+        for (name, standard) in [
+            ("Europe/London", 0),
+            ("Europe/Berlin", 60),
+            ("Europe/Helsinki", 120),
+        ] {
+            let zone = zone(name);
+            switches(zone, utc(2024, 3, 31, 1, 0), standard, standard + 60);
+            switches(zone, utc(2024, 10, 27, 1, 0), standard + 60, standard);
+            switches(zone, utc(2025, 3, 30, 1, 0), standard, standard + 60);
+            switches(zone, utc(2025, 10, 26, 1, 0), standard + 60, standard);
+        }
+    }
+
+    #[test]
+    fn australia() {
+        let sydney = zone("Australia/Sydney");
+        // 03:00 AEDT on the first Sunday in April, and 02:00 AEST on the first Sunday
+        // in October, which is the day before in UTC
+        switches(sydney, utc(2024, 4, 6, 16, 0), 660, 600);
+        switches(sydney, utc(2024, 10, 5, 16, 0), 600, 660);
+        // Summer spans the new year
+        assert_eq!(sydney.offset_at(utc(2025, 1, 1, 0, 0)), 660);
+        assert_eq!(sydney.offset_at(utc(2024, 7, 1, 0, 0)), 600);
+    }
+
+    #[test]
+    fn new_zealand() {
+        let auckland = zone("Pacific/Auckland");
+        // 03:00 NZDT on the first Sunday in April, and 02:00 NZST on the last Sunday
+        // in September
+        switches(auckland, utc(2024, 4, 6, 14, 0), 780, 720);
+        switches(auckland, utc(2024, 9, 28, 14, 0), 720, 780);
+        switches(auckland, utc(2025, 4, 5, 14, 0), 780, 720);
+        switches(auckland, utc(2025, 9, 27, 14, 0), 720, 780);
+    }
+
+    #[test]
+    fn local_times() {
+        let berlin = zone("Europe/Berlin");
+        let time = berlin.local_time(utc(2024, 3, 31, 1, 0));
+        assert_eq!(time.to_string(), "2024-03-31T03:00:00.000+02:00");
+        assert_eq!(time.weekday, 0);
+        let time = zone("America/New_York").local_time(utc(2024, 1, 1, 4, 59) + 999);
+        assert_eq!(time.to_string(), "2023-12-31T23:59:00.999-05:00");
+        assert_eq!(time.weekday, 0);
+    }
+
+    #[test]
+    fn dates_round_trip() {
+        for days in [-719468, -1, 0, 11016, 19782, 2932896] {
+            let (year, month, day) = civil_from_days(days);
+            assert_eq!(days_from_civil(year, month, day), days);
+        }
+        assert_eq!(civil_from_days(0), (1970, 1, 1));
+        assert_eq!(civil_from_days(days_from_civil(2024, 2, 29)), (2024, 2, 29));
+    }
+}
@@ -6,6 +6,10 @@ use crate::{mem::MemoryManager, services::SystemServices};
 use core::mem;
 use xous_kernel::{MemoryAddress, MemoryRange, MemorySize, Message, MessageSender, PID, SID, TID};
 
+/// The most messages a server can have queued. A `SenderID` only has a byte for
+/// the index of a message in the queue.
+const MAX_QUEUED_MESSAGES: usize = 256;
+
 /// A pointer to resolve a server ID to a particular process
 #[derive(PartialEq, Debug)]
 pub struct Server {
@@ -46,29 +50,88 @@ pub struct SenderID {
     pub idx: usize,
     /// The process ID that sent this message
     pid: Option<PID>,
+    /// The process ID of the server that forwarded this message, if any
+    proxy: Option<PID>,
+    /// The thread that sent this message, if it wasn't forwarded
+    tid: Option<TID>,
 }
 
 impl SenderID {
     pub fn new(sidx: usize, idx: usize, pid: Option<PID>) -> Self {
-        SenderID { sidx, idx, pid }
+        SenderID {
+            sidx,
+            idx,
+            pid,
+            proxy: None,
+            tid: None,
+        }
+    }
+
+    /// Mark this message as having been forwarded by the given process
+    pub fn with_proxy(mut self, proxy: Option<PID>) -> Self {
+        self.proxy = proxy;
+        self
     }
+
+    /// Record the thread that sent this message
+    pub fn with_tid(mut self, tid: TID) -> Self {
+        self.tid = Some(tid);
+        self
+    }
+
+    /// Fill in the sender, proxy and sending thread from a queued message
+    fn set_queued_sender(&mut self, queued_pid: u16, tid: u8) {
+        self.pid = PID::new(queued_pid as u8);
+        self.proxy = PID::new((queued_pid >> 8) as u8);
+        self.tid = Some(tid as TID);
+    }
+}
+
+/// Queued messages keep the PID of the process that sent them in the low
+/// byte of their PID field, and the PID of the server that forwarded them
+/// (if any) in the high byte.
+fn queued_pid(pid: PID, proxy: Option<PID>) -> u16 {
+    pid.get() as u16 | (proxy.map(|p| p.get() as u16).unwrap_or(0) << 8)
 }
 
+/// Set in bits 8-15 of a `MessageSender` when they hold a proxy's PID rather than
+/// the sending thread's TID
+const SENDER_PROXY_FLAG: usize = 0x80;
+
+// A `MessageSender` packs the sender's PID into bits 24-31, the server index into
+// bits 16-23, the sending thread or the proxy into bits 8-15 and the queue index
+// into bits 0-7. The queue index used to have 16 bits, but no queue is longer
+// than `MAX_QUEUED_MESSAGES`, so the upper byte was free. It holds the proxy's PID
+// with `SENDER_PROXY_FLAG` set for a forwarded message, and the TID of the
+// sending thread otherwise. Both PIDs and TIDs fit in the remaining 7 bits.
 impl From<usize> for SenderID {
     fn from(item: usize) -> SenderID {
+        let thread_or_proxy = (item >> 8) & 0xff;
+        let (proxy, tid) = if thread_or_proxy & SENDER_PROXY_FLAG != 0 {
+            (PID::new((thread_or_proxy & !SENDER_PROXY_FLAG) as u8), None)
+        } else {
+            (None, Some(thread_or_proxy as TID).filter(|&tid| tid != 0))
+        };
         SenderID {
             sidx: (item >> 16) & 0xff,
-            idx: item & 0xffff,
+            idx: item & 0xff,
             pid: PID::new((item >> 24) as u8),
+            proxy,
+            tid,
         }
     }
 }
 
 impl From<SenderID> for usize {
     fn from(val: SenderID) -> Self {
+        let thread_or_proxy = match val.proxy {
+            Some(proxy) => proxy.get() as usize | SENDER_PROXY_FLAG,
+            None => val.tid.unwrap_or(0) & !SENDER_PROXY_FLAG,
+        };
         (val.pid.map(|x| x.get() as usize).unwrap_or(0) << 24)
             | ((val.sidx << 16) & 0x00ff0000)
-            | (val.idx & 0xffff)
+            | ((thread_or_proxy << 8) & 0xff00)
+            | (val.idx & 0xff)
     }
 }
 
@@ -102,12 +165,30 @@ pub enum WaitingMessage {
     ForgetMemory(MemoryRange),
 }
 
+/// The kind of reply that a message sitting in a server is waiting for.
+#[derive(Debug, PartialEq)]
+pub enum WaitingKind {
+    /// A BlockingScalar message is waiting for a scalar result
+    Scalar,
+
+    /// Memory was lent immutably and is waiting to be returned
+    Borrow,
+
+    /// Memory was lent mutably and is waiting to be returned
+    MutableBorrow,
+}
+
 /// Internal representation of a queued message for a server. This should be
 /// exactly 8 words / 32 bytes, yielding 128 queued messages per server
 #[repr(usize)]
 #[derive(PartialEq, Debug)]
 enum QueuedMessage {
     Empty,
+
+    /// A slot that was set aside for a message that is about to be added. This
+    /// never outlives the syscall that reserved it.
+    Reserved,
+
     BlockingScalarMessage(
         u16,   /* client PID */
         u8,    /* client TID */
@@ -217,6 +298,7 @@ enum QueuedMessage {
         usize, /* address of memory base in server */
         usize, /* client base address */
         usize, /* Range size */
+        bool,  /* lent mutably */
     ),
 
     /// When a server goes away, its memory must be forgotten instead of being returned
@@ -250,7 +332,7 @@ impl QueuedMessage {
         matches!(
             self,
             &QueuedMessage::WaitingForget(_, _, _, _, _, _)
-                | &QueuedMessage::WaitingReturnMemory(_, _, _, _, _, _)
+                | &QueuedMessage::WaitingReturnMemory(_, _, _, _, _, _, _)
                 | &QueuedMessage::WaitingReturnScalar(_, _, _, _)
         )
     }
@@ -277,7 +359,7 @@ impl Server {
         let queue = unsafe {
             core::slice::from_raw_parts_mut(
                 _backing.as_mut_ptr() as *mut QueuedMessage,
-                _backing.len() / mem::size_of::<QueuedMessage>(),
+                (_backing.len() / mem::size_of::<QueuedMessage>()).min(MAX_QUEUED_MESSAGES),
             )
         };
 
@@ -286,7 +368,8 @@ impl Server {
             let mut queue = vec![];
             // TODO: Replace this with a direct operation on a passed-in page
             queue.resize_with(
-                crate::arch::mem::PAGE_SIZE / mem::size_of::<QueuedMessage>(),
+                (crate::arch::mem::PAGE_SIZE / mem::size_of::<QueuedMessage>())
+                    .min(MAX_QUEUED_MESSAGES),
                 || QueuedMessage::Empty,
             );
             queue
@@ -325,13 +408,15 @@ impl Server {
                 // If there are `Waiting` messages, then something is seriously wrong because
                 // we already determined above that this wouldn't happen.
                 QueuedMessage::WaitingForget(_, _, _, _, _, _)
-                | QueuedMessage::WaitingReturnMemory(_, _, _, _, _, _)
+                | QueuedMessage::WaitingReturnMemory(_, _, _, _, _, _, _)
                 | QueuedMessage::WaitingReturnScalar(_, _, _, _) => panic!("message was waiting"),
 
                 // For `Empty` and `Scalar` messages, all we have to do is ignore them.
                 // The sending process will not be blocked. These messages will be dropped,
                 // and the server will never see them.
-                QueuedMessage::Empty | QueuedMessage::ScalarMessage(_, _, _, _, _, _, _, _, _) => {}
+                QueuedMessage::Empty
+                | QueuedMessage::Reserved
+                | QueuedMessage::ScalarMessage(_, _, _, _, _, _, _, _, _) => {}
 
                 // For `Send` messages, the Server has not yet seen these messages. Simply
                 // prevent this memory from getting mapped into the Server and free it.
@@ -506,7 +591,7 @@ impl Server {
                     arg5,
                     arg6,
                 ) => {
-                    if msg_pid == pid.get() as _ {
+                    if msg_pid as u8 == pid.get() {
                         *entry = QueuedMessage::MemoryMessageROLendTerminated(
                             msg_pid, tid, idx, arg1, arg2, arg3, arg4, arg5, arg6,
                         );
@@ -523,7 +608,7 @@ impl Server {
                     arg5,
                     arg6,
                 ) => {
-                    if msg_pid == pid.get() as _ {
+                    if msg_pid as u8 == pid.get() {
                         *entry = QueuedMessage::MemoryMessageRWLendTerminated(
                             msg_pid, tid, idx, arg1, arg2, arg3, arg4, arg5, arg6,
                         );
@@ -540,7 +625,7 @@ impl Server {
                     arg5,
                     arg6,
                 ) => {
-                    if msg_pid == pid.get() as _ {
+                    if msg_pid as u8 == pid.get() {
                         *entry = QueuedMessage::BlockingScalarTerminated(
                             msg_pid, tid, idx, arg1, arg2, arg3, arg4, arg5, arg6,
                         );
@@ -554,6 +639,49 @@ impl Server {
         }
     }
 
+    /// Return the kind of reply that the message at `message_index` is
+    /// waiting for, or `None` if there is no such message or it is not
+    /// expecting a reply.
+    pub fn waiting_message_kind(&self, message_index: usize) -> Option<WaitingKind> {
+        match self.queue.get(message_index)? {
+            QueuedMessage::WaitingReturnScalar(_, _, _, _) => Some(WaitingKind::Scalar),
+            QueuedMessage::WaitingReturnMemory(_, _, _, _, _, _, false) => {
+                Some(WaitingKind::Borrow)
+            }
+            QueuedMessage::WaitingReturnMemory(_, _, _, _, _, _, true) => {
+                Some(WaitingKind::MutableBorrow)
+            }
+            _ => None,
+        }
+    }
+
+    /// Set aside an empty slot, so that a `queue_message()` or `queue_response()`
+    /// made after handing it back with `release_slot()` is certain to succeed.
+    ///
+    /// # Errors
+    ///
+    /// * **ServerQueueFull**: The server queue cannot accept any more messages
+    pub fn reserve_slot(&mut self) -> Result<usize, xous_kernel::Error> {
+        if self.tail_generation == self.head_generation.wrapping_sub(1) {
+            return Err(xous_kernel::Error::ServerQueueFull);
+        }
+        let queue_idx = self
+            .queue
+            .iter()
+            .position(|entry| *entry == QueuedMessage::Empty)
+            .ok_or(xous_kernel::Error::ServerQueueFull)?;
+        self.queue[queue_idx] = QueuedMessage::Reserved;
+        Ok(queue_idx)
+    }
+
+    /// Empty a slot that was set aside with `reserve_slot()`, or that holds the
+    /// response to a message which was never delivered.
+    pub fn release_slot(&mut self, queue_idx: usize) {
+        if let Some(entry) = self.queue.get_mut(queue_idx) {
+            *entry = QueuedMessage::Empty;
+        }
+    }
+
     /// Convert a `QueuedMesage::WaitingReturnMemory` into `QueuedMessage::Empty`
     /// and return the pair.  Advance the tail.  Note that the `idx` could be
     /// somewhere other than the tail, but as long as it points to a valid
@@ -575,7 +703,7 @@ impl Server {
         // klog!("memory in queue[{}]: {:?}", message_index, current_val);
         let (pid, tid, _idx, server_addr, client_addr, len, forget, is_memory) = match *current_val
         {
-            QueuedMessage::WaitingReturnMemory(pid, tid, idx, server_addr, client_addr, len) => {
+            QueuedMessage::WaitingReturnMemory(pid, tid, idx, server_addr, client_addr, len, _) => {
                 (pid, tid, idx, server_addr, client_addr, len, false, true)
             }
             QueuedMessage::WaitingForget(pid, tid, idx, server_addr, client_addr, len) => {
@@ -678,7 +806,6 @@ impl Server {
             return None;
         }
 
-        use core::convert::TryInto;
         let mut queue_idx = self.queue_tail;
         loop {
             let mut sender = SenderID::new(sidx, queue_idx, None);
@@ -695,7 +822,7 @@ impl Server {
                     offset,
                     valid,
                 ) if idx == self.head_generation => {
-                    sender.pid = PID::new(pid.try_into().unwrap());
+                    sender.set_queued_sender(pid, tid);
                     (
                         xous_kernel::MessageEnvelope {
                             sender: sender.into(),
@@ -713,6 +840,7 @@ impl Server {
                             server_addr,
                             client_addr,
                             buf_size,
+                            false,
                         ),
                     )
                 }
@@ -727,7 +855,7 @@ impl Server {
                     offset,
                     valid,
                 ) if idx == self.head_generation => {
-                    sender.pid = PID::new(pid.try_into().unwrap());
+                    sender.set_queued_sender(pid, tid);
                     (
                         xous_kernel::MessageEnvelope {
                             sender: sender.into(),
@@ -745,6 +873,7 @@ impl Server {
                             server_addr,
                             client_addr,
                             buf_size,
+                            true,
                         ),
                     )
                 }
@@ -759,7 +888,7 @@ impl Server {
                     offset,
                     valid,
                 ) if idx == self.head_generation => {
-                    sender.pid = PID::new(pid.try_into().unwrap());
+                    sender.set_queued_sender(pid, tid);
                     (
                         xous_kernel::MessageEnvelope {
                             sender: sender.into(),
@@ -777,6 +906,7 @@ impl Server {
                             server_addr,
                             client_addr,
                             buf_size,
+                            false,
                         ),
                     )
                 }
@@ -791,7 +921,7 @@ impl Server {
                     offset,
                     valid,
                 ) if idx == self.head_generation => {
-                    sender.pid = PID::new(pid.try_into().unwrap());
+                    sender.set_queued_sender(pid, tid);
                     (
                         xous_kernel::MessageEnvelope {
                             sender: sender.into(),
@@ -809,6 +939,7 @@ impl Server {
                             server_addr,
                             client_addr,
                             buf_size,
+                            true,
                         ),
                     )
                 }
@@ -824,7 +955,7 @@ impl Server {
                     arg3,
                     arg4,
                 ) if idx == self.head_generation => {
-                    sender.pid = PID::new(pid.try_into().unwrap());
+                    sender.set_queued_sender(pid, tid);
                     (
                         xous_kernel::MessageEnvelope {
                             sender: sender.into(),
@@ -843,7 +974,7 @@ impl Server {
                 }
                 QueuedMessage::MemoryMessageSend(
                     pid,
-                    _tid,
+                    tid,
                     idx,
                     _reserved,
                     id,
@@ -852,7 +983,7 @@ impl Server {
                     offset,
                     valid,
                 ) if idx == self.head_generation => {
-                    sender.pid = PID::new(pid.try_into().unwrap());
+                    sender.set_queued_sender(pid, tid);
                     let msg = xous_kernel::MessageEnvelope {
                         sender: sender.into(),
                         body: xous_kernel::Message::Move(xous_kernel::MemoryMessage {
@@ -876,7 +1007,7 @@ impl Server {
                 // Scalar messages have nothing to return, so they can go straight to the `Free` state
                 QueuedMessage::ScalarMessage(
                     pid,
-                    _tid,
+                    tid,
                     idx,
                     _reserved,
                     id,
@@ -885,7 +1016,7 @@ impl Server {
                     arg3,
                     arg4,
                 ) if idx == self.head_generation => {
-                    sender.pid = PID::new(pid.try_into().unwrap());
+                    sender.set_queued_sender(pid, tid);
                     let msg = xous_kernel::MessageEnvelope {
                         sender: sender.into(),
                         body: xous_kernel::Message::Scalar(xous_kernel::ScalarMessage {
@@ -908,7 +1039,7 @@ impl Server {
                 }
                 QueuedMessage::BlockingScalarTerminated(
                     pid,
-                    _tid,
+                    tid,
                     idx,
                     _reserved,
                     id,
@@ -917,7 +1048,7 @@ impl Server {
                     arg3,
                     arg4,
                 ) if idx == self.head_generation => {
-                    sender.pid = PID::new(pid.try_into().unwrap());
+                    sender.set_queued_sender(pid, tid);
                     let msg = xous_kernel::MessageEnvelope {
                         sender: sender.into(),
                         body: xous_kernel::Message::Scalar(xous_kernel::ScalarMessage {
@@ -972,6 +1103,7 @@ impl Server {
         &mut self,
         pid: PID,
         tid: TID,
+        proxy: Option<PID>,
         message: xous_kernel::Message,
         original_address: Option<MemoryAddress>,
     ) -> core::result::Result<usize, xous_kernel::Error> {
@@ -1010,7 +1142,7 @@ impl Server {
         let queue_entry = &mut self.queue[queue_idx];
         *queue_entry = match message {
             xous_kernel::Message::Scalar(msg) => QueuedMessage::ScalarMessage(
-                pid.get() as _,
+                queued_pid(pid, proxy),
                 tid as _,
                 self.tail_generation,
                 0,
@@ -1021,7 +1153,7 @@ impl Server {
                 msg.arg4,
             ),
             xous_kernel::Message::BlockingScalar(msg) => QueuedMessage::BlockingScalarMessage(
-                pid.get() as _,
+                queued_pid(pid, proxy),
                 tid as _,
                 self.tail_generation,
                 0,
@@ -1032,7 +1164,7 @@ impl Server {
                 msg.arg4,
             ),
             xous_kernel::Message::Move(msg) => QueuedMessage::MemoryMessageSend(
-                pid.get() as _,
+                queued_pid(pid, proxy),
                 tid as _,
                 self.tail_generation,
                 original_address.map(|x| x.get()).unwrap_or(0),
@@ -1043,7 +1175,7 @@ impl Server {
                 msg.valid.map(|x| x.get()).unwrap_or(0) as usize,
             ),
             xous_kernel::Message::MutableBorrow(msg) => QueuedMessage::MemoryMessageRWLend(
-                pid.get() as _,
+                queued_pid(pid, proxy),
                 tid as _,
                 self.tail_generation,
                 original_address.map(|x| x.get()).unwrap_or(0),
@@ -1054,7 +1186,7 @@ impl Server {
                 msg.valid.map(|x| x.get()).unwrap_or(0) as usize,
             ),
             xous_kernel::Message::Borrow(msg) => QueuedMessage::MemoryMessageROLend(
-                pid.get() as _,
+                queued_pid(pid, proxy),
                 tid as _,
                 self.tail_generation,
                 original_address.map(|x| x.get()).unwrap_or(0),
@@ -1087,6 +1219,7 @@ impl Server {
         &mut self,
         pid: PID,
         tid: TID,
+        proxy: Option<PID>,
         message: &Message,
         client_address: Option<MemoryAddress>,
     ) -> core::result::Result<usize, xous_kernel::Error> {
@@ -1107,7 +1240,7 @@ impl Server {
         self.queue[queue_idx] = match message {
             xous_kernel::Message::Scalar(_) | xous_kernel::Message::BlockingScalar(_) => {
                 QueuedMessage::WaitingReturnScalar(
-                    pid.get() as _,
+                    queued_pid(pid, proxy),
                     tid as _,
                     0,
                     client_address.map(|x| x.get()).unwrap_or(0),
@@ -1117,7 +1250,7 @@ impl Server {
                 let server_address = msg.buf.as_ptr() as _;
                 let len = msg.buf.len();
                 QueuedMessage::WaitingForget(
-                    pid.get() as _,
+                    queued_pid(pid, proxy),
                     tid as _,
                     0,
                     server_address,
@@ -1129,12 +1262,13 @@ impl Server {
                 let server_address = msg.buf.as_ptr() as _;
                 let len = msg.buf.len();
                 QueuedMessage::WaitingReturnMemory(
-                    pid.get() as _,
+                    queued_pid(pid, proxy),
                     tid as _,
                     0,
                     server_address,
                     client_address.map(|x| x.get()).unwrap_or(0),
                     len,
+                    matches!(message, xous_kernel::Message::MutableBorrow(_)),
                 )
             }
         };
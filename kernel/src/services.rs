@@ -12,10 +12,11 @@ use xous_kernel::MemoryRange;
 use core::num::NonZeroU8;
 
 use crate::filled_array;
-use crate::server::Server;
+use crate::server::{SenderID, Server};
 // use core::mem;
 use xous_kernel::{
-    pid_from_usize, Error, MemoryAddress, Message, ProcessInit, ThreadInit, CID, PID, SID, TID,
+    pid_from_usize, Error, MemoryAddress, Message, MessageEnvelope, ProcessInit, ScalarMessage,
+    ThreadInit, CID, PID, PROCESS_NAME_ID, PROCESS_TERMINATED_ID, SID, TID,
 };
 
 const MAX_SERVER_COUNT: usize = 128;
@@ -293,6 +294,41 @@ impl core::fmt::Debug for Process {
     }
 }
 
+/// The PIDs and names of the processes in the boot image, from the `PNam` argument
+/// that the loader passes in. Each entry is the PID, the length of the name, and the
+/// name, padded to a multiple of four bytes.
+#[cfg(baremetal)]
+fn boot_process_names() -> impl Iterator<Item = (PID, &'static str)> {
+    crate::args::KernelArguments::get()
+        .iter()
+        .filter(|arg| arg.name == u32::from_le_bytes(*b"PNam"))
+        .flat_map(|arg| {
+            let data =
+                unsafe { core::slice::from_raw_parts(arg.data.as_ptr() as *const u8, arg.size) };
+            let mut offset = 0;
+            core::iter::from_fn(move || {
+                let header = data.get(offset..offset + 8)?;
+                let pid = u32::from_le_bytes([header[0], header[1], header[2], header[3]]);
+                let len = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+                let name = data.get(offset + 8..offset + 8 + len as usize)?;
+                offset += 8 + len as usize;
+                offset += (4 - (offset & 3)) & 3;
+                Some((pid, name))
+            })
+        })
+        .filter_map(|(pid, name)| {
+            let pid = PID::new(pid as u8).filter(|p| p.get() as u32 == pid)?;
+            Some((pid, core::str::from_utf8(name).ok()?))
+        })
+}
+
+/// Hosted processes are started by the host rather than from a boot image, so there
+/// are no names that the kernel can vouch for.
+#[cfg(not(baremetal))]
+fn boot_process_names() -> impl Iterator<Item = (PID, &'static str)> {
+    core::iter::empty()
+}
+
 impl SystemServices {
     /// Calls the provided function with the current inner process state.
     pub fn with<F, R>(f: F) -> R
@@ -1319,6 +1355,23 @@ impl SystemServices {
         dest_virt: *mut usize,
         len: usize,
         mutable: bool,
+    ) -> Result<*mut usize, xous_kernel::Error> {
+        let current_pid = self.current_pid();
+        self.lend_memory_from(current_pid, src_virt, dest_pid, dest_virt, len, mutable)
+    }
+
+    /// Lend memory from `src_pid` to `dest_pid`. This is the same as
+    /// `lend_memory()`, except the memory need not belong to the current
+    /// process. The address space of `src_pid` must already be active.
+    #[cfg(baremetal)]
+    fn lend_memory_from(
+        &mut self,
+        src_pid: PID,
+        src_virt: *mut usize,
+        dest_pid: PID,
+        dest_virt: *mut usize,
+        len: usize,
+        mutable: bool,
     ) -> Result<*mut usize, xous_kernel::Error> {
         if len == 0 {
             return Err(xous_kernel::Error::BadAddress);
@@ -1337,10 +1390,9 @@ impl SystemServices {
         let usize_len = len / core::mem::size_of::<usize>();
         let usize_page = crate::mem::PAGE_SIZE / core::mem::size_of::<usize>();
 
-        let current_pid = self.current_pid();
         // If it's within the same process, ignore the move operation and
         // just ensure the pages actually exist.
-        if current_pid == dest_pid {
+        if src_pid == dest_pid {
             MemoryManager::with_mut(|mm| {
                 for offset in (0..usize_len).step_by(usize_page) {
                     assert!(((src_virt.wrapping_add(offset) as usize) & 0xfff) == 0);
@@ -1350,7 +1402,7 @@ impl SystemServices {
             })?;
             return Ok(src_virt);
         }
-        let src_mapping = self.get_process(current_pid)?.mapping;
+        let src_mapping = self.get_process(src_pid)?.mapping;
         let dest_mapping = self.get_process(dest_pid)?.mapping;
         use crate::mem::MemoryManager;
         MemoryManager::with_mut(|mm| {
@@ -1395,7 +1447,7 @@ impl SystemServices {
                 |e| {
                     panic!(
                         "unable to lend {:08x} in pid {} to {:08x} in pid {}: {:?}",
-                        src_virt as usize, current_pid, dest_virt as usize, dest_pid, e
+                        src_virt as usize, src_pid, dest_virt as usize, dest_pid, e
                     )
                 },
             )
@@ -1431,6 +1483,23 @@ impl SystemServices {
         &mut self,
         src_virt: *mut usize,
         dest_pid: PID,
+        dest_tid: TID,
+        dest_virt: *mut usize,
+        len: usize,
+    ) -> Result<*mut usize, xous_kernel::Error> {
+        let current_pid = self.current_pid();
+        self.return_memory_from(current_pid, src_virt, dest_pid, dest_tid, dest_virt, len)
+    }
+
+    /// Return memory from `src_pid` to `dest_pid`. This is the same as
+    /// `return_memory()`, except the memory need not be in the current
+    /// process. The address space of `src_pid` must already be active.
+    #[cfg(baremetal)]
+    fn return_memory_from(
+        &mut self,
+        src_pid: PID,
+        src_virt: *mut usize,
+        dest_pid: PID,
         _dest_tid: TID,
         dest_virt: *mut usize,
         len: usize,
@@ -1468,12 +1537,11 @@ impl SystemServices {
         let usize_len = len / core::mem::size_of::<usize>();
         let usize_page = crate::mem::PAGE_SIZE / core::mem::size_of::<usize>();
 
-        let current_pid = self.current_pid();
         // If it's within the same process, ignore the operation.
-        if current_pid == dest_pid {
+        if src_pid == dest_pid {
             return Ok(src_virt);
         }
-        let src_mapping = self.get_process(current_pid)?.mapping;
+        let src_mapping = self.get_process(src_pid)?.mapping;
         let dest_mapping = self.get_process(dest_pid)?.mapping;
         use crate::mem::MemoryManager;
         MemoryManager::with_mut(|mm| {
@@ -1531,6 +1599,109 @@ impl SystemServices {
         Ok(src_virt as *mut usize)
     }
 
+    /// Hand memory that the client lent to the current process on to
+    /// `dest_pid`, as though the client had lent it there directly.
+    ///
+    /// The memory is first returned to the client, then lent from the client
+    /// to the new process. Ownership never overlaps, so the memory is only
+    /// ever visible in one of the two servers.
+    ///
+    /// # Returns
+    ///
+    /// Returns the virtual address of the memory region in the target process.
+    #[cfg(baremetal)]
+    #[allow(clippy::too_many_arguments)]
+    pub fn forward_memory(
+        &mut self,
+        src_virt: *mut usize,
+        client_pid: PID,
+        client_tid: TID,
+        client_virt: *mut usize,
+        dest_pid: PID,
+        len: usize,
+        mutable: bool,
+    ) -> Result<*mut usize, xous_kernel::Error> {
+        let current_pid = self.current_pid();
+        self.return_memory(src_virt, client_pid, client_tid, client_virt, len)?;
+        self.get_process(client_pid)?.mapping.activate()?;
+        let result = self.lend_memory_from(
+            client_pid,
+            client_virt,
+            dest_pid,
+            core::ptr::null_mut(),
+            len,
+            mutable,
+        );
+        self.get_process(current_pid)?.mapping.activate()?;
+        result
+    }
+
+    /// When hosted, the contents of lent memory travel along with the message,
+    /// and the proxy's copy is freed by its side of the syscall once the kernel
+    /// accepts the forward. The target gets the kernel's copy of the buffer, and
+    /// `return_memory()` writes the result back to the client. All that's left to
+    /// do here is to make sure the client is still around to receive it.
+    #[cfg(not(baremetal))]
+    #[allow(clippy::too_many_arguments)]
+    pub fn forward_memory(
+        &mut self,
+        src_virt: *mut usize,
+        client_pid: PID,
+        _client_tid: TID,
+        _client_virt: *mut usize,
+        dest_pid: PID,
+        len: usize,
+        _mutable: bool,
+    ) -> Result<*mut usize, xous_kernel::Error> {
+        if len == 0 {
+            return Err(xous_kernel::Error::BadAddress);
+        }
+        self.get_process(client_pid)?;
+        self.get_process(dest_pid)?;
+        Ok(src_virt)
+    }
+
+    /// Give memory that `forward_memory()` lent to `dest_pid` back to the
+    /// client, for when the forwarded message can't be delivered after all.
+    #[cfg(baremetal)]
+    pub fn unforward_memory(
+        &mut self,
+        dest_pid: PID,
+        dest_virt: *mut usize,
+        client_pid: PID,
+        client_tid: TID,
+        client_virt: *mut usize,
+        len: usize,
+    ) -> Result<(), xous_kernel::Error> {
+        let current_pid = self.current_pid();
+        self.get_process(dest_pid)?.mapping.activate()?;
+        let result = self.return_memory_from(
+            dest_pid,
+            dest_virt,
+            client_pid,
+            client_tid,
+            client_virt,
+            len,
+        );
+        self.get_process(current_pid)?.mapping.activate()?;
+        result.map(|_| ())
+    }
+
+    /// When hosted, the client's memory never left it, so there is nothing to
+    /// give back.
+    #[cfg(not(baremetal))]
+    pub fn unforward_memory(
+        &mut self,
+        _dest_pid: PID,
+        _dest_virt: *mut usize,
+        _client_pid: PID,
+        _client_tid: TID,
+        _client_virt: *mut usize,
+        _len: usize,
+    ) -> Result<(), xous_kernel::Error> {
+        Ok(())
+    }
+
     /// Create a new thread in the current process.  Execution begins at
     /// `entrypoint`, with the stack pointer set to `stack_pointer`.  A single
     /// argument will be passed to the new function.
@@ -1692,7 +1863,7 @@ impl SystemServices {
             );
         }
 
-        for entry in self.servers.iter_mut() {
+        for (sidx, entry) in self.servers.iter_mut().enumerate() {
             if *entry == None {
                 #[cfg(baremetal)]
                 // Allocate a single page for the server queue
@@ -1710,6 +1881,12 @@ impl SystemServices {
                 // Initialize the server with the given memory page.
                 Server::init(entry, pid, sid, backing).unwrap();
 
+                if sid == SID::from_bytes(b"xous-name-server").unwrap()
+                    && boot_process_names().any(|(p, name)| p == pid && name == "xous-names")
+                {
+                    self.send_process_names(sidx);
+                }
+
                 let cid = if connect {
                     self.connect_to_server(sid)?
                 } else {
@@ -1986,6 +2163,16 @@ impl SystemServices {
         })
     }
 
+    /// Return `true` if `pid` owns the name server, which answers questions about
+    /// other processes for the processes that it trusts
+    pub fn runs_name_server(&self, pid: PID) -> bool {
+        let sid = SID::from_bytes(b"xous-name-server").unwrap();
+        self.servers
+            .iter()
+            .flatten()
+            .any(|server| server.sid == sid && server.pid == pid)
+    }
+
     /// Switch to the server's memory space and add the message to its server
     /// queue
     pub fn queue_server_message(
@@ -1993,6 +2180,7 @@ impl SystemServices {
         sidx: usize,
         pid: PID,
         thread: TID,
+        proxy: Option<PID>,
         message: Message,
         original_address: Option<MemoryAddress>,
     ) -> Result<usize, xous_kernel::Error> {
@@ -2009,7 +2197,7 @@ impl SystemServices {
             let server = self
                 .server_from_sidx_mut(sidx)
                 .expect("couldn't re-discover server index");
-            server.queue_message(pid, thread, message, original_address)
+            server.queue_message(pid, thread, proxy, message, original_address)
         };
         let current_process = self
             .get_process(current_pid)
@@ -2023,11 +2211,13 @@ impl SystemServices {
     pub fn remember_server_message(
         &mut self,
         sidx: usize,
-        current_pid: PID,
-        current_thread: TID,
+        client_pid: PID,
+        client_thread: TID,
+        proxy: Option<PID>,
         message: &Message,
         client_address: Option<MemoryAddress>,
     ) -> Result<usize, xous_kernel::Error> {
+        let current_pid = self.current_pid();
         let server_pid = self
             .server_from_sidx(sidx)
             .ok_or(xous_kernel::Error::ServerNotFound)?
@@ -2039,7 +2229,34 @@ impl SystemServices {
         let server = self
             .server_from_sidx_mut(sidx)
             .expect("couldn't re-discover server index");
-        let result = server.queue_response(current_pid, current_thread, message, client_address);
+        let result =
+            server.queue_response(client_pid, client_thread, proxy, message, client_address);
+        let current_process = self
+            .get_process(current_pid)
+            .expect("couldn't find old process");
+        current_process
+            .mapping
+            .activate()
+            .expect("couldn't switch back to previous address space");
+        result
+    }
+
+    /// Switch to the server's address space and set aside an empty slot in its
+    /// queue, then switch back to the original address space.
+    pub fn reserve_server_slot(&mut self, sidx: usize) -> Result<usize, xous_kernel::Error> {
+        let current_pid = self.current_pid();
+        let server_pid = self
+            .server_from_sidx(sidx)
+            .ok_or(xous_kernel::Error::ServerNotFound)?
+            .pid;
+        {
+            let server_process = self.get_process(server_pid)?;
+            server_process.mapping.activate()?;
+        }
+        let result = self
+            .server_from_sidx_mut(sidx)
+            .expect("couldn't re-discover server index")
+            .reserve_slot();
         let current_process = self
             .get_process(current_pid)
             .expect("couldn't find old process");
@@ -2050,6 +2267,34 @@ impl SystemServices {
         result
     }
 
+    /// Switch to the server's address space and empty a slot in its queue that
+    /// was reserved, or that holds a message which couldn't be delivered, then
+    /// switch back to the original address space.
+    pub fn release_server_slot(&mut self, sidx: usize, queue_idx: usize) {
+        let current_pid = self.current_pid();
+        let server_pid = match self.server_from_sidx(sidx) {
+            Some(server) => server.pid,
+            None => return,
+        };
+        match self.get_process(server_pid) {
+            Ok(server_process) => server_process
+                .mapping
+                .activate()
+                .expect("couldn't switch to server address space"),
+            Err(_) => return,
+        }
+        self.server_from_sidx_mut(sidx)
+            .expect("couldn't re-discover server index")
+            .release_slot(queue_idx);
+        let current_process = self
+            .get_process(current_pid)
+            .expect("couldn't find old process");
+        current_process
+            .mapping
+            .activate()
+            .expect("couldn't switch back to previous address space");
+    }
+
     // /// Get a server index based on a SID
     // pub fn server_sidx(&mut self, sid: SID) -> Option<usize> {
     //     for (idx, server) in self.servers.iter_mut().enumerate() {
@@ -2062,6 +2307,110 @@ impl SystemServices {
     //     None
     // }
 
+    /// Tell the name server the names of the processes in the boot image, with
+    /// `PROCESS_NAME_ID` messages. These are queued as soon as its server is created,
+    /// so it has every name before it receives its first request.
+    fn send_process_names(&mut self, sidx: usize) {
+        let kernel_pid = PID::new(1).unwrap();
+        for (pid, name) in boot_process_names() {
+            let name = name.as_bytes();
+            if name.len() > 0xff {
+                klog!("name of PID {} is too long to send", pid);
+                continue;
+            }
+            for (index, part) in name.chunks(12).enumerate() {
+                let mut words = [0usize; 3];
+                for (i, byte) in part.iter().enumerate() {
+                    words[i / 4] |= (*byte as usize) << ((i % 4) * 8);
+                }
+                let message = Message::Scalar(ScalarMessage {
+                    id: PROCESS_NAME_ID,
+                    arg1: pid.get() as usize | (index * 12) << 8 | name.len() << 16,
+                    arg2: words[0],
+                    arg3: words[1],
+                    arg4: words[2],
+                });
+                if self
+                    .queue_server_message(sidx, kernel_pid, 0, None, message, None)
+                    .is_err()
+                {
+                    klog!("couldn't tell the name server the name of PID {}", pid);
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Tell each server that `target_pid` was connected to that the process has
+    /// terminated, so that it can let go of anything it was keeping for the process.
+    /// See `xous_kernel::PROCESS_TERMINATED_ID`. Servers that belong to the process
+    /// itself aren't told, and a server whose queue is full misses out.
+    fn notify_termination(&mut self, target_pid: PID) -> Result<(), xous_kernel::Error> {
+        let current_pid = self.current_pid();
+        let mut connected = [false; MAX_SERVER_COUNT];
+        self.get_process(target_pid)?.activate()?;
+        ArchProcess::with_inner(|process_inner| {
+            // Connection map entries are offset by two, because 0 == free and
+            // 1 == "tombstone".
+            for mapping in process_inner.connection_map.iter().flatten() {
+                if let Some(sidx) = (mapping.get() as usize).checked_sub(2) {
+                    connected[sidx] = true;
+                }
+            }
+        });
+        self.get_process(current_pid)?.activate()?;
+
+        let kernel_pid = PID::new(1).unwrap();
+        for sidx in (0..MAX_SERVER_COUNT).filter(|&sidx| connected[sidx]) {
+            let server_pid = match self.server_from_sidx(sidx) {
+                Some(server) if server.pid != target_pid => server.pid,
+                _ => continue,
+            };
+            let message = Message::Scalar(ScalarMessage {
+                id: PROCESS_TERMINATED_ID,
+                arg1: target_pid.get() as usize,
+                arg2: 0,
+                arg3: 0,
+                arg4: 0,
+            });
+            let server = self
+                .server_from_sidx_mut(sidx)
+                .expect("server couldn't be located");
+            if let Some(server_tid) = server.take_available_thread() {
+                let envelope = MessageEnvelope {
+                    sender: SenderID::new(sidx, 0, Some(kernel_pid)).into(),
+                    body: message,
+                };
+                let readied = if cfg!(baremetal) {
+                    self.ready_thread(server_pid, server_tid)
+                } else {
+                    self.switch_to_thread(server_pid, Some(server_tid))
+                };
+                if readied.is_err() {
+                    self.server_from_sidx_mut(sidx)
+                        .expect("server couldn't be located")
+                        .return_available_thread(server_tid);
+                    continue;
+                }
+                self.set_thread_result(
+                    server_pid,
+                    server_tid,
+                    xous_kernel::Result::MessageEnvelope(envelope),
+                )?;
+            } else if self
+                .queue_server_message(sidx, kernel_pid, 0, None, message, None)
+                .is_err()
+            {
+                klog!(
+                    "couldn't tell PID {} that PID {} terminated",
+                    server_pid,
+                    target_pid
+                );
+            }
+        }
+        Ok(())
+    }
+
     /// Terminate the given process. Returns the process' parent PID.
     pub fn terminate_process(&mut self, target_pid: PID) -> Result<PID, xous_kernel::Error> {
         // To terminate a process, we must perform the following:
@@ -2071,6 +2420,7 @@ impl SystemServices {
         // 3. If there are any incoming server requests queued, dequeue them and return an error
         // 4. Mark all "Borrowed" memory as "Free-when-returned". That way, if we've shared
         //    memory to a Server, it will be reclaimed by the system when it comes back
+        // 5. Tell the servers that we were connected to, so they can clean up after us
 
         // 1. Find all servers associated with this PID and remove them.
         for (idx, server) in self.servers.iter_mut().enumerate() {
@@ -2115,6 +2465,9 @@ impl SystemServices {
             }
         }
 
+        // 5. Tell the servers that this process was connected to.
+        self.notify_termination(target_pid)?;
+
         let process = self.get_process_mut(target_pid)?;
         process.activate()?;
         let parent_pid = process.ppid;
@@ -2281,41 +2634,6 @@ impl SystemServices {
     /// Returns the process name, if any, of a given PID
     #[cfg(baremetal)]
     pub fn process_name(&self, pid: PID) -> Option<&str> {
-        let args = crate::args::KernelArguments::get();
-        for arg in args.iter() {
-            if arg.name != u32::from_le_bytes(*b"PNam") {
-                continue;
-            }
-            let data = unsafe {
-                let ptr = arg.data.as_ptr();
-                let len = arg.size;
-                core::slice::from_raw_parts(ptr as *const u8, len * 4)
-            };
-            let mut offset = 0;
-            while offset <= arg.size {
-                let check_pid = u32::from_le_bytes([
-                    data[offset],
-                    data[offset + 1],
-                    data[offset + 2],
-                    data[offset + 3],
-                ]);
-                let str_len = u32::from_le_bytes([
-                    data[offset + 4],
-                    data[offset + 5],
-                    data[offset + 6],
-                    data[offset + 7],
-                ]) as usize;
-                if check_pid == pid.get() as _ {
-                    if let Ok(s) = core::str::from_utf8(&data[offset + 8..offset + 8 + str_len]) {
-                        return Some(s);
-                    } else {
-                        return None;
-                    }
-                }
-                offset += str_len + 8;
-                offset += (4 - (offset & 3)) & 3;
-            }
-        }
-        None
+        boot_process_names().find_map(|(p, name)| (p == pid).then_some(name))
     }
 }
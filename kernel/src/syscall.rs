@@ -5,7 +5,7 @@ use crate::arch;
 use crate::arch::process::Process as ArchProcess;
 use crate::irq::interrupt_claim;
 use crate::mem::{MemoryManager, PAGE_SIZE};
-use crate::server::{SenderID, WaitingMessage};
+use crate::server::{SenderID, WaitingKind, WaitingMessage};
 use crate::services::SystemServices;
 use core::mem;
 use xous_kernel::*;
@@ -155,7 +155,7 @@ fn send_message(pid: PID, thread: TID, cid: CID, message: Message) -> SysCallRes
             //     server_pid
             // );
             let sender_idx = if message.is_blocking() {
-                ss.remember_server_message(sidx, pid, thread, &message, client_address)
+                ss.remember_server_message(sidx, pid, thread, None, &message, client_address)
                     .map_err(|e| {
                         klog!("error remembering server message: {:?}", e);
                         ss.server_from_sidx_mut(sidx)
@@ -166,7 +166,7 @@ fn send_message(pid: PID, thread: TID, cid: CID, message: Message) -> SysCallRes
             } else {
                 0
             };
-            let sender = SenderID::new(sidx, sender_idx, Some(pid));
+            let sender = SenderID::new(sidx, sender_idx, Some(pid)).with_tid(thread);
             klog!(
                 "server connection data: sidx: {}, idx: {}, server pid: {}",
                 sidx,
@@ -253,7 +253,8 @@ fn send_message(pid: PID, thread: TID, cid: CID, message: Message) -> SysCallRes
         );
         // Add this message to the queue.  If the queue is full, this
         // returns an error.
-        let _queue_idx = ss.queue_server_message(sidx, pid, thread, message, client_address)?;
+        let _queue_idx =
+            ss.queue_server_message(sidx, pid, thread, None, message, client_address)?;
         klog!("queued into index {:x}", _queue_idx);
 
         // Park this context if it's blocking.  This is roughly
@@ -278,6 +279,257 @@ fn send_message(pid: PID, thread: TID, cid: CID, message: Message) -> SysCallRes
     })
 }
 
+/// Hand a message that `proxy_pid` is waiting to respond to over to another
+/// server. The message keeps its original sender, so the new server responds
+/// directly to the client, and the proxy is free to carry on.
+fn forward_message(
+    proxy_pid: PID,
+    _proxy_tid: TID,
+    sender: MessageSender,
+    cid: CID,
+    message: Message,
+) -> SysCallResult {
+    SystemServices::with_mut(|ss| {
+        let sender = SenderID::from(sender);
+
+        // Check everything that can be checked before taking the message, so
+        // that these failures leave the proxy still holding it.
+        let server = ss
+            .server_from_sidx(sender.sidx)
+            .ok_or(xous_kernel::Error::ServerNotFound)?;
+        if server.pid != proxy_pid {
+            return Err(xous_kernel::Error::ServerNotFound);
+        }
+        let kind = server
+            .waiting_message_kind(sender.idx)
+            .ok_or(xous_kernel::Error::ProcessNotFound)?;
+        let mutable = match (kind, &message) {
+            (WaitingKind::Scalar, Message::BlockingScalar(_)) => false,
+            (WaitingKind::Borrow, Message::Borrow(_))
+            | (WaitingKind::MutableBorrow, Message::Borrow(_)) => false,
+            (WaitingKind::MutableBorrow, Message::MutableBorrow(_)) => true,
+            (WaitingKind::Borrow, Message::MutableBorrow(_)) => {
+                return Err(xous_kernel::Error::ShareViolation)
+            }
+            _ => return Err(xous_kernel::Error::InvalidSyscall),
+        };
+
+        let sidx = ss
+            .sidx_from_cid(cid)
+            .ok_or(xous_kernel::Error::ServerNotFound)?;
+        let server_pid = ss
+            .server_from_sidx(sidx)
+            .ok_or(xous_kernel::Error::ServerNotFound)?
+            .pid;
+
+        // Set aside room for the message in the new server, and a thread to
+        // receive it if one is waiting, so that delivering it can't fail for
+        // want of either once the proxy has given it up.
+        let slot = ss.reserve_server_slot(sidx)?;
+        let server_tid = ss
+            .server_from_sidx_mut(sidx)
+            .expect("server couldn't be located")
+            .take_available_thread();
+        let release = |ss: &mut SystemServices| {
+            ss.release_server_slot(sidx, slot);
+            if let Some(server_tid) = server_tid {
+                ss.server_from_sidx_mut(sidx)
+                    .expect("server couldn't be located")
+                    .return_available_thread(server_tid);
+            }
+        };
+
+        let buf = match &message {
+            Message::Borrow(msg) | Message::MutableBorrow(msg) => Some(msg.buf),
+            _ => None,
+        };
+        let waiting = match ss
+            .server_from_sidx_mut(sender.sidx)
+            .expect("proxy server couldn't be located")
+            .take_waiting_message(sender.idx, buf.as_ref())
+        {
+            Ok(waiting) => waiting,
+            Err(e) => {
+                release(ss);
+                return Err(e);
+            }
+        };
+
+        // From here on the proxy no longer holds the message, so the client is
+        // the only one left to tell about a failure.
+        let fail = |ss: &mut SystemServices, client_pid: PID, client_tid: TID, e: Error| {
+            klog!("unable to forward message: {:?}", e);
+            ss.ready_thread(client_pid, client_tid).ok();
+            ss.set_thread_result(
+                client_pid,
+                client_tid,
+                xous_kernel::Result::Error(Error::from_usize(e.to_usize())),
+            )
+            .ok();
+            e
+        };
+
+        // Move any lent memory from the proxy to the new server.
+        let (client_pid, client_tid, client_address, message) = match waiting {
+            WaitingMessage::ScalarMessage(client_pid, client_tid) => {
+                (client_pid, client_tid, None, message)
+            }
+            WaitingMessage::BorrowedMemory(
+                client_pid,
+                client_tid,
+                _server_addr,
+                client_addr,
+                len,
+            ) => {
+                let mut msg = match message {
+                    Message::Borrow(msg) | Message::MutableBorrow(msg) => msg,
+                    _ => unreachable!("message kind was already checked"),
+                };
+                #[cfg(baremetal)]
+                let src_virt = _server_addr.get() as _;
+                #[cfg(not(baremetal))]
+                let src_virt = msg.buf.as_mut_ptr() as _;
+                let new_virt = ss
+                    .forward_memory(
+                        src_virt,
+                        client_pid,
+                        client_tid,
+                        client_addr.get() as _,
+                        server_pid,
+                        len.get(),
+                        mutable,
+                    )
+                    .map_err(|e| {
+                        release(ss);
+                        fail(ss, client_pid, client_tid, e)
+                    })?;
+                msg.buf = unsafe { MemoryRange::new(new_virt as usize, len.get()) }?;
+                let message = if mutable {
+                    Message::MutableBorrow(msg)
+                } else {
+                    Message::Borrow(msg)
+                };
+                (client_pid, client_tid, Some(client_addr), message)
+            }
+            _ => {
+                release(ss);
+                return Err(xous_kernel::Error::InternalError);
+            }
+        };
+        let forwarded = match &message {
+            Message::Borrow(msg) | Message::MutableBorrow(msg) => Some(msg.buf),
+            _ => None,
+        };
+
+        // Hand the reserved slot back right before filling it, so the message is
+        // certain to fit.
+        ss.release_server_slot(sidx, slot);
+        let delivered = if let Some(server_tid) = server_tid {
+            deliver_forwarded_message(
+                ss,
+                sidx,
+                server_pid,
+                server_tid,
+                client_pid,
+                client_tid,
+                proxy_pid,
+                message,
+                client_address,
+            )
+        } else {
+            ss.queue_server_message(
+                sidx,
+                client_pid,
+                client_tid,
+                Some(proxy_pid),
+                message,
+                client_address,
+            )
+            .map(|_| ())
+        };
+        if let Err(e) = delivered {
+            // Give the memory back to the client along with the error.
+            if let (Some(buf), Some(client_addr)) = (forwarded, client_address) {
+                ss.unforward_memory(
+                    server_pid,
+                    buf.as_mut_ptr() as _,
+                    client_pid,
+                    client_tid,
+                    client_addr.get() as _,
+                    buf.len(),
+                )
+                .ok();
+            }
+            return Err(fail(ss, client_pid, client_tid, e));
+        }
+
+        klog!(
+            "forwarded message from {}:{} via {}:{} to {}",
+            client_pid,
+            client_tid,
+            proxy_pid,
+            _proxy_tid,
+            server_pid
+        );
+        Ok(xous_kernel::Result::Ok)
+    })
+}
+
+/// Give a forwarded message straight to `server_tid`, which was waiting for
+/// one. If the thread can't be woken, it's left waiting and the slot that was
+/// filled in its queue is emptied again.
+#[allow(clippy::too_many_arguments)]
+fn deliver_forwarded_message(
+    ss: &mut SystemServices,
+    sidx: usize,
+    server_pid: PID,
+    server_tid: TID,
+    client_pid: PID,
+    client_tid: TID,
+    proxy_pid: PID,
+    message: Message,
+    client_address: Option<MemoryAddress>,
+) -> core::result::Result<(), xous_kernel::Error> {
+    let return_thread = |ss: &mut SystemServices| {
+        ss.server_from_sidx_mut(sidx)
+            .expect("server couldn't be located")
+            .return_available_thread(server_tid);
+    };
+    let sender_idx = ss
+        .remember_server_message(
+            sidx,
+            client_pid,
+            client_tid,
+            Some(proxy_pid),
+            &message,
+            client_address,
+        )
+        .map_err(|e| {
+            return_thread(ss);
+            e
+        })?;
+    if let Err(e) = ss.ready_thread(server_pid, server_tid) {
+        ss.release_server_slot(sidx, sender_idx);
+        return_thread(ss);
+        return Err(e);
+    }
+    let sender = SenderID::new(sidx, sender_idx, Some(client_pid)).with_proxy(Some(proxy_pid));
+    let envelope = MessageEnvelope {
+        sender: sender.into(),
+        body: message,
+    };
+
+    // "Switch to" the server PID when not running on bare metal. This ensures
+    // that it's "Running".
+    #[cfg(not(baremetal))]
+    ss.switch_to_thread(server_pid, Some(server_tid))?;
+    ss.set_thread_result(
+        server_pid,
+        server_tid,
+        xous_kernel::Result::MessageEnvelope(envelope),
+    )
+}
+
 fn return_memory(
     server_pid: PID,
     server_tid: TID,
@@ -1081,6 +1333,9 @@ pub fn handle_inner(pid: PID, tid: TID, in_irq: bool, call: SysCall) -> SysCallR
             reply_and_receive_next(pid, tid, in_irq, sender, a0, a1, a2, a3, a4, scalar_type)
         }
         SysCall::TrySendMessage(cid, message) => send_message(pid, tid, cid, message),
+        SysCall::ForwardMessage(sender, cid, message) => {
+            forward_message(pid, tid, sender, cid, message)
+        }
         SysCall::TerminateProcess(_ret) => SystemServices::with_mut(|ss| {
             ss.unschedule_thread(pid, tid)?;
             ss.terminate_process(pid)?;
@@ -1159,6 +1414,27 @@ pub fn handle_inner(pid: PID, tid: TID, in_irq: bool, call: SysCall) -> SysCallR
                 }
                 Ok(xous_kernel::Result::Scalar2(index, p.mem_heap_size))
             }),
+            // The RAM owned by the process `current`, which can't be adjusted. Only the
+            // name server may ask about processes other than the caller.
+            3 => {
+                let other = Some(current)
+                    .filter(|current| *current <= arch::process::MAX_PROCESS_COUNT)
+                    .and_then(|current| PID::new(current as u8))
+                    .ok_or(xous_kernel::Error::ProcessNotFound)?;
+                SystemServices::with(|ss| {
+                    if other != pid && !ss.runs_name_server(pid) {
+                        return Err(xous_kernel::Error::AccessDenied);
+                    }
+                    if ss.processes[other.get() as usize - 1].free() {
+                        return Err(xous_kernel::Error::ProcessNotFound);
+                    }
+                    #[cfg(baremetal)]
+                    let bytes = MemoryManager::with(|mm| mm.ram_used_by(other));
+                    #[cfg(not(baremetal))]
+                    let bytes = 0;
+                    Ok(xous_kernel::Result::Scalar2(index, bytes))
+                })
+            }
             _ => Err(xous_kernel::Error::InvalidLimit),
         },
         #[cfg(feature = "v2p")]
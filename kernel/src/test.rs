@@ -336,6 +336,61 @@ fn send_scalar_message() {
     main_thread.join().expect("couldn't join kernel process");
 }
 
+#[test]
+fn process_terminated_message() {
+    let main_thread = start_kernel(SERVER_SPEC);
+    let (server_addr_send, server_addr_recv) = unbounded();
+
+    let xous_server = xous_kernel::create_process_as_thread(xous_kernel::ProcessArgsAsThread::new(
+        "process_terminated_message server",
+        move || {
+            let sid = xous_kernel::create_server().expect("couldn't create test server");
+            server_addr_send.send(sid).unwrap();
+            // The client sends its PID, and then exits
+            let envelope = xous_kernel::receive_message(sid).expect("couldn't receive messages");
+            let client_pid = envelope.body.scalar_message().unwrap().arg1;
+            assert_eq!(
+                Some(client_pid),
+                envelope.sender.pid().map(|p| p.get() as usize)
+            );
+
+            let envelope = xous_kernel::receive_message(sid).expect("couldn't receive messages");
+            assert_eq!(envelope.sender.pid().map(|p| p.get()), Some(1));
+            assert_eq!(
+                envelope.body,
+                xous_kernel::Message::Scalar(xous_kernel::ScalarMessage {
+                    id: xous_kernel::PROCESS_TERMINATED_ID,
+                    arg1: client_pid,
+                    arg2: 0,
+                    arg3: 0,
+                    arg4: 0
+                })
+            );
+        },
+    ))
+    .expect("couldn't spawn server process");
+
+    let xous_client = xous_kernel::create_process_as_thread(xous_kernel::ProcessArgsAsThread::new(
+        "process_terminated_message client",
+        move || {
+            let sid = server_addr_recv.recv().unwrap();
+            let conn = xous_kernel::try_connect(sid).expect("couldn't connect to server");
+            xous_kernel::send_message(
+                conn,
+                xous_kernel::Message::new_scalar(1, xous_kernel::process::id() as usize, 0, 0, 0),
+            )
+            .expect("couldn't send message");
+        },
+    ))
+    .expect("couldn't spawn client process");
+
+    crate::wait_process_as_thread(xous_client).expect("couldn't join client process");
+    crate::wait_process_as_thread(xous_server).expect("couldn't join server process");
+    shutdown_kernel();
+
+    main_thread.join().expect("couldn't join kernel process");
+}
+
 #[test]
 fn try_receive_message() {
     // Start the server in another thread
@@ -984,6 +1039,81 @@ fn send_repeat_mutableborrow_message() {
     main_thread.join().expect("couldn't join kernel process");
 }
 
+#[test]
+fn send_streamed_stringbuffer_message() {
+    let main_thread = start_kernel(SERVER_SPEC);
+    let (server_addr_send, server_addr_recv) = unbounded();
+    let (result_send, result_recv) = unbounded();
+
+    // Long enough to span several pages. The prefix is sized so that the
+    // first chunk boundary falls in the middle of a two-byte "ö".
+    let mut test_string = String::from("Stream:");
+    while test_string.len() < 3 * 4096 {
+        test_string.push_str("Hello, wörld! ");
+    }
+    let expected = test_string.clone();
+
+    let xous_server = xous_kernel::create_process_as_thread(xous_kernel::ProcessArgsAsThread::new(
+        "send_streamed_stringbuffer server",
+        move || {
+            let sid = xous_kernel::create_server_with_address(b"send_strm_string")
+                .expect("couldn't create test server");
+            server_addr_send.send(sid).unwrap();
+
+            let mut assembled = xous_kernel::StringBuffer::new();
+            let mut chunks = 0;
+            loop {
+                let envelope =
+                    xous_kernel::receive_message(sid).expect("couldn't receive messages");
+                let message = envelope.body;
+                if let xous_kernel::Message::Borrow(m) = message {
+                    chunks += 1;
+                    let complete = assembled.append_chunk(&m);
+                    xous_kernel::return_memory(envelope.sender, m.buf).unwrap();
+                    if complete {
+                        break;
+                    }
+                } else {
+                    panic!("unexpected message type");
+                }
+            }
+            result_send
+                .send((chunks, assembled.to_str().to_owned()))
+                .unwrap();
+        },
+    ))
+    .expect("couldn't start server");
+
+    let xous_client = xous_kernel::create_process_as_thread(xous_kernel::ProcessArgsAsThread::new(
+        "send_streamed_stringbuffer client",
+        move || {
+            // Get the server address (out of band) so we know what to connect to
+            let sid = server_addr_recv.recv().unwrap();
+
+            // Perform a connection to the server
+            let conn = xous_kernel::connect(sid).expect("couldn't connect to server");
+
+            let sb: xous_kernel::StringBuffer = test_string.parse().unwrap();
+            sb.lend_streamed(conn, 1)
+                .expect("couldn't stream string to server");
+        },
+    ))
+    .expect("couldn't start client");
+
+    // Wait for both processes to finish
+    crate::wait_process_as_thread(xous_server).expect("couldn't join server process");
+    crate::wait_process_as_thread(xous_client).expect("couldn't join client process");
+
+    let (chunks, assembled) = result_recv.recv().unwrap();
+    assert_eq!(chunks, (expected.len() + 4095) / 4096);
+    assert_eq!(assembled, expected);
+
+    // Any process ought to be able to shut down the system currently.
+    shutdown_kernel();
+
+    main_thread.join().expect("couldn't join kernel process");
+}
+
 // #[cfg(feature = "report-memory")]
 // #[test]
 // fn measure_memory_usage() {
@@ -1012,6 +1142,156 @@ fn send_repeat_mutableborrow_message() {
 //     println!("After test: {:#?} ({} bytes in use)", after_join, miu);
 // }
 
+#[test]
+fn sender_id_round_trip() {
+    use crate::server::SenderID;
+    use xous_kernel::{MessageSender, PID};
+
+    // Use the largest server and queue indices there can be, so that neither
+    // spills into the proxy's byte
+    let pid = PID::new(0xfe);
+    let proxy = PID::new(0x7f);
+    let sender: MessageSender = SenderID::new(0xff, 0xff, pid).with_proxy(proxy).into();
+    assert_eq!(sender.pid(), pid);
+    assert_eq!(sender.proxy_pid(), proxy);
+    let id = SenderID::from(sender);
+    assert_eq!((id.sidx, id.idx), (0xff, 0xff));
+
+    let sender: MessageSender = SenderID::new(3, 0, pid).into();
+    assert_eq!(sender.proxy_pid(), None);
+    assert_eq!(sender.tid(), None);
+
+    // Direct messages carry the sending thread instead of a proxy
+    let sender: MessageSender = SenderID::new(0xff, 0xff, pid).with_tid(31).into();
+    assert_eq!(sender.pid(), pid);
+    assert_eq!(sender.tid(), Some(31));
+    assert_eq!(sender.proxy_pid(), None);
+    let id = SenderID::from(sender);
+    assert_eq!((id.sidx, id.idx), (0xff, 0xff));
+
+    // A forwarded message's proxy takes the place of the thread
+    let sender: MessageSender = SenderID::new(3, 0, pid)
+        .with_tid(4)
+        .with_proxy(proxy)
+        .into();
+    assert_eq!(sender.proxy_pid(), proxy);
+    assert_eq!(sender.tid(), None);
+}
+
+#[test]
+fn forward_message() {
+    let main_thread = start_kernel(SERVER_SPEC);
+    let (target_addr_send, target_addr_recv) = unbounded();
+    let (proxy_addr_send, proxy_addr_recv) = unbounded();
+    let (pid_send, pid_recv) = unbounded();
+    let test_bytes = b"Hello, world!";
+
+    // The target checks that it can see both the original sender and the
+    // proxy, and then responds directly to the client.
+    let xous_target = xous_kernel::create_process_as_thread(xous_kernel::ProcessArgsAsThread::new(
+        "forward_message target",
+        move || {
+            let sid = xous_kernel::create_server_with_address(b"forward_msg_targ")
+                .expect("couldn't create target server");
+            target_addr_send.send(sid).unwrap();
+            let (client_pid, proxy_pid) = pid_recv.recv().unwrap();
+
+            let envelope = xous_kernel::receive_message(sid).expect("couldn't receive messages");
+            assert_eq!(envelope.sender.pid(), Some(client_pid));
+            assert_eq!(envelope.sender.proxy_pid(), Some(proxy_pid));
+            assert_eq!(envelope.sender.tid(), None);
+            if let xous_kernel::Message::BlockingScalar(m) = &envelope.body {
+                xous_kernel::return_scalar(envelope.sender, m.arg1 + 1)
+                    .expect("couldn't return scalar");
+            } else {
+                panic!("unexpected message type");
+            }
+
+            let envelope = xous_kernel::receive_message(sid).expect("couldn't receive messages");
+            assert_eq!(envelope.sender.pid(), Some(client_pid));
+            assert_eq!(envelope.sender.proxy_pid(), Some(proxy_pid));
+            if let xous_kernel::Message::MutableBorrow(m) = &envelope.body {
+                let bt =
+                    unsafe { core::slice::from_raw_parts_mut(m.buf.as_mut_ptr(), m.buf.len()) };
+                for letter in bt.iter_mut() {
+                    *letter += 1;
+                }
+                xous_kernel::return_memory(envelope.sender, m.buf).unwrap();
+            } else {
+                panic!("unexpected message type");
+            }
+        },
+    ))
+    .expect("couldn't start target");
+
+    // The proxy hands each message on without waiting for the response.
+    let xous_proxy = xous_kernel::create_process_as_thread(xous_kernel::ProcessArgsAsThread::new(
+        "forward_message proxy",
+        move || {
+            let target_sid = target_addr_recv.recv().unwrap();
+            let target_conn = xous_kernel::connect(target_sid).expect("couldn't connect to target");
+            let sid = xous_kernel::create_server_with_address(b"forward_msg_prxy")
+                .expect("couldn't create proxy server");
+            proxy_addr_send.send(sid).unwrap();
+
+            for _ in 0..2 {
+                let envelope =
+                    xous_kernel::receive_message(sid).expect("couldn't receive messages");
+                assert_eq!(envelope.sender.proxy_pid(), None);
+                assert!(envelope.sender.tid().is_some());
+                pid_send
+                    .send((
+                        envelope.sender.pid().unwrap(),
+                        xous_kernel::current_pid().unwrap(),
+                    ))
+                    .ok();
+                envelope
+                    .forward(target_conn, 0)
+                    .map_err(|(_, e)| e)
+                    .expect("couldn't forward message");
+            }
+        },
+    ))
+    .expect("couldn't start proxy");
+
+    let xous_client = xous_kernel::create_process_as_thread(xous_kernel::ProcessArgsAsThread::new(
+        "forward_message client",
+        move || {
+            let sid = proxy_addr_recv.recv().unwrap();
+            let conn = xous_kernel::connect(sid).expect("couldn't connect to proxy");
+
+            let result = xous_kernel::send_message(
+                conn,
+                xous_kernel::Message::BlockingScalar(xous_kernel::ScalarMessage {
+                    id: 1,
+                    arg1: 41,
+                    arg2: 0,
+                    arg3: 0,
+                    arg4: 0,
+                }),
+            )
+            .expect("couldn't send message");
+            assert_eq!(result, xous_kernel::Result::Scalar1(42));
+
+            let mut carton = xous_kernel::carton::Carton::from_bytes(test_bytes);
+            carton
+                .lend_mut(conn, 2)
+                .expect("couldn't mutably lend data");
+            let check_bytes: Vec<u8> = test_bytes.iter().map(|b| b + 1).collect();
+            let modified_bytes: &[u8] = carton.as_ref();
+            assert_eq!(&check_bytes, &modified_bytes);
+        },
+    ))
+    .expect("couldn't start client");
+
+    crate::wait_process_as_thread(xous_client).expect("couldn't join client process");
+    crate::wait_process_as_thread(xous_proxy).expect("couldn't join proxy process");
+    crate::wait_process_as_thread(xous_target).expect("couldn't join target process");
+    shutdown_kernel();
+
+    main_thread.join().expect("couldn't join kernel process");
+}
+
 /// Test that a server can be its own client
 #[test]
 fn server_client_same_process() {
@@ -1177,3 +1457,115 @@ fn process_restart_server() {
 
     main_thread.join().expect("couldn't join kernel process");
 }
+
+/// A server whose opcodes, client and dispatcher are generated by `#[service]`
+mod counter {
+    use xous_kernel as xous;
+
+    #[xous_api_derive::service(opcode = Opcode, client = CounterClient)]
+    pub trait Counter {
+        fn add(&mut self, amount: u32);
+        fn get(&mut self) -> u64;
+        #[deferred]
+        fn double(&mut self, value: u32) -> u32;
+        #[manual]
+        fn quit(&mut self);
+    }
+
+    #[derive(Default)]
+    pub struct CounterServer {
+        pub count: u64,
+        pub pending: Option<(xous::MessageSender, u32)>,
+    }
+
+    impl Counter for CounterServer {
+        fn add(&mut self, _sender: xous::MessageSender, amount: u32) {
+            self.count += amount as u64;
+        }
+
+        fn get(&mut self, _sender: xous::MessageSender) -> u64 {
+            self.count
+        }
+
+        fn double(&mut self, sender: xous::MessageSender, value: u32) -> Option<u32> {
+            // Odd values are answered by the main loop, once the dispatcher has returned
+            if value % 2 == 0 {
+                Some(value * 2)
+            } else {
+                self.pending = Some((sender, value));
+                None
+            }
+        }
+    }
+}
+
+#[test]
+fn generated_service() {
+    use counter::{CounterClient, CounterServer, Opcode};
+
+    let main_thread = start_kernel(SERVER_SPEC);
+
+    let (server_addr_send, server_addr_recv) = unbounded();
+
+    let xous_server = xous_kernel::create_process_as_thread(xous_kernel::ProcessArgsAsThread::new(
+        "generated_service server",
+        move || {
+            let sid = xous_kernel::create_server_with_address(b"generated_servic")
+                .expect("couldn't create test server");
+            server_addr_send.send(sid).unwrap();
+
+            let mut server = CounterServer::default();
+            let mut invalid = 0;
+            let mut msg_opt = None;
+            let mut return_type = 0;
+            loop {
+                xous_kernel::reply_and_receive_next_legacy(sid, &mut msg_opt, &mut return_type)
+                    .expect("couldn't receive messages");
+                match counter::dispatch_counter(&mut server, &mut msg_opt, &mut return_type) {
+                    None => (),
+                    Some(Opcode::Quit) => break,
+                    Some(Opcode::InvalidCall) => invalid += 1,
+                    Some(opcode) => panic!("{:?} wasn't dispatched", opcode),
+                }
+                if let Some((sender, value)) = server.pending.take() {
+                    // The deferred message must not be responded to a second time
+                    assert!(msg_opt.is_none());
+                    xous_kernel::return_scalar(sender, value as usize * 2)
+                        .expect("couldn't return scalar");
+                }
+            }
+            assert_eq!(server.count, 7);
+            assert_eq!(invalid, 1);
+        },
+    ))
+    .expect("couldn't spawn server process");
+
+    let xous_client = xous_kernel::create_process_as_thread(xous_kernel::ProcessArgsAsThread::new(
+        "generated_service client",
+        move || {
+            let sid = server_addr_recv.recv().unwrap();
+            let conn = xous_kernel::try_connect(sid).expect("couldn't connect to server");
+            let counter = CounterClient::new(conn);
+            counter.add(3).expect("couldn't add");
+            counter.add(4).expect("couldn't add");
+            assert_eq!(counter.get(), Ok(7));
+            assert_eq!(counter.double(4), Ok(8));
+            assert_eq!(counter.double(5), Ok(10));
+
+            xous_kernel::send_message(conn, xous_kernel::Message::new_scalar(0x1234, 0, 0, 0, 0))
+                .expect("couldn't send message");
+            xous_kernel::send_message(
+                conn,
+                xous_kernel::Message::new_scalar(Opcode::Quit as usize, 0, 0, 0, 0),
+            )
+            .expect("couldn't send message");
+        },
+    ))
+    .expect("couldn't spawn client process");
+
+    xous_kernel::wait_process_as_thread(xous_server).expect("couldn't join server process");
+    xous_kernel::wait_process_as_thread(xous_client).expect("couldn't join client process");
+    shutdown_kernel();
+
+    main_thread.join().expect("couldn't join kernel process");
+}
@@ -0,0 +1,1095 @@
+//! `#[service]` turns a trait that describes the methods of a Xous server into the
+//! boilerplate that is otherwise written by hand for every API crate:
+//!
+//!   - an opcode enum, with one variant per method, suitable for `FromPrimitive` decoding
+//!   - a client struct with one typed method per trait method
+//!   - the trait itself, for the server to implement. Each method takes the
+//!     `xous::MessageSender` of the message as its first argument after `self`.
+//!   - a `dispatch_<trait>()` function that decodes a `MessageEnvelope`, calls the matching
+//!     trait method on the server, and writes the response back into the message
+//!
+//! The message encoding is picked from the argument types of each method:
+//!
+//!   - integers and `bool` are packed into the four scalar arguments. `u64` and `i64`
+//!     take two arguments (low word first).
+//!   - a single `&T` argument is sent as a `lend` of an rkyv-serialized `xous_ipc::Buffer`
+//!   - a single `&mut T` argument is sent as a `lend_mut`, and the server's updated copy is
+//!     read back into the caller's value when the message returns
+//!
+//! Scalar methods that return a value, or that are marked `#[blocking]`, are sent as
+//! `BlockingScalar` messages. One-word return values come back as `Scalar1`; `u64`, `i64`
+//! and two-element tuples come back as `Scalar2`.
+//!
+//! ```ignore
+//! #[xous_api_derive::service(opcode = Opcode, client = CounterClient)]
+//! pub trait Counter {
+//!     /// Add `amount` to the counter
+//!     fn add(&mut self, amount: u32);
+//!     /// Return the current count
+//!     fn get(&mut self) -> u64;
+//!     /// Fill in the stats structure
+//!     fn stats(&mut self, stats: &mut CounterStats);
+//!     /// Wait until the count reaches `target`
+//!     #[deferred]
+//!     fn wait(&mut self, target: u64);
+//!     /// Handled by hand in the server's main loop
+//!     #[opcode = 16]
+//!     #[manual]
+//!     fn quit(&mut self);
+//! }
+//!
+//! // ...which the server implements as
+//! impl Counter for CounterServer {
+//!     fn add(&mut self, sender: xous::MessageSender, amount: u32) { /* ... */ }
+//!     fn get(&mut self, sender: xous::MessageSender) -> u64 { /* ... */ }
+//!     fn stats(&mut self, sender: xous::MessageSender, stats: &mut CounterStats) { /* ... */ }
+//!     fn wait(&mut self, sender: xous::MessageSender, target: u64) -> Option<()> { /* ... */ }
+//! }
+//! ```
+//!
+//! Method attributes understood by the macro:
+//!
+//!   - `#[opcode = N]`: pin the discriminant of this method's opcode. Opcodes that follow
+//!     count up from here, as with any Rust enum.
+//!   - `#[blocking]`: send a method with no return value as a `BlockingScalar`, so that the
+//!     client waits for the server to respond.
+//!   - `#[deferred]`: a blocking scalar method that the server may respond to later. The
+//!     server's method returns `Option<T>`: `Some` is sent back right away, while `None`
+//!     means that the server has kept the sender and will answer it with
+//!     `xous::return_scalar()` (or `return_scalar2()`) itself.
+//!   - `#[manual]`: only emit the opcode. The method is removed from the trait, no client
+//!     method is generated, and `dispatch_<trait>()` hands the opcode back to the caller.
+//!
+//! The opcode enum always ends with an `InvalidCall` variant, which is what
+//! `dispatch_<trait>()` returns for message IDs that don't decode.
+//!
+//! `dispatch_<trait>()` is meant for a main loop built on
+//! `xous::reply_and_receive_next_legacy()`: responses to scalar messages are written into
+//! the message and its `return_type`, and are sent when the next message is received.
+//!
+//! The crate using the macro must depend on `xous`, `num-derive` and `num-traits`, and on
+//! `xous-ipc` if any method takes a reference. Types passed by reference must implement
+//! `Clone` and the rkyv `Archive`, `Serialize` and `Deserialize` traits.
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::{format_ident, quote};
+use syn::{
+    parse::{Parse, ParseStream},
+    parse_macro_input,
+    spanned::Spanned,
+    Attribute, Error, Expr, FnArg, Ident, ItemTrait, Pat, ReturnType, Token, TraitItem,
+    TraitItemMethod, Type,
+};
+
+/// Generate an opcode enum, a client and a server dispatcher from a trait.
+/// See the crate documentation for the encoding rules.
+#[proc_macro_attribute]
+pub fn service(attr: TokenStream, item: TokenStream) -> TokenStream {
+    let args = parse_macro_input!(attr as ServiceArgs);
+    let item = parse_macro_input!(item as ItemTrait);
+
+    match expand(args, item) {
+        Ok(tokens) => tokens.into(),
+        Err(e) => e.to_compile_error().into(),
+    }
+}
+
+#[derive(Default)]
+struct ServiceArgs {
+    opcode: Option<Ident>,
+    client: Option<Ident>,
+}
+
+impl Parse for ServiceArgs {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let mut args = ServiceArgs::default();
+        while !input.is_empty() {
+            let key: Ident = input.parse()?;
+            input.parse::<Token![=]>()?;
+            let value: Ident = input.parse()?;
+            if key == "opcode" {
+                args.opcode = Some(value);
+            } else if key == "client" {
+                args.client = Some(value);
+            } else {
+                return Err(Error::new(
+                    key.span(),
+                    "expected `opcode = Name` or `client = Name`",
+                ));
+            }
+            if !input.is_empty() {
+                input.parse::<Token![,]>()?;
+            }
+        }
+        Ok(args)
+    }
+}
+
+/// How a single value is packed into the scalar arguments of a message
+#[derive(Clone)]
+enum Scalar {
+    /// Anything that fits in one `usize`
+    Word(Box<Type>),
+    /// Sent as 0 or 1
+    Bool,
+    /// Split into a low and a high word
+    Wide(Box<Type>),
+}
+
+impl Scalar {
+    fn words(&self) -> usize {
+        match self {
+            Scalar::Word(_) | Scalar::Bool => 1,
+            Scalar::Wide(_) => 2,
+        }
+    }
+}
+
+enum Arg {
+    Scalar(Ident, Scalar),
+    Lend(Ident, Type),
+    LendMut(Ident, Type),
+}
+
+enum Return {
+    Unit,
+    One(Scalar),
+    Pair(Scalar, Scalar),
+}
+
+struct Method {
+    name: Ident,
+    variant: Ident,
+    docs: Vec<Attribute>,
+    discriminant: Option<Expr>,
+    blocking: bool,
+    deferred: bool,
+    manual: bool,
+    args: Vec<Arg>,
+    ret: Return,
+    ret_ty: ReturnType,
+}
+
+fn expand(args: ServiceArgs, mut item: ItemTrait) -> syn::Result<TokenStream2> {
+    let trait_name = item.ident.clone();
+    let vis = item.vis.clone();
+    let opcode = args
+        .opcode
+        .unwrap_or_else(|| format_ident!("{}Opcode", trait_name));
+    let client = args
+        .client
+        .unwrap_or_else(|| format_ident!("{}Client", trait_name));
+
+    let dispatch = format_ident!("dispatch_{}", snake_case(&trait_name.to_string()));
+
+    let mut methods = vec![];
+    let mut kept_items = vec![];
+    for trait_item in item.items.drain(..) {
+        match trait_item {
+            TraitItem::Method(mut f) => {
+                let method = parse_method(&mut f)?;
+                if !method.manual {
+                    server_signature(&mut f, &method);
+                    kept_items.push(TraitItem::Method(f));
+                }
+                methods.push(method);
+            }
+            other => {
+                return Err(Error::new(
+                    other.span(),
+                    "only methods may appear in a #[service] trait",
+                ))
+            }
+        }
+    }
+    item.items = kept_items;
+
+    let variants = methods.iter().map(|m| {
+        let docs = &m.docs;
+        let variant = &m.variant;
+        match &m.discriminant {
+            Some(d) => quote! { #(#docs)* #variant = #d, },
+            None => quote! { #(#docs)* #variant, },
+        }
+    });
+
+    let client_methods = methods
+        .iter()
+        .filter(|m| !m.manual)
+        .map(|m| client_method(m, &opcode));
+
+    let dispatch_arms = methods.iter().map(|m| dispatch_arm(m, &opcode));
+
+    let client_doc = format!(
+        "Client for the [`{}`] API. Each method sends one message to the server.",
+        trait_name
+    );
+    let dispatch_doc = format!(
+        "Decode the message in `msg`, call the matching [`{}`] method on `server`, and write \
+         the response back into `msg` and `return_type` for \
+         `xous::reply_and_receive_next_legacy()`. `msg` is left empty when a `#[deferred]` \
+         method keeps the sender.\n\n\
+         Returns `None` if the message was handled. Returns `Some(opcode)` for opcodes marked \
+         `#[manual]`, for message IDs that don't decode (`InvalidCall`), and for messages whose \
+         type doesn't match the method signature; these are left for the caller to deal with.",
+        trait_name
+    );
+
+    Ok(quote! {
+        #item
+
+        #[repr(usize)]
+        #[derive(num_derive::FromPrimitive, num_derive::ToPrimitive, Debug, Copy, Clone, PartialEq, Eq)]
+        #vis enum #opcode {
+            #(#variants)*
+            /// Invalid call -- an error occurred decoding the opcode
+            InvalidCall = u32::MAX as usize,
+        }
+
+        #[doc = #client_doc]
+        #[derive(Debug)]
+        #vis struct #client {
+            conn: xous::CID,
+        }
+
+        impl #client {
+            /// Wrap an existing connection to the server.
+            pub fn new(conn: xous::CID) -> Self {
+                #client { conn }
+            }
+
+            /// The connection that messages are sent on.
+            pub fn conn(&self) -> xous::CID {
+                self.conn
+            }
+
+            #(#client_methods)*
+        }
+
+        #[doc = #dispatch_doc]
+        #vis fn #dispatch<S: #trait_name + ?Sized>(
+            server: &mut S,
+            msg_opt: &mut Option<xous::MessageEnvelope>,
+            return_type: &mut usize,
+        ) -> Option<#opcode> {
+            let msg = msg_opt.as_mut()?;
+            let opcode: #opcode = num_traits::FromPrimitive::from_usize(msg.body.id())
+                .unwrap_or(#opcode::InvalidCall);
+            match opcode {
+                #(#dispatch_arms)*
+                #opcode::InvalidCall => Some(opcode),
+            }
+        }
+    })
+}
+
+/// Rewrite a trait method into the one that the server implements: it gets the sender of
+/// the message, and a `#[deferred]` method may decline to respond right away.
+fn server_signature(f: &mut TraitItemMethod, m: &Method) {
+    let sender: FnArg = syn::parse_quote! { sender: xous::MessageSender };
+    f.sig.inputs.insert(1, sender);
+    if m.deferred {
+        f.sig.output = match &m.ret_ty {
+            ReturnType::Default => syn::parse_quote! { -> Option<()> },
+            ReturnType::Type(_, ty) => syn::parse_quote! { -> Option<#ty> },
+        };
+    }
+}
+
+fn parse_method(f: &mut TraitItemMethod) -> syn::Result<Method> {
+    let sig = &f.sig;
+    if !sig.generics.params.is_empty() || sig.asyncness.is_some() || sig.variadic.is_some() {
+        return Err(Error::new(
+            sig.span(),
+            "#[service] methods must not be generic, async or variadic",
+        ));
+    }
+    if f.default.is_some() {
+        return Err(Error::new(
+            f.span(),
+            "#[service] methods must not have a default body",
+        ));
+    }
+
+    let mut discriminant = None;
+    let mut blocking = false;
+    let mut deferred = false;
+    let mut manual = false;
+    let mut docs = vec![];
+    let mut kept_attrs = vec![];
+    for attr in f.attrs.drain(..) {
+        if attr.path.is_ident("opcode") {
+            discriminant = Some(match attr.parse_meta()? {
+                syn::Meta::NameValue(nv) => Expr::Lit(syn::ExprLit {
+                    attrs: vec![],
+                    lit: nv.lit,
+                }),
+                syn::Meta::List(_) => attr.parse_args::<Expr>()?,
+                syn::Meta::Path(_) => {
+                    return Err(Error::new(attr.span(), "expected `#[opcode = N]`"))
+                }
+            });
+        } else if attr.path.is_ident("blocking") {
+            blocking = true;
+        } else if attr.path.is_ident("deferred") {
+            deferred = true;
+        } else if attr.path.is_ident("manual") {
+            manual = true;
+        } else {
+            if attr.path.is_ident("doc") {
+                docs.push(attr.clone());
+            }
+            kept_attrs.push(attr);
+        }
+    }
+    f.attrs = kept_attrs;
+
+    let sig = &f.sig;
+    let mut inputs = sig.inputs.iter();
+    match inputs.next() {
+        Some(FnArg::Receiver(r)) if r.reference.is_some() => {}
+        _ => {
+            return Err(Error::new(
+                sig.span(),
+                "#[service] methods must take `&mut self` or `&self`",
+            ))
+        }
+    }
+
+    let mut args = vec![];
+    for input in inputs {
+        let FnArg::Typed(pat_type) = input else {
+            unreachable!("receiver can only be the first argument");
+        };
+        let name = match &*pat_type.pat {
+            Pat::Ident(pat) if pat.ident == "sender" => {
+                return Err(Error::new(
+                    pat.span(),
+                    "`sender` is the name of the argument that the server gets the sender in",
+                ))
+            }
+            Pat::Ident(pat) => pat.ident.clone(),
+            other => {
+                return Err(Error::new(
+                    other.span(),
+                    "#[service] arguments must be plain identifiers",
+                ))
+            }
+        };
+        match &*pat_type.ty {
+            Type::Reference(r) if r.mutability.is_some() => {
+                args.push(Arg::LendMut(name, (*r.elem).clone()))
+            }
+            Type::Reference(r) => args.push(Arg::Lend(name, (*r.elem).clone())),
+            ty => args.push(Arg::Scalar(name, scalar_kind(ty)?)),
+        }
+    }
+
+    let ret = match &sig.output {
+        ReturnType::Default => Return::Unit,
+        ReturnType::Type(_, ty) => match &**ty {
+            Type::Tuple(t) if t.elems.is_empty() => Return::Unit,
+            Type::Tuple(t) if t.elems.len() == 2 => {
+                let a = scalar_kind(&t.elems[0])?;
+                let b = scalar_kind(&t.elems[1])?;
+                if a.words() != 1 || b.words() != 1 {
+                    return Err(Error::new(
+                        t.span(),
+                        "tuple return values may only hold one-word scalars",
+                    ));
+                }
+                Return::Pair(a, b)
+            }
+            ty => Return::One(scalar_kind(ty)?),
+        },
+    };
+
+    let memory_args = args
+        .iter()
+        .filter(|a| !matches!(a, Arg::Scalar(..)))
+        .count();
+    if memory_args > 0 {
+        if args.len() != 1 {
+            return Err(Error::new(
+                sig.inputs.span(),
+                "a method that takes a reference must take exactly one argument",
+            ));
+        }
+        if !matches!(ret, Return::Unit) {
+            return Err(Error::new(
+                sig.output.span(),
+                "a method that takes a reference can't return a value; take `&mut T` instead",
+            ));
+        }
+        if deferred {
+            return Err(Error::new(
+                sig.span(),
+                "only methods that take scalar arguments can be #[deferred]",
+            ));
+        }
+    } else {
+        let words: usize = args
+            .iter()
+            .map(|a| match a {
+                Arg::Scalar(_, s) => s.words(),
+                _ => 0,
+            })
+            .sum();
+        if words > 4 {
+            return Err(Error::new(
+                sig.inputs.span(),
+                "scalar arguments don't fit in the four words of a scalar message",
+            ));
+        }
+    }
+
+    let name = sig.ident.clone();
+    Ok(Method {
+        variant: Ident::new(&camel_case(&name.to_string()), name.span()),
+        name,
+        docs,
+        discriminant,
+        blocking: blocking || deferred || !matches!(ret, Return::Unit),
+        deferred,
+        manual,
+        args,
+        ret,
+        ret_ty: sig.output.clone(),
+    })
+}
+
+fn scalar_kind(ty: &Type) -> syn::Result<Scalar> {
+    if let Type::Path(p) = ty {
+        if let Some(ident) = p.path.get_ident() {
+            match ident.to_string().as_str() {
+                "u8" | "u16" | "u32" | "usize" | "i8" | "i16" | "i32" | "isize" => {
+                    return Ok(Scalar::Word(Box::new(ty.clone())))
+                }
+                "u64" | "i64" => return Ok(Scalar::Wide(Box::new(ty.clone()))),
+                "bool" => return Ok(Scalar::Bool),
+                _ => (),
+            }
+        }
+    }
+    Err(Error::new(
+        ty.span(),
+        "unsupported type: pass integers and `bool` by value, and anything else by reference",
+    ))
+}
+
+/// Pack the scalar arguments of `m` into the four message words
+fn encode_args(m: &Method) -> Vec<TokenStream2> {
+    let mut words = vec![];
+    for arg in &m.args {
+        if let Arg::Scalar(name, kind) = arg {
+            match kind {
+                Scalar::Word(_) => words.push(quote! { #name as usize }),
+                Scalar::Bool => words.push(quote! { #name as usize }),
+                Scalar::Wide(_) => {
+                    words.push(quote! { (#name as u64 & 0xFFFF_FFFF) as usize });
+                    words.push(quote! { ((#name as u64) >> 32) as usize });
+                }
+            }
+        }
+    }
+    while words.len() < 4 {
+        words.push(quote! { 0 });
+    }
+    words
+}
+
+/// Unpack the scalar arguments of `m` from `scalar`, binding each to its own name
+fn decode_args(m: &Method) -> Vec<TokenStream2> {
+    let fields = [
+        quote! { scalar.arg1 },
+        quote! { scalar.arg2 },
+        quote! { scalar.arg3 },
+        quote! { scalar.arg4 },
+    ];
+    let mut idx = 0;
+    let mut bindings = vec![];
+    for arg in &m.args {
+        if let Arg::Scalar(name, kind) = arg {
+            let field = &fields[idx];
+            match kind {
+                Scalar::Word(ty) => bindings.push(quote! { let #name = #field as #ty; }),
+                Scalar::Bool => bindings.push(quote! { let #name = #field != 0; }),
+                Scalar::Wide(ty) => {
+                    let high = &fields[idx + 1];
+                    bindings.push(quote! {
+                        let #name = ((#field as u64 & 0xFFFF_FFFF) | ((#high as u64) << 32)) as #ty;
+                    });
+                }
+            }
+            idx += kind.words();
+        }
+    }
+    bindings
+}
+
+fn client_method(m: &Method, opcode: &Ident) -> TokenStream2 {
+    let name = &m.name;
+    let variant = &m.variant;
+    let docs = &m.docs;
+    let ret_ty = match &m.ret_ty {
+        ReturnType::Default => quote! { () },
+        ReturnType::Type(_, ty) => quote! { #ty },
+    };
+    let params = m.args.iter().map(|a| match a {
+        Arg::Scalar(name, Scalar::Word(ty)) | Arg::Scalar(name, Scalar::Wide(ty)) => {
+            quote! { #name: #ty }
+        }
+        Arg::Scalar(name, Scalar::Bool) => quote! { #name: bool },
+        Arg::Lend(name, ty) => quote! { #name: &#ty },
+        Arg::LendMut(name, ty) => quote! { #name: &mut #ty },
+    });
+
+    let body = match m.args.first() {
+        Some(Arg::Lend(arg, _)) => quote! {
+            let buf = xous_ipc::Buffer::into_buf(#arg.clone()).or(Err(xous::Error::InternalError))?;
+            buf.lend(self.conn, #opcode::#variant as u32).map(|_| ())
+        },
+        Some(Arg::LendMut(arg, ty)) => quote! {
+            let mut buf = xous_ipc::Buffer::into_buf(#arg.clone()).or(Err(xous::Error::InternalError))?;
+            buf.lend_mut(self.conn, #opcode::#variant as u32)?;
+            *#arg = buf.to_original::<#ty, _>().or(Err(xous::Error::InternalError))?;
+            Ok(())
+        },
+        _ => {
+            let words = encode_args(m);
+            if !m.blocking {
+                quote! {
+                    xous::send_message(
+                        self.conn,
+                        xous::Message::new_scalar(#opcode::#variant as usize, #(#words),*),
+                    )
+                    .map(|_| ())
+                }
+            } else {
+                let decode = match &m.ret {
+                    Return::Unit => quote! { Ok(_) => Ok(()), },
+                    Return::One(Scalar::Word(ty)) => {
+                        quote! { Ok(xous::Result::Scalar1(v)) => Ok(v as #ty), }
+                    }
+                    Return::One(Scalar::Bool) => {
+                        quote! { Ok(xous::Result::Scalar1(v)) => Ok(v != 0), }
+                    }
+                    Return::One(Scalar::Wide(ty)) => quote! {
+                        Ok(xous::Result::Scalar2(low, high)) =>
+                            Ok(((low as u64 & 0xFFFF_FFFF) | ((high as u64) << 32)) as #ty),
+                    },
+                    Return::Pair(a, b) => {
+                        let a = unpack_word(a, quote! { v1 });
+                        let b = unpack_word(b, quote! { v2 });
+                        quote! { Ok(xous::Result::Scalar2(v1, v2)) => Ok((#a, #b)), }
+                    }
+                };
+                quote! {
+                    match xous::send_message(
+                        self.conn,
+                        xous::Message::new_blocking_scalar(#opcode::#variant as usize, #(#words),*),
+                    ) {
+                        #decode
+                        Ok(_) => Err(xous::Error::InternalError),
+                        Err(e) => Err(e),
+                    }
+                }
+            }
+        }
+    };
+
+    quote! {
+        #(#docs)*
+        pub fn #name(&self, #(#params),*) -> Result<#ret_ty, xous::Error> {
+            #body
+        }
+    }
+}
+
+fn unpack_word(kind: &Scalar, word: TokenStream2) -> TokenStream2 {
+    match kind {
+        Scalar::Bool => quote! { #word != 0 },
+        Scalar::Word(ty) | Scalar::Wide(ty) => quote! { #word as #ty },
+    }
+}
+
+fn dispatch_arm(m: &Method, opcode: &Ident) -> TokenStream2 {
+    let name = &m.name;
+    let variant = &m.variant;
+    if m.manual {
+        return quote! { #opcode::#variant => Some(opcode), };
+    }
+
+    let body = match m.args.first() {
+        Some(Arg::Lend(_, ty)) => quote! {
+            let sender = msg.sender;
+            if let xous::Message::Borrow(mem) = &msg.body {
+                let buffer = unsafe { xous_ipc::Buffer::from_memory_message(mem) };
+                match buffer.to_original::<#ty, _>() {
+                    Ok(arg) => {
+                        server.#name(sender, &arg);
+                        None
+                    }
+                    Err(_) => Some(opcode),
+                }
+            } else {
+                Some(opcode)
+            }
+        },
+        Some(Arg::LendMut(_, ty)) => quote! {
+            let sender = msg.sender;
+            if let xous::Message::MutableBorrow(mem) = &mut msg.body {
+                let mut buffer = unsafe { xous_ipc::Buffer::from_memory_message_mut(mem) };
+                match buffer.to_original::<#ty, _>() {
+                    Ok(mut arg) => {
+                        server.#name(sender, &mut arg);
+                        buffer
+                            .replace(arg)
+                            .expect("couldn't serialize the response");
+                        None
+                    }
+                    Err(_) => Some(opcode),
+                }
+            } else {
+                Some(opcode)
+            }
+        },
+        _ => {
+            let bindings = decode_args(m);
+            let call_args: Vec<&Ident> = m
+                .args
+                .iter()
+                .map(|a| match a {
+                    Arg::Scalar(name, _) | Arg::Lend(name, _) | Arg::LendMut(name, _) => name,
+                })
+                .collect();
+            if !m.blocking {
+                quote! {
+                    if msg.body.is_blocking() {
+                        return Some(opcode);
+                    }
+                    let sender = msg.sender;
+                    if let Some(scalar) = msg.body.scalar_message() {
+                        #(#bindings)*
+                        server.#name(sender #(, #call_args)*);
+                        None
+                    } else {
+                        Some(opcode)
+                    }
+                }
+            } else {
+                let (pattern, reply) = reply_words(&m.ret);
+                let call = if m.deferred {
+                    quote! {
+                        match server.#name(sender #(, #call_args)*) {
+                            Some(#pattern) => { #reply }
+                            // The server answers the sender later, so don't respond now
+                            None => core::mem::forget(msg_opt.take()),
+                        }
+                    }
+                } else {
+                    quote! {
+                        let #pattern = server.#name(sender #(, #call_args)*);
+                        #reply
+                    }
+                };
+                quote! {
+                    if !msg.body.is_blocking() {
+                        return Some(opcode);
+                    }
+                    let sender = msg.sender;
+                    if let Some(scalar) = msg.body.scalar_message_mut() {
+                        #(#bindings)*
+                        #call
+                        None
+                    } else {
+                        Some(opcode)
+                    }
+                }
+            }
+        }
+    };
+
+    quote! { #opcode::#variant => { #body } }
+}
+
+/// The pattern that a blocking method's return value is bound to, and the statements that
+/// write it into `scalar` as the response
+fn reply_words(ret: &Return) -> (TokenStream2, TokenStream2) {
+    match ret {
+        Return::Unit => (
+            quote! { () },
+            quote! {
+                scalar.id = 0;
+                scalar.arg1 = 0;
+                *return_type = 1;
+            },
+        ),
+        Return::One(Scalar::Wide(_)) => (
+            quote! { r },
+            quote! {
+                let r = r as u64;
+                scalar.id = 0;
+                scalar.arg1 = (r & 0xFFFF_FFFF) as usize;
+                scalar.arg2 = (r >> 32) as usize;
+                *return_type = 2;
+            },
+        ),
+        Return::One(_) => (
+            quote! { r },
+            quote! {
+                scalar.id = 0;
+                scalar.arg1 = r as usize;
+                *return_type = 1;
+            },
+        ),
+        Return::Pair(_, _) => (
+            quote! { r },
+            quote! {
+                scalar.id = 0;
+                scalar.arg1 = r.0 as usize;
+                scalar.arg2 = r.1 as usize;
+                *return_type = 2;
+            },
+        ),
+    }
+}
+
+fn camel_case(name: &str) -> String {
+    let mut out = String::new();
+    for word in name.split('_').filter(|w| !w.is_empty()) {
+        let mut chars = word.chars();
+        if let Some(first) = chars.next() {
+            out.extend(first.to_uppercase());
+            out.push_str(chars.as_str());
+        }
+    }
+    out
+}
+
+fn snake_case(name: &str) -> String {
+    let mut out = String::new();
+    for (i, c) in name.chars().enumerate() {
+        if c.is_uppercase() {
+            if i != 0 {
+                out.push('_');
+            }
+            out.extend(c.to_lowercase());
+        } else {
+            out.push(c);
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use quote::ToTokens;
+
+    fn expand_str(attr: TokenStream2, item: TokenStream2) -> syn::Result<String> {
+        let args: ServiceArgs = syn::parse2(attr)?;
+        let item: ItemTrait = syn::parse2(item)?;
+        expand(args, item).map(|tokens| tokens.to_string())
+    }
+
+    fn expand_file(attr: TokenStream2, item: TokenStream2) -> syn::File {
+        let tokens = expand_str(attr, item).unwrap();
+        syn::parse_str(&tokens).unwrap()
+    }
+
+    fn expand_err(item: TokenStream2) -> String {
+        expand_str(quote! {}, item).err().unwrap().to_string()
+    }
+
+    /// The body of the client method `name`, as a string
+    fn client_body(file: &syn::File, name: &str) -> Option<String> {
+        file.items.iter().find_map(|item| match item {
+            syn::Item::Impl(i) => i.items.iter().find_map(|item| match item {
+                syn::ImplItem::Method(m) if m.sig.ident == name => {
+                    Some(m.block.to_token_stream().to_string())
+                }
+                _ => None,
+            }),
+            _ => None,
+        })
+    }
+
+    fn counter() -> syn::File {
+        expand_file(
+            quote! { opcode = Opcode, client = CounterClient },
+            quote! {
+                pub trait Counter {
+                    /// Add `amount` to the counter
+                    fn add(&mut self, amount: u32);
+                    fn reset(&mut self, hard: bool, epoch: u64);
+                    #[blocking]
+                    fn sync(&mut self);
+                    fn get(&mut self) -> u64;
+                    #[deferred]
+                    fn wait(&mut self, count: u32) -> bool;
+                    fn range(&mut self) -> (u32, bool);
+                    fn stats(&mut self, stats: &mut Stats);
+                    fn configure(&mut self, config: &Config);
+                    #[opcode = 16]
+                    #[manual]
+                    fn quit(&mut self);
+                    fn after_quit(&mut self);
+                }
+            },
+        )
+    }
+
+    #[test]
+    fn opcode_enum() {
+        let file = counter();
+        let opcodes = file
+            .items
+            .iter()
+            .find_map(|item| match item {
+                syn::Item::Enum(e) if e.ident == "Opcode" => Some(e),
+                _ => None,
+            })
+            .unwrap();
+        let variants: Vec<String> = opcodes
+            .variants
+            .iter()
+            .map(|v| {
+                let discriminant = v
+                    .discriminant
+                    .as_ref()
+                    .map(|(_, d)| d.to_token_stream().to_string());
+                format!(
+                    "{}{}",
+                    v.ident,
+                    discriminant
+                        .map(|d| format!(" = {}", d))
+                        .unwrap_or_default()
+                )
+            })
+            .collect();
+        assert_eq!(
+            variants,
+            [
+                "Add",
+                "Reset",
+                "Sync",
+                "Get",
+                "Wait",
+                "Range",
+                "Stats",
+                "Configure",
+                "Quit = 16",
+                "AfterQuit",
+                "InvalidCall = u32 :: MAX as usize",
+            ]
+        );
+        // doc comments are carried over to the opcode
+        assert!(opcodes.variants[0]
+            .attrs
+            .iter()
+            .any(|a| a.path.is_ident("doc")));
+    }
+
+    #[test]
+    fn emitted_items() {
+        let file = counter();
+        let kinds: Vec<&str> = file
+            .items
+            .iter()
+            .map(|item| match item {
+                syn::Item::Trait(_) => "trait",
+                syn::Item::Enum(_) => "enum",
+                syn::Item::Struct(_) => "struct",
+                syn::Item::Impl(_) => "impl",
+                syn::Item::Fn(_) => "fn",
+                _ => "other",
+            })
+            .collect();
+        assert_eq!(kinds, ["trait", "enum", "struct", "impl", "fn"]);
+    }
+
+    /// The signature of the server's trait method `name`, as a string
+    fn server_signature(file: &syn::File, name: &str) -> Option<String> {
+        file.items.iter().find_map(|item| match item {
+            syn::Item::Trait(t) => t.items.iter().find_map(|item| match item {
+                syn::TraitItem::Method(m) if m.sig.ident == name => {
+                    Some(m.sig.to_token_stream().to_string())
+                }
+                _ => None,
+            }),
+            _ => None,
+        })
+    }
+
+    /// The body of the generated dispatcher, as a string
+    fn dispatch_body(file: &syn::File) -> String {
+        file.items
+            .iter()
+            .find_map(|item| match item {
+                syn::Item::Fn(f) if f.sig.ident == "dispatch_counter" => {
+                    Some(f.block.to_token_stream().to_string())
+                }
+                _ => None,
+            })
+            .unwrap()
+    }
+
+    #[test]
+    fn server_trait() {
+        let file = counter();
+        assert_eq!(
+            server_signature(&file, "add").unwrap(),
+            "fn add (& mut self , sender : xous :: MessageSender , amount : u32)"
+        );
+        assert_eq!(
+            server_signature(&file, "stats").unwrap(),
+            "fn stats (& mut self , sender : xous :: MessageSender , stats : & mut Stats)"
+        );
+        // a deferred method may hold on to the sender instead of returning
+        assert_eq!(
+            server_signature(&file, "wait").unwrap(),
+            "fn wait (& mut self , sender : xous :: MessageSender , count : u32) -> Option < bool >"
+        );
+        assert!(server_signature(&file, "quit").is_none());
+        assert!(server_signature(&file, "after_quit").is_some());
+    }
+
+    #[test]
+    fn dispatch() {
+        let body = dispatch_body(&counter());
+        assert!(body.contains(
+            "Opcode :: Add => { if msg . body . is_blocking () { return Some (opcode) ; }"
+        ));
+        assert!(body.contains("server . add (sender , amount)"));
+        assert!(body.contains("Opcode :: Quit => Some (opcode)"));
+        assert!(body.contains("Opcode :: InvalidCall => Some (opcode)"));
+
+        // a `u64` is read back from two words
+        assert!(body.contains(
+            "let epoch = ((scalar . arg2 as u64 & 0xFFFF_FFFF) | ((scalar . arg3 as u64) << 32)) as u64 ;"
+        ));
+
+        // responses are written into the message for `reply_and_receive_next_legacy()`
+        assert!(body.contains(
+            "let r = server . get (sender) ; let r = r as u64 ; scalar . id = 0 ; \
+             scalar . arg1 = (r & 0xFFFF_FFFF) as usize ; scalar . arg2 = (r >> 32) as usize ; \
+             * return_type = 2 ;"
+        ));
+        assert!(body.contains("let () = server . sync (sender) ;"));
+        assert!(body.contains(
+            "match server . wait (sender , count) { Some (r) => { scalar . id = 0 ; \
+             scalar . arg1 = r as usize ; * return_type = 1 ; } \
+             None => core :: mem :: forget (msg_opt . take ()) , }"
+        ));
+        assert!(body.contains("server . stats (sender , & mut arg) ; buffer . replace (arg)"));
+    }
+
+    #[test]
+    fn deferred_methods_block() {
+        let file = counter();
+        let wait = client_body(&file, "wait").unwrap();
+        assert!(wait.contains("new_blocking_scalar (Opcode :: Wait as usize"));
+        assert!(wait.contains("Ok (v != 0)"));
+    }
+
+    #[test]
+    fn scalar_encoding() {
+        let file = counter();
+        let add = client_body(&file, "add").unwrap();
+        assert!(add.contains("new_scalar (Opcode :: Add as usize , amount as usize , 0 , 0 , 0)"));
+
+        // a `u64` takes two words, low word first
+        let reset = client_body(&file, "reset").unwrap();
+        assert!(reset.contains(
+            "new_scalar (Opcode :: Reset as usize , hard as usize , \
+             (epoch as u64 & 0xFFFF_FFFF) as usize , ((epoch as u64) >> 32) as usize , 0)"
+        ));
+
+        let sync = client_body(&file, "sync").unwrap();
+        assert!(sync.contains("new_blocking_scalar (Opcode :: Sync as usize"));
+    }
+
+    #[test]
+    fn methods_that_return_a_value_block() {
+        let file = counter();
+        let get = client_body(&file, "get").unwrap();
+        assert!(get.contains("new_blocking_scalar (Opcode :: Get as usize"));
+        assert!(get.contains("Scalar2 (low , high)"));
+
+        let range = client_body(&file, "range").unwrap();
+        assert!(range.contains("new_blocking_scalar (Opcode :: Range as usize"));
+        assert!(range.contains("Ok ((v1 as u32 , v2 != 0))"));
+    }
+
+    #[test]
+    fn references_are_lent() {
+        let file = counter();
+        let stats = client_body(&file, "stats").unwrap();
+        assert!(stats.contains("lend_mut (self . conn , Opcode :: Stats as u32)"));
+        assert!(stats.contains("to_original :: < Stats , _ >"));
+
+        let configure = client_body(&file, "configure").unwrap();
+        assert!(configure.contains("lend (self . conn , Opcode :: Configure as u32)"));
+    }
+
+    #[test]
+    fn manual_methods_have_no_client() {
+        let file = counter();
+        assert!(client_body(&file, "quit").is_none());
+        assert!(client_body(&file, "after_quit").is_some());
+    }
+
+    #[test]
+    fn default_names() {
+        let tokens = expand_str(
+            quote! {},
+            quote! { pub trait Counter { fn add(&mut self); } },
+        )
+        .unwrap();
+        assert!(tokens.contains("pub enum CounterOpcode"));
+        assert!(tokens.contains("pub struct CounterClient"));
+        assert!(tokens.contains("pub fn dispatch_counter"));
+        assert_eq!(snake_case("TicktimerApi"), "ticktimer_api");
+    }
+
+    #[test]
+    fn errors() {
+        assert!(
+            expand_err(quote! { trait T { fn f(&mut self, a: u64, b: u64, c: u32); } })
+                .contains("don't fit in the four words")
+        );
+        assert!(
+            expand_err(quote! { trait T { fn f(&mut self, a: &A, b: u32); } })
+                .contains("exactly one argument")
+        );
+        assert!(
+            expand_err(quote! { trait T { fn f(&mut self, a: &mut A) -> u32; } })
+                .contains("can't return a value")
+        );
+        assert!(
+            expand_err(quote! { trait T { fn f(&mut self, s: String); } })
+                .contains("unsupported type")
+        );
+        assert!(
+            expand_err(quote! { trait T { fn f<X>(&mut self, x: u32); } })
+                .contains("must not be generic")
+        );
+        assert!(expand_err(quote! { trait T { fn f(a: u32); } }).contains("`&mut self`"));
+        assert!(
+            expand_err(quote! { trait T { fn f(&mut self) -> (u64, u32); } })
+                .contains("one-word scalars")
+        );
+        assert!(
+            expand_err(quote! { trait T { #[deferred] fn f(&mut self, a: &mut A); } })
+                .contains("can be #[deferred]")
+        );
+        assert!(
+            expand_err(quote! { trait T { fn f(&mut self, sender: u32); } })
+                .contains("`sender` is the name")
+        );
+        assert!(expand_err(quote! { trait T { const X: u32; } }).contains("only methods"));
+        assert!(expand_str(quote! { server = S }, quote! { trait T {} }).is_err());
+    }
+}
@@ -0,0 +1,162 @@
+//! The layout of a packet on the mailbox.
+//!
+//! A packet is what is written to the FIFO between two `DONE` strobes, and starts with
+//! a four-word header:
+//!
+//! | Word | Bits  | Field                                               |
+//! |------|-------|-----------------------------------------------------|
+//! | 0    | 31:16 | length of the packet in words, including the header |
+//! | 0    | 15:8  | kind, as in `Kind`                                  |
+//! | 0    | 7:0   | protocol version                                    |
+//! | 1    | 31:16 | opcode                                              |
+//! | 1    | 15:0  | sequence number                                     |
+//! | 2    | 31:16 | epoch of the requester                              |
+//! | 2    | 15:8  | index of this fragment, from 0                      |
+//! | 2    | 7:0   | number of fragments in the message                  |
+//! | 3    | 31:0  | CRC-32 of words 0 to 2 and the payload              |
+//!
+//! The length is in the top half of the first word because the client side of the
+//! mailbox has no count of the words waiting to be read.
+
+use crate::crc32::Crc32;
+
+/// The depth of each of the mailbox FIFOs, which is the longest possible packet
+pub const FIFO_DEPTH: usize = 1024;
+pub const HEADER_WORDS: usize = 4;
+/// The most payload that fits into a single packet
+pub const MAX_PAYLOAD_WORDS: usize = FIFO_DEPTH - HEADER_WORDS;
+/// The most packets that a message may be split into
+pub const MAX_FRAGMENTS: usize = 16;
+/// The longest payload of a request or response
+pub const MAX_MESSAGE_WORDS: usize = MAX_PAYLOAD_WORDS * MAX_FRAGMENTS;
+
+const VERSION: u32 = 2;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Kind {
+    Request = 0,
+    Response = 1,
+    /// Sent by an endpoint that has just started, to be given an epoch
+    Hello = 2,
+    /// Gives the peer the epoch that its requests have to carry
+    Welcome = 3,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Header {
+    pub kind: Kind,
+    pub opcode: u16,
+    pub seq: u16,
+    /// Tells the requests of one run of an endpoint from those of the run before it
+    pub epoch: u16,
+    pub fragment: u8,
+    pub fragments: u8,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum FrameError {
+    /// The packet is shorter than its header says, or than a header
+    Truncated,
+    /// The packet is longer than its header says
+    Overlength,
+    /// The packet was made by a different version of the protocol
+    Version,
+    /// The fragment fields are out of range
+    Fragment,
+    /// The checksum doesn't match
+    Crc,
+}
+
+fn checksum(words: &[u32], payload: &[u32]) -> u32 {
+    let mut crc = Crc32::new();
+    crc.update_words(words);
+    crc.update_words(payload);
+    crc.finish()
+}
+
+/// Build a packet. `payload` must be no longer than `MAX_PAYLOAD_WORDS`.
+pub fn encode(header: &Header, payload: &[u32]) -> Vec<u32> {
+    assert!(
+        payload.len() <= MAX_PAYLOAD_WORDS,
+        "payload doesn't fit into a packet"
+    );
+    let words = [
+        ((HEADER_WORDS + payload.len()) as u32) << 16 | (header.kind as u32) << 8 | VERSION,
+        (header.opcode as u32) << 16 | header.seq as u32,
+        (header.epoch as u32) << 16 | (header.fragment as u32) << 8 | header.fragments as u32,
+    ];
+    let mut packet = Vec::with_capacity(HEADER_WORDS + payload.len());
+    packet.extend_from_slice(&words);
+    packet.push(checksum(&words, payload));
+    packet.extend_from_slice(payload);
+    packet
+}
+
+/// Check a packet, and split it into its header and payload
+pub fn decode(packet: &[u32]) -> Result<(Header, &[u32]), FrameError> {
+    if packet.len() < HEADER_WORDS {
+        return Err(FrameError::Truncated);
+    }
+    let length = packet_len(packet[0]);
+    if length < HEADER_WORDS || packet.len() < length {
+        return Err(FrameError::Truncated);
+    }
+    if packet.len() > length {
+        return Err(FrameError::Overlength);
+    }
+    if packet[0] & 0xff != VERSION {
+        return Err(FrameError::Version);
+    }
+    let kind = match (packet[0] >> 8) & 0xff {
+        0 => Kind::Request,
+        1 => Kind::Response,
+        2 => Kind::Hello,
+        3 => Kind::Welcome,
+        _ => return Err(FrameError::Version),
+    };
+    let fragment = ((packet[2] >> 8) & 0xff) as u8;
+    let fragments = (packet[2] & 0xff) as u8;
+    if fragments == 0 || fragments as usize > MAX_FRAGMENTS || fragment >= fragments {
+        return Err(FrameError::Fragment);
+    }
+    let payload = &packet[HEADER_WORDS..];
+    if checksum(&packet[..3], payload) != packet[3] {
+        return Err(FrameError::Crc);
+    }
+    let header = Header {
+        kind,
+        opcode: (packet[1] >> 16) as u16,
+        seq: packet[1] as u16,
+        epoch: (packet[2] >> 16) as u16,
+        fragment,
+        fragments,
+    };
+    Ok((header, payload))
+}
+
+/// The length of a packet in words, from its first word
+pub fn packet_len(first_word: u32) -> usize {
+    (first_word >> 16) as usize
+}
+
+/// Split a message into packets
+pub fn fragment(kind: Kind, opcode: u16, seq: u16, epoch: u16, payload: &[u32]) -> Vec<Vec<u32>> {
+    assert!(payload.len() <= MAX_MESSAGE_WORDS, "message is too long");
+    let fragments = payload.len().div_ceil(MAX_PAYLOAD_WORDS).max(1);
+    let mut header = Header {
+        kind,
+        opcode,
+        seq,
+        epoch,
+        fragment: 0,
+        fragments: fragments as u8,
+    };
+    (0..fragments)
+        .map(|index| {
+            header.fragment = index as u8;
+            let start = index * MAX_PAYLOAD_WORDS;
+            let end = (start + MAX_PAYLOAD_WORDS).min(payload.len());
+            encode(&header, &payload[start..end])
+        })
+        .collect()
+}
@@ -0,0 +1,446 @@
+//! A request/response protocol for the inter-core mailbox.
+//!
+//! Each message is split into packets that fit the FIFO, and every packet carries a
+//! header with the opcode, a sequence number and a CRC-32 (see `frame`). Requests are
+//! sent again if no response arrives in time, and the responder keeps its last few
+//! responses so that a repeated request is answered without being run twice.
+//!
+//! Sequence numbers start again from 0 when an endpoint is restarted, so requests also
+//! carry an epoch, which the peer hands out. A new endpoint sends `Hello` until the
+//! peer answers with `Welcome` and an epoch that it hasn't given out before. The peer
+//! forgets the requests of the previous run at the same time, and a request or
+//! response from an older epoch is never mistaken for a new one. A request that
+//! carries the wrong epoch, such as after the peer was restarted, is answered with
+//! `Welcome` instead, and sent again with the epoch that it gives.
+//!
+//! `Endpoint` knows nothing about the hardware. The mailbox driver gives it each packet
+//! that it reads with `receive()`, writes out whatever `next_packet()` returns, and
+//! calls `poll()` from time to time so that requests can be retried. `Link` does this
+//! with the driver in `mailbox`, which can run against the hardware or against the
+//! software model in `model`. `server` is what the two mailbox servers do with the
+//! events that come out of it.
+
+pub mod crc32;
+pub mod frame;
+pub mod link;
+pub mod mailbox;
+pub mod model;
+pub mod server;
+#[cfg(test)]
+mod test;
+
+pub use link::Link;
+
+use frame::{FrameError, Kind};
+use std::collections::{BTreeMap, VecDeque};
+
+/// The number of responses that are kept to answer repeated requests
+const ANSWERED_LEN: usize = 8;
+
+#[derive(Debug, Copy, Clone)]
+pub struct Config {
+    /// How long to wait for a response before sending a request again
+    pub timeout_ms: u64,
+    /// How many times a request is sent again before giving up on it
+    pub retries: u32,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            timeout_ms: 100,
+            retries: 3,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Event {
+    /// The peer sent a request, which should be answered with `respond()`
+    Request {
+        seq: u16,
+        opcode: u16,
+        payload: Vec<u32>,
+    },
+    /// The answer to a `call()`
+    Response {
+        seq: u16,
+        opcode: u16,
+        payload: Vec<u32>,
+    },
+    /// A `call()` got no response, even after it was retried
+    Timeout { seq: u16, opcode: u16 },
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum RpcError {
+    /// The payload is longer than `frame::MAX_MESSAGE_WORDS`
+    TooLong,
+    /// Every sequence number is waiting for a response
+    Busy,
+    /// There is no request with that sequence number waiting to be answered
+    NoRequest,
+}
+
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct Stats {
+    pub packets_sent: u32,
+    pub packets_received: u32,
+    pub crc_errors: u32,
+    /// Packets that were cut short, too long, or out of order
+    pub malformed: u32,
+    pub retransmits: u32,
+    pub timeouts: u32,
+    /// Requests that were received again, and responses that arrived too late or
+    /// belong to an earlier epoch
+    pub duplicates: u32,
+    /// Epochs that were given to the peer, which happens whenever either side starts
+    pub welcomes: u32,
+}
+
+/// A request that is waiting for its response
+struct Pending {
+    opcode: u16,
+    /// Kept rather than its packets, which depend on the epoch
+    payload: Vec<u32>,
+    deadline_ms: u64,
+    retries_left: u32,
+}
+
+impl Pending {
+    fn packets(&self, seq: u16, epoch: u16) -> Vec<Vec<u32>> {
+        frame::fragment(Kind::Request, self.opcode, seq, epoch, &self.payload)
+    }
+}
+
+/// A message whose packets are still arriving
+struct Reassembly {
+    kind: Kind,
+    opcode: u16,
+    seq: u16,
+    epoch: u16,
+    fragments: u8,
+    next: u8,
+    payload: Vec<u32>,
+}
+
+pub struct Endpoint {
+    config: Config,
+    next_seq: u16,
+    /// The epoch that the peer gave this endpoint's requests, or 0 until it has
+    epoch: u16,
+    /// When to send `Hello` again, while `epoch` is 0
+    hello_deadline_ms: u64,
+    /// The epoch that the peer's requests have to carry, or 0 if none was given out
+    peer_epoch: u16,
+    outgoing: VecDeque<Vec<u32>>,
+    pending: BTreeMap<u16, Pending>,
+    reassembly: Option<Reassembly>,
+    /// Requests from the peer that have been handed out, but not answered yet
+    unanswered: BTreeMap<u16, u16>,
+    /// The packets of recent responses, by sequence number and opcode
+    answered: VecDeque<(u16, u16, Vec<Vec<u32>>)>,
+    stats: Stats,
+}
+
+impl Endpoint {
+    pub fn new(config: Config) -> Self {
+        Endpoint {
+            config,
+            next_seq: 0,
+            epoch: 0,
+            hello_deadline_ms: 0,
+            peer_epoch: 0,
+            outgoing: VecDeque::new(),
+            pending: BTreeMap::new(),
+            reassembly: None,
+            unanswered: BTreeMap::new(),
+            answered: VecDeque::new(),
+            stats: Stats::default(),
+        }
+    }
+
+    pub fn stats(&self) -> Stats {
+        self.stats
+    }
+
+    /// Send a request, returning the sequence number that its `Event::Response` or
+    /// `Event::Timeout` will carry. Requests are held until the peer has given this
+    /// endpoint an epoch.
+    pub fn call(&mut self, opcode: u16, payload: &[u32], now_ms: u64) -> Result<u16, RpcError> {
+        if payload.len() > frame::MAX_MESSAGE_WORDS {
+            return Err(RpcError::TooLong);
+        }
+        let seq = (0..=u16::MAX)
+            .map(|offset| self.next_seq.wrapping_add(offset))
+            .find(|seq| !self.pending.contains_key(seq))
+            .ok_or(RpcError::Busy)?;
+        self.next_seq = seq.wrapping_add(1);
+
+        if self.epoch != 0 {
+            let packets = frame::fragment(Kind::Request, opcode, seq, self.epoch, payload);
+            self.outgoing.extend(packets);
+        }
+        self.pending.insert(
+            seq,
+            Pending {
+                opcode,
+                payload: payload.to_vec(),
+                deadline_ms: now_ms + self.config.timeout_ms,
+                retries_left: self.config.retries,
+            },
+        );
+        Ok(seq)
+    }
+
+    /// Answer an `Event::Request`
+    pub fn respond(&mut self, seq: u16, payload: &[u32]) -> Result<(), RpcError> {
+        if payload.len() > frame::MAX_MESSAGE_WORDS {
+            return Err(RpcError::TooLong);
+        }
+        let opcode = self.unanswered.remove(&seq).ok_or(RpcError::NoRequest)?;
+        let packets = frame::fragment(Kind::Response, opcode, seq, self.peer_epoch, payload);
+        self.outgoing.extend(packets.iter().cloned());
+        if self.answered.len() == ANSWERED_LEN {
+            self.answered.pop_front();
+        }
+        self.answered.push_back((seq, opcode, packets));
+        Ok(())
+    }
+
+    /// The next packet to write to the FIFO
+    pub fn next_packet(&mut self) -> Option<Vec<u32>> {
+        let packet = self.outgoing.pop_front()?;
+        self.stats.packets_sent += 1;
+        Some(packet)
+    }
+
+    /// Return a packet from `next_packet()` that couldn't be written, so that it is the
+    /// next one out
+    pub fn put_back(&mut self, packet: Vec<u32>) {
+        self.stats.packets_sent -= 1;
+        self.outgoing.push_front(packet);
+    }
+
+    /// Handle a packet that was read from the FIFO, returning the message that it
+    /// completed, if any. Packets that are damaged are dropped and counted.
+    pub fn receive(&mut self, packet: &[u32]) -> Option<Event> {
+        self.stats.packets_received += 1;
+        let (header, payload) = match frame::decode(packet) {
+            Ok(decoded) => decoded,
+            Err(FrameError::Crc) => {
+                self.stats.crc_errors += 1;
+                return None;
+            }
+            Err(_) => {
+                self.stats.malformed += 1;
+                return None;
+            }
+        };
+
+        if header.fragment == 0 {
+            if self.reassembly.is_some() {
+                // The rest of the previous message was lost
+                self.stats.malformed += 1;
+            }
+            self.reassembly = Some(Reassembly {
+                kind: header.kind,
+                opcode: header.opcode,
+                seq: header.seq,
+                epoch: header.epoch,
+                fragments: header.fragments,
+                next: 0,
+                payload: Vec::new(),
+            });
+        }
+        let message = match self.reassembly.as_mut() {
+            Some(message)
+                if message.kind == header.kind
+                    && message.opcode == header.opcode
+                    && message.seq == header.seq
+                    && message.epoch == header.epoch
+                    && message.fragments == header.fragments
+                    && message.next == header.fragment =>
+            {
+                message
+            }
+            _ => {
+                self.reassembly = None;
+                self.stats.malformed += 1;
+                return None;
+            }
+        };
+        message.payload.extend_from_slice(payload);
+        message.next += 1;
+        if message.next < message.fragments {
+            return None;
+        }
+
+        let message = self.reassembly.take().unwrap();
+        match message.kind {
+            Kind::Request => self.request(message),
+            Kind::Response => self.response(message),
+            Kind::Hello => {
+                self.welcome_peer();
+                None
+            }
+            Kind::Welcome => {
+                self.welcomed(message.epoch);
+                None
+            }
+        }
+    }
+
+    /// The peer has started again: give it an epoch that it hasn't had before, and
+    /// forget what it asked for under the old one
+    fn welcome_peer(&mut self) {
+        self.peer_epoch = match self.peer_epoch.wrapping_add(1) {
+            0 => 1,
+            epoch => epoch,
+        };
+        self.unanswered.clear();
+        self.answered.clear();
+        self.stats.welcomes += 1;
+        self.send_welcome();
+    }
+
+    fn send_welcome(&mut self) {
+        let packets = frame::fragment(Kind::Welcome, 0, 0, self.peer_epoch, &[]);
+        self.outgoing.extend(packets);
+    }
+
+    /// The peer gave this endpoint an epoch. A later `Welcome` replaces an earlier one,
+    /// and the requests that are waiting are sent again with it.
+    fn welcomed(&mut self, epoch: u16) {
+        if epoch == 0 || epoch == self.epoch {
+            return;
+        }
+        self.epoch = epoch;
+        for (seq, pending) in self.pending.iter() {
+            self.outgoing.extend(pending.packets(*seq, epoch));
+        }
+    }
+
+    fn request(&mut self, message: Reassembly) -> Option<Event> {
+        let seq = message.seq;
+        if self.peer_epoch == 0 || message.epoch != self.peer_epoch {
+            if self.peer_epoch == 0 {
+                // This endpoint started again, and the peer didn't
+                self.welcome_peer();
+            } else {
+                self.send_welcome();
+            }
+            if message.epoch != self.peer_epoch {
+                // The peer sends it again with the epoch in `Welcome`
+                self.stats.duplicates += 1;
+                return None;
+            }
+        }
+        if let Some((_, _, packets)) = self
+            .answered
+            .iter()
+            .find(|(answered, opcode, _)| *answered == seq && *opcode == message.opcode)
+        {
+            // The response was lost, so send it again
+            self.stats.duplicates += 1;
+            self.outgoing.extend(packets.iter().cloned());
+            return None;
+        }
+        if self.unanswered.get(&seq) == Some(&message.opcode) {
+            // Still being worked on
+            self.stats.duplicates += 1;
+            return None;
+        }
+        self.unanswered.insert(seq, message.opcode);
+        Some(Event::Request {
+            seq,
+            opcode: message.opcode,
+            payload: message.payload,
+        })
+    }
+
+    fn response(&mut self, message: Reassembly) -> Option<Event> {
+        match self.pending.get(&message.seq) {
+            Some(pending) if pending.opcode == message.opcode && message.epoch == self.epoch => {
+                self.pending.remove(&message.seq);
+                Some(Event::Response {
+                    seq: message.seq,
+                    opcode: message.opcode,
+                    payload: message.payload,
+                })
+            }
+            _ => {
+                self.stats.duplicates += 1;
+                None
+            }
+        }
+    }
+
+    /// Send again the requests whose responses are late, and give up on those that have
+    /// run out of retries
+    pub fn poll(&mut self, now_ms: u64) -> Vec<Event> {
+        if self.epoch == 0 && now_ms >= self.hello_deadline_ms {
+            self.send_hello(now_ms);
+        }
+        let mut events = vec![];
+        let mut expired = vec![];
+        for (seq, pending) in self.pending.iter_mut() {
+            if now_ms < pending.deadline_ms {
+                continue;
+            }
+            if pending.retries_left == 0 {
+                expired.push(*seq);
+                continue;
+            }
+            pending.retries_left -= 1;
+            pending.deadline_ms = now_ms + self.config.timeout_ms;
+            // Without an epoch, the request can only wait for the peer to answer `Hello`
+            if self.epoch != 0 {
+                self.outgoing.extend(pending.packets(*seq, self.epoch));
+                self.stats.retransmits += 1;
+            }
+        }
+        for seq in expired {
+            let pending = self.pending.remove(&seq).unwrap();
+            self.stats.timeouts += 1;
+            events.push(Event::Timeout {
+                seq,
+                opcode: pending.opcode,
+            });
+        }
+        events
+    }
+
+    fn send_hello(&mut self, now_ms: u64) {
+        self.outgoing
+            .extend(frame::fragment(Kind::Hello, 0, 0, 0, &[]));
+        self.hello_deadline_ms = now_ms + self.config.timeout_ms;
+    }
+
+    /// The time at which `poll()` next has something to do
+    pub fn next_deadline(&self) -> Option<u64> {
+        let hello = (self.epoch == 0).then_some(self.hello_deadline_ms);
+        self.pending
+            .values()
+            .map(|p| p.deadline_ms)
+            .chain(hello)
+            .min()
+    }
+
+    /// The FIFOs were emptied by an abort. Whatever was in flight is lost, so the
+    /// requests that are waiting are sent again, or `Hello` if the peer hasn't
+    /// answered it yet.
+    pub fn abort(&mut self, now_ms: u64) {
+        self.reassembly = None;
+        self.outgoing.clear();
+        if self.epoch == 0 {
+            self.send_hello(now_ms);
+        }
+        for (seq, pending) in self.pending.iter_mut() {
+            pending.deadline_ms = now_ms + self.config.timeout_ms;
+            if self.epoch != 0 {
+                self.outgoing.extend(pending.packets(*seq, self.epoch));
+            }
+        }
+    }
+}
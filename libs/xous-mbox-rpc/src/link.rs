@@ -0,0 +1,155 @@
+//! An `Endpoint` attached to one side of the mailbox.
+//!
+//! `Link` does what both mailbox servers need to do with the events of the mailbox
+//! interrupt: read packets, run the abort handshake, and start an abort to get back in
+//! step with the peer when a FIFO error or a damaged packet shows that words were lost.
+
+use crate::mailbox::{Csr, Mailbox, MailboxError, Pending};
+use crate::{Config, Endpoint, Event};
+
+pub struct Link<C> {
+    mailbox: Mailbox<C>,
+    endpoint: Endpoint,
+    /// An abort that was started here is in progress, so nothing can be sent
+    aborting: bool,
+}
+
+impl<C: Csr> Link<C> {
+    pub fn new(mailbox: Mailbox<C>, config: Config) -> Self {
+        Link {
+            mailbox,
+            endpoint: Endpoint::new(config),
+            aborting: false,
+        }
+    }
+
+    pub fn endpoint(&mut self) -> &mut Endpoint {
+        &mut self.endpoint
+    }
+
+    pub fn is_aborting(&self) -> bool {
+        self.aborting
+    }
+
+    /// Handle all of the events of an interrupt, in the order that the interrupt
+    /// handlers of the servers pass them on
+    pub fn handle(&mut self, pending: Pending, now_ms: u64) -> Vec<Event> {
+        if pending.error() {
+            self.error(now_ms);
+        }
+        if pending.abort_init() {
+            self.abort_init(now_ms);
+        }
+        if pending.abort_done() {
+            self.abort_done(now_ms);
+        }
+        if pending.available() {
+            self.incoming(now_ms)
+        } else {
+            vec![]
+        }
+    }
+
+    /// The `available` event: read the packets that have arrived
+    pub fn incoming(&mut self, now_ms: u64) -> Vec<Event> {
+        let packets = match self.mailbox.receive() {
+            Ok(packets) => packets,
+            // let the abort handler run
+            Err(MailboxError::Aborting) => return vec![],
+            Err(e) => {
+                log::warn!("Couldn't read the mailbox: {:?}", e);
+                self.abort(now_ms);
+                return vec![];
+            }
+        };
+        let damaged = self.damaged();
+        let events = packets
+            .iter()
+            .filter_map(|packet| self.endpoint.receive(packet))
+            .collect();
+        if self.damaged() != damaged {
+            // the packets that follow a damaged one can't be trusted to line up
+            log::warn!("Damaged packet received, resynchronizing");
+            self.abort(now_ms);
+        }
+        events
+    }
+
+    fn damaged(&self) -> u32 {
+        let stats = self.endpoint.stats();
+        stats.crc_errors + stats.malformed
+    }
+
+    /// The `abort_init` event
+    pub fn abort_init(&mut self, now_ms: u64) {
+        if self.mailbox.acknowledge_abort() {
+            log::info!("peer initiated abort");
+            // whatever was in flight is gone, so send it again
+            self.endpoint.abort(now_ms);
+        }
+    }
+
+    /// The `abort_done` event
+    pub fn abort_done(&mut self, now_ms: u64) {
+        if self.aborting {
+            self.aborting = false;
+            log::info!("abort protocol done");
+        } else {
+            // the peer emptied the FIFOs without telling us, e.g. because it was reset. A
+            // peer that was reset sends `Hello`, which makes the endpoint forget the
+            // requests that it made before.
+            log::warn!("abort done, but none was started here");
+            self.mailbox.flush();
+            self.endpoint.abort(now_ms);
+        }
+    }
+
+    /// The `error` event
+    pub fn error(&mut self, now_ms: u64) {
+        log::error!("Mailbox FIFO error, resynchronizing");
+        self.abort(now_ms);
+    }
+
+    /// Empty the FIFOs on both sides. Nothing is sent until the peer has acknowledged.
+    pub fn abort(&mut self, now_ms: u64) {
+        if self.aborting {
+            return;
+        }
+        log::warn!("abort initiated");
+        // when the peer has started an abort at the same time, this acknowledges it
+        self.aborting = self.mailbox.abort();
+        self.endpoint.abort(now_ms);
+    }
+
+    /// Send again the requests whose responses are late
+    pub fn poll(&mut self, now_ms: u64) -> Vec<Event> {
+        self.endpoint.poll(now_ms)
+    }
+
+    /// Write out the packets that the endpoint has queued. `ready` is called while the
+    /// FIFO is full, and returns `false` to give up; whatever is left is sent next time.
+    /// Returns `false` if packets were left because the FIFO had no room.
+    pub fn pump(&mut self, now_ms: u64, mut ready: impl FnMut() -> bool) -> bool {
+        if self.aborting {
+            // the abort handler sends everything again
+            return true;
+        }
+        while let Some(packet) = self.endpoint.next_packet() {
+            match self.mailbox.send(&packet, &mut ready) {
+                Ok(()) => {}
+                Err(MailboxError::Busy) => {
+                    self.endpoint.put_back(packet);
+                    return false;
+                }
+                // the abort handler sends everything again
+                Err(MailboxError::Aborting) => break,
+                Err(e) => {
+                    log::warn!("Dropped a packet of {} words: {:?}", packet.len(), e);
+                    self.abort(now_ms);
+                    break;
+                }
+            }
+        }
+        true
+    }
+}
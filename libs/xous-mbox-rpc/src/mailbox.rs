@@ -0,0 +1,366 @@
+//! A driver for one side of the mailbox hardware.
+//!
+//! The core side (`utra::mailbox`) and the client side (`utra::mb_client`) have the same
+//! registers, except for `STATUS`: the core side counts the words in each FIFO, while the
+//! client side only says whether a word can be read or written.
+//!
+//! Registers are reached through the `Csr` trait, so that the driver can run against
+//! `model::Model` as well as the hardware.
+
+use crate::frame;
+use utralib::generated::*;
+use utralib::{Field, Register};
+
+/// Access to a block of 32-bit registers, as `utralib::CSR<u32>` provides
+pub trait Csr {
+    fn r(&self, reg: Register) -> u32;
+    fn wo(&mut self, reg: Register, value: u32);
+
+    fn rf(&self, field: Field) -> u32 {
+        (self.r(field.register()) >> field.offset()) & field.mask() as u32
+    }
+    fn wfo(&mut self, field: Field, value: u32) {
+        self.wo(field.register(), self.ms(field, value));
+    }
+    /// Shift & mask a value to its final field position
+    fn ms(&self, field: Field, value: u32) -> u32 {
+        (value & field.mask() as u32) << field.offset()
+    }
+}
+
+impl Csr for CSR<u32> {
+    fn r(&self, reg: Register) -> u32 {
+        CSR::r(self, reg)
+    }
+    fn wo(&mut self, reg: Register, value: u32) {
+        CSR::wo(self, reg, value)
+    }
+    fn rf(&self, field: Field) -> u32 {
+        CSR::rf(self, field)
+    }
+    fn wfo(&mut self, field: Field, value: u32) {
+        CSR::wfo(self, field, value)
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Side {
+    /// `utra::mailbox`
+    Core,
+    /// `utra::mb_client`
+    Client,
+}
+
+impl Side {
+    pub fn peer(self) -> Side {
+        match self {
+            Side::Core => Side::Client,
+            Side::Client => Side::Core,
+        }
+    }
+
+    pub(crate) fn index(self) -> usize {
+        self as usize
+    }
+
+    pub(crate) fn regs(self) -> &'static Regs {
+        match self {
+            Side::Core => &CORE,
+            Side::Client => &CLIENT,
+        }
+    }
+}
+
+/// The registers and fields that both sides have
+pub(crate) struct Regs {
+    pub wdata: Field,
+    pub rdata: Field,
+    pub ev_status: Register,
+    pub ev_pending: Register,
+    pub ev_enable: Register,
+    pub status: Register,
+    pub control_abort: Field,
+    pub done: Field,
+    pub ev_available: Field,
+    pub ev_abort_init: Field,
+    pub ev_abort_done: Field,
+    pub ev_error: Field,
+    pub abort_in_progress: Field,
+    pub abort_ack: Field,
+    pub tx_err: Field,
+    pub rx_err: Field,
+}
+
+const CORE: Regs = Regs {
+    wdata: utra::mailbox::WDATA_WDATA,
+    rdata: utra::mailbox::RDATA_RDATA,
+    ev_status: utra::mailbox::EV_STATUS,
+    ev_pending: utra::mailbox::EV_PENDING,
+    ev_enable: utra::mailbox::EV_ENABLE,
+    status: utra::mailbox::STATUS,
+    control_abort: utra::mailbox::CONTROL_ABORT,
+    done: utra::mailbox::DONE_DONE,
+    ev_available: utra::mailbox::EV_PENDING_AVAILABLE,
+    ev_abort_init: utra::mailbox::EV_PENDING_ABORT_INIT,
+    ev_abort_done: utra::mailbox::EV_PENDING_ABORT_DONE,
+    ev_error: utra::mailbox::EV_PENDING_ERROR,
+    abort_in_progress: utra::mailbox::STATUS_ABORT_IN_PROGRESS,
+    abort_ack: utra::mailbox::STATUS_ABORT_ACK,
+    tx_err: utra::mailbox::STATUS_TX_ERR,
+    rx_err: utra::mailbox::STATUS_RX_ERR,
+};
+
+const CLIENT: Regs = Regs {
+    wdata: utra::mb_client::WDATA_WDATA,
+    rdata: utra::mb_client::RDATA_RDATA,
+    ev_status: utra::mb_client::EV_STATUS,
+    ev_pending: utra::mb_client::EV_PENDING,
+    ev_enable: utra::mb_client::EV_ENABLE,
+    status: utra::mb_client::STATUS,
+    control_abort: utra::mb_client::CONTROL_ABORT,
+    done: utra::mb_client::DONE_DONE,
+    ev_available: utra::mb_client::EV_PENDING_AVAILABLE,
+    ev_abort_init: utra::mb_client::EV_PENDING_ABORT_INIT,
+    ev_abort_done: utra::mb_client::EV_PENDING_ABORT_DONE,
+    ev_error: utra::mb_client::EV_PENDING_ERROR,
+    abort_in_progress: utra::mb_client::STATUS_ABORT_IN_PROGRESS,
+    abort_ack: utra::mb_client::STATUS_ABORT_ACK,
+    tx_err: utra::mb_client::STATUS_TX_ERR,
+    rx_err: utra::mb_client::STATUS_RX_ERR,
+};
+
+/// The events that raised an interrupt
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Pending {
+    bits: u32,
+    side: Side,
+}
+
+impl Pending {
+    /// Read and clear `EV_PENDING`. This is what an interrupt handler should call.
+    pub fn take(csr: &mut impl Csr, side: Side) -> Pending {
+        let bits = csr.r(side.regs().ev_pending);
+        csr.wo(side.regs().ev_pending, bits);
+        Pending { bits, side }
+    }
+
+    /// The events in `bits`, as returned by `bits()`
+    pub fn from_bits(bits: u32, side: Side) -> Pending {
+        Pending { bits, side }
+    }
+
+    pub fn bits(&self) -> u32 {
+        self.bits
+    }
+
+    fn is_set(&self, field: Field) -> bool {
+        (self.bits >> field.offset()) & field.mask() as u32 != 0
+    }
+
+    /// The peer has finished writing a packet
+    pub fn available(&self) -> bool {
+        self.is_set(self.side.regs().ev_available)
+    }
+    /// The peer started an abort, which has to be acknowledged
+    pub fn abort_init(&self) -> bool {
+        self.is_set(self.side.regs().ev_abort_init)
+    }
+    /// The peer acknowledged an abort
+    pub fn abort_done(&self) -> bool {
+        self.is_set(self.side.regs().ev_abort_done)
+    }
+    /// `tx_err` or `rx_err` was set
+    pub fn error(&self) -> bool {
+        self.is_set(self.side.regs().ev_error)
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum MailboxError {
+    /// The peer hasn't taken the previous packet, and nothing was written
+    Busy,
+    /// The peer stopped taking words partway through, so the packet was cut short
+    Stalled,
+    /// The packet is longer than the FIFO
+    TooLong,
+    /// An abort is in progress, so the FIFO is ignoring reads and writes
+    Aborting,
+    /// The write FIFO overflowed
+    TxError,
+    /// The read FIFO underflowed
+    RxError,
+}
+
+pub struct Mailbox<C> {
+    csr: C,
+    side: Side,
+    /// The start of a packet whose end hasn't been read yet
+    partial: Vec<u32>,
+}
+
+impl<C: Csr> Mailbox<C> {
+    pub fn new(csr: C, side: Side) -> Self {
+        Mailbox {
+            csr,
+            side,
+            partial: Vec::new(),
+        }
+    }
+
+    pub fn side(&self) -> Side {
+        self.side
+    }
+
+    pub fn enable_interrupts(&mut self) {
+        self.csr.wo(self.side.regs().ev_enable, !0);
+    }
+
+    /// An abort was started, and the peer hasn't acknowledged it yet
+    pub fn is_aborting(&self) -> bool {
+        self.csr.rf(self.side.regs().abort_in_progress) != 0
+    }
+
+    fn defer_aborts(&mut self, defer: bool) {
+        let regs = self.side.regs();
+        let enable = self.csr.r(regs.ev_enable);
+        let abort_init = self.csr.ms(regs.ev_abort_init, 1);
+        self.csr.wo(
+            regs.ev_enable,
+            if defer {
+                enable & !abort_init
+            } else {
+                enable | abort_init
+            },
+        );
+    }
+
+    /// Write a packet and signal `DONE`. `ready` is called while the FIFO has no room,
+    /// and returns `false` to give up. On the core side, the previous packet has to be
+    /// read before this one is written, so that packets don't run together.
+    pub fn send(
+        &mut self,
+        packet: &[u32],
+        mut ready: impl FnMut() -> bool,
+    ) -> Result<(), MailboxError> {
+        let regs = self.side.regs();
+        if packet.len() > frame::FIFO_DEPTH {
+            return Err(MailboxError::TooLong);
+        }
+        if self.is_aborting() {
+            return Err(MailboxError::Aborting);
+        }
+        if self.side == Side::Core {
+            while self.csr.rf(utra::mailbox::STATUS_TX_WORDS) != 0 {
+                if !ready() {
+                    return Err(MailboxError::Busy);
+                }
+            }
+        }
+        // defer aborts until this interaction is done
+        self.defer_aborts(true);
+        for (index, &word) in packet.iter().enumerate() {
+            if self.side == Side::Client {
+                while self.csr.rf(utra::mb_client::STATUS_TX_FREE) == 0 {
+                    if !ready() {
+                        self.defer_aborts(false);
+                        return Err(if index == 0 {
+                            MailboxError::Busy
+                        } else {
+                            MailboxError::Stalled
+                        });
+                    }
+                }
+            }
+            self.csr.wfo(regs.wdata, word);
+        }
+        self.csr.wfo(regs.done, 1);
+        self.defer_aborts(false);
+
+        let status = self.csr.r(regs.status);
+        if status & self.csr.ms(regs.tx_err, 1) != 0 {
+            Err(MailboxError::TxError)
+        } else if status & self.csr.ms(regs.abort_in_progress, 1) != 0 {
+            Err(MailboxError::Aborting)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Read whatever is in the FIFO, and return the packets that are complete. The start
+    /// of a packet that is still being written is kept until the rest of it arrives. If a
+    /// length doesn't make sense, the rest of the words are returned as a single packet,
+    /// which will fail to decode.
+    pub fn receive(&mut self) -> Result<Vec<Vec<u32>>, MailboxError> {
+        let regs = self.side.regs();
+        if self.is_aborting() {
+            return Err(MailboxError::Aborting);
+        }
+        // defer aborts until this interaction is done
+        self.defer_aborts(true);
+        match self.side {
+            Side::Core => {
+                let count = self.csr.rf(utra::mailbox::STATUS_RX_WORDS);
+                for _ in 0..count {
+                    self.partial.push(self.csr.rf(regs.rdata));
+                }
+            }
+            Side::Client => {
+                let mut count = 0;
+                while count < frame::FIFO_DEPTH
+                    && self.csr.rf(utra::mb_client::STATUS_RX_AVAIL) != 0
+                {
+                    self.partial.push(self.csr.rf(regs.rdata));
+                    count += 1;
+                }
+            }
+        }
+        self.defer_aborts(false);
+
+        if self.csr.rf(regs.rx_err) != 0 {
+            self.partial.clear();
+            return Err(MailboxError::RxError);
+        }
+        let mut packets = vec![];
+        while let Some(&first) = self.partial.first() {
+            let length = frame::packet_len(first);
+            if !(frame::HEADER_WORDS..=frame::FIFO_DEPTH).contains(&length) {
+                packets.push(std::mem::take(&mut self.partial));
+            } else if length <= self.partial.len() {
+                packets.push(self.partial.drain(..length).collect());
+            } else {
+                break;
+            }
+        }
+        Ok(packets)
+    }
+
+    /// Start an abort, which empties both FIFOs. Returns `false` if this acknowledged an
+    /// abort that the peer had already started, in which case it is already over and no
+    /// `abort_done` event will follow.
+    pub fn abort(&mut self) -> bool {
+        self.csr.wfo(self.side.regs().control_abort, 1);
+        self.partial.clear();
+        self.is_aborting()
+    }
+
+    /// Acknowledge an `abort_init` event. Returns `false` if there was nothing to
+    /// acknowledge, because this side's own `abort()` crossed with the peer's and
+    /// acknowledged it already.
+    pub fn acknowledge_abort(&mut self) -> bool {
+        let regs = self.side.regs();
+        let status = self.csr.r(regs.status);
+        if status & self.csr.ms(regs.abort_in_progress, 1) == 0
+            || status & self.csr.ms(regs.abort_ack, 1) != 0
+        {
+            return false;
+        }
+        self.csr.wfo(regs.control_abort, 1);
+        self.partial.clear();
+        true
+    }
+
+    /// Forget the start of a packet that the FIFOs were emptied under
+    pub fn flush(&mut self) {
+        self.partial.clear();
+    }
+}
@@ -0,0 +1,254 @@
+//! A software model of the mailbox hardware, for testing on the host.
+//!
+//! `Model::csr()` gives each side a `Csr` that behaves like the `utra::mailbox` or
+//! `utra::mb_client` registers: words written by one side can be read by the other,
+//! `DONE` raises `available` on the peer, and `CONTROL_ABORT` runs the abort handshake.
+//! Interrupts aren't delivered; whoever drives the model checks `irq()` and calls the
+//! handler itself.
+//!
+//! Faults can be injected with `inject()`, to check that the protocol recovers from
+//! them.
+
+use crate::frame::FIFO_DEPTH;
+use crate::mailbox::{Csr, Side};
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use utralib::{Field, Register};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Fault {
+    /// A word that is written is lost, and `tx_err` is set
+    TxError,
+    /// A word that is read is lost, zero is read instead, and `rx_err` is set
+    RxError,
+    /// The peer writes `CONTROL_ABORT` right after a word is written, as if its core
+    /// had been reset
+    Abort,
+}
+
+/// The registers of one side
+#[derive(Default)]
+struct Port {
+    ev_pending: u32,
+    ev_enable: u32,
+    tx_err: bool,
+    rx_err: bool,
+    /// Faults waiting to happen, with the number of words to let through first
+    faults: Vec<(usize, Fault)>,
+}
+
+#[derive(Default)]
+struct State {
+    /// The words that each side has yet to read, by `Side::index()`
+    fifo: [VecDeque<u32>; 2],
+    port: [Port; 2],
+    /// The side that started the abort in progress
+    aborting: Option<Side>,
+    abort_ack: bool,
+    /// The number of aborts that were started
+    aborts: usize,
+}
+
+fn bits(field: Field, value: u32) -> u32 {
+    (value & field.mask() as u32) << field.offset()
+}
+
+impl State {
+    fn raise(&mut self, side: Side, event: Field) {
+        self.port[side.index()].ev_pending |= bits(event, 1);
+    }
+
+    /// Count a word against the faults of `side` that `matches`, and return the one that
+    /// is due, if any
+    fn fault(&mut self, side: Side, matches: fn(Fault) -> bool) -> Option<Fault> {
+        let faults = &mut self.port[side.index()].faults;
+        let index = faults.iter().position(|&(_, fault)| matches(fault))?;
+        if faults[index].0 == 0 {
+            return Some(faults.remove(index).1);
+        }
+        faults[index].0 -= 1;
+        None
+    }
+
+    fn error(&mut self, side: Side, tx: bool) {
+        let port = &mut self.port[side.index()];
+        if tx {
+            port.tx_err = true;
+        } else {
+            port.rx_err = true;
+        }
+        self.raise(side, side.regs().ev_error);
+    }
+
+    fn read(&mut self, side: Side, reg: Register) -> u32 {
+        let regs = side.regs();
+        let offset = reg.offset();
+        if offset == regs.rdata.register().offset() {
+            if self.aborting.is_some() {
+                return 0;
+            }
+            if self.fault(side, |fault| fault == Fault::RxError).is_some() {
+                self.fifo[side.index()].pop_front();
+                self.error(side, false);
+                return 0;
+            }
+            match self.fifo[side.index()].pop_front() {
+                Some(word) => word,
+                None => {
+                    self.error(side, false);
+                    0
+                }
+            }
+        } else if offset == regs.ev_status.offset() {
+            let port = &self.port[side.index()];
+            bits(
+                regs.ev_available,
+                !self.fifo[side.index()].is_empty() as u32,
+            ) | bits(
+                regs.ev_abort_init,
+                (self.aborting == Some(side.peer())) as u32,
+            ) | bits(regs.ev_error, (port.tx_err || port.rx_err) as u32)
+        } else if offset == regs.ev_pending.offset() {
+            self.port[side.index()].ev_pending
+        } else if offset == regs.ev_enable.offset() {
+            self.port[side.index()].ev_enable
+        } else if offset == regs.status.offset() {
+            let rx = self.fifo[side.index()].len() as u32;
+            let tx = self.fifo[side.peer().index()].len() as u32;
+            let port = &mut self.port[side.index()];
+            let mut status = bits(regs.abort_in_progress, self.aborting.is_some() as u32)
+                | bits(regs.abort_ack, self.abort_ack as u32)
+                | bits(regs.tx_err, port.tx_err as u32)
+                | bits(regs.rx_err, port.rx_err as u32);
+            // the error bits are cleared on read
+            port.tx_err = false;
+            port.rx_err = false;
+            status |= match side {
+                Side::Core => {
+                    use utralib::generated::utra::mailbox;
+                    bits(mailbox::STATUS_RX_WORDS, rx) | bits(mailbox::STATUS_TX_WORDS, tx)
+                }
+                Side::Client => {
+                    use utralib::generated::utra::mb_client;
+                    bits(mb_client::STATUS_RX_AVAIL, (rx != 0) as u32)
+                        | bits(mb_client::STATUS_TX_FREE, (tx < FIFO_DEPTH as u32) as u32)
+                }
+            };
+            status
+        } else {
+            0
+        }
+    }
+
+    fn write(&mut self, side: Side, reg: Register, value: u32) {
+        let regs = side.regs();
+        let offset = reg.offset();
+        if offset == regs.wdata.register().offset() {
+            if self.aborting.is_some() {
+                return;
+            }
+            let full = self.fifo[side.peer().index()].len() >= FIFO_DEPTH;
+            if full || self.fault(side, |fault| fault == Fault::TxError).is_some() {
+                self.error(side, true);
+            } else {
+                self.fifo[side.peer().index()].push_back(value);
+            }
+            if self.fault(side, |fault| fault == Fault::Abort).is_some() {
+                self.abort(side.peer());
+            }
+        } else if offset == regs.done.register().offset() {
+            if self.aborting.is_none() && value & bits(regs.done, 1) != 0 {
+                self.raise(side.peer(), side.peer().regs().ev_available);
+            }
+        } else if offset == regs.control_abort.register().offset() {
+            if value & bits(regs.control_abort, 1) != 0 {
+                self.abort(side);
+            }
+        } else if offset == regs.ev_pending.offset() {
+            self.port[side.index()].ev_pending &= !value;
+        } else if offset == regs.ev_enable.offset() {
+            self.port[side.index()].ev_enable = value;
+        }
+    }
+
+    /// `side` wrote `CONTROL_ABORT`, which either starts an abort or acknowledges the
+    /// one that the peer started
+    fn abort(&mut self, side: Side) {
+        match self.aborting {
+            None => {
+                self.aborting = Some(side);
+                self.abort_ack = false;
+                self.aborts += 1;
+                self.raise(side.peer(), side.peer().regs().ev_abort_init);
+            }
+            Some(initiator) if initiator != side => {
+                self.aborting = None;
+                self.abort_ack = true;
+                self.raise(initiator, initiator.regs().ev_abort_done);
+            }
+            Some(_) => return,
+        }
+        self.fifo[0].clear();
+        self.fifo[1].clear();
+    }
+}
+
+/// A pair of mailbox register blocks, connected to each other
+#[derive(Clone, Default)]
+pub struct Model {
+    state: Arc<Mutex<State>>,
+}
+
+impl Model {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The registers of one side
+    pub fn csr(&self, side: Side) -> ModelCsr {
+        ModelCsr {
+            state: self.state.clone(),
+            side,
+        }
+    }
+
+    /// An enabled event is pending on `side`
+    pub fn irq(&self, side: Side) -> bool {
+        let state = self.state.lock().unwrap();
+        let port = &state.port[side.index()];
+        port.ev_pending & port.ev_enable != 0
+    }
+
+    /// Make `fault` happen to `side` once `after_words` words have gone through. Words
+    /// that are read count towards `Fault::RxError`, and words that are written count
+    /// towards the others.
+    pub fn inject(&self, side: Side, after_words: usize, fault: Fault) {
+        let mut state = self.state.lock().unwrap();
+        state.port[side.index()].faults.push((after_words, fault));
+    }
+
+    /// The number of aborts that were started, not counting acknowledgements
+    pub fn aborts(&self) -> usize {
+        self.state.lock().unwrap().aborts
+    }
+
+    /// The number of words that `side` has yet to read
+    pub fn rx_words(&self, side: Side) -> usize {
+        self.state.lock().unwrap().fifo[side.index()].len()
+    }
+}
+
+#[derive(Clone)]
+pub struct ModelCsr {
+    state: Arc<Mutex<State>>,
+    side: Side,
+}
+
+impl Csr for ModelCsr {
+    fn r(&self, reg: Register) -> u32 {
+        self.state.lock().unwrap().read(self.side, reg)
+    }
+    fn wo(&mut self, reg: Register, value: u32) {
+        self.state.lock().unwrap().write(self.side, reg, value)
+    }
+}
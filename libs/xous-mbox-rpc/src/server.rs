@@ -0,0 +1,112 @@
+//! What the mailbox servers do with the events of their `Link`.
+//!
+//! `mbox1` makes calls for its clients and hands each response to the caller that is
+//! waiting for it, and `mbox2` answers the requests of the loopback test. Neither part
+//! knows about Xous, so the tests run the same code over the software model.
+
+use crate::link::Link;
+use crate::mailbox::Csr;
+use crate::Event;
+use std::collections::HashMap;
+
+/// Opcodes of the loopback test that `mbox1` runs. Both send back the request ^ 0xaaaa_0000.
+pub const TEST_ECHO: u16 = 1;
+/// The first time a request arrives, abort instead of answering. The answer is sent
+/// when the request is retried.
+pub const TEST_ABORT_ONCE: u16 = 2;
+
+/// Answer the requests of the loopback test, as `mbox2` does
+pub fn answer<C: Csr>(link: &mut Link<C>, events: Vec<Event>, now_ms: u64) {
+    let mut abort = false;
+    for event in events {
+        let (seq, opcode, payload) = match event {
+            Event::Request {
+                seq,
+                opcode,
+                payload,
+            } => (seq, opcode, payload),
+            event => {
+                log::warn!("Unexpected event: {:?}", event);
+                continue;
+            }
+        };
+        log::info!("rx seq {}, opcode {}, {} words", seq, opcode, payload.len());
+        let response: Vec<u32> = payload.iter().map(|d| d ^ 0xAAAA_0000).collect();
+        match opcode {
+            TEST_ECHO => {
+                link.endpoint().respond(seq, &response).ok();
+            }
+            TEST_ABORT_ONCE => {
+                // the answer is kept, and sent when the request is retried
+                link.endpoint().respond(seq, &response).ok();
+                abort = true;
+            }
+            _ => {
+                log::warn!("Unknown opcode {}", opcode);
+                link.endpoint().respond(seq, &[]).ok();
+            }
+        }
+    }
+    if abort {
+        link.abort(now_ms);
+    }
+}
+
+/// The calls that are waiting for their response, as `mbox1` keeps them, each with
+/// whatever is needed to answer its caller
+pub struct Calls<T> {
+    waiting: HashMap<u16, T>,
+}
+
+impl<T> Default for Calls<T> {
+    fn default() -> Self {
+        Calls {
+            waiting: HashMap::new(),
+        }
+    }
+}
+
+impl<T> Calls<T> {
+    pub fn new() -> Self {
+        Calls::default()
+    }
+
+    /// `caller` waits for the response to the call with sequence number `seq`
+    pub fn wait(&mut self, seq: u16, caller: T) {
+        self.waiting.insert(seq, caller);
+    }
+
+    /// Match responses and timeouts to their callers, returning each caller with its
+    /// response, or `None` if the call timed out. Requests from the peer aren't served
+    /// here, and get an empty answer.
+    pub fn dispatch<C: Csr>(
+        &mut self,
+        link: &mut Link<C>,
+        events: Vec<Event>,
+    ) -> Vec<(T, Option<Vec<u32>>)> {
+        let mut results = vec![];
+        for event in events {
+            match event {
+                Event::Response { seq, payload, .. } => match self.waiting.remove(&seq) {
+                    Some(caller) => results.push((caller, Some(payload))),
+                    None => log::warn!("Response {} has no caller", seq),
+                },
+                Event::Timeout { seq, opcode } => {
+                    log::error!("Request {} with opcode {} timed out", seq, opcode);
+                    if let Some(caller) = self.waiting.remove(&seq) {
+                        results.push((caller, None));
+                    }
+                }
+                Event::Request { seq, opcode, .. } => {
+                    log::warn!(
+                        "Peer sent request {} with opcode {}, which isn't served here",
+                        seq,
+                        opcode
+                    );
+                    link.endpoint().respond(seq, &[]).ok();
+                }
+            }
+        }
+        results
+    }
+}
@@ -0,0 +1,367 @@
+//! The `mbox1`/`mbox2` loopback test, run over the software model of the mailbox.
+//!
+//! The core side makes calls with the code of `mbox1`, and the client side answers them
+//! with the code of `mbox2`. Time is simulated: each step is one millisecond.
+
+use crate::frame;
+use crate::mailbox::{Csr, Mailbox, Pending, Side};
+use crate::model::{Fault, Model, ModelCsr};
+use crate::server::{self, Calls, TEST_ABORT_ONCE, TEST_ECHO};
+use crate::{Config, Event, Link};
+use std::collections::BTreeMap;
+
+/// How many times `ready()` lets the peer run before a send gives up
+const READY_LIMIT: usize = 100;
+/// How long a call may take. Recovering from a fault shouldn't have to wait for the
+/// request to be retried, which `Config::default()` does after 100 ms.
+const CALL_LIMIT_MS: u64 = 50;
+
+struct Node {
+    link: Link<ModelCsr>,
+    side: Side,
+    /// The registers, as the interrupt handler sees them
+    irq: ModelCsr,
+    /// Calls that are waiting for their response, whose callers are their sequence numbers
+    calls: Calls<u16>,
+    /// Results of calls, by sequence number
+    results: BTreeMap<u16, Option<Vec<u32>>>,
+}
+
+impl Node {
+    fn new(model: &Model, side: Side) -> Self {
+        let mut mailbox = Mailbox::new(model.csr(side), side);
+        mailbox.enable_interrupts();
+        Node {
+            link: Link::new(mailbox, Config::default()),
+            side,
+            irq: model.csr(side),
+            calls: Calls::new(),
+            results: BTreeMap::new(),
+        }
+    }
+
+    /// Run the interrupt handler and the server loop, if the interrupt is raised
+    fn service(&mut self, model: &Model, now: u64) {
+        if !model.irq(self.side) {
+            return;
+        }
+        let pending = Pending::take(&mut self.irq, self.side);
+        let events = self.link.handle(pending, now);
+        self.dispatch(events, now);
+    }
+
+    /// The server loop of the core side is `mbox1`'s, and of the client side `mbox2`'s
+    fn dispatch(&mut self, events: Vec<Event>, now: u64) {
+        match self.side {
+            Side::Core => self
+                .results
+                .extend(self.calls.dispatch(&mut self.link, events)),
+            Side::Client => server::answer(&mut self.link, events, now),
+        }
+    }
+}
+
+/// Run one side of the mailbox. While it waits for room in the FIFO, the other side gets
+/// to read it.
+fn run(this: &mut Node, peer: &mut Node, model: &Model, now: u64) {
+    this.service(model, now);
+    let events = this.link.poll(now);
+    this.dispatch(events, now);
+    let mut tries = 0;
+    this.link.pump(now, || {
+        tries += 1;
+        peer.service(model, now);
+        tries < READY_LIMIT
+    });
+}
+
+struct Loopback {
+    model: Model,
+    core: Node,
+    client: Node,
+    now: u64,
+}
+
+impl Loopback {
+    fn new() -> Self {
+        let model = Model::new();
+        let core = Node::new(&model, Side::Core);
+        let client = Node::new(&model, Side::Client);
+        let mut test = Loopback {
+            model,
+            core,
+            client,
+            now: 0,
+        };
+        // let the endpoints give each other epochs
+        test.settle();
+        test
+    }
+
+    /// Run both sides for a millisecond
+    fn step(&mut self) {
+        self.now += 1;
+        run(&mut self.core, &mut self.client, &self.model, self.now);
+        run(&mut self.client, &mut self.core, &self.model, self.now);
+    }
+
+    fn settle(&mut self) {
+        for _ in 0..10 {
+            self.step();
+        }
+    }
+
+    fn start(&mut self, opcode: u16, request: &[u32]) -> u16 {
+        let now = self.now;
+        let seq = self
+            .core
+            .link
+            .endpoint()
+            .call(opcode, request, now)
+            .unwrap();
+        self.core.calls.wait(seq, seq);
+        seq
+    }
+
+    fn finish(&mut self, seq: u16) -> Option<Vec<u32>> {
+        let deadline = self.now + CALL_LIMIT_MS;
+        while self.now < deadline {
+            if let Some(result) = self.core.results.remove(&seq) {
+                return result;
+            }
+            self.step();
+        }
+        panic!("call {} took too long", seq);
+    }
+
+    /// Start one side again from scratch, as if its core had been reset
+    fn restart(&mut self, side: Side) {
+        let node = Node::new(&self.model, side);
+        match side {
+            Side::Core => self.core = node,
+            Side::Client => self.client = node,
+        }
+    }
+
+    /// Make a call from the core side, and check the response
+    fn call(&mut self, opcode: u16, words: usize) {
+        let request: Vec<u32> = (0..words as u32).map(|i| 0x1317_0000 + i).collect();
+        let seq = self.start(opcode, &request);
+        let response = self.finish(seq).expect("call timed out");
+        let expected: Vec<u32> = request.iter().map(|d| d ^ 0xAAAA_0000).collect();
+        assert_eq!(response, expected);
+    }
+}
+
+#[test]
+fn loopback() {
+    let mut test = Loopback::new();
+    for (opcode, words) in [
+        (TEST_ECHO, 1),
+        (TEST_ECHO, 16),
+        (TEST_ECHO, 1),
+        (TEST_ECHO, 1024),
+        (TEST_ECHO, 1),
+        (TEST_ECHO, 2),
+        (TEST_ABORT_ONCE, 4),
+        (TEST_ECHO, 3),
+        (TEST_ECHO, 4000),
+        (TEST_ECHO, frame::MAX_MESSAGE_WORDS),
+    ] {
+        test.call(opcode, words);
+    }
+    let stats = test.core.link.endpoint().stats();
+    assert_eq!(stats.timeouts, 0);
+    assert_eq!(stats.crc_errors + stats.malformed, 0);
+    // the request that TEST_ABORT_ONCE dropped was sent again by the abort handshake,
+    // not because its response was late
+    assert_eq!(stats.retransmits, 0);
+    assert_eq!(test.client.link.endpoint().stats().duplicates, 1);
+}
+
+#[test]
+fn abort_before_call() {
+    let mut test = Loopback::new();
+    let now = test.now;
+    test.core.link.abort(now);
+    assert!(test.core.link.is_aborting());
+    // held until the abort is done
+    test.call(TEST_ECHO, 8);
+    assert!(!test.core.link.is_aborting());
+}
+
+#[test]
+fn aborts_cross() {
+    let mut test = Loopback::new();
+    test.call(TEST_ECHO, 1);
+    let now = test.now;
+    test.core.link.abort(now);
+    // the client starts its own abort before it has seen the core's
+    test.client.link.abort(now);
+    test.settle();
+    assert!(!test.core.link.is_aborting());
+    assert!(!test.client.link.is_aborting());
+    // the client's abort acknowledged the core's, so there was only one
+    assert_eq!(test.model.aborts(), 1);
+    test.call(TEST_ECHO, 16);
+}
+
+#[test]
+fn abort_with_data_pending() {
+    let mut test = Loopback::new();
+    let seq = test.start(TEST_ECHO, &[1, 2, 3]);
+    let now = test.now;
+    test.core.link.pump(now, || false);
+    assert!(test.model.rx_words(Side::Client) != 0);
+    // the request is thrown away, so `available` and `abort_init` arrive together
+    test.core.link.abort(now);
+    assert_eq!(test.model.rx_words(Side::Client), 0);
+    assert_eq!(
+        test.finish(seq),
+        Some(vec![0xAAAA_0001, 0xAAAA_0002, 0xAAAA_0003])
+    );
+}
+
+#[test]
+fn abort_during_packet() {
+    let mut test = Loopback::new();
+    for side in [Side::Core, Side::Client] {
+        test.model.inject(side, 500, Fault::Abort);
+        test.call(TEST_ECHO, 2000);
+        test.call(TEST_ECHO, 1);
+    }
+}
+
+#[test]
+fn tx_error() {
+    let mut test = Loopback::new();
+    for side in [Side::Core, Side::Client] {
+        test.model.inject(side, 100, Fault::TxError);
+        test.call(TEST_ECHO, 3000);
+        test.call(TEST_ECHO, 1);
+    }
+}
+
+#[test]
+fn rx_error() {
+    let mut test = Loopback::new();
+    for side in [Side::Core, Side::Client] {
+        test.model.inject(side, 1500, Fault::RxError);
+        test.call(TEST_ECHO, 3000);
+        test.call(TEST_ECHO, 1);
+    }
+}
+
+#[test]
+fn bad_lengths() {
+    let mut test = Loopback::new();
+    for side in [Side::Core, Side::Client] {
+        let packet = frame::fragment(frame::Kind::Request, TEST_ECHO, 0x55, 0, &[0; 10]).remove(0);
+        let length = frame::FIFO_DEPTH as u32 + 16;
+        for words in [
+            // a packet that is cut short, so the next one would be read as the rest of it
+            packet[..6].to_vec(),
+            // a packet that is longer than its header says
+            [&packet[..], &[4, 5, 6, 7, 8, 9]].concat(),
+            // a packet that is longer than the FIFO
+            (0..length).map(|word| length << 16 | word).collect(),
+        ] {
+            let aborts = test.model.aborts();
+            let mut csr = test.model.csr(side);
+            for word in words {
+                csr.wfo(side.regs().wdata, word);
+            }
+            csr.wfo(side.regs().done, 1);
+            test.settle();
+            test.call(TEST_ECHO, 5);
+            // one side or the other noticed, and started an abort to get back in step
+            assert!(test.model.aborts() > aborts);
+        }
+    }
+}
+
+#[test]
+fn restarts() {
+    let mut test = Loopback::new();
+    test.call(TEST_ECHO, 1);
+    // the core's sequence numbers start again, but the client doesn't answer from the
+    // responses that it kept for the core's previous run
+    test.restart(Side::Core);
+    test.settle();
+    test.call(TEST_ECHO, 2);
+    assert_eq!(test.client.link.endpoint().stats().welcomes, 2);
+
+    // the core's epoch came from the client's previous run, so its request is turned
+    // away with a new epoch, and sent again
+    test.restart(Side::Client);
+    test.settle();
+    test.call(TEST_ECHO, 3);
+    let stats = test.client.link.endpoint().stats();
+    assert_eq!(stats.duplicates, 1);
+    assert_eq!(stats.welcomes, 1);
+    // the client's `Hello`
+    assert_eq!(test.core.link.endpoint().stats().welcomes, 1);
+}
+
+#[test]
+fn stale_responses_are_dropped() {
+    let mut test = Loopback::new();
+    let seq = test.start(TEST_ECHO, &[1]);
+    let now = test.now;
+    test.core.link.pump(now, || false);
+    test.client.service(&test.model, now);
+    test.client.link.pump(now, || false);
+    // the core is restarted before it reads the response, which has the same sequence
+    // number as its next request
+    test.restart(Side::Core);
+    let request = [2, 3];
+    assert_eq!(test.start(TEST_ECHO, &request), seq);
+    let expected: Vec<u32> = request.iter().map(|d| d ^ 0xAAAA_0000).collect();
+    assert_eq!(test.finish(seq), Some(expected));
+    assert_eq!(test.core.link.endpoint().stats().duplicates, 1);
+}
+
+#[test]
+fn packet_read_in_halves() {
+    let model = Model::new();
+    for side in [Side::Core, Side::Client] {
+        let mut tx = model.csr(side);
+        let regs = side.regs();
+        let mut rx = Mailbox::new(model.csr(side.peer()), side.peer());
+        let first = frame::fragment(frame::Kind::Request, TEST_ECHO, 1, 0, &[1; 100]).remove(0);
+        let second = frame::fragment(frame::Kind::Request, TEST_ECHO, 2, 0, &[2; 100]).remove(0);
+
+        // the reader gets to the FIFO while the second packet is being written
+        for &word in first.iter().chain(&second[..50]) {
+            tx.wfo(regs.wdata, word);
+        }
+        tx.wfo(regs.done, 1);
+        assert_eq!(rx.receive(), Ok(vec![first]));
+        for &word in &second[50..] {
+            tx.wfo(regs.wdata, word);
+        }
+        tx.wfo(regs.done, 1);
+        assert_eq!(rx.receive(), Ok(vec![second]));
+    }
+}
+
+#[test]
+fn peer_gone() {
+    let mut test = Loopback::new();
+    let request: Vec<u32> = (0..10).collect();
+    let seq = test.start(TEST_ECHO, &request);
+    // nobody is there to answer
+    let now = test.now;
+    let mut events = vec![];
+    for now in now..now + 1_000 {
+        test.core.link.pump(now, || false);
+        events.extend(test.core.link.poll(now));
+    }
+    assert_eq!(
+        events,
+        vec![Event::Timeout {
+            seq,
+            opcode: TEST_ECHO
+        }]
+    );
+}
@@ -0,0 +1,154 @@
+//! Commands that report on the state of the running system.
+
+/// Print every server that is registered with the name server, so that it's
+/// possible to see why a connection is being refused.
+pub fn names(xns: &xous_api_names::XousNames) {
+    let servers = match xns.list_servers() {
+        Ok(servers) => servers,
+        Err(e) => {
+            println!("couldn't list servers: {:?}", e);
+            return;
+        }
+    };
+    println!(
+        "{:<32} {:>5} {:>9} {:>5} {:>4} {:>7}",
+        "NAME", "OWNER", "CONNS", "AUTH", "KEY", "STATE"
+    );
+    for server in servers.iter() {
+        let conns = match server.max_conns {
+            Some(max) => format!("{}/{}", server.current_conns, max),
+            None => format!("{}/-", server.current_conns),
+        };
+        println!(
+            "{:<32} {:>5} {:>9} {:>5} {:>4} {:>7}",
+            server.name.as_str().unwrap_or("<invalid>"),
+            server.owner,
+            conns,
+            server.auth_conns,
+            if server.authenticated { "yes" } else { "no" },
+            if server.running { "running" } else { "stopped" },
+        );
+    }
+    println!("{} servers registered", servers.len());
+}
+
+/// The running processes, with the number of bytes of RAM that each owns. Only the
+/// name server may ask the kernel about other processes, and it only does so for
+/// trusted processes such as the console.
+fn processes(xns: &xous_api_names::XousNames) -> Result<Vec<(xous::PID, usize)>, xous::Error> {
+    let mut processes = vec![];
+    for pid in (1..=u8::MAX).filter_map(xous::PID::new) {
+        if let Some(bytes) = xns.process_ram(pid)? {
+            processes.push((pid, bytes));
+        }
+    }
+    Ok(processes)
+}
+
+/// The name of `pid`, or `-` if it doesn't have one
+fn process_name(xns: &xous_api_names::XousNames, pid: xous::PID) -> String {
+    match xns.process_name(pid) {
+        Ok(Some(name)) => name,
+        _ => "-".to_owned(),
+    }
+}
+
+/// Print the running processes, with the servers that each owns
+pub fn ps(xns: &xous_api_names::XousNames) {
+    let processes = match processes(xns) {
+        Ok(processes) => processes,
+        Err(e) => {
+            println!("couldn't list processes: {:?}", e);
+            return;
+        }
+    };
+    let servers = xns.list_servers().unwrap_or_default();
+    let me = xous::process::id() as u8;
+
+    println!("{:>5} {:<24} SERVERS", "PID", "NAME");
+    for (pid, _) in processes.iter() {
+        let owned: Vec<&str> = servers
+            .iter()
+            .filter(|s| s.running && s.owner == pid.get())
+            .map(|s| s.name.as_str().unwrap_or("<invalid>"))
+            .collect();
+        let marker = if pid.get() == me { " (this shell)" } else { "" };
+        println!(
+            "{:>5} {:<24} {}{}",
+            pid,
+            process_name(xns, *pid),
+            owned.join(", "),
+            marker
+        );
+    }
+    println!("{} processes", processes.len());
+}
+
+/// Print the RAM owned by each process, largest first. This doesn't count memory that
+/// isn't RAM, such as mapped peripherals.
+pub fn mem(xns: &xous_api_names::XousNames) {
+    let mut processes = match processes(xns) {
+        Ok(processes) => processes,
+        Err(e) => {
+            println!("couldn't get memory usage: {:?}", e);
+            return;
+        }
+    };
+    processes.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+
+    println!("{:>5} {:<24} {:>8}", "PID", "NAME", "RAM");
+    for (pid, bytes) in processes.iter() {
+        println!(
+            "{:>5} {:<24} {:>4} KiB",
+            pid,
+            process_name(xns, *pid),
+            bytes / 1024
+        );
+    }
+    let total: usize = processes.iter().map(|(_, bytes)| bytes).sum();
+    println!("{:>5} {:<24} {:>4} KiB", "", "total", total / 1024);
+}
+
+/// Print what the ticktimer is keeping track of, or the state of contended locks if
+/// `args` is `locks`
+pub fn ticktimer(tt: &xous_api_ticktimer::Ticktimer, args: &str) {
+    if args == "locks" {
+        match tt.lock_diagnostics() {
+            Ok((report, deadlocks)) => {
+                print!("{}", report);
+                println!("{} deadlocks", deadlocks);
+            }
+            Err(e) => println!("couldn't get lock diagnostics: {:?}", e),
+        }
+        return;
+    }
+    let stats = match tt.stats() {
+        Ok(stats) => stats,
+        Err(e) => {
+            println!("couldn't get ticktimer stats: {:?}", e);
+            return;
+        }
+    };
+    let uptime_s = stats.elapsed_ms / 1000;
+    println!(
+        "uptime:          {}:{:02}:{:02}.{:03}",
+        uptime_s / 3600,
+        uptime_s / 60 % 60,
+        uptime_s % 60,
+        stats.elapsed_ms % 1000
+    );
+    match tt.local_time() {
+        Some(time) if tt.wall_clock_synchronized() => println!("wall clock:      {}", time),
+        Some(time) => println!("wall clock:      {} (not synchronized)", time),
+        None => println!("wall clock:      not set"),
+    }
+    println!("messages:        {}", stats.messages);
+    println!("sleepers:        {}", stats.sleepers);
+    println!("timeouts:        {}", stats.timeouts);
+    println!("mutex waiters:   {}", stats.mutex_waiters);
+    println!("condvar waiters: {}", stats.condvar_waiters);
+    println!("semaphores:      {}", stats.semaphores);
+    println!("rwlocks:         {}", stats.rwlocks);
+    println!("timers:          {}", stats.timers);
+    println!("heartbeats:      {}", stats.heartbeats);
+}
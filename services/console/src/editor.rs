@@ -0,0 +1,350 @@
+//! Line editing for the shell, for a VT100-style terminal on the other end of the UART.
+//!
+//! Only printable ASCII is kept in the line. The cursor keys, Home, End and Delete are
+//! understood, along with the usual Emacs-style control keys:
+//!
+//! | Key    | Action                                |
+//! |--------|---------------------------------------|
+//! | Ctrl-A | move to the start of the line         |
+//! | Ctrl-E | move to the end of the line           |
+//! | Ctrl-B | move left                             |
+//! | Ctrl-F | move right                            |
+//! | Ctrl-P | previous line in the history          |
+//! | Ctrl-N | next line in the history              |
+//! | Ctrl-D | delete the character under the cursor |
+//! | Ctrl-K | delete to the end of the line         |
+//! | Ctrl-U | delete to the start of the line       |
+//! | Ctrl-W | delete the word before the cursor     |
+//! | Ctrl-C | abandon the line                      |
+
+use std::collections::VecDeque;
+
+pub const PROMPT: &str = "> ";
+
+/// The number of lines kept in the history
+const HISTORY_LEN: usize = 32;
+
+/// The longest line, so that a stuck key can't use up the console's memory
+const MAX_LINE: usize = 256;
+
+enum Escape {
+    None,
+    /// Received `ESC`
+    Start,
+    /// Received `ESC [` or `ESC O`, followed by these parameter bytes
+    Sequence(Vec<u8>),
+}
+
+pub struct LineEditor {
+    line: Vec<u8>,
+    cursor: usize,
+    history: VecDeque<String>,
+    /// The entry of `history` being shown, or `None` while editing a new line
+    browsing: Option<usize>,
+    /// The new line, kept while browsing the history
+    draft: Vec<u8>,
+    escape: Escape,
+    /// Set after a `\r`, so that the `\n` of a `\r\n` doesn't submit an empty line
+    after_cr: bool,
+    /// Bytes to send back to the terminal
+    echo: Vec<u8>,
+}
+
+impl LineEditor {
+    pub fn new() -> Self {
+        LineEditor {
+            line: vec![],
+            cursor: 0,
+            history: VecDeque::new(),
+            browsing: None,
+            draft: vec![],
+            escape: Escape::None,
+            after_cr: false,
+            echo: vec![],
+        }
+    }
+
+    /// The lines that were entered, oldest first
+    pub fn history(&self) -> impl Iterator<Item = &str> {
+        self.history.iter().map(|line| line.as_str())
+    }
+
+    /// Take the bytes that should be sent back to the terminal
+    pub fn take_echo(&mut self) -> Vec<u8> {
+        core::mem::take(&mut self.echo)
+    }
+
+    /// Show the prompt and the line being edited, as after some other output
+    pub fn redraw(&mut self) {
+        self.echo.extend_from_slice(b"\r");
+        self.echo.extend_from_slice(PROMPT.as_bytes());
+        self.echo.extend_from_slice(&self.line);
+        self.echo.extend_from_slice(b"\x1b[K");
+        let back = self.line.len() - self.cursor;
+        if back > 0 {
+            self.echo
+                .extend_from_slice(format!("\x1b[{}D", back).as_bytes());
+        }
+    }
+
+    /// Handle a byte from the terminal, returning the line if it was completed
+    pub fn input(&mut self, c: u8) -> Option<String> {
+        let after_cr = core::mem::replace(&mut self.after_cr, false);
+        match core::mem::replace(&mut self.escape, Escape::None) {
+            Escape::None => {}
+            Escape::Start => {
+                if c == b'[' || c == b'O' {
+                    self.escape = Escape::Sequence(vec![]);
+                }
+                return None;
+            }
+            Escape::Sequence(mut params) => {
+                // Parameters are digits and `;`, and the final byte is a letter or `~`
+                if (c.is_ascii_digit() || c == b';') && params.len() < 8 {
+                    params.push(c);
+                    self.escape = Escape::Sequence(params);
+                } else {
+                    self.escape_sequence(&params, c);
+                }
+                return None;
+            }
+        }
+
+        match c {
+            b'\r' | b'\n' => {
+                if c == b'\n' && after_cr {
+                    return None;
+                }
+                self.after_cr = c == b'\r';
+                return Some(self.submit());
+            }
+            0x1b => self.escape = Escape::Start,
+            0x01 => self.move_to(0),
+            0x05 => self.move_to(self.line.len()),
+            0x02 => self.move_to(self.cursor.saturating_sub(1)),
+            0x06 => self.move_to((self.cursor + 1).min(self.line.len())),
+            0x10 => self.browse_back(),
+            0x0e => self.browse_forward(),
+            0x04 => self.delete(self.cursor, self.cursor + 1),
+            0x0b => self.delete(self.cursor, self.line.len()),
+            0x15 => self.delete(0, self.cursor),
+            0x17 => {
+                let line = &self.line[..self.cursor];
+                let end = line
+                    .iter()
+                    .rposition(|c| *c != b' ')
+                    .map(|i| i + 1)
+                    .unwrap_or(0);
+                let start = line[..end]
+                    .iter()
+                    .rposition(|c| *c == b' ')
+                    .map(|i| i + 1)
+                    .unwrap_or(0);
+                self.delete(start, self.cursor);
+            }
+            0x03 => {
+                self.echo.extend_from_slice(b"^C\r\n");
+                self.line.clear();
+                self.cursor = 0;
+                self.browsing = None;
+                self.redraw();
+            }
+            0x08 | 0x7f => {
+                if self.cursor > 0 {
+                    self.delete(self.cursor - 1, self.cursor);
+                }
+            }
+            0x20..=0x7e => self.insert(c),
+            _ => {}
+        }
+        None
+    }
+
+    fn escape_sequence(&mut self, params: &[u8], last: u8) {
+        match (params, last) {
+            (_, b'A') => self.browse_back(),
+            (_, b'B') => self.browse_forward(),
+            (_, b'C') => self.move_to((self.cursor + 1).min(self.line.len())),
+            (_, b'D') => self.move_to(self.cursor.saturating_sub(1)),
+            (_, b'H') | (b"1", b'~') | (b"7", b'~') => self.move_to(0),
+            (_, b'F') | (b"4", b'~') | (b"8", b'~') => self.move_to(self.line.len()),
+            (b"3", b'~') => self.delete(self.cursor, self.cursor + 1),
+            _ => {}
+        }
+    }
+
+    fn insert(&mut self, c: u8) {
+        if self.line.len() >= MAX_LINE {
+            return;
+        }
+        self.line.insert(self.cursor, c);
+        self.cursor += 1;
+        if self.cursor == self.line.len() {
+            self.echo.push(c);
+        } else {
+            self.redraw();
+        }
+    }
+
+    fn delete(&mut self, start: usize, end: usize) {
+        let end = end.min(self.line.len());
+        if start >= end {
+            return;
+        }
+        self.line.drain(start..end);
+        self.cursor = start;
+        self.redraw();
+    }
+
+    fn move_to(&mut self, cursor: usize) {
+        if cursor < self.cursor {
+            self.echo
+                .extend_from_slice(format!("\x1b[{}D", self.cursor - cursor).as_bytes());
+        } else if cursor > self.cursor {
+            self.echo
+                .extend_from_slice(format!("\x1b[{}C", cursor - self.cursor).as_bytes());
+        }
+        self.cursor = cursor;
+    }
+
+    fn show(&mut self, line: Vec<u8>) {
+        self.line = line;
+        self.cursor = self.line.len();
+        self.redraw();
+    }
+
+    fn browse_back(&mut self) {
+        let index = match self.browsing {
+            Some(0) => return,
+            Some(index) => index - 1,
+            None if self.history.is_empty() => return,
+            None => {
+                self.draft = self.line.clone();
+                self.history.len() - 1
+            }
+        };
+        self.browsing = Some(index);
+        self.show(self.history[index].as_bytes().to_vec());
+    }
+
+    fn browse_forward(&mut self) {
+        match self.browsing {
+            None => {}
+            Some(index) if index + 1 < self.history.len() => {
+                self.browsing = Some(index + 1);
+                self.show(self.history[index + 1].as_bytes().to_vec());
+            }
+            Some(_) => {
+                self.browsing = None;
+                let draft = core::mem::take(&mut self.draft);
+                self.show(draft);
+            }
+        }
+    }
+
+    fn submit(&mut self) -> String {
+        self.echo.extend_from_slice(b"\r\n");
+        // Only printable ASCII is ever inserted
+        let line = String::from_utf8(core::mem::take(&mut self.line)).unwrap_or_default();
+        self.cursor = 0;
+        self.browsing = None;
+        self.draft.clear();
+
+        let trimmed = line.trim();
+        if !trimmed.is_empty() && self.history.back().map(|l| l.as_str()) != Some(trimmed) {
+            if self.history.len() == HISTORY_LEN {
+                self.history.pop_front();
+            }
+            self.history.push_back(trimmed.to_owned());
+        }
+        line
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Type `keys`, returning the lines that were entered
+    fn type_keys(editor: &mut LineEditor, keys: &[u8]) -> Vec<String> {
+        keys.iter().filter_map(|c| editor.input(*c)).collect()
+    }
+
+    #[test]
+    fn lines_are_entered_once() {
+        let mut editor = LineEditor::new();
+        let lines = type_keys(&mut editor, b"ps\r\nnames\nmem\r\r\n");
+        assert_eq!(lines, ["ps", "names", "mem", ""]);
+        assert_eq!(editor.take_echo(), b"ps\r\nnames\r\nmem\r\n\r\n");
+        // Blank lines aren't kept
+        assert_eq!(editor.history().collect::<Vec<_>>(), ["ps", "names", "mem"]);
+    }
+
+    #[test]
+    fn editing_keys() {
+        let mut editor = LineEditor::new();
+        // Ctrl-A, then insert at the start
+        assert_eq!(type_keys(&mut editor, b"elp\x01h\r"), ["help"]);
+        // Left arrow twice, Backspace, then Delete
+        assert_eq!(
+            type_keys(&mut editor, b"abcd\x1b[D\x1b[D\x7f\x1b[3~\r"),
+            ["ad"]
+        );
+        // Ctrl-W deletes the last word, along with the spaces after it
+        assert_eq!(
+            type_keys(&mut editor, b"one two  \x17three\r"),
+            ["one three"]
+        );
+        // Ctrl-B and Ctrl-K, then Ctrl-E
+        assert_eq!(
+            type_keys(&mut editor, b"abcdef\x02\x02\x0b\x05!\r"),
+            ["abcd!"]
+        );
+        // Ctrl-U deletes back to the start, and Home and End move the cursor
+        assert_eq!(
+            type_keys(&mut editor, b"xyz\x1b[D\x15a\x1b[Hb\x1bOF\r"),
+            ["baz"]
+        );
+    }
+
+    #[test]
+    fn control_c_abandons_the_line() {
+        let mut editor = LineEditor::new();
+        assert!(type_keys(&mut editor, b"reboot\x03").is_empty());
+        assert!(editor.take_echo().ends_with(b"^C\r\n\r> \x1b[K"));
+        assert_eq!(type_keys(&mut editor, b"ps\r"), ["ps"]);
+        assert_eq!(editor.history().count(), 1);
+    }
+
+    #[test]
+    fn only_printable_ascii_is_kept() {
+        let mut editor = LineEditor::new();
+        let lines = type_keys(&mut editor, b"a\x00b\xc3\xa9c\x07\r");
+        assert_eq!(lines, ["abc"]);
+
+        let long = vec![b'x'; MAX_LINE + 10];
+        let lines = type_keys(&mut editor, &long);
+        assert!(lines.is_empty());
+        assert_eq!(type_keys(&mut editor, b"\r")[0].len(), MAX_LINE);
+    }
+
+    #[test]
+    fn history_is_browsed() {
+        let mut editor = LineEditor::new();
+        type_keys(&mut editor, b"one\rtwo\rtwo\r");
+        assert_eq!(editor.history().collect::<Vec<_>>(), ["one", "two"]);
+
+        // Up twice, then down, with the arrows and with Ctrl-P and Ctrl-N
+        assert_eq!(type_keys(&mut editor, b"\x1b[A\x1b[A\x1b[B\r"), ["two"]);
+        assert_eq!(type_keys(&mut editor, b"\x10\x10\x10\r"), ["one"]);
+        // The line being typed is kept while browsing
+        assert_eq!(type_keys(&mut editor, b"thr\x10\x0e\x0eee\r"), ["three"]);
+
+        for index in 0..HISTORY_LEN + 5 {
+            type_keys(&mut editor, format!("line {}\r", index).as_bytes());
+        }
+        let history: Vec<_> = editor.history().collect();
+        assert_eq!(history.len(), HISTORY_LEN);
+        assert_eq!(history[0], "line 5");
+    }
+}
@@ -1,4 +1,10 @@
+#[cfg(feature="hwsim")]
 use utralib::generated::*;
+
+mod cmds;
+mod editor;
+mod shell;
+
 fn main() {
     log_server::init_wait().unwrap();
     log::set_max_level(log::LevelFilter::Info);
@@ -17,43 +23,10 @@ fn main() {
     #[cfg(feature="hwsim")]
     core_csr.wfo(utra::main::REPORT_REPORT, 0x600d_0000);
 
-    #[cfg(feature="hwsim")]
-    core_csr.wfo(utra::main::REPORT_REPORT, 0xa51d_0000);
-    let coreuser_csr = xous::syscall::map_memory(
-        xous::MemoryAddress::new(utra::coreuser::HW_COREUSER_BASE),
-        None,
-        4096,
-        xous::MemoryFlags::R | xous::MemoryFlags::W,
-    )
-    .expect("couldn't map Core User CSR range");
-    let mut coreuser = CSR::new(coreuser_csr.as_mut_ptr() as *mut u32);
-    // first, clear the ASID table to 0
-    for asid in 0..512 {
-        coreuser.wo(utra::coreuser::SET_ASID,
-            coreuser.ms(utra::coreuser::SET_ASID_ASID, asid)
-            | coreuser.ms(utra::coreuser::SET_ASID_TRUSTED, 0)
-        );
-    }
-    // set my PID to trusted
-    coreuser.wo(utra::coreuser::SET_ASID,
-        coreuser.ms(utra::coreuser::SET_ASID_ASID, xous::process::id() as u32)
-        | coreuser.ms(utra::coreuser::SET_ASID_TRUSTED, 1)
-    );
-    // set the required `mpp` state to user code (mpp == 0)
-    coreuser.wfo(utra::coreuser::SET_PRIVILEGE_MPP, 0);
-    // turn on the coreuser computation
-    coreuser.wo(utra::coreuser::CONTROL,
-        coreuser.ms(utra::coreuser::CONTROL_ASID, 1)
-        | coreuser.ms(utra::coreuser::CONTROL_ENABLE, 1)
-        | coreuser.ms(utra::coreuser::CONTROL_PRIVILEGE, 1)
-    );
-    // turn off coreuser control updates
-    coreuser.wo(utra::coreuser::PROTECT, 1);
-    #[cfg(feature="hwsim")]
-    core_csr.wfo(utra::main::REPORT_REPORT, 0xa51d_600d);
-
     log::info!("my PID is {}", xous::process::id());
 
+    let xns = xous_api_names::XousNames::new().unwrap();
+
     #[cfg(feature="pio-test")]
     {
         log::info!("running PIO tests");
@@ -114,4 +87,6 @@ fn main() {
     #[cfg(feature="hwsim")]
     core_csr.wfo(utra::main::DONE_DONE, 1); // this should stop the simulation
     log::info!("message passing test done at {}ms!", tt.elapsed_ms());
+
+    shell::run(xns);
 }
@@ -0,0 +1,452 @@
+//! The interactive shell on the console UART.
+//!
+//! The log server reads the UART, and passes what is typed on to the shell through a
+//! private server, so that no other process can type commands. Services add commands
+//! through the `_console_` server with `xous_api_console`, and are lent an
+//! `api::Invocation` each time one of their commands is run.
+//!
+//! Invocations are sent from a thread of their own, so that a service that doesn't
+//! reply can't hang the shell. The shell gives up on a command after
+//! `INVOKE_TIMEOUT_MS`, or when Ctrl-C is typed, and holds on to what is typed in the
+//! meantime.
+
+use crate::cmds;
+use crate::editor::LineEditor;
+use num_traits::FromPrimitive;
+use std::io::Write;
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use xous_api_console::api;
+
+/// How many times to ask to listen to the console, 100 ms apart, while the log server
+/// may not know yet that we are trusted
+const LISTEN_ATTEMPTS: u32 = 50;
+
+/// How long a command that another service added has to reply
+const INVOKE_TIMEOUT_MS: u64 = 10_000;
+
+/// The most characters that are held while a command runs
+const TYPEAHEAD_LEN: usize = 256;
+
+/// Sent to the input server by an invocation thread once the service has replied,
+/// with the serial number of the invocation in `arg1`
+const INVOCATION_DONE: usize = 0x1_0000;
+
+/// Sent to the input server by the ticktimer when an invocation has taken too long,
+/// with the timer ID in `arg1`
+const INVOCATION_TIMEOUT: usize = 0x1_0001;
+
+/// Commands that are built into the shell, with their help
+const BUILTINS: &[(&str, &str)] = &[
+    ("help", "list the commands"),
+    ("history", "show the lines that were entered"),
+    ("ps", "list the running processes"),
+    ("mem", "show the RAM used by each process"),
+    ("names", "list the servers registered with the name server"),
+    ("ticktimer", "show ticktimer counters, or `ticktimer locks`"),
+];
+
+/// A command added by another service
+struct Command {
+    name: String,
+    help: String,
+    pid: Option<xous::PID>,
+    cid: xous::CID,
+    opcode: usize,
+}
+
+type Commands = Arc<Mutex<Vec<Command>>>;
+
+#[repr(C, align(4096))]
+struct InvocationPage(api::Invocation);
+
+/// What an invocation thread hands back: the serial number of the invocation, the
+/// connection it was sent on, and the output of the command
+type Reply = (usize, xous::CID, Result<String, xous::Error>);
+
+/// A command that the shell is waiting on
+struct Pending {
+    name: String,
+    cid: xous::CID,
+    serial: usize,
+    timer: Option<u32>,
+}
+
+struct Shell {
+    editor: LineEditor,
+    commands: Commands,
+    xns: xous_api_names::XousNames,
+    tt: xous_api_ticktimer::Ticktimer,
+    input_sid: xous::SID,
+    /// A connection to our own input server, for invocation threads to say they're done
+    input_cid: xous::CID,
+    replies: mpsc::Receiver<Reply>,
+    reply_sender: mpsc::Sender<Reply>,
+    serial: usize,
+    pending: Option<Pending>,
+    /// What was typed while `pending` was set
+    typeahead: Vec<u8>,
+    /// Connections that are still waiting for the reply to a command that was given
+    /// up on, one entry per command
+    abandoned: Vec<xous::CID>,
+}
+
+impl Shell {
+    /// Send what the editor has to say to the terminal
+    fn flush(&mut self) {
+        let echo = self.editor.take_echo();
+        if !echo.is_empty() {
+            let mut stdout = std::io::stdout();
+            stdout.write_all(&echo).ok();
+            stdout.flush().ok();
+        }
+    }
+
+    fn input(&mut self, chars: &[u8]) {
+        for c in chars {
+            if self.pending.is_none() {
+                self.edit(*c);
+            } else if *c == 0x03 {
+                self.typeahead.clear();
+                self.give_up("interrupted");
+            } else if self.typeahead.len() < TYPEAHEAD_LEN {
+                self.typeahead.push(*c);
+            }
+        }
+        self.flush();
+    }
+
+    fn edit(&mut self, c: u8) {
+        if let Some(line) = self.editor.input(c) {
+            self.flush();
+            self.execute(line.trim());
+            std::io::stdout().flush().ok();
+            if self.pending.is_none() {
+                self.editor.redraw();
+            }
+        }
+    }
+
+    /// Go back to the prompt once a command is over, and catch up with what was typed
+    /// while it ran
+    fn finished(&mut self) {
+        self.pending = None;
+        self.editor.redraw();
+        let typeahead = core::mem::take(&mut self.typeahead);
+        self.input(&typeahead);
+    }
+
+    /// Stop waiting for the command that is running. Its thread stays blocked until the
+    /// service replies, and the service isn't sent another command until then.
+    fn give_up(&mut self, reason: &str) {
+        let pending = match self.pending.take() {
+            Some(pending) => pending,
+            None => return,
+        };
+        if let Some(timer) = pending.timer {
+            self.tt.cancel_timer(timer).ok();
+        }
+        self.abandoned.push(pending.cid);
+        println!("{}: {}", pending.name, reason);
+        self.finished();
+    }
+
+    fn timed_out(&mut self, timer: u32) {
+        if self.pending.as_ref().and_then(|p| p.timer) == Some(timer) {
+            self.give_up("no reply, stopped waiting");
+        }
+    }
+
+    /// Take the replies that invocation threads have left
+    fn replied(&mut self) {
+        while let Ok((serial, cid, result)) = self.replies.try_recv() {
+            let pending = match self.pending.take() {
+                Some(pending) if pending.serial == serial => pending,
+                other => {
+                    // The reply to a command that was given up on
+                    self.pending = other;
+                    if let Some(index) = self.abandoned.iter().position(|c| *c == cid) {
+                        self.abandoned.remove(index);
+                    }
+                    continue;
+                }
+            };
+            if let Some(timer) = pending.timer {
+                self.tt.cancel_timer(timer).ok();
+            }
+            match result {
+                // Send the output in one piece, so that it isn't interleaved with log
+                // lines from other processes
+                Ok(output) => {
+                    log_server::print(&output).ok();
+                }
+                Err(e) => {
+                    println!("{}: {:?}", pending.name, e);
+                    // The service has gone away, and so has its command
+                    if e == xous::Error::ServerNotFound {
+                        self.commands.lock().unwrap().retain(|c| c.cid != cid);
+                    }
+                }
+            }
+            self.finished();
+        }
+        self.flush();
+    }
+
+    fn execute(&mut self, line: &str) {
+        let (name, args) = match line.split_once(char::is_whitespace) {
+            Some((name, args)) => (name, args.trim()),
+            None => (line, ""),
+        };
+        match name {
+            "" => {}
+            "help" => self.help(),
+            "history" => {
+                for (index, line) in self.editor.history().enumerate() {
+                    println!("{:>3}  {}", index + 1, line);
+                }
+            }
+            "ps" => cmds::ps(&self.xns),
+            "mem" => cmds::mem(&self.xns),
+            "names" => cmds::names(&self.xns),
+            "ticktimer" => cmds::ticktimer(&self.tt, args),
+            _ => self.invoke(name, args),
+        }
+    }
+
+    fn help(&self) {
+        for (name, help) in BUILTINS {
+            println!("{:<16} {}", name, help);
+        }
+        for command in self.commands.lock().unwrap().iter() {
+            println!("{:<16} {}", command.name, command.help);
+        }
+    }
+
+    /// Start a command that another service added. The shell carries on receiving
+    /// input, and prints the output when the reply comes in.
+    fn invoke(&mut self, name: &str, args: &str) {
+        let target = self
+            .commands
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|c| c.name == name)
+            .map(|c| (c.cid, c.opcode));
+        let (cid, opcode) = match target {
+            Some(target) => target,
+            None => {
+                println!("{}: command not found, try `help`", name);
+                return;
+            }
+        };
+        if self.abandoned.contains(&cid) {
+            println!("{}: the service hasn't finished its last command", name);
+            return;
+        }
+
+        let mut page = Box::new(InvocationPage(api::Invocation {
+            args: [0u8; api::ARGS_LEN],
+            args_length: 0,
+            output: [0u8; api::OUTPUT_LEN],
+            output_length: 0,
+        }));
+        // The editor only lets ASCII through, so the arguments can be cut anywhere
+        let args_length = args.len().min(api::ARGS_LEN);
+        page.0.args[..args_length].copy_from_slice(&args.as_bytes()[..args_length]);
+        page.0.args_length = args_length as u32;
+
+        self.serial = self.serial.wrapping_add(1);
+        let serial = self.serial;
+        let (replies, done) = (self.reply_sender.clone(), self.input_cid);
+        let thread = xous::create_thread(move || {
+            let result = send_invocation(cid, opcode, &mut page);
+            replies.send((serial, cid, result)).ok();
+            let done_message = xous::Message::new_scalar(INVOCATION_DONE, serial, 0, 0, 0);
+            xous::send_message(done, done_message).ok();
+        });
+        if let Err(e) = thread {
+            println!("{}: couldn't start the command: {:?}", name, e);
+            return;
+        }
+        let timer = self
+            .tt
+            .subscribe_timer(
+                self.input_sid,
+                INVOCATION_TIMEOUT as u32,
+                INVOKE_TIMEOUT_MS,
+                false,
+            )
+            .map_err(|e| log::warn!("couldn't time the command {}: {:?}", name, e))
+            .ok();
+        self.pending = Some(Pending {
+            name: name.to_owned(),
+            cid,
+            serial,
+            timer,
+        });
+    }
+}
+
+/// Lend `page` to the service behind `cid`, returning what the command wrote
+fn send_invocation(
+    cid: xous::CID,
+    opcode: usize,
+    page: &mut InvocationPage,
+) -> Result<String, xous::Error> {
+    let buf = unsafe {
+        xous::MemoryRange::new(
+            page as *mut InvocationPage as usize,
+            core::mem::size_of::<InvocationPage>(),
+        )
+        .unwrap()
+    };
+    xous::send_message(cid, xous::Message::new_lend_mut(opcode, buf, None, None))?;
+    let length = (page.0.output_length as usize).min(api::OUTPUT_LEN);
+    let mut output = String::from_utf8_lossy(&page.0.output[..length]).into_owned();
+    if !output.is_empty() && !output.ends_with('\n') {
+        output.push('\n');
+    }
+    Ok(output)
+}
+
+fn register(
+    commands: &mut Vec<Command>,
+    pid: Option<xous::PID>,
+    request: &api::CommandRegistration,
+) -> Result<(), xous::Error> {
+    let name = request
+        .name
+        .get(..request.name_length as usize)
+        .and_then(|name| core::str::from_utf8(name).ok())
+        .filter(|name| !name.is_empty() && !name.contains(char::is_whitespace))
+        .ok_or(xous::Error::InvalidString)?;
+    let help = request
+        .help
+        .get(..request.help_length as usize)
+        .and_then(|help| core::str::from_utf8(help).ok())
+        .unwrap_or("");
+    if BUILTINS.iter().any(|(builtin, _)| *builtin == name)
+        || commands.iter().any(|c| c.name == name)
+    {
+        return Err(xous::Error::ServerExists);
+    }
+    if commands.len() >= api::MAX_COMMANDS {
+        return Err(xous::Error::OutOfMemory);
+    }
+    let cid = xous::try_connect(xous::SID::from_array(request.sid))?;
+    commands.push(Command {
+        name: name.to_owned(),
+        help: help.to_owned(),
+        pid,
+        cid,
+        opcode: request.opcode as usize,
+    });
+    log::info!("PID {:?} added the command {}", pid, name);
+    Ok(())
+}
+
+fn unregister(
+    commands: &mut Vec<Command>,
+    pid: Option<xous::PID>,
+    request: &api::CommandRegistration,
+) -> Result<(), xous::Error> {
+    let name = request
+        .name
+        .get(..request.name_length as usize)
+        .ok_or(xous::Error::InvalidString)?;
+    let index = commands
+        .iter()
+        .position(|c| c.name.as_bytes() == name && c.pid == pid)
+        .ok_or(xous::Error::ServerNotFound)?;
+    // The connection is kept, since other commands may share it
+    let command = commands.remove(index);
+    log::info!("PID {:?} removed the command {}", pid, command.name);
+    Ok(())
+}
+
+/// Serve `RegisterCommand` and `UnregisterCommand`, while the main thread waits for input
+fn registration_thread(sid: xous::SID, commands: Commands) {
+    loop {
+        let mut envelope = xous::receive_message(sid).expect("couldn't receive registration");
+        let pid = envelope.sender.pid();
+        let opcode: Option<api::Opcode> = FromPrimitive::from_usize(envelope.body.id());
+        if !matches!(envelope.body, xous::Message::MutableBorrow(_)) {
+            log::warn!("unexpected message {:?} from PID {:?}", opcode, pid);
+            continue;
+        }
+        let mem = match envelope.body.memory_message_mut() {
+            Some(mem) if mem.buf.len() >= core::mem::size_of::<api::CommandRegistration>() => mem,
+            _ => continue,
+        };
+        let request = unsafe { &mut *(mem.buf.as_mut_ptr() as *mut api::CommandRegistration) };
+        let result = match opcode {
+            Some(api::Opcode::RegisterCommand) => {
+                register(&mut commands.lock().unwrap(), pid, request)
+            }
+            Some(api::Opcode::UnregisterCommand) => {
+                unregister(&mut commands.lock().unwrap(), pid, request)
+            }
+            _ => Err(xous::Error::UnknownError),
+        };
+        request.error = match result {
+            Ok(()) => 0,
+            Err(e) => e.to_usize() as u32,
+        };
+    }
+}
+
+/// Run the shell on this thread, forever
+pub fn run(xns: xous_api_names::XousNames) -> ! {
+    let commands: Commands = Arc::new(Mutex::new(vec![]));
+    let registration_sid = xns
+        .register_name(api::SERVER_NAME, None)
+        .expect("can't register server");
+    let registration_commands = commands.clone();
+    xous::create_thread(move || registration_thread(registration_sid, registration_commands))
+        .expect("couldn't start the registration thread");
+
+    let tt = xous_api_ticktimer::Ticktimer::new().unwrap();
+    let input_sid = xous::create_server().expect("couldn't create input server");
+    // The log server only lets trusted processes listen, and may not know yet that we are
+    let mut attempts = 0;
+    while let Err(e) = log_server::listen_input(input_sid, api::Opcode::Input as u32) {
+        attempts += 1;
+        if e != xous::Error::AccessDenied || attempts >= LISTEN_ATTEMPTS {
+            panic!("couldn't listen to console input: {:?}", e);
+        }
+        tt.sleep_ms(100).ok();
+    }
+
+    let (reply_sender, replies) = mpsc::channel();
+    let mut shell = Shell {
+        editor: LineEditor::new(),
+        commands,
+        xns,
+        tt,
+        input_sid,
+        input_cid: xous::connect(input_sid).expect("couldn't connect to the input server"),
+        replies,
+        reply_sender,
+        serial: 0,
+        pending: None,
+        typeahead: vec![],
+        abandoned: vec![],
+    };
+    println!("Xous console shell. Type `help` for a list of commands.");
+    shell.editor.redraw();
+    shell.flush();
+    loop {
+        let envelope = xous::receive_message(input_sid).expect("couldn't receive input");
+        if let Some(scalar) = envelope.body.scalar_message() {
+            match envelope.body.id() {
+                id if id == api::Opcode::Input as usize => {
+                    shell.input(&log_server::input_chars(scalar))
+                }
+                INVOCATION_DONE => shell.replied(),
+                INVOCATION_TIMEOUT => shell.timed_out(scalar.arg1 as u32),
+                _ => {}
+            }
+        }
+    }
+}
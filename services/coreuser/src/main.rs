@@ -0,0 +1,72 @@
+use utralib::generated::*;
+
+/// The number of entries in the ASID table
+const ASID_COUNT: u32 = 512;
+
+fn main() {
+    log_server::init_wait().unwrap();
+    log::set_max_level(log::LevelFilter::Info);
+    log::info!("my PID is {}", xous::process::id());
+
+    #[cfg(feature = "hwsim")]
+    let csr = xous::syscall::map_memory(
+        xous::MemoryAddress::new(utra::main::HW_MAIN_BASE),
+        None,
+        4096,
+        xous::MemoryFlags::R | xous::MemoryFlags::W,
+    )
+    .expect("couldn't map Core Control CSR range");
+    #[cfg(feature = "hwsim")]
+    let mut core_csr = CSR::new(csr.as_mut_ptr() as *mut u32);
+    #[cfg(feature = "hwsim")]
+    core_csr.wfo(utra::main::REPORT_REPORT, 0xa51d_0000);
+
+    // The name server knows which image each boot process was loaded from, and
+    // which of those are trusted, before it answers its first request
+    let xns = xous_api_names::XousNames::new().unwrap();
+    let trusted = xns
+        .trusted_processes()
+        .expect("couldn't get the trusted processes");
+    for pid in trusted.iter() {
+        log::info!("trusting PID {}", pid);
+    }
+
+    let coreuser_csr = xous::syscall::map_memory(
+        xous::MemoryAddress::new(utra::coreuser::HW_COREUSER_BASE),
+        None,
+        4096,
+        xous::MemoryFlags::R | xous::MemoryFlags::W,
+    )
+    .expect("couldn't map Core User CSR range");
+    let mut coreuser = CSR::new(coreuser_csr.as_mut_ptr() as *mut u32);
+    for asid in 0..ASID_COUNT {
+        let is_trusted = xous::PID::new(asid as u8)
+            .filter(|pid| pid.get() as u32 == asid)
+            .map(|pid| trusted.contains(pid))
+            .unwrap_or(false);
+        coreuser.wo(
+            utra::coreuser::SET_ASID,
+            coreuser.ms(utra::coreuser::SET_ASID_ASID, asid)
+                | coreuser.ms(utra::coreuser::SET_ASID_TRUSTED, is_trusted as u32),
+        );
+    }
+    // set the required `mpp` state to user code (mpp == 0)
+    coreuser.wfo(utra::coreuser::SET_PRIVILEGE_MPP, 0);
+    // turn on the coreuser computation
+    coreuser.wo(
+        utra::coreuser::CONTROL,
+        coreuser.ms(utra::coreuser::CONTROL_ASID, 1)
+            | coreuser.ms(utra::coreuser::CONTROL_ENABLE, 1)
+            | coreuser.ms(utra::coreuser::CONTROL_PRIVILEGE, 1),
+    );
+    // turn off coreuser control updates, until the next reset
+    coreuser.wo(utra::coreuser::PROTECT, 1);
+    log::info!(
+        "locked the table with {} trusted PIDs",
+        trusted.iter().count()
+    );
+
+    #[cfg(feature = "hwsim")]
+    core_csr.wfo(utra::main::REPORT_REPORT, 0xa51d_600d);
+    // The table can't be changed again, so there is nothing left to do
+}
@@ -0,0 +1,116 @@
+use utralib::generated::*;
+use num_traits::*;
+
+use xous_mbox_rpc::server::{TEST_ABORT_ONCE, TEST_ECHO};
+
+use crate::Opcode;
+
+struct Test {
+    opcode: u16,
+    words: usize,
+    /// Start an abort just before the request, so that it has to wait for the abort
+    abort_first: bool,
+}
+
+// tests to run:
+// all tests take the form of a call, whose response is the request ^ 0xaaaa_0000
+//
+// 1. single word test
+// 2. 16-word test
+// 3. single word test (again)
+// 4. 1024-word test, which is split into two packets
+// 5. single word test (again)
+// 6. abort, then an 8-word test that is held until the abort is done
+// 7. 2-word test
+// 8. 4-word test. receiver aborts instead of answering, and answers the retry
+// 9. 3-word test
+// 10. 4000-word test, which is split into four packets
+const TESTS: [Test; 10] = [
+    Test { opcode: TEST_ECHO, words: 1, abort_first: false },
+    Test { opcode: TEST_ECHO, words: 16, abort_first: false },
+    Test { opcode: TEST_ECHO, words: 1, abort_first: false },
+    Test { opcode: TEST_ECHO, words: 1024, abort_first: false },
+    Test { opcode: TEST_ECHO, words: 1, abort_first: false },
+    Test { opcode: TEST_ECHO, words: 8, abort_first: true },
+    Test { opcode: TEST_ECHO, words: 2, abort_first: false },
+    Test { opcode: TEST_ABORT_ONCE, words: 4, abort_first: false },
+    Test { opcode: TEST_ECHO, words: 3, abort_first: false },
+    Test { opcode: TEST_ECHO, words: 4000, abort_first: false },
+];
+
+/// Run the loopback test against `mbox2` through the mailbox server, as a client would.
+/// `mbox_cid` is only used to start aborts.
+pub fn run(mbox_cid: xous::CID) {
+    let csr = xous::syscall::map_memory(
+        xous::MemoryAddress::new(utra::main::HW_MAIN_BASE),
+        None,
+        4096,
+        xous::MemoryFlags::R | xous::MemoryFlags::W,
+    )
+    .expect("couldn't map Core Control CSR range");
+    let mut core_csr = CSR::new(csr.as_mut_ptr() as *mut u32);
+
+    core_csr.wfo(utra::main::REPORT_REPORT, 0x600d_0000);
+    let xns = xous_api_names::XousNames::new().unwrap();
+    let mbox = xous_api_mbox::Mailbox::new(&xns).expect("couldn't connect to the mailbox server");
+    core_csr.wfo(utra::main::REPORT_REPORT, 0x600d_c0de);
+
+    let mut generator: u32 = 0x1317_0000;
+    for (index, test) in TESTS.iter().enumerate() {
+        let number = index as u32 + 1;
+        core_csr.wfo(utra::main::REPORT_REPORT, number); // indicate the test start
+        if test.abort_first {
+            xous::send_message(mbox_cid, xous::Message::new_scalar(
+                Opcode::Abort.to_usize().unwrap(), 0, 0, 0, 0)
+            ).ok();
+        }
+        let request: Vec<u32> = (0..test.words).map(|_| {
+            generator += 1;
+            generator
+        }).collect();
+        match mbox.call(test.opcode, &request) {
+            Ok(response) => {
+                if !check_results(&mut core_csr, &request, &response) {
+                    log::error!("Aborting test, errors encountered");
+                    break;
+                }
+            }
+            Err(e) => {
+                log::error!("Test {} failed: {:?}", number, e);
+                core_csr.wfo(utra::main::REPORT_REPORT, 0xdead_0000 | number);
+                break;
+            }
+        }
+        if number as usize == TESTS.len() {
+            log::info!("Last test done");
+            core_csr.wfo(utra::main::REPORT_REPORT, 0x600d_0000 | (number + 1));
+        }
+    }
+
+    core_csr.wfo(utra::main::SUCCESS_SUCCESS, 1);
+    core_csr.wfo(utra::main::DONE_DONE, 1); // this should stop the simulation
+}
+
+fn check_results(core_csr: &mut CSR::<u32>, request: &[u32], response: &[u32]) -> bool {
+    if request.len() != response.len() {
+        log::error!("Response length is incorrect: expected {:x}, got {:x}", request.len(), response.len());
+        core_csr.wfo(utra::main::REPORT_REPORT, 0xdead_c0de);
+        return false;
+    }
+    let mut errcnt = 0;
+    for (index, (&tx, &rx)) in request.iter().zip(response).enumerate() {
+        if rx != tx ^ 0xAAAA_0000 {
+            if errcnt < 16 { // limit log spew
+                log::error!("Test failure at {}: {:x}->{:x}", index, tx ^ 0xAAAA_0000, rx);
+            }
+            errcnt += 1;
+        }
+    }
+    if errcnt == 0 {
+        core_csr.wfo(utra::main::REPORT_REPORT, 0x600d_0000 | request.len() as u32);
+        true
+    } else {
+        core_csr.wfo(utra::main::REPORT_REPORT, 0xdead_0000 | request.len() as u32);
+        false
+    }
+}
@@ -1,71 +1,12 @@
 use utralib::generated::*;
 use num_traits::*;
+use xous_mbox_rpc::mailbox::{Mailbox, Pending, Side};
+use xous_mbox_rpc::{server, Config, Link};
 
-pub struct MailboxClient {
-    csr: utralib::CSR<u32>,
+/// What the interrupt handler needs
+struct Irq {
+    csr: CSR<u32>,
     cid: xous::CID,
-    abort_pending: bool,
-}
-
-impl MailboxClient {
-    pub fn send(&mut self, data: &[u32]) {
-        // defer aborts until this interaction is done
-        self.csr.wo(utra::mb_client::EV_ENABLE,
-            self.csr.r(utra::mb_client::EV_ENABLE) &
-            !self.csr.ms(utra::mb_client::EV_ENABLE_ABORT_INIT, 1)
-        );
-        // interact with the FIFO
-        for &d in data {
-            while self.csr.rf(utra::mb_client::STATUS_TX_FREE) == 0 {
-                // busy-wait
-            }
-            self.csr.wfo(utra::mb_client::WDATA_WDATA, d);
-        }
-        self.csr.wfo(utra::mb_client::DONE_DONE, 1);
-        // re-enable aborts
-        self.csr.wo(utra::mb_client::EV_ENABLE,
-            self.csr.r(utra::mb_client::EV_ENABLE) |
-            self.csr.ms(utra::mb_client::EV_ENABLE_ABORT_INIT, 1)
-        );
-    }
-    pub fn get(&mut self, ret: &mut [u32]) -> usize {
-        // defer aborts until this interaction is done
-        self.csr.wo(utra::mb_client::EV_ENABLE,
-            self.csr.r(utra::mb_client::EV_ENABLE) &
-            !self.csr.ms(utra::mb_client::EV_ENABLE_ABORT_INIT, 1)
-        );
-        // interact with the FIFO
-        // note: this only works because rx_words is the LSB of the register. We don't have to shift the MS'd value.
-        while self.csr.rf(utra::mb_client::STATUS_RX_AVAIL) == 0 {
-            // wait for data to be available
-        }
-        let test_definition = self.csr.r(utra::mb_client::RDATA);
-        let rx_words = (test_definition >> 16) as usize;
-        if rx_words > ret.len() {
-            log::warn!("rx_words is too large: {} vs {}", rx_words, ret.len());
-            // this will lead to a test failure, but this is handled by the tester driver...mostly...
-        }
-        ret[0] = test_definition;
-        if rx_words > 1 {
-            let limit = rx_words.min(ret.len());
-            for d in ret[1..limit].iter_mut() {
-                while self.csr.rf(utra::mb_client::STATUS_RX_AVAIL) == 0 {
-                    // wait for data to be available
-                }
-                *d = self.csr.r(utra::mb_client::RDATA);
-            }
-        }
-        // re-enable aborts
-        self.csr.wo(utra::mb_client::EV_ENABLE,
-            self.csr.r(utra::mb_client::EV_ENABLE) |
-            self.csr.ms(utra::mb_client::EV_ENABLE_ABORT_INIT, 1)
-        );
-        rx_words
-    }
-    pub fn abort(&mut self) {
-        log::warn!("abort initiated");
-        self.csr.wfo(utra::mb_client::CONTROL_ABORT, 1);
-    }
 }
 
 #[derive(num_derive::FromPrimitive, num_derive::ToPrimitive, Debug)]
@@ -77,34 +18,25 @@ pub enum Opcode {
     InvalidCall,
 }
 
+/// How long to wait for room in the FIFO
+const SEND_TIMEOUT_MS: u64 = 50;
+
 fn handle_irq(_irq_no: usize, arg: *mut usize) {
-    let mb_client = unsafe { &mut *(arg as *mut MailboxClient) };
+    let irq = unsafe { &mut *(arg as *mut Irq) };
 
-    let pending = mb_client.csr.r(utra::mb_client::EV_PENDING);
-    if pending & mb_client.csr.ms(utra::mb_client::EV_PENDING_ERROR, 1) != 0 {
-        xous::try_send_message(mb_client.cid, xous::Message::new_scalar(
-            Opcode::ProtocolError.to_usize().unwrap(), pending as usize, 0, 0, 0)
-        ).ok();
-    }
-    if pending & mb_client.csr.ms(utra::mb_client::EV_PENDING_ABORT_INIT, 1) != 0 {
-        mb_client.abort_pending = true;
-        xous::try_send_message(mb_client.cid, xous::Message::new_scalar(
-            Opcode::AbortInit.to_usize().unwrap(), pending as usize, 0, 0, 0)
-        ).ok();
-    }
-    if pending & mb_client.csr.ms(utra::mb_client::EV_PENDING_ABORT_DONE, 1) != 0 {
-        xous::try_send_message(mb_client.cid, xous::Message::new_scalar(
-            Opcode::AbortDone.to_usize().unwrap(), pending as usize, 0, 0, 0)
-        ).ok();
-    }
-    if pending & mb_client.csr.ms(utra::mb_client::EV_PENDING_AVAILABLE, 1) != 0 {
-        xous::try_send_message(mb_client.cid, xous::Message::new_scalar(
-            Opcode::Incoming.to_usize().unwrap(), pending as usize, 0, 0, 0)
-        ).ok();
+    let pending = Pending::take(&mut irq.csr, Side::Client);
+    for (raised, opcode) in [
+        (pending.error(), Opcode::ProtocolError),
+        (pending.abort_init(), Opcode::AbortInit),
+        (pending.abort_done(), Opcode::AbortDone),
+        (pending.available(), Opcode::Incoming),
+    ] {
+        if raised {
+            xous::try_send_message(irq.cid, xous::Message::new_scalar(
+                opcode.to_usize().unwrap(), pending.bits() as usize, 0, 0, 0)
+            ).ok();
+        }
     }
-
-    mb_client.csr
-        .wo(utra::mb_client::EV_PENDING, mb_client.csr.r(utra::mb_client::EV_PENDING));
 }
 
 fn main() {
@@ -121,25 +53,26 @@ fn main() {
         4096,
         xous::MemoryFlags::R | xous::MemoryFlags::W,
     )
-    .expect("couldn't map Core User CSR range");
-    let mb_client = CSR::new(mb_client_csr.as_mut_ptr() as *mut u32);
+    .expect("couldn't map mailbox client CSR range");
+    let csr = CSR::new(mb_client_csr.as_mut_ptr() as *mut u32);
 
-    let mut mb_client = MailboxClient {
-        csr: mb_client,
+    let mut irq = Irq {
+        csr,
         cid: client_cid,
-        abort_pending: false,
     };
     xous::claim_interrupt(
         utra::mb_client::MB_CLIENT_IRQ,
         handle_irq,
-        (&mut mb_client) as *mut MailboxClient as *mut usize,
+        (&mut irq) as *mut Irq as *mut usize,
     )
     .expect("couldn't claim irq");
-    mb_client.csr.wo(utra::mb_client::EV_ENABLE, !0); // enable everything
+    let mut mailbox = Mailbox::new(csr, Side::Client);
+    mailbox.enable_interrupts();
 
+    let tt = xous_api_ticktimer::Ticktimer::new().unwrap();
+    let mut link = Link::new(mailbox, Config::default());
     let mut msg_opt = None;
     let mut return_type = 0;
-    let mut test_data = [0u32; 1024];
 
     loop {
         xous::reply_and_receive_next_legacy(client_sid, &mut msg_opt, &mut return_type)
@@ -151,49 +84,35 @@ fn main() {
         match op
         {
             Opcode::Incoming => {
-                if mb_client.abort_pending {
-                    log::info!("Got abort in between rx IRQ and rx handler");
-                    // ignore the packet, let the abort handler run
-                    continue;
-                }
                 if let Some(_scalar) = msg.body.scalar_message() {
-                    let len = mb_client.get(&mut test_data);
-                    for d in test_data[..len].iter_mut() {
-                        *d = *d ^ 0xAAAA_0000;
-                    }
-                    let test_seq = test_data[0] & 0xFFFF;
-                    log::info!("rx seq {}, {} words", test_seq, len);
-                    if test_seq == 8 { // abort on case #8
-                        mb_client.abort();
-                    } else {
-                        mb_client.send(&test_data[..len]);
-                    }
+                    let events = link.incoming(tt.elapsed_ms());
+                    server::answer(&mut link, events, tt.elapsed_ms());
                 } else {
-                    log::error!("Wrong message type for RunTest");
+                    log::error!("Wrong message type for Incoming");
                 }
             }
             Opcode::AbortInit => {
-                log::info!("test peer initiated abort!");
-                mb_client.abort_pending = false;
-                mb_client.csr.wfo(utra::mb_client::CONTROL_ABORT, 1);
+                link.abort_init(tt.elapsed_ms());
             }
             Opcode::AbortDone => {
-                mb_client.abort_pending = false;
-                log::info!("client abort protocol done");
+                link.abort_done(tt.elapsed_ms());
             }
             Opcode::ProtocolError => {
                 if let Some(scalar) = msg.body.scalar_message() {
-                    log::error!("Protocol error received: {:x}, aborting test", scalar.arg1);
-                    break;
+                    log::error!("Protocol error received: {:x}", scalar.arg1);
+                    link.error(tt.elapsed_ms());
                 } else {
-                    log::error!("Wrong message type for ProtocolError; aborting test");
-                    break;
+                    log::error!("Wrong message type for ProtocolError");
                 }
             }
             Opcode::InvalidCall => {
                 log::error!("Invalid opcode: {:?}", msg);
             }
         }
-
+        let deadline = tt.elapsed_ms() + SEND_TIMEOUT_MS;
+        link.pump(tt.elapsed_ms(), || {
+            xous::yield_slice();
+            tt.elapsed_ms() < deadline
+        });
     }
-}
+}
\ No newline at end of file
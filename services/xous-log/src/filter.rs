@@ -0,0 +1,175 @@
+//! Level filters that are set at runtime with `SetLevelFilter`.
+//!
+//! Clients fetch the filters that apply to them and drop records before sending them.
+//! The same filters are applied here as well, for clients that haven't caught up with
+//! a change yet.
+
+use log::LevelFilter;
+use xous_api_log::api;
+
+struct Rule {
+    module: String,
+    /// 0 for every process
+    pid: u32,
+    level: LevelFilter,
+}
+
+pub struct LevelFilters {
+    rules: Vec<Rule>,
+    /// Increases every time the rules change
+    generation: u32,
+}
+
+impl LevelFilters {
+    pub fn new() -> Self {
+        LevelFilters {
+            rules: Vec::new(),
+            generation: 1,
+        }
+    }
+
+    pub fn generation(&self) -> u32 {
+        self.generation
+    }
+
+    /// Set the filter for `module` in `pid`, replacing any existing one. Returns `false`
+    /// if there is no room for another rule.
+    pub fn set(&mut self, module: &str, pid: u32, level: LevelFilter) -> bool {
+        if let Some(rule) = self
+            .rules
+            .iter_mut()
+            .find(|r| r.module == module && r.pid == pid)
+        {
+            rule.level = level;
+        } else if self.rules.len() < api::MAX_LEVEL_FILTERS {
+            self.rules.push(Rule {
+                module: module.to_owned(),
+                pid,
+                level,
+            });
+        } else {
+            return false;
+        }
+        self.generation = self.generation.wrapping_add(1);
+        true
+    }
+
+    /// Remove the filter for `module` in `pid`, returning `false` if there wasn't one
+    pub fn remove(&mut self, module: &str, pid: u32) -> bool {
+        let len = self.rules.len();
+        self.rules.retain(|r| r.module != module || r.pid != pid);
+        if self.rules.len() == len {
+            return false;
+        }
+        self.generation = self.generation.wrapping_add(1);
+        true
+    }
+
+    /// The rules that apply to `pid`. Where a module has both a rule for `pid` and a
+    /// rule for every process, only the one for `pid` is returned.
+    fn rules_for(&self, pid: u32) -> impl Iterator<Item = &Rule> {
+        self.rules.iter().filter(move |r| {
+            (r.pid == pid)
+                || (r.pid == 0
+                    && !self
+                        .rules
+                        .iter()
+                        .any(|other| other.pid == pid && other.module == r.module))
+        })
+    }
+
+    /// `false` if a record from `module` in `pid` at `level` is filtered out
+    pub fn enabled(&self, pid: u32, module: &str, level: log::Level) -> bool {
+        self.rules_for(pid)
+            .filter(|r| module.starts_with(&r.module))
+            .max_by_key(|r| r.module.len())
+            .map(|r| level <= r.level)
+            .unwrap_or(true)
+    }
+
+    /// Fill in `filters` with the rules that apply to `pid`
+    pub fn fill(&self, pid: u32, filters: &mut api::LevelFilters) {
+        filters.generation = self.generation;
+        filters.count = 0;
+        for (rule, slot) in self.rules_for(pid).zip(filters.filters.iter_mut()) {
+            let len = rule.module.len().min(slot.module.len());
+            slot.module[..len].copy_from_slice(&rule.module.as_bytes()[..len]);
+            slot.module_length = len as u32;
+            slot.level = rule.level as u32;
+            filters.count += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_longest_prefix_wins() {
+        let mut filters = LevelFilters::new();
+        assert!(filters.enabled(5, "app::net", log::Level::Trace));
+        assert!(filters.set("app", 0, LevelFilter::Warn));
+        assert!(filters.set("app::net", 0, LevelFilter::Debug));
+        assert!(!filters.enabled(5, "app::ui", log::Level::Info));
+        assert!(filters.enabled(5, "app::ui", log::Level::Warn));
+        assert!(filters.enabled(5, "app::net::tcp", log::Level::Debug));
+        assert!(!filters.enabled(5, "app::net::tcp", log::Level::Trace));
+        assert!(filters.enabled(5, "other", log::Level::Trace));
+    }
+
+    #[test]
+    fn rules_for_a_process_win_over_rules_for_every_process() {
+        let mut filters = LevelFilters::new();
+        assert!(filters.set("app", 0, LevelFilter::Error));
+        assert!(filters.set("app", 5, LevelFilter::Trace));
+        assert!(filters.enabled(5, "app", log::Level::Trace));
+        assert!(!filters.enabled(6, "app", log::Level::Warn));
+
+        let mut reply = api::LevelFilters {
+            generation: 0,
+            count: 0,
+            filters: [api::ModuleLevel {
+                module: [0; 128],
+                module_length: 0,
+                level: 0,
+            }; api::MAX_LEVEL_FILTERS],
+        };
+        filters.fill(5, &mut reply);
+        assert_eq!(reply.count, 1);
+        assert_eq!(reply.generation, filters.generation());
+        assert_eq!(&reply.filters[0].module[..3], b"app");
+        assert_eq!(reply.filters[0].module_length, 3);
+        assert_eq!(reply.filters[0].level, LevelFilter::Trace as u32);
+        filters.fill(6, &mut reply);
+        assert_eq!(reply.count, 1);
+        assert_eq!(reply.filters[0].level, LevelFilter::Error as u32);
+    }
+
+    #[test]
+    fn changes_bump_the_generation() {
+        let mut filters = LevelFilters::new();
+        let first = filters.generation();
+        assert!(filters.set("app", 0, LevelFilter::Info));
+        assert!(filters.set("app", 0, LevelFilter::Debug));
+        assert_eq!(filters.generation(), first + 2);
+        assert!(!filters.enabled(1, "app", log::Level::Trace));
+        assert!(!filters.remove("app", 5));
+        assert_eq!(filters.generation(), first + 2);
+        assert!(filters.remove("app", 0));
+        assert_eq!(filters.generation(), first + 3);
+        assert!(filters.enabled(1, "app", log::Level::Trace));
+    }
+
+    #[test]
+    fn the_number_of_rules_is_bounded() {
+        let mut filters = LevelFilters::new();
+        for i in 0..api::MAX_LEVEL_FILTERS {
+            assert!(filters.set(&format!("m{}", i), 0, LevelFilter::Info));
+        }
+        assert!(!filters.set("one_more", 0, LevelFilter::Info));
+        // Existing rules can still be changed
+        assert!(filters.set("m0", 0, LevelFilter::Off));
+        assert!(!filters.enabled(1, "m0", log::Level::Error));
+    }
+}
@@ -0,0 +1,92 @@
+//! Characters that arrive on the console, which are passed on to a listener such as the
+//! console shell.
+//!
+//! The listener is kept in atomics rather than in the reader thread, because on hardware
+//! the characters are sent from the UART interrupt handler.
+
+use core::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use xous_api_log::api;
+
+/// The connection to the listener, or 0 if there is none
+static LISTENER_CID: AtomicUsize = AtomicUsize::new(0);
+static LISTENER_OPCODE: AtomicUsize = AtomicUsize::new(0);
+/// The process that registered the listener, or 0 if there is none
+static LISTENER_PID: AtomicU32 = AtomicU32::new(0);
+/// Whether the thread that reads stdin in hosted mode has been started
+#[cfg(not(target_os = "xous"))]
+static READING_STDIN: core::sync::atomic::AtomicBool = core::sync::atomic::AtomicBool::new(false);
+
+/// Send input to the server in `request`, on behalf of `pid`. Whatever is typed on the
+/// console goes to the listener, so only a trusted process may listen, and only one may
+/// do so until it terminates.
+pub fn listen(pid: u32, trusted: bool, request: &api::InputListener) -> Result<(), xous::Error> {
+    if !trusted {
+        return Err(xous::Error::AccessDenied);
+    }
+    if LISTENER_PID.load(Ordering::Acquire) != 0 {
+        return Err(xous::Error::ServerExists);
+    }
+    let cid = xous::try_connect(xous::SID::from_array(request.sid))?;
+    LISTENER_OPCODE.store(request.opcode as usize, Ordering::Release);
+    LISTENER_CID.store(cid as usize, Ordering::Release);
+    LISTENER_PID.store(pid, Ordering::Release);
+
+    #[cfg(not(target_os = "xous"))]
+    if !READING_STDIN.swap(true, Ordering::AcqRel) {
+        read_stdin();
+    }
+    Ok(())
+}
+
+/// Stop sending input to `pid` once it has terminated, so that another process can
+/// listen in its place
+pub fn forget(pid: u32) {
+    if pid == 0 || LISTENER_PID.load(Ordering::Acquire) != pid {
+        return;
+    }
+    let cid = LISTENER_CID.swap(0, Ordering::AcqRel);
+    LISTENER_PID.store(0, Ordering::Release);
+    if cid != 0 {
+        unsafe { xous::disconnect(cid as xous::CID).ok() };
+    }
+}
+
+/// Send `chars` to the listener, returning `false` if there is none. Characters are
+/// dropped if the listener's queue is full, since the interrupt handler can't wait.
+pub fn send(chars: &[u8]) -> bool {
+    let cid = LISTENER_CID.load(Ordering::Acquire);
+    if cid == 0 {
+        return false;
+    }
+    let opcode = LISTENER_OPCODE.load(Ordering::Acquire);
+    for chunk in chars.chunks(api::INPUT_CHARS) {
+        let mut packed = [0u8; api::INPUT_CHARS];
+        packed[..chunk.len()].copy_from_slice(chunk);
+        let word = |i: usize| {
+            u32::from_le_bytes([packed[i], packed[i + 1], packed[i + 2], packed[i + 3]]) as usize
+        };
+        xous::try_send_message(
+            cid as xous::CID,
+            xous::Message::new_scalar(opcode, chunk.len(), word(0), word(4), 0),
+        )
+        .ok();
+    }
+    true
+}
+
+/// In hosted mode, the console is the terminal that the log server was started from
+#[cfg(not(target_os = "xous"))]
+fn read_stdin() {
+    xous::create_thread(|| {
+        use std::io::Read;
+        let mut stdin = std::io::stdin();
+        let mut buf = [0u8; 64];
+        while let Ok(count) = stdin.read(&mut buf) {
+            if count == 0 {
+                break;
+            }
+            send(&buf[..count]);
+        }
+    })
+    .ok();
+}
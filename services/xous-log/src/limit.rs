@@ -0,0 +1,288 @@
+//! Per-process rate limits on log records, so that one process logging in a tight loop
+//! can't drown out everyone else.
+//!
+//! Each process has a token bucket that holds up to `burst` records and refills at
+//! `records_per_second`. Records that arrive to an empty bucket are dropped and counted,
+//! and the count is reported at most once every `SUMMARY_INTERVAL_MS`.
+//!
+//! The reader can't ask the ticktimer for the time when a record arrives, so buckets are
+//! refilled whenever the time does arrive instead. While a bucket is refilling, the
+//! reader asks for the time every `REFILL_INTERVAL_MS`, so that a process that pauses
+//! isn't held to its empty bucket. A bucket that is full doesn't need the time, so the
+//! reader stops asking once all of them are.
+
+use std::collections::HashMap;
+
+/// How often the number of dropped records is reported for each process
+pub const SUMMARY_INTERVAL_MS: u64 = 5000;
+
+/// How often buckets are refilled while some of them aren't full
+pub const REFILL_INTERVAL_MS: u64 = 10;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Limit {
+    /// 0 for no limit
+    pub records_per_second: u32,
+    pub burst: u32,
+}
+
+const DEFAULT_LIMIT: Limit = Limit {
+    records_per_second: 100,
+    burst: 500,
+};
+
+struct Bucket {
+    /// Thousandths of a record, so that slow rates still refill every millisecond
+    tokens: u64,
+    dropped: u32,
+    last_summary_ms: u64,
+}
+
+pub struct RateLimits {
+    default: Limit,
+    overrides: HashMap<u32, Limit>,
+    buckets: HashMap<u32, Bucket>,
+    /// The latest time that the clock thread sent
+    now_ms: u64,
+    /// Whether some bucket wasn't full at `now_ms`. Buckets that were all full are not
+    /// credited for the time until the next update, which may have been spent idle.
+    refilling: bool,
+}
+
+impl RateLimits {
+    pub fn new() -> Self {
+        RateLimits {
+            default: DEFAULT_LIMIT,
+            overrides: HashMap::new(),
+            buckets: HashMap::new(),
+            now_ms: 0,
+            refilling: false,
+        }
+    }
+
+    /// Refill the buckets up to `now_ms`, a time that the clock thread just sent
+    pub fn set_now(&mut self, now_ms: u64) {
+        let elapsed_ms = now_ms.saturating_sub(self.now_ms);
+        self.now_ms = self.now_ms.max(now_ms);
+        let mut refilling = false;
+        for (pid, bucket) in self.buckets.iter_mut() {
+            let limit = *self.overrides.get(pid).unwrap_or(&self.default);
+            if limit.records_per_second == 0 {
+                continue;
+            }
+            let capacity = limit.burst.max(1) as u64 * 1000;
+            if self.refilling {
+                bucket.tokens += elapsed_ms * limit.records_per_second as u64;
+            }
+            bucket.tokens = bucket.tokens.min(capacity);
+            refilling |= bucket.tokens < capacity;
+        }
+        self.refilling = refilling;
+    }
+
+    /// `true` if the reader should keep sending the time, because some bucket is
+    /// refilling or some dropped records haven't been reported yet
+    pub fn needs_time(&self) -> bool {
+        self.refilling || self.has_drops()
+    }
+
+    /// Set the limit of `pid`, or the default limit if `pid` is 0
+    pub fn set(&mut self, pid: u32, limit: Limit) {
+        if pid == 0 {
+            self.default = limit;
+        } else {
+            self.overrides.insert(pid, limit);
+        }
+    }
+
+    /// Return `pid` to the default limit, returning `false` if it didn't have its own
+    pub fn remove(&mut self, pid: u32) -> bool {
+        self.overrides.remove(&pid).is_some()
+    }
+
+    /// Forget `pid`'s limit and bucket once it has terminated, so that a process that
+    /// reuses the PID starts afresh. Returns the number of records that it dropped and
+    /// that haven't been reported yet.
+    pub fn forget(&mut self, pid: u32) -> u32 {
+        self.overrides.remove(&pid);
+        self.buckets.remove(&pid).map(|b| b.dropped).unwrap_or(0)
+    }
+
+    /// Take a record from `pid`'s bucket, returning `false` if the record should be
+    /// dropped
+    pub fn allow(&mut self, pid: u32) -> bool {
+        let limit = *self.overrides.get(&pid).unwrap_or(&self.default);
+        if limit.records_per_second == 0 {
+            return true;
+        }
+        let capacity = limit.burst.max(1) as u64 * 1000;
+        let bucket = self.buckets.entry(pid).or_insert(Bucket {
+            tokens: capacity,
+            dropped: 0,
+            last_summary_ms: self.now_ms,
+        });
+        if bucket.tokens >= 1000 {
+            bucket.tokens -= 1000;
+            true
+        } else {
+            bucket.dropped = bucket.dropped.saturating_add(1);
+            false
+        }
+    }
+
+    /// `true` if some records were dropped and haven't been reported yet
+    pub fn has_drops(&self) -> bool {
+        self.buckets.values().any(|b| b.dropped > 0)
+    }
+
+    /// The processes whose dropped records are due to be reported, with the number of
+    /// records that each dropped
+    pub fn take_summaries(&mut self) -> Vec<(u32, u32)> {
+        let now_ms = self.now_ms;
+        let mut summaries = vec![];
+        for (pid, bucket) in self.buckets.iter_mut() {
+            if bucket.dropped > 0 && now_ms >= bucket.last_summary_ms + SUMMARY_INTERVAL_MS {
+                summaries.push((*pid, bucket.dropped));
+                bucket.dropped = 0;
+                bucket.last_summary_ms = now_ms;
+            }
+        }
+        summaries.sort_unstable();
+        summaries
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limit(records_per_second: u32, burst: u32) -> Limit {
+        Limit {
+            records_per_second,
+            burst,
+        }
+    }
+
+    #[test]
+    fn bursts_are_allowed_and_then_dropped() {
+        let mut limits = RateLimits::new();
+        limits.set(5, limit(10, 3));
+        for _ in 0..3 {
+            assert!(limits.allow(5));
+        }
+        assert!(!limits.allow(5));
+        assert!(limits.has_drops());
+        // Other processes have their own buckets
+        assert!(limits.allow(6));
+    }
+
+    #[test]
+    fn buckets_refill_over_time() {
+        let mut limits = RateLimits::new();
+        limits.set(5, limit(10, 2));
+        assert!(limits.allow(5));
+        assert!(limits.allow(5));
+        assert!(!limits.allow(5));
+        // The reader asks for the time right after the records. Every bucket was full
+        // before them, so the time until then isn't credited.
+        limits.set_now(1);
+        assert!(limits.needs_time());
+        limits.set_now(100);
+        assert!(!limits.allow(5));
+        limits.set_now(101);
+        assert!(limits.allow(5));
+        // The bucket doesn't fill past the burst
+        limits.set_now(10_000);
+        assert!(limits.allow(5));
+        assert!(limits.allow(5));
+        assert!(!limits.allow(5));
+    }
+
+    #[test]
+    fn the_default_applies_to_processes_without_a_limit() {
+        let mut limits = RateLimits::new();
+        limits.set(0, limit(1, 1));
+        limits.set(5, limit(0, 0));
+        assert!(limits.allow(6));
+        assert!(!limits.allow(6));
+        // A rate of 0 is no limit at all
+        for _ in 0..1000 {
+            assert!(limits.allow(5));
+        }
+        assert!(limits.remove(5));
+        assert!(!limits.remove(5));
+        assert!(limits.allow(5));
+        assert!(!limits.allow(5));
+    }
+
+    #[test]
+    fn full_buckets_are_not_credited_for_idle_time() {
+        let mut limits = RateLimits::new();
+        limits.set(5, limit(10, 2));
+        limits.set_now(1);
+        assert!(!limits.needs_time());
+        // An hour passes without any records, and without the time being sent
+        for _ in 0..2 {
+            assert!(limits.allow(5));
+        }
+        assert!(!limits.allow(5));
+        limits.set_now(3_600_000);
+        assert!(!limits.allow(5));
+        limits.set_now(3_600_100);
+        assert!(limits.allow(5));
+    }
+
+    #[test]
+    fn a_paused_process_gets_its_bucket_back() {
+        let mut limits = RateLimits::new();
+        limits.set(5, limit(10, 2));
+        for _ in 0..2 {
+            assert!(limits.allow(5));
+        }
+        limits.set_now(1);
+        // The reader keeps sending the time until the bucket is full again
+        let mut now = 1;
+        while limits.needs_time() {
+            now += REFILL_INTERVAL_MS;
+            limits.set_now(now);
+        }
+        assert!(now <= 200 + REFILL_INTERVAL_MS);
+        assert!(limits.allow(5));
+        assert!(limits.allow(5));
+        assert!(!limits.allow(5));
+    }
+
+    #[test]
+    fn terminated_processes_are_forgotten() {
+        let mut limits = RateLimits::new();
+        limits.set(5, limit(1, 1));
+        assert!(limits.allow(5));
+        assert!(!limits.allow(5));
+        assert_eq!(limits.forget(5), 1);
+        assert!(!limits.has_drops());
+        // The next process with this PID gets the default limit and a full bucket
+        assert!(!limits.remove(5));
+        for _ in 0..DEFAULT_LIMIT.burst {
+            assert!(limits.allow(5));
+        }
+        assert_eq!(limits.forget(6), 0);
+    }
+
+    #[test]
+    fn drops_are_summarized_once_per_interval() {
+        let mut limits = RateLimits::new();
+        limits.set(5, limit(1, 1));
+        assert!(limits.allow(5));
+        assert!(!limits.allow(5));
+        assert!(!limits.allow(5));
+        limits.set_now(1);
+        assert!(limits.take_summaries().is_empty());
+        limits.set_now(SUMMARY_INTERVAL_MS);
+        assert_eq!(limits.take_summaries(), [(5, 2)]);
+        assert!(!limits.has_drops());
+        assert!(limits.take_summaries().is_empty());
+        // The time doesn't go backwards
+        limits.set_now(1);
+        assert!(limits.allow(5));
+    }
+}
@@ -6,9 +6,28 @@ use xous_api_log::api;
 #[cfg(any(feature="precursor", feature="cramium-fpga", feature="cramium-soc", feature="renode"))]
 #[macro_use]
 mod debug;
+mod filter;
+mod input;
+mod limit;
+mod ring;
+mod sink;
 
 use core::fmt::Write;
 use num_traits::FromPrimitive;
+use std::collections::HashMap;
+
+/// Streams that grow past this many bytes are flushed before they complete,
+/// so a misbehaving process cannot exhaust the log server's memory.
+const MAX_STREAM_LENGTH: usize = 64 * 1024;
+
+/// Sent to ourselves by `clock_thread()`, with the ticktimer's elapsed time in
+/// milliseconds in `arg1` (low word) and `arg2` (high word), and the delay that was
+/// asked for in `arg3`. This is outside of the range of `api::Opcode`.
+const CLOCK_UPDATE: usize = 0x1_0000;
+
+/// Sent to ourselves by `fetch_trusted_processes()`, with the `ProcessSet` of trusted
+/// processes in `arg1` and `arg2`
+const TRUSTED_PROCESSES: usize = 0x1_0001;
 
 #[cfg(any(not(target_os = "xous"),
     not(any(feature="precursor", feature="cramium-fpga", feature="cramium-soc", feature="renode", not(target_os = "xous"))) // makes this the default implementation
@@ -79,6 +98,7 @@ mod implementation {
         }
     }
 
+    #[derive(Clone)]
     pub struct OutputWriter {
         tx: Sender<ControlMessage>,
     }
@@ -186,10 +206,11 @@ mod implementation {
             // println!("rxe {}", uart_csr.rf(utra::uart::RXEMPTY_RXEMPTY));
             while uart_csr.rf(utra::uart::RXEMPTY_RXEMPTY) == 0 {
                 // I really rather think this is more readable, than the "Rusty" version below.
-                inject_csr.wfo(
-                    utra::keyinject::UART_CHAR_CHAR,
-                    uart_csr.rf(utra::uart::RXTX_RXTX),
-                );
+                let c = uart_csr.rf(utra::uart::RXTX_RXTX);
+                // A listener such as the console shell takes the characters instead
+                if !crate::input::send(&[c as u8]) {
+                    inject_csr.wfo(utra::keyinject::UART_CHAR_CHAR, c);
+                }
                 uart_csr.wfo(utra::uart::EV_PENDING_RX, 1);
 
                 // I guess this is how you would do it if you were "really doing Rust"
@@ -215,6 +236,7 @@ mod implementation {
         }
     }
 
+    #[derive(Clone)]
     pub struct OutputWriter {}
 
     impl OutputWriter {
@@ -278,9 +300,10 @@ fn handle_scalar(
     sender: xous::MessageSender,
     msg: &xous::ScalarMessage,
     sender_pid: xous::PID,
+    sinks: &mut sink::Sinks,
 ) {
     match msg.id {
-        1000 => writeln!(output, "PANIC in PID {}:", sender_pid).unwrap(),
+        1000 => sinks.line(format!("PANIC in PID {}:", sender_pid).as_bytes()),
         1100 => (),
         1101..=1132 => {
             let mut output_bfr = [0u8; core::mem::size_of::<usize>() * 4];
@@ -301,20 +324,28 @@ fn handle_scalar(
             for (dest, src) in output_iter.zip(input_iter) {
                 *dest = *src;
             }
-            let total_chars = msg.id - 1100;
-            for (idx, c) in output_bfr.iter().enumerate() {
-                if idx >= total_chars {
-                    break;
-                }
-                output.putc(*c);
-            }
+            let total_chars = (msg.id - 1100).min(output_bfr.len());
+            sinks.text(&output_bfr[..total_chars]);
         }
-        1200 => writeln!(output, "Terminating process").unwrap(),
+        1200 => sinks.line(b"Terminating process"),
         2000 => {
             #[cfg(any(feature="precursor", feature="renode"))]
             crate::debug::DEFAULT.enable_rx();
             writeln!(output, "Resuming logger").unwrap();
         }
+        id if id == api::Opcode::CompactScalar as usize => {
+            // The arguments are packed into the last three words, after their length and
+            // level
+            let mut args = [0u8; 12];
+            let words = [msg.arg2, msg.arg3, msg.arg4];
+            for (chunk, word) in args.chunks_mut(4).zip(words.iter()) {
+                chunk.copy_from_slice(&(*word as u32).to_le_bytes());
+            }
+            let len = ((args[0] & 0xf) as usize).min(args.len() - 1);
+            let level = (args[0] >> 4) as u32;
+            let pid = sender_pid.get() as u32;
+            write_compact(sinks, pid, level, msg.arg1, &args[1..1 + len]);
+        }
         _ => writeln!(
             output,
             "Unrecognized scalar message from {}: {:#?}",
@@ -324,15 +355,87 @@ fn handle_scalar(
     }
 }
 
+fn handle_sink_request(
+    opcode: api::Opcode,
+    request: &mut api::SinkRequest,
+    sinks: &mut sink::Sinks,
+) -> Result<(), xous::Error> {
+    let level = api::level_filter_from_u32(request.level).ok_or(xous::Error::InvalidLimit)?;
+    match opcode {
+        api::Opcode::RemoveSink => sinks.remove(request.id),
+        api::Opcode::SetSinkLevel => sinks.set_level(request.id, level),
+        _ => {
+            let new: Box<dyn sink::Sink> = match FromPrimitive::from_u32(request.kind) {
+                #[cfg(any(
+                    feature = "precursor",
+                    feature = "cramium-fpga",
+                    feature = "cramium-soc",
+                    feature = "renode"
+                ))]
+                Some(api::SinkKind::Uart) => Box::new(sink::Uart::new(request.address as usize)?),
+                Some(api::SinkKind::Forward) => Box::new(sink::Forward::new(
+                    xous::SID::from_array(request.sid),
+                    request.opcode as usize,
+                )?),
+                #[cfg(not(target_os = "xous"))]
+                Some(api::SinkKind::File) => {
+                    let path = request
+                        .path
+                        .get(..request.path_length as usize)
+                        .and_then(|p| core::str::from_utf8(p).ok())
+                        .ok_or(xous::Error::InvalidString)?;
+                    let file = std::fs::OpenOptions::new()
+                        .create(true)
+                        .append(true)
+                        .open(path)
+                        .map_err(|_| xous::Error::InvalidString)?;
+                    Box::new(sink::File(file))
+                }
+                _ => return Err(xous::Error::UnhandledSyscall),
+            };
+            request.id = sinks.add(new, level);
+            Ok(())
+        }
+    }
+}
+
+/// Write a compact record for `tools/src/bin/decode-log.rs` to turn back into text. The
+/// format is described in `xous_api_log::compact`.
+fn write_compact(sinks: &mut sink::Sinks, pid: u32, level: u32, entry: usize, args: &[u8]) {
+    let mut line = format!("XLOG {} {:08x} ", pid, entry);
+    for byte in args {
+        write!(line, "{:02x}", byte).ok();
+    }
+    sinks.compact(api::level_from_u32(level), pid, &line);
+}
+
 fn handle_opcode(
     output: &mut implementation::OutputWriter,
     sender: xous::MessageSender,
     opcode: api::Opcode,
     message: &xous::Message,
+    streams: &mut HashMap<Option<xous::PID>, Vec<u8>>,
+    sinks: &mut sink::Sinks,
+    filters: &mut filter::LevelFilters,
+    limits: &mut limit::RateLimits,
+    trusted: &xous_api_names::ProcessSet,
 ) {
+    let pid = sender.pid().map(|p| p.get() as u32).unwrap_or(0);
+    let is_trusted = sender.pid().map(|p| trusted.contains(p)).unwrap_or(false);
+    // Settings that apply to other processes, or to every process, may only be changed
+    // by a trusted process
+    let may_configure = |target: u32| (target == pid && pid != 0) || is_trusted;
     if let Some(mem) = message.memory_message() {
         match opcode {
             api::Opcode::LogRecord => {
+                // Let the client know which filters are current, so that it can fetch
+                // them again if they have changed.
+                if matches!(message, xous::Message::MutableBorrow(_))
+                    && mem.buf.len() >= core::mem::size_of::<api::LogRecord>()
+                {
+                    let lr = unsafe { &mut *(mem.buf.as_mut_ptr() as *mut api::LogRecord) };
+                    lr.filter_generation = filters.generation();
+                }
                 // This transmute is safe because even if the resulting buffer is garbage,
                 // there are no invalid values in the resulting struct.
                 let lr = unsafe { &*(mem.buf.as_ptr() as *const api::LogRecord) };
@@ -365,23 +468,209 @@ fn handle_opcode(
 
                 let module_slice = &lr.module[0..lr.module_length as usize];
 
-                write!(output, "{}:", level).ok();
-                for c in module_slice {
-                    output.putc(*c);
+                let record_level = api::level_from_u32(lr.level);
+                if let Some(record_level) = record_level {
+                    let module = core::str::from_utf8(module_slice).unwrap_or_default();
+                    if !filters.enabled(pid, module, record_level) || !sinks.wants(record_level) {
+                        return;
+                    }
                 }
-                write!(output, ": ").ok();
-                for c in args_slice {
-                    output.putc(*c);
+
+                // Format the record once, for every sink
+                let mut line = Vec::with_capacity(
+                    level.len() + module_slice.len() + args_slice.len() + file_slice.len() + 16,
+                );
+                line.extend_from_slice(level.as_bytes());
+                line.push(b':');
+                line.extend_from_slice(module_slice);
+                line.extend_from_slice(b": ");
+                line.extend_from_slice(args_slice);
+                line.extend_from_slice(b" (");
+                line.extend_from_slice(file_slice);
+                if let Some(number) = lr.line {
+                    line.extend_from_slice(format!(":{}", number.get()).as_bytes());
                 }
+                line.push(b')');
 
-                write!(output, " (").ok();
-                for c in file_slice {
-                    output.putc(*c);
+                match record_level {
+                    Some(record_level) => sinks.record(lr, record_level, pid, &line),
+                    None => sinks.line(&line),
+                }
+            }
+            api::Opcode::QueryRecords => {
+                if !matches!(message, xous::Message::MutableBorrow(_))
+                    || mem.buf.len() < core::mem::size_of::<api::RecordQuery>()
+                {
+                    return;
+                }
+                // As with `LogRecord`, any contents are a valid query. The buffer was lent
+                // mutably, so the results can be written straight into it.
+                let query = unsafe { &mut *(mem.buf.as_mut_ptr() as *mut api::RecordQuery) };
+                sinks.ring.query(query);
+            }
+            api::Opcode::SetLevelFilter => {
+                if !matches!(message, xous::Message::MutableBorrow(_))
+                    || mem.buf.len() < core::mem::size_of::<api::LevelFilterRule>()
+                {
+                    return;
+                }
+                let rule = unsafe { &mut *(mem.buf.as_mut_ptr() as *mut api::LevelFilterRule) };
+                let module = match rule
+                    .module
+                    .get(..rule.module_length as usize)
+                    .and_then(|m| core::str::from_utf8(m).ok())
+                {
+                    Some(module) => module,
+                    None => {
+                        rule.accepted = 0;
+                        return;
+                    }
+                };
+                let accepted = if !may_configure(rule.pid) {
+                    writeln!(
+                        output,
+                        "LOG: PID {} isn't trusted to change the level filters of PID {}",
+                        pid, rule.pid
+                    )
+                    .ok();
+                    false
+                } else if rule.level == api::REMOVE_FILTER {
+                    let removed = filters.remove(module, rule.pid);
+                    if removed {
+                        writeln!(
+                            output,
+                            "LOG: PID {} removed the level filter for {:?} in PID {}",
+                            pid, module, rule.pid
+                        )
+                        .ok();
+                    }
+                    removed
+                } else if let Some(level) = api::level_filter_from_u32(rule.level) {
+                    let set = filters.set(module, rule.pid, level);
+                    if set {
+                        writeln!(
+                            output,
+                            "LOG: PID {} limited {:?} in PID {} to {}",
+                            pid, module, rule.pid, level
+                        )
+                        .ok();
+                    }
+                    set
+                } else {
+                    false
+                };
+                rule.accepted = accepted as u32;
+            }
+            api::Opcode::GetLevelFilters => {
+                if !matches!(message, xous::Message::MutableBorrow(_))
+                    || mem.buf.len() < core::mem::size_of::<api::LevelFilters>()
+                {
+                    return;
+                }
+                let reply = unsafe { &mut *(mem.buf.as_mut_ptr() as *mut api::LevelFilters) };
+                filters.fill(pid, reply);
+            }
+            api::Opcode::CompactRecord => {
+                if mem.buf.len() < core::mem::size_of::<api::CompactRecord>() {
+                    return;
+                }
+                let record = unsafe { &*(mem.buf.as_ptr() as *const api::CompactRecord) };
+                if let Some(args) = record.args.get(..record.args_length as usize) {
+                    write_compact(sinks, pid, record.level, record.entry as usize, args);
+                }
+            }
+            api::Opcode::SetRateLimit => {
+                if !matches!(message, xous::Message::MutableBorrow(_))
+                    || mem.buf.len() < core::mem::size_of::<api::RateLimit>()
+                {
+                    return;
+                }
+                let request = unsafe { &mut *(mem.buf.as_mut_ptr() as *mut api::RateLimit) };
+                let accepted = if !may_configure(request.pid) {
+                    writeln!(
+                        output,
+                        "LOG: PID {} isn't trusted to change the rate limit of PID {}",
+                        pid, request.pid
+                    )
+                    .ok();
+                    false
+                } else if request.records_per_second == api::REMOVE_RATE_LIMIT {
+                    let removed = limits.remove(request.pid);
+                    if removed {
+                        writeln!(
+                            output,
+                            "LOG: PID {} removed the rate limit of PID {}",
+                            pid, request.pid
+                        )
+                        .ok();
+                    }
+                    removed
+                } else {
+                    limits.set(
+                        request.pid,
+                        limit::Limit {
+                            records_per_second: request.records_per_second,
+                            burst: request.burst,
+                        },
+                    );
+                    writeln!(
+                        output,
+                        "LOG: PID {} limited PID {} to {} records per second, {} at once",
+                        pid, request.pid, request.records_per_second, request.burst
+                    )
+                    .ok();
+                    true
+                };
+                request.accepted = accepted as u32;
+            }
+            api::Opcode::ListenInput => {
+                if !matches!(message, xous::Message::MutableBorrow(_))
+                    || mem.buf.len() < core::mem::size_of::<api::InputListener>()
+                {
+                    return;
                 }
-                if let Some(line) = lr.line {
-                    write!(output, ":{}", line.get()).ok();
+                let request = unsafe { &mut *(mem.buf.as_mut_ptr() as *mut api::InputListener) };
+                let result = input::listen(pid, is_trusted, request);
+                match &result {
+                    Ok(()) => writeln!(output, "LOG: PID {} is listening to input", pid).ok(),
+                    Err(e) => {
+                        writeln!(output, "LOG: PID {} couldn't listen to input: {:?}", pid, e).ok()
+                    }
+                };
+                request.error = match &result {
+                    Ok(()) => 0,
+                    Err(e) => e.to_usize() as u32,
+                };
+            }
+            api::Opcode::AddSink | api::Opcode::RemoveSink | api::Opcode::SetSinkLevel => {
+                if !matches!(message, xous::Message::MutableBorrow(_))
+                    || mem.buf.len() < core::mem::size_of::<api::SinkRequest>()
+                {
+                    return;
                 }
-                writeln!(output, ")").ok();
+                let request = unsafe { &mut *(mem.buf.as_mut_ptr() as *mut api::SinkRequest) };
+                // Sinks see every process' output, and turning them off hides it, so
+                // they are for trusted processes only
+                let result = if !may_configure(0) {
+                    Err(xous::Error::AccessDenied)
+                } else {
+                    handle_sink_request(opcode, request, sinks)
+                };
+                match &result {
+                    Ok(()) => writeln!(
+                        output,
+                        "LOG: PID {} {:?} {} with level {}",
+                        pid, opcode, request.id, request.level
+                    )
+                    .ok(),
+                    Err(e) => {
+                        writeln!(output, "LOG: PID {} {:?} failed: {:?}", pid, opcode, e).ok()
+                    }
+                };
+                request.error = match &result {
+                    Ok(()) => 0,
+                    Err(e) => e.to_usize() as u32,
+                };
             }
             api::Opcode::StandardOutput | api::Opcode::StandardError => {
                 // let mut buffer_start_offset = mem.offset.map(|o| o.get()).unwrap_or(0);
@@ -405,7 +694,25 @@ fn handle_opcode(
                         buffer_length,
                     )
                 };
-                output.write_all(buffer).unwrap();
+
+                // Long strings arrive as a stream of chunks. Hold on to the chunks
+                // until the final one arrives so that output from other processes
+                // doesn't end up in the middle of a large dump. Panics don't come
+                // this way: libstd sends them as `BeginPanic` and `PanicMessage` scalars.
+                let pid = sender.pid();
+                if mem.offset.map(|o| o.get()) == Some(api::STREAM_CONTINUES) {
+                    let pending = streams.entry(pid).or_default();
+                    pending.extend_from_slice(buffer);
+                    if pending.len() >= MAX_STREAM_LENGTH {
+                        sinks.text(pending);
+                        pending.clear();
+                    }
+                } else {
+                    if let Some(pending) = streams.remove(&pid) {
+                        sinks.text(&pending);
+                    }
+                    sinks.text(buffer);
+                }
                 // TODO: If the buffer is mutable, set `length` to 0.
             }
             _ => {
@@ -414,10 +721,57 @@ fn handle_opcode(
         }
     } else if let Some(scalar) = message.scalar_message() {
         // Scalar message
-        handle_scalar(output, sender, scalar, sender.pid().unwrap());
+        handle_scalar(output, sender, scalar, sender.pid().unwrap(), sinks);
+    }
+}
+
+/// Records in the ring and the rate limits are timed by the ticktimer. The reader thread
+/// can't ask it for the time itself, because the ticktimer logs through us, and each
+/// would end up waiting for the other. Instead this thread asks whenever the reader sends
+/// it a request, and sends the time back as a `CLOCK_UPDATE`. The time is always read
+/// after the request was sent, so it is never earlier than the records that the reader
+/// had when it asked. A request with a delay in `arg1` is answered after sleeping for
+/// that many milliseconds.
+fn clock_thread(requests: xous::SID, log_cid: xous::CID) {
+    let ticktimer = xous_api_ticktimer::Ticktimer::new().unwrap();
+    loop {
+        let envelope =
+            xous::syscall::receive_message(requests).expect("couldn't get clock request");
+        let delay_ms = envelope.body.scalar_message().map(|s| s.arg1).unwrap_or(0);
+        if delay_ms != 0 {
+            ticktimer.sleep_ms(delay_ms).ok();
+        }
+        let now = ticktimer.elapsed_ms();
+        xous::send_message(
+            log_cid,
+            xous::Message::new_scalar(
+                CLOCK_UPDATE,
+                (now & 0xFFFF_FFFF) as usize,
+                (now >> 32) as usize,
+                delay_ms,
+                0,
+            ),
+        )
+        .ok();
     }
 }
 
+/// Ask the name server which processes are trusted, and hand the answer to the reader
+/// thread. This is done on a thread of its own because the name server logs through us,
+/// and won't answer until we are up.
+fn fetch_trusted_processes(log_cid: usize) {
+    let xns = xous_api_names::XousNames::new().unwrap();
+    let (lo, hi) = xns
+        .trusted_processes()
+        .expect("couldn't get the trusted processes")
+        .to_words();
+    xous::send_message(
+        log_cid as xous::CID,
+        xous::Message::new_scalar(TRUSTED_PROCESSES, lo, hi, 0, 0),
+    )
+    .expect("couldn't hand over the trusted processes");
+}
+
 fn reader_thread(arg: usize) {
     let output = unsafe { &mut *(arg as *mut implementation::OutputWriter) };
     writeln!(output, "LOG: Xous Logging Server starting up...").unwrap();
@@ -426,6 +780,39 @@ fn reader_thread(arg: usize) {
 
     println!("LOG: my PID is {}", xous::process::id());
     let mut counter: usize = 0;
+    let mut streams = HashMap::new();
+    let mut sinks = sink::Sinks::new(output.clone());
+    let mut filters = filter::LevelFilters::new();
+    let mut limits = limit::RateLimits::new();
+
+    let clock_sid = xous::create_server().expect("couldn't create clock server");
+    let clock_cid = xous::connect(clock_sid).expect("couldn't connect to clock server");
+    let log_cid = xous::connect(server_addr).expect("couldn't connect to self");
+    xous::create_thread(move || clock_thread(clock_sid, log_cid)).unwrap();
+    // Whether the clock thread has been asked for the time and hasn't answered yet, and
+    // the newest record in the ring when it was asked
+    let mut clock_pending = false;
+    let mut clock_through = 0;
+
+    // A second clock thread wakes us up to refill the rate limits and report dropped
+    // records, so that the first one can keep answering right away
+    let alarm_sid = xous::create_server().expect("couldn't create alarm server");
+    let alarm_cid = xous::connect(alarm_sid).expect("couldn't connect to alarm server");
+    xous::create_thread(move || clock_thread(alarm_sid, log_cid)).unwrap();
+    let mut alarm_pending = false;
+
+    // Nobody is trusted until the name server has said who is
+    let mut trusted = xous_api_names::ProcessSet::default();
+    xous::create_thread_1(fetch_trusted_processes, log_cid as usize)
+        .expect("couldn't start the thread that fetches the trusted processes");
+
+    let ask_clock =
+        || xous::try_send_message(clock_cid, xous::Message::new_scalar(0, 0, 0, 0, 0)).is_ok();
+    let set_alarm = || {
+        let delay = limit::REFILL_INTERVAL_MS as usize;
+        xous::try_send_message(alarm_cid, xous::Message::new_scalar(0, delay, 0, 0, 0)).is_ok()
+    };
+
     loop {
         if counter.trailing_zeros() >= 12 {
             writeln!(output, "LOG: Counter tick: {}", counter).unwrap();
@@ -434,8 +821,79 @@ fn reader_thread(arg: usize) {
         // writeln!(output, "LOG: Waiting for an event...").unwrap();
         let envelope = xous::syscall::receive_message(server_addr).expect("couldn't get address");
         let sender = envelope.sender;
-        if let Some(opcode) = FromPrimitive::from_usize(envelope.body.id()) {
-            handle_opcode(output, sender, opcode, &envelope.body);
+        if envelope.body.id() == CLOCK_UPDATE
+            && sender.pid().map(|p| p.get() as u32) == Some(xous::process::id())
+        {
+            if let Some(scalar) = envelope.body.scalar_message() {
+                let now = (scalar.arg1 as u64 & 0xFFFF_FFFF) | ((scalar.arg2 as u64) << 32);
+                limits.set_now(now);
+                if scalar.arg3 == 0 {
+                    sinks.ring.stamp(clock_through, now);
+                    clock_pending = false;
+                } else {
+                    alarm_pending = false;
+                }
+            }
+            for (pid, dropped) in limits.take_summaries() {
+                sinks.line(format!("LOG: PID {} dropped {} records", pid, dropped).as_bytes());
+            }
+            if limits.needs_time() && !alarm_pending {
+                alarm_pending = set_alarm();
+            }
+            // Records that arrived after the clock was asked need a later time
+            if sinks.ring.needs_time() && !clock_pending {
+                clock_through = sinks.ring.last_sequence();
+                clock_pending = ask_clock();
+            }
+        } else if envelope.body.id() == TRUSTED_PROCESSES
+            && sender.pid().map(|p| p.get() as u32) == Some(xous::process::id())
+        {
+            if let Some(scalar) = envelope.body.scalar_message() {
+                trusted = xous_api_names::ProcessSet::from_words(scalar.arg1, scalar.arg2);
+            }
+        } else if let Some(pid) = xous_api_names::terminated_pid(&envelope) {
+            // The PID may be reused by a process that isn't trusted
+            trusted.remove(pid);
+            input::forget(pid.get() as u32);
+            // A process that reuses the PID gets the default limit and a full bucket
+            let dropped = limits.forget(pid.get() as u32);
+            if dropped > 0 {
+                let line = format!("LOG: PID {} dropped {} records", pid.get(), dropped);
+                sinks.line(line.as_bytes());
+            }
+            // Don't hold on to the unfinished output of a process that has gone away
+            if let Some(pending) = streams.remove(&Some(pid)) {
+                sinks.text(&pending);
+            }
+        } else if let Some(opcode) = FromPrimitive::from_usize(envelope.body.id()) {
+            let is_record = matches!(
+                opcode,
+                api::Opcode::LogRecord | api::Opcode::CompactScalar | api::Opcode::CompactRecord
+            );
+            let pid = sender.pid().map(|p| p.get() as u32).unwrap_or(0);
+            if is_record && !limits.allow(pid) {
+                if !alarm_pending {
+                    alarm_pending = set_alarm();
+                }
+            } else {
+                handle_opcode(
+                    output,
+                    sender,
+                    opcode,
+                    &envelope.body,
+                    &mut streams,
+                    &mut sinks,
+                    &mut filters,
+                    &mut limits,
+                    &trusted,
+                );
+            }
+            // Ask for the time of this record, and to start refilling its bucket, without
+            // waiting for it
+            if is_record && !clock_pending {
+                clock_through = sinks.ring.last_sequence();
+                clock_pending = ask_clock();
+            }
         } else {
             writeln!(
                 output,
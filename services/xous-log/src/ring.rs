@@ -0,0 +1,372 @@
+//! The most recent log records, kept in RAM so that they can be read back with
+//! `QueryRecords` when nobody was watching the UART.
+
+use std::collections::VecDeque;
+use xous_api_log::api;
+
+/// The number of bytes of text that the ring holds. The oldest records are dropped to
+/// make room for new ones.
+const CAPACITY: usize = 16 * 1024;
+
+/// Messages longer than this are truncated in the ring, so that one record can't push
+/// out all of the others
+const MAX_ARGS_LENGTH: usize = 512;
+
+/// A record as it is kept in the ring. Its strings share one allocation, which is
+/// only turned back into an `api::Record` when it is read out.
+struct Entry {
+    sequence: u64,
+    timestamp_ms: u64,
+    pid: u32,
+    level: log::Level,
+    line: Option<u32>,
+    module_length: u16,
+    file_length: u16,
+    /// The module, file and arguments, one after the other
+    text: Box<str>,
+}
+
+impl Entry {
+    fn module(&self) -> &str {
+        &self.text[..self.module_length as usize]
+    }
+
+    fn file(&self) -> &str {
+        let start = self.module_length as usize;
+        &self.text[start..start + self.file_length as usize]
+    }
+
+    fn args(&self) -> &str {
+        &self.text[self.module_length as usize + self.file_length as usize..]
+    }
+
+    fn to_record(&self) -> api::Record {
+        api::Record {
+            sequence: self.sequence,
+            timestamp_ms: self.timestamp_ms,
+            pid: self.pid,
+            level: self.level,
+            module: self.module().to_owned(),
+            file: self.file().to_owned(),
+            line: self.line,
+            args: self.args().to_owned(),
+        }
+    }
+}
+
+pub struct RecordRing {
+    entries: VecDeque<Entry>,
+    /// The number of bytes of text in `entries`
+    size: usize,
+    last_sequence: u64,
+    /// Records up to this sequence number have been given their time. Newer ones are
+    /// waiting for the clock, and aren't read out until they have it.
+    stamped: u64,
+}
+
+impl RecordRing {
+    pub fn new() -> Self {
+        RecordRing {
+            entries: VecDeque::new(),
+            size: 0,
+            last_sequence: 0,
+            stamped: 0,
+        }
+    }
+
+    /// The sequence number of the newest record
+    pub fn last_sequence(&self) -> u64 {
+        self.last_sequence
+    }
+
+    /// `true` if some records are waiting for their time
+    pub fn needs_time(&self) -> bool {
+        self.stamped < self.last_sequence
+    }
+
+    /// Give `now_ms` to the records up to sequence number `through` that don't have a time
+    /// yet. The time must have been read after those records arrived, so that none of
+    /// them is given a time from before it was logged.
+    pub fn stamp(&mut self, through: u64, now_ms: u64) {
+        let through = through.min(self.last_sequence);
+        for entry in self.entries.iter_mut().rev() {
+            if entry.sequence <= self.stamped {
+                break;
+            }
+            if entry.sequence <= through {
+                entry.timestamp_ms = now_ms;
+            }
+        }
+        self.stamped = self.stamped.max(through);
+    }
+
+    /// Keep a copy of `lr`, whose strings have already been checked against their
+    /// buffers
+    pub fn push(&mut self, lr: &api::LogRecord, pid: u32) {
+        let level = match api::level_from_u32(lr.level) {
+            Some(level) => level,
+            None => return,
+        };
+        let module = String::from_utf8_lossy(&lr.module[..lr.module_length as usize]);
+        let file = String::from_utf8_lossy(&lr.file[..lr.file_length as usize]);
+        let mut args = &lr.args[..lr.args_length as usize];
+        if args.len() > MAX_ARGS_LENGTH {
+            args = &args[..MAX_ARGS_LENGTH];
+        }
+        let args = String::from_utf8_lossy(args);
+        self.insert(pid, level, &module, &file, lr.line.map(|l| l.get()), &args);
+    }
+
+    /// Keep a compact record, which was written out as `line` for the host to decode.
+    /// The module and file aren't known until then, so they are left empty.
+    pub fn push_compact(&mut self, pid: u32, level: log::Level, line: &str) {
+        self.insert(pid, level, "", "", None, line);
+    }
+
+    fn insert(
+        &mut self,
+        pid: u32,
+        level: log::Level,
+        module: &str,
+        file: &str,
+        line: Option<u32>,
+        args: &str,
+    ) {
+        let mut text = String::with_capacity(module.len() + file.len() + args.len());
+        text.push_str(module);
+        text.push_str(file);
+        text.push_str(args);
+        while !self.entries.is_empty() && self.size + text.len() > CAPACITY {
+            if let Some(oldest) = self.entries.pop_front() {
+                self.size -= oldest.text.len();
+            }
+        }
+        self.size += text.len();
+        self.last_sequence += 1;
+        self.entries.push_back(Entry {
+            sequence: self.last_sequence,
+            timestamp_ms: 0,
+            pid,
+            level,
+            line,
+            module_length: module.len() as u16,
+            file_length: file.len() as u16,
+            text: text.into_boxed_str(),
+        });
+    }
+
+    /// Fill in the results of `query` with as many matching records as fit
+    pub fn query(&self, query: &mut api::RecordQuery) {
+        let module_length = (query.module_length as usize).min(query.module.len());
+        let module = &query.module[..module_length];
+        let (after, level, pid) = (query.after, query.level, query.pid);
+        let stamped = self.stamped;
+        let matching = self.entries.iter().filter(|e| {
+            e.sequence > after
+                && e.sequence <= stamped
+                && (level == 0 || e.level as u32 <= level)
+                && (pid == 0 || e.pid == pid)
+                && e.module().as_bytes().starts_with(module)
+        });
+
+        query.count = 0;
+        query.more = 0;
+        let mut offset = 0;
+        for entry in matching {
+            match entry.to_record().encode(&mut query.data[offset..]) {
+                Some(len) => {
+                    offset += len;
+                    query.count += 1;
+                }
+                None => {
+                    query.more = 1;
+                    break;
+                }
+            }
+        }
+        query.data_length = offset as u32;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn log_record(module: &str, level: log::Level, args: &str) -> Box<api::LogRecord> {
+        let mut lr = Box::new(api::LogRecord {
+            file: [0; 128],
+            file_length: 7,
+            line: core::num::NonZeroU32::new(12),
+            module: [0; 128],
+            module_length: module.len() as u32,
+            level: level as u32,
+            args: [0; 2048],
+            args_length: args.len() as u32,
+            filter_generation: 0,
+        });
+        lr.file[..7].copy_from_slice(b"main.rs");
+        lr.module[..module.len()].copy_from_slice(module.as_bytes());
+        lr.args[..args.len()].copy_from_slice(args.as_bytes());
+        lr
+    }
+
+    fn query(after: u64, level: u32, pid: u32, module: &str) -> Box<api::RecordQuery> {
+        let mut query = Box::new(api::RecordQuery {
+            after,
+            level,
+            pid,
+            module: [0; 128],
+            module_length: module.len() as u32,
+            count: 0,
+            more: 0,
+            data_length: 0,
+            data: [0; api::QUERY_DATA_LEN],
+        });
+        query.module[..module.len()].copy_from_slice(module.as_bytes());
+        query
+    }
+
+    fn records(query: &api::RecordQuery) -> Vec<api::Record> {
+        let mut data = &query.data[..query.data_length as usize];
+        let mut records = vec![];
+        while let Some((record, len)) = api::Record::decode(data) {
+            records.push(record);
+            data = &data[len..];
+        }
+        assert!(data.is_empty());
+        assert_eq!(records.len(), query.count as usize);
+        records
+    }
+
+    #[test]
+    fn records_read_back_with_their_times() {
+        let mut ring = RecordRing::new();
+        ring.push(&log_record("app::net", log::Level::Info, "connected"), 5);
+        assert!(ring.needs_time());
+        ring.stamp(ring.last_sequence(), 100);
+        assert!(!ring.needs_time());
+        ring.push(&log_record("app::ui", log::Level::Debug, "redraw"), 6);
+        ring.push(&log_record("app::net", log::Level::Error, "lost"), 5);
+        // The clock was asked before the third record arrived, so it waits for the next time
+        ring.stamp(2, 250);
+        assert!(ring.needs_time());
+
+        let mut q = query(0, 0, 0, "");
+        ring.query(&mut q);
+        let all = records(&q);
+        assert_eq!(q.more, 0);
+        assert_eq!(all.len(), 2);
+        assert_eq!(all[0].sequence, 1);
+        assert_eq!(all[0].timestamp_ms, 100);
+        assert_eq!(all[0].pid, 5);
+        assert_eq!(all[0].level, log::Level::Info);
+        assert_eq!(all[0].module, "app::net");
+        assert_eq!(all[0].file, "main.rs");
+        assert_eq!(all[0].line, Some(12));
+        assert_eq!(all[0].args, "connected");
+        assert_eq!(all[1].timestamp_ms, 250);
+
+        ring.stamp(ring.last_sequence(), 260);
+        let mut q = query(2, 0, 0, "");
+        ring.query(&mut q);
+        let rest = records(&q);
+        assert_eq!(rest.len(), 1);
+        assert_eq!(rest[0].args, "lost");
+        assert_eq!(rest[0].timestamp_ms, 260);
+    }
+
+    #[test]
+    fn queries_are_filtered() {
+        let mut ring = RecordRing::new();
+        ring.push(&log_record("app::net", log::Level::Info, "connected"), 5);
+        ring.push(&log_record("app::ui", log::Level::Debug, "redraw"), 6);
+        ring.push(&log_record("app::net", log::Level::Error, "lost"), 5);
+        ring.stamp(ring.last_sequence(), 0);
+
+        let mut q = query(0, 0, 0, "app::net");
+        ring.query(&mut q);
+        let args: Vec<_> = records(&q).into_iter().map(|r| r.args).collect();
+        assert_eq!(args, ["connected", "lost"]);
+
+        let mut q = query(0, log::Level::Info as u32, 0, "");
+        ring.query(&mut q);
+        let args: Vec<_> = records(&q).into_iter().map(|r| r.args).collect();
+        assert_eq!(args, ["connected", "lost"]);
+
+        let mut q = query(0, 0, 6, "");
+        ring.query(&mut q);
+        let args: Vec<_> = records(&q).into_iter().map(|r| r.args).collect();
+        assert_eq!(args, ["redraw"]);
+
+        let mut q = query(1, 0, 0, "");
+        ring.query(&mut q);
+        let sequences: Vec<_> = records(&q).into_iter().map(|r| r.sequence).collect();
+        assert_eq!(sequences, [2, 3]);
+    }
+
+    #[test]
+    fn compact_records_are_kept() {
+        let mut ring = RecordRing::new();
+        ring.push_compact(5, log::Level::Warn, "XLOG 5 00001234 0003");
+        ring.push(&log_record("app", log::Level::Info, "text"), 5);
+        ring.stamp(ring.last_sequence(), 0);
+        let mut q = query(0, log::Level::Warn as u32, 0, "");
+        ring.query(&mut q);
+        let all = records(&q);
+        assert_eq!(all.len(), 1);
+        assert_eq!(all[0].args, "XLOG 5 00001234 0003");
+        assert_eq!(all[0].module, "");
+        assert_eq!(all[0].line, None);
+    }
+
+    #[test]
+    fn full_queries_are_paged() {
+        let mut ring = RecordRing::new();
+        let args = "x".repeat(MAX_ARGS_LENGTH);
+        for _ in 0..20 {
+            ring.push(&log_record("app", log::Level::Info, &args), 5);
+        }
+        ring.stamp(ring.last_sequence(), 0);
+        let mut after = 0;
+        let mut read = 0;
+        loop {
+            let mut q = query(after, 0, 0, "");
+            ring.query(&mut q);
+            let page = records(&q);
+            assert!(!page.is_empty());
+            for record in page.iter() {
+                read += 1;
+                assert_eq!(record.sequence, read);
+            }
+            after = page.last().unwrap().sequence;
+            if q.more == 0 {
+                break;
+            }
+        }
+        assert_eq!(read, 20);
+    }
+
+    #[test]
+    fn the_oldest_records_are_dropped_when_full() {
+        let mut ring = RecordRing::new();
+        // Longer arguments are truncated
+        let args = "y".repeat(MAX_ARGS_LENGTH * 2);
+        let count = 3 * CAPACITY / MAX_ARGS_LENGTH;
+        for _ in 0..count {
+            ring.push(&log_record("app", log::Level::Info, &args), 5);
+            assert!(ring.size <= CAPACITY);
+        }
+        let kept = ring.entries.len();
+        assert!(kept < count && kept >= CAPACITY / (MAX_ARGS_LENGTH + 10));
+        assert_eq!(ring.entries[0].args().len(), MAX_ARGS_LENGTH);
+
+        // Sequence numbers carry on from the newest record that was dropped
+        ring.stamp(ring.last_sequence(), 0);
+        let mut q = query(0, 0, 0, "");
+        ring.query(&mut q);
+        let first = records(&q)[0].sequence;
+        assert_eq!(first, (count - kept + 1) as u64);
+        let last = ring.entries.back().unwrap().sequence;
+        assert_eq!(last, count as u64);
+    }
+}
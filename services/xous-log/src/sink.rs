@@ -0,0 +1,342 @@
+//! The places that log output is written to.
+//!
+//! The console is always present, as is the RAM ring that serves `QueryRecords`. More
+//! sinks can be added at runtime with `AddSink`: a second UART, a server that is sent a
+//! copy of the output, or a file in hosted mode. Each sink has its own level filter,
+//! which applies to log records. Other output, such as stdout and panics, goes to every
+//! sink whose level isn't `Off`.
+
+use crate::implementation::OutputWriter;
+use crate::ring::RecordRing;
+use core::fmt::Write;
+use log::LevelFilter;
+use xous_api_log::api;
+
+pub trait Sink {
+    /// Write a log record or message, formatted as one line without a line ending
+    fn line(&mut self, line: &[u8]);
+
+    /// Write other output, such as the stdout of a process, as it arrived
+    fn text(&mut self, text: &[u8]);
+
+    /// `true` once the sink can no longer be written to, so that it should be removed
+    fn is_gone(&self) -> bool {
+        false
+    }
+}
+
+pub struct Console(pub OutputWriter);
+
+impl Sink for Console {
+    fn line(&mut self, line: &[u8]) {
+        self.0.write_all(line).ok();
+        writeln!(self.0).ok();
+    }
+
+    fn text(&mut self, text: &[u8]) {
+        self.0.write_all(text).ok();
+    }
+}
+
+/// The UARTs that may be added as sinks. Any other address could be the registers of
+/// some other device, which the log server would then write to on a client's behalf.
+#[cfg(any(feature = "precursor", feature = "renode"))]
+const UARTS: &[usize] = &[utralib::generated::utra::app_uart::HW_APP_UART_BASE];
+
+/// The DUART is the console, and the other UARTs of the Cramium SoC have a different
+/// register layout, so there are none to add
+#[cfg(any(feature = "cramium-fpga", feature = "cramium-soc"))]
+const UARTS: &[usize] = &[];
+
+/// A UART other than the console, with the same register layout
+#[cfg(any(
+    feature = "precursor",
+    feature = "cramium-fpga",
+    feature = "cramium-soc",
+    feature = "renode"
+))]
+pub struct Uart {
+    range: xous::MemoryRange,
+}
+
+#[cfg(any(
+    feature = "precursor",
+    feature = "cramium-fpga",
+    feature = "cramium-soc",
+    feature = "renode"
+))]
+impl Uart {
+    pub fn new(address: usize) -> Result<Uart, xous::Error> {
+        if !UARTS.contains(&address) {
+            return Err(xous::Error::AccessDenied);
+        }
+        let range = xous::syscall::map_memory(
+            Some(xous::MemoryAddress::new(address).ok_or(xous::Error::BadAddress)?),
+            None,
+            4096,
+            xous::MemoryFlags::R | xous::MemoryFlags::W,
+        )?;
+        Ok(Uart { range })
+    }
+
+    fn putc(&mut self, c: u8) {
+        use utralib::generated::*;
+        let mut csr = CSR::new(self.range.as_mut_ptr() as *mut u32);
+        #[cfg(any(feature = "cramium-fpga", feature = "cramium-soc"))]
+        {
+            while csr.r(utra::duart::SFR_SR) != 0 {}
+            csr.wo(utra::duart::SFR_TXD, c as u32);
+        }
+        #[cfg(any(feature = "precursor", feature = "renode"))]
+        {
+            while csr.rf(utra::uart::TXFULL_TXFULL) != 0 {}
+            csr.wfo(utra::uart::RXTX_RXTX, c as u32);
+        }
+    }
+}
+
+#[cfg(any(
+    feature = "precursor",
+    feature = "cramium-fpga",
+    feature = "cramium-soc",
+    feature = "renode"
+))]
+impl Sink for Uart {
+    fn line(&mut self, line: &[u8]) {
+        for c in line.iter().chain(b"\r\n".iter()) {
+            self.putc(*c);
+        }
+    }
+
+    fn text(&mut self, text: &[u8]) {
+        for c in text {
+            self.putc(*c);
+        }
+    }
+}
+
+#[cfg(any(
+    feature = "precursor",
+    feature = "cramium-fpga",
+    feature = "cramium-soc",
+    feature = "renode"
+))]
+impl Drop for Uart {
+    fn drop(&mut self) {
+        xous::syscall::unmap_memory(self.range).ok();
+    }
+}
+
+/// Sends a copy of the output to another server. Each piece of output is moved to it in
+/// a page of its own, so that the log server never waits for it, even if it logs in turn.
+pub struct Forward {
+    cid: xous::CID,
+    opcode: usize,
+    gone: bool,
+}
+
+impl Forward {
+    pub fn new(sid: xous::SID, opcode: usize) -> Result<Forward, xous::Error> {
+        Ok(Forward {
+            cid: xous::try_connect(sid)?,
+            opcode,
+            gone: false,
+        })
+    }
+
+    fn send(&mut self, bytes: &[u8]) {
+        for chunk in bytes.chunks(4096) {
+            let page = match xous::syscall::map_memory(
+                None,
+                None,
+                4096,
+                xous::MemoryFlags::R | xous::MemoryFlags::W,
+            ) {
+                Ok(page) => page,
+                Err(_) => return,
+            };
+            unsafe { core::slice::from_raw_parts_mut(page.as_mut_ptr(), chunk.len()) }
+                .copy_from_slice(chunk);
+            let message = xous::Message::Move(xous::MemoryMessage {
+                id: self.opcode,
+                buf: page,
+                offset: None,
+                valid: xous::MemorySize::new(chunk.len()),
+            });
+            match xous::try_send_message(self.cid, message) {
+                Ok(_) => {}
+                Err(e) => {
+                    // The page is still ours if it couldn't be sent. A full queue drops
+                    // the output, but a server that has gone away is removed.
+                    xous::syscall::unmap_memory(page).ok();
+                    if e != xous::Error::ServerQueueFull {
+                        self.gone = true;
+                        return;
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl Sink for Forward {
+    fn line(&mut self, line: &[u8]) {
+        let mut buf = Vec::with_capacity(line.len() + 1);
+        buf.extend_from_slice(line);
+        buf.push(b'\n');
+        self.send(&buf);
+    }
+
+    fn text(&mut self, text: &[u8]) {
+        self.send(text);
+    }
+
+    fn is_gone(&self) -> bool {
+        self.gone
+    }
+}
+
+/// A file on the host, in hosted mode
+#[cfg(not(target_os = "xous"))]
+pub struct File(pub std::fs::File);
+
+#[cfg(not(target_os = "xous"))]
+impl Sink for File {
+    fn line(&mut self, line: &[u8]) {
+        use std::io::Write;
+        self.0.write_all(line).ok();
+        self.0.write_all(b"\n").ok();
+    }
+
+    fn text(&mut self, text: &[u8]) {
+        use std::io::Write;
+        self.0.write_all(text).ok();
+    }
+}
+
+struct Registered {
+    id: u32,
+    level: LevelFilter,
+    sink: Box<dyn Sink>,
+}
+
+pub struct Sinks {
+    sinks: Vec<Registered>,
+    pub ring: RecordRing,
+    ring_level: LevelFilter,
+    last_id: u32,
+}
+
+impl Sinks {
+    pub fn new(console: OutputWriter) -> Self {
+        Sinks {
+            sinks: vec![Registered {
+                id: api::CONSOLE_SINK,
+                level: LevelFilter::Trace,
+                sink: Box::new(Console(console)),
+            }],
+            ring: RecordRing::new(),
+            ring_level: LevelFilter::Trace,
+            last_id: api::RING_SINK,
+        }
+    }
+
+    pub fn add(&mut self, sink: Box<dyn Sink>, level: LevelFilter) -> u32 {
+        self.last_id += 1;
+        self.sinks.push(Registered {
+            id: self.last_id,
+            level,
+            sink,
+        });
+        self.last_id
+    }
+
+    /// Remove the sink `id`. The console and the RAM ring can't be removed, but can be
+    /// turned off with `set_level()`.
+    pub fn remove(&mut self, id: u32) -> Result<(), xous::Error> {
+        if id == api::CONSOLE_SINK || id == api::RING_SINK {
+            return Err(xous::Error::AccessDenied);
+        }
+        let len = self.sinks.len();
+        self.sinks.retain(|s| s.id != id);
+        if self.sinks.len() == len {
+            return Err(xous::Error::BadAddress);
+        }
+        Ok(())
+    }
+
+    pub fn set_level(&mut self, id: u32, level: LevelFilter) -> Result<(), xous::Error> {
+        if id == api::RING_SINK {
+            self.ring_level = level;
+            return Ok(());
+        }
+        let sink = self
+            .sinks
+            .iter_mut()
+            .find(|s| s.id == id)
+            .ok_or(xous::Error::BadAddress)?;
+        sink.level = level;
+        Ok(())
+    }
+
+    /// `true` if any sink takes records at `level`, so that they are worth formatting
+    pub fn wants(&self, level: log::Level) -> bool {
+        level <= self.ring_level || self.sinks.iter().any(|s| level <= s.level)
+    }
+
+    /// Write a log record, which was formatted as `line`
+    pub fn record(&mut self, record: &api::LogRecord, level: log::Level, pid: u32, line: &[u8]) {
+        if level <= self.ring_level {
+            self.ring.push(record, pid);
+        }
+        for s in self.sinks.iter_mut().filter(|s| level <= s.level) {
+            s.sink.line(line);
+        }
+        self.remove_gone();
+    }
+
+    /// Write a compact record, which was written out as `line` for the host to decode.
+    /// Records from clients that don't say what level they are at go to every sink,
+    /// but not to the ring.
+    pub fn compact(&mut self, level: Option<log::Level>, pid: u32, line: &str) {
+        let level = match level {
+            Some(level) => level,
+            None => return self.line(line.as_bytes()),
+        };
+        if level <= self.ring_level {
+            self.ring.push_compact(pid, level, line);
+        }
+        for s in self.sinks.iter_mut().filter(|s| level <= s.level) {
+            s.sink.line(line.as_bytes());
+        }
+        self.remove_gone();
+    }
+
+    /// Write a line that isn't a log record, such as the start of a panic message
+    pub fn line(&mut self, line: &[u8]) {
+        for s in self
+            .sinks
+            .iter_mut()
+            .filter(|s| s.level != LevelFilter::Off)
+        {
+            s.sink.line(line);
+        }
+        self.remove_gone();
+    }
+
+    /// Write output such as the stdout of a process
+    pub fn text(&mut self, text: &[u8]) {
+        for s in self
+            .sinks
+            .iter_mut()
+            .filter(|s| s.level != LevelFilter::Off)
+        {
+            s.sink.text(text);
+        }
+        self.remove_gone();
+    }
+
+    fn remove_gone(&mut self) {
+        self.sinks.retain(|s| !s.sink.is_gone());
+    }
+}
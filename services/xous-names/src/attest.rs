@@ -0,0 +1,187 @@
+//! The names of the processes in the boot image, as given by the loader.
+//!
+//! Processes name themselves with `Identify`, but that is only a claim. The kernel
+//! knows which image each boot process was loaded from, and sends those names to the
+//! name server as soon as it is created, in `PROCESS_NAME_ID` messages. These are
+//! what the trust list in `trusted.conf` is checked against.
+
+use std::collections::{HashMap, HashSet};
+
+use xous_api_names::api::{ProcessSet, PROCESS_NAME_ID};
+
+/// The processes that are trusted, which is embedded into the image.
+pub const DEFAULT_TRUSTED: &str = include_str!("../trusted.conf");
+
+/// Number of bytes of a name that each `PROCESS_NAME_ID` message carries
+const PART_LEN: usize = 12;
+
+/// Parse the process names in `trusted.conf`
+pub fn trusted_names(text: &str) -> HashSet<String> {
+    text.lines()
+        .map(|line| line.split('#').next().unwrap_or("").trim())
+        .filter(|line| !line.is_empty())
+        .map(str::to_owned)
+        .collect()
+}
+
+#[derive(Debug, Default)]
+pub struct ImageNames {
+    /// Names of the boot processes that are still running
+    names: HashMap<xous::PID, String>,
+
+    /// Names that are still being received
+    partial: HashMap<xous::PID, Vec<u8>>,
+
+    /// Every name in the boot image, which only its own process may claim
+    reserved: HashSet<String>,
+}
+
+impl ImageNames {
+    /// Take in `msg` if it is one of the kernel's `PROCESS_NAME_ID` messages,
+    /// returning `true` if it was.
+    pub fn receive(&mut self, msg: &xous::MessageEnvelope) -> bool {
+        if msg.body.id() != PROCESS_NAME_ID || msg.sender.pid().map(|p| p.get()) != Some(1) {
+            return false;
+        }
+        if let Some(scalar) = msg.body.scalar_message() {
+            let words = [scalar.arg2, scalar.arg3, scalar.arg4];
+            let mut part = [0u8; PART_LEN];
+            for (dest, word) in part.chunks_exact_mut(4).zip(words) {
+                dest.copy_from_slice(&(word as u32).to_le_bytes());
+            }
+            if let Some(pid) = xous::PID::new(scalar.arg1 as u8) {
+                let offset = (scalar.arg1 >> 8) & 0xff;
+                let len = (scalar.arg1 >> 16) & 0xff;
+                self.add_part(pid, offset, len, &part);
+            }
+        }
+        true
+    }
+
+    /// Add the bytes of the name of `pid` that start at `offset`, out of a name that
+    /// is `len` bytes long. The kernel sends the parts in order.
+    fn add_part(&mut self, pid: xous::PID, offset: usize, len: usize, part: &[u8; PART_LEN]) {
+        let name = self.partial.entry(pid).or_default();
+        if name.len() != offset {
+            log::warn!("the name of PID {:?} arrived out of order", pid);
+            self.partial.remove(&pid);
+            return;
+        }
+        name.extend_from_slice(&part[..len.saturating_sub(offset).min(PART_LEN)]);
+        if name.len() < len {
+            return;
+        }
+        let name = self.partial.remove(&pid).unwrap_or_default();
+        match String::from_utf8(name) {
+            Ok(name) => {
+                log::info!("process {:?} was loaded as '{}'", pid, name);
+                self.reserved.insert(name.clone());
+                self.names.insert(pid, name);
+            }
+            Err(_) => log::warn!("the name of PID {:?} isn't UTF-8", pid),
+        }
+    }
+
+    pub fn get(&self, pid: xous::PID) -> Option<&str> {
+        self.names.get(&pid).map(|name| name.as_str())
+    }
+
+    /// Forget the name of `pid` once it has terminated, so that a process that is
+    /// given the same PID isn't mistaken for it. Its name stays reserved.
+    pub fn release(&mut self, pid: xous::PID) {
+        self.names.remove(&pid);
+        self.partial.remove(&pid);
+    }
+
+    /// Return `true` if `pid` may identify itself as `name`. A boot process may only
+    /// take the name that it was loaded with, and nothing else may take a boot
+    /// process' name, even after that process has terminated.
+    pub fn may_claim(&self, pid: xous::PID, name: &str) -> bool {
+        match self.get(pid) {
+            Some(image) => image == name,
+            None => !self.reserved.contains(name),
+        }
+    }
+
+    /// The running processes whose image names are in `trusted`
+    pub fn trusted(&self, trusted: &HashSet<String>) -> ProcessSet {
+        let mut set = ProcessSet::default();
+        for (pid, name) in self.names.iter() {
+            if trusted.contains(name) {
+                set.insert(*pid);
+            }
+        }
+        set
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pid(pid: u8) -> xous::PID {
+        xous::PID::new(pid).unwrap()
+    }
+
+    /// Split `name` into parts, the way that the kernel sends it
+    fn load(names: &mut ImageNames, pid: xous::PID, name: &str) {
+        for (index, chunk) in name.as_bytes().chunks(PART_LEN).enumerate() {
+            let mut part = [0u8; PART_LEN];
+            part[..chunk.len()].copy_from_slice(chunk);
+            names.add_part(pid, index * PART_LEN, name.len(), &part);
+        }
+    }
+
+    #[test]
+    fn names_are_put_back_together() {
+        let mut names = ImageNames::default();
+        load(&mut names, pid(2), "xous-ticktimer");
+        load(&mut names, pid(3), "console");
+        load(&mut names, pid(4), "abcdefghijkl");
+        assert_eq!(names.get(pid(2)), Some("xous-ticktimer"));
+        assert_eq!(names.get(pid(3)), Some("console"));
+        assert_eq!(names.get(pid(4)), Some("abcdefghijkl"));
+    }
+
+    #[test]
+    fn parts_out_of_order_are_dropped() {
+        let mut names = ImageNames::default();
+        names.add_part(pid(2), PART_LEN, 14, b"rx\0\0\0\0\0\0\0\0\0\0");
+        assert_eq!(names.get(pid(2)), None);
+        load(&mut names, pid(2), "xous-ticktimer");
+        assert_eq!(names.get(pid(2)), Some("xous-ticktimer"));
+    }
+
+    #[test]
+    fn boot_processes_keep_their_names() {
+        let mut names = ImageNames::default();
+        load(&mut names, pid(3), "console");
+        assert!(names.may_claim(pid(3), "console"));
+        assert!(!names.may_claim(pid(3), "shellchat"));
+        assert!(!names.may_claim(pid(9), "console"));
+        assert!(names.may_claim(pid(9), "shellchat"));
+        // The name isn't freed when its process terminates
+        names.release(pid(3));
+        assert!(!names.may_claim(pid(3), "console"));
+        assert!(!names.may_claim(pid(9), "console"));
+    }
+
+    #[test]
+    fn trust_follows_the_image_name() {
+        let trusted = trusted_names("# comment\n  console  # the console\n\n");
+        assert_eq!(trusted.len(), 1);
+        let mut names = ImageNames::default();
+        load(&mut names, pid(2), "xous-ticktimer");
+        load(&mut names, pid(3), "console");
+        let set = names.trusted(&trusted);
+        assert!(set.contains(pid(3)));
+        assert!(!set.contains(pid(2)));
+        names.release(pid(3));
+        assert_eq!(names.trusted(&trusted), ProcessSet::default());
+    }
+
+    #[test]
+    fn the_default_trust_list_parses() {
+        assert!(trusted_names(DEFAULT_TRUSTED).contains("console"));
+    }
+}
@@ -4,13 +4,18 @@
 use xous_api_names::*;
 use xous_api_names::api::*;
 
+mod attest;
+use attest::ImageNames;
+mod policy;
+use policy::{Policy, ProcessNames};
+
 use num_traits::FromPrimitive;
 use xous::{msg_blocking_scalar_unpack, MessageEnvelope};
 use xous_ipc::{Buffer, String};
 
 use log::{error, info};
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
 #[derive(PartialEq)]
 #[repr(C)]
@@ -29,6 +34,28 @@ enum ConnectError {
 
     /// The server does not currently exist, and a blocking request was made
     ServerNotFound = 5,
+
+    /// The access policy does not allow the caller to connect to this server
+    AccessDenied = 6,
+
+    /// The server has no free connections left, and further clients have to
+    /// authenticate with an `AuthenticatedLookup`
+    AuthenticationRequired = 7,
+}
+
+impl ConnectError {
+    /// The kernel error reported alongside this code in the error envelope
+    fn kernel_error(&self) -> xous::Error {
+        match self {
+            ConnectError::InvalidMemoryBuffer => xous::Error::BadAddress,
+            ConnectError::KernelConnectFailure => xous::Error::InternalError,
+            ConnectError::InvalidString => xous::Error::InvalidString,
+            ConnectError::InvalidMessageType => xous::Error::InvalidSyscall,
+            ConnectError::ServerNotFound => xous::Error::ServerNotFound,
+            ConnectError::AccessDenied => xous::Error::AccessDenied,
+            ConnectError::AuthenticationRequired => xous::Error::AccessDenied,
+        }
+    }
 }
 
 #[derive(PartialEq)]
@@ -42,8 +69,6 @@ enum ConnectSuccess {
 
     /// There is no server with that name -- block this message
     Wait,
-    // /// The client needs to make an authentication request
-    // AuthenticationRequest
 }
 
 #[cfg(any(feature="precursor", feature="cramium-fpga", feature="cramium-soc", feature="renode"))]
@@ -112,11 +137,14 @@ and we can use heap-allocated Rust primitives...
 #[derive(Debug, Copy, Clone)]
 struct Connection {
     pub sid: xous::SID,
+    pub owner: xous::PID, // the process that registered the server
     pub current_conns: u32, // number of unauthenticated (inherentely trusted) connections
     pub max_conns: Option<u32>, // if None, unlimited connections allowed
-    pub _allow_authenticate: bool,
-    pub _auth_conns: u32,        // number of authenticated connections
+    pub pubkey: Option<[u8; 32]>, // if set, clients holding the private key may connect past `max_conns`
+    pub auth_conns: u32,          // number of authenticated connections
     pub token: Option<[u32; 4]>, // a random number that must be presented to allow for disconnection
+    pub restart_token: Option<[u32; 4]>, // if set, a new instance that presents this may take over the name
+    pub running: bool, // false once the server's process has terminated
 }
 #[derive(Debug)]
 struct CheckedHashMap {
@@ -132,7 +160,10 @@ impl CheckedHashMap {
         &mut self,
         name: XousServerName,
         sid: xous::SID,
+        owner: xous::PID,
         max_conns: Option<u32>,
+        pubkey: Option<[u8; 32]>,
+        restart_token: Option<[u32; 4]>,
     ) -> Result<(), xous::Error> {
         let token =
             // for use with 1-connection servers, provision a one-time use token for disconnects
@@ -149,11 +180,14 @@ impl CheckedHashMap {
             name,
             Connection {
                 sid,
+                owner,
                 current_conns: 0,
                 max_conns,
-                _allow_authenticate: false, // for now, we don't support authenticated connections
-                _auth_conns: 0,
+                pubkey,
+                auth_conns: 0,
                 token,
+                restart_token,
+                running: true,
             },
         );
         Ok(())
@@ -176,11 +210,54 @@ impl CheckedHashMap {
         removed_name
     }
 
-    pub fn contains_key(&self, name: &XousServerName) -> bool {
-        self.map.contains_key(name)
+    /// Called when the process `pid` has terminated. The servers it registered release
+    /// their connections, and their names are either freed or, if they registered as
+    /// restartable, held for the process that presents the restart token.
+    pub fn process_terminated(&mut self, pid: xous::PID) {
+        self.map.retain(|name, entry| {
+            if entry.owner != pid || !entry.running {
+                return true;
+            }
+            log::warn!("server '{}' has terminated, releasing its connections", name);
+            entry.running = false;
+            entry.current_conns = 0;
+            entry.auth_conns = 0;
+            entry.restart_token.is_some()
+        });
+    }
+
+    /// Return `true` if the server registered as `name` is running.
+    fn is_running(&self, name: &XousServerName) -> bool {
+        self.map
+            .get(name)
+            .map(|entry| entry.running)
+            .unwrap_or(false)
+    }
+
+    /// Return `true` if `name` may be registered by a process without a restart token.
+    pub fn is_available(&self, name: &XousServerName) -> bool {
+        !self.map.contains_key(name)
+    }
+
+    /// Return `true` if the server registered as `name` has terminated, and `token`
+    /// is the restart token it was given.
+    pub fn can_restart(&self, name: &XousServerName, token: [u32; 4]) -> bool {
+        match self.map.get(name) {
+            Some(entry) => !entry.running && entry.restart_token == Some(token),
+            None => false,
+        }
+    }
+
+    /// The public key that the server registered as `name` had before it terminated,
+    /// which its replacement inherits
+    pub fn restart_pubkey(&self, name: &XousServerName) -> Option<[u8; 32]> {
+        self.map.get(name).and_then(|entry| entry.pubkey)
     }
 
     pub fn connect(&mut self, name: &XousServerName) -> (Option<xous::SID>, Option<[u32; 4]>) {
+        if !self.is_running(name) {
+            return (None, None);
+        }
         if let Some(entry) = self.map.get_mut(name) {
             match entry.max_conns {
                 // single-connection case
@@ -219,6 +296,66 @@ impl CheckedHashMap {
         }
     }
 
+    /// Return the public key that clients may authenticate against, if the server
+    /// registered one.
+    pub fn pubkey(&self, name: &XousServerName) -> Option<[u8; 32]> {
+        self.map
+            .get(name)
+            .filter(|entry| entry.running)
+            .and_then(|entry| entry.pubkey)
+    }
+
+    /// Connect a client that has proven it holds the server's private key. This
+    /// is not subject to the connection limit. No disconnect token is handed out,
+    /// because the token frees up one of the limited connections, which this
+    /// client never took.
+    pub fn connect_authenticated(&mut self, name: &XousServerName) -> Option<xous::SID> {
+        if !self.is_running(name) {
+            return None;
+        }
+        match self.map.get_mut(name) {
+            Some(entry) if entry.pubkey.is_some() => {
+                entry.auth_conns += 1;
+                Some(entry.sid)
+            }
+            _ => None,
+        }
+    }
+
+    /// Undo `connect_authenticated()` when the kernel couldn't make the connection.
+    pub fn disconnect_authenticated(&mut self, name: &XousServerName) {
+        if let Some(entry) = self.map.get_mut(name) {
+            entry.auth_conns = entry.auth_conns.saturating_sub(1);
+        }
+    }
+
+    /// Fill `list` with the servers starting at `list.start`, sorted by name so that
+    /// the pages line up from one request to the next.
+    pub fn list(&self, list: &mut ServerList) {
+        let mut names: Vec<&XousServerName> = self.map.keys().collect();
+        names.sort_by(|a, b| a.to_str().cmp(b.to_str()));
+
+        list.total = names.len() as u32;
+        list.count = 0;
+        for (info, name) in list
+            .servers
+            .iter_mut()
+            .zip(names.iter().skip(list.start as usize))
+        {
+            let entry = &self.map[*name];
+            *info = ServerInfo {
+                name: String::<64>::from_str(name.to_str()),
+                owner: entry.owner.get(),
+                current_conns: entry.current_conns,
+                auth_conns: entry.auth_conns,
+                max_conns: entry.max_conns,
+                authenticated: entry.pubkey.is_some(),
+                running: entry.running,
+            };
+            list.count += 1;
+        }
+    }
+
     pub fn trusted_init_done(&self) -> bool {
         let mut trusted_done = true;
         for (name, entry) in self.map.iter() {
@@ -338,6 +475,12 @@ fn blocking_connect(
         }
     }
 
+    // Only `Lookup` can hand out an authentication challenge, so there's no point
+    // in waiting for a connection to a server that is already full
+    if name_table.pubkey(&name).is_some() {
+        return Err(ConnectError::AuthenticationRequired);
+    }
+
     // There is no connection, so block the sender
     log::trace!("No server currently registered to '{}', blocking...", name);
     Ok(ConnectSuccess::Wait)
@@ -345,13 +488,11 @@ fn blocking_connect(
 
 fn respond_connect_error(mut msg: MessageEnvelope, result: ConnectError) {
     let mem = msg.body.memory_message_mut().unwrap();
-    let s = unsafe {
-        core::slice::from_raw_parts_mut(mem.buf.as_mut_ptr() as *mut u32, mem.buf.len() / 4)
-    };
-    s[0] = 1;
-    s[1] = result as u32;
-    mem.valid = None;
-    mem.offset = None;
+    let kernel_error = result.kernel_error();
+    // The buffer is always at least a page, so the envelope fits
+    ServiceError::new(result as u32, kernel_error)
+        .respond(mem)
+        .ok();
 }
 
 fn respond_connect_success(mut msg: MessageEnvelope, cid: xous::CID, disc: Option<[u32; 4]>) {
@@ -369,6 +510,83 @@ fn respond_connect_success(mut msg: MessageEnvelope, cid: xous::CID, disc: Optio
     mem.offset = None;
 }
 
+/// Check the access policy for a request from `pid` to reach `name`, logging the
+/// request if it is denied.
+fn policy_allows(
+    policy: &Policy,
+    process_names: &ProcessNames,
+    pid: xous::PID,
+    name: &XousServerName,
+) -> bool {
+    let process = process_names.get(pid);
+    if policy.allows(process, name.to_str()) {
+        return true;
+    }
+    log::warn!(
+        "access policy denied process {:?} ({}) access to '{}'",
+        pid,
+        process.unwrap_or("unidentified"),
+        name
+    );
+    false
+}
+
+/// Check that `auth_lookup` answers `challenge` with a signature made by the private
+/// key matching `pubkey`.
+fn verify_authentication(
+    auth_lookup: &AuthenticatedLookup,
+    name: &XousServerName,
+    challenge: &[u32; 4],
+    pubkey: &[u8; 32],
+) -> bool {
+    use core::convert::TryFrom;
+    use ed25519_dalek::{PublicKey, Signature};
+
+    if auth_lookup.pubkey_id != pubkey_id(pubkey) {
+        return false;
+    }
+    let pubkey = match PublicKey::from_bytes(pubkey) {
+        Ok(pubkey) => pubkey,
+        Err(_) => return false,
+    };
+    let mut signature = [0u8; 64];
+    for (dest, word) in signature
+        .chunks_exact_mut(4)
+        .zip(auth_lookup.response.iter())
+    {
+        dest.copy_from_slice(&word.to_le_bytes());
+    }
+    let signature = match Signature::try_from(&signature[..]) {
+        Ok(signature) => signature,
+        Err(_) => return false,
+    };
+    let message = authentication_message(name.to_str(), challenge, &auth_lookup.response_nonce);
+    pubkey.verify_strict(&message, &signature).is_ok()
+}
+
+/// The `AdjustProcessLimit` index that reads the RAM owned by a process
+const LIMIT_RAM_USED: usize = 3;
+
+/// The processes that are trusted with privileged requests.
+#[cfg(target_os = "xous")]
+fn trusted_processes(
+    image_names: &ImageNames,
+    trusted: &HashSet<std::string::String>,
+) -> ProcessSet {
+    image_names.trusted(trusted)
+}
+
+/// Hosted processes are started by the host rather than from a boot image, so there
+/// are no image names to go by. They aren't isolated from each other either, so there
+/// is nothing to protect, and every process is trusted.
+#[cfg(not(target_os = "xous"))]
+fn trusted_processes(
+    _image_names: &ImageNames,
+    _trusted: &HashSet<std::string::String>,
+) -> ProcessSet {
+    ProcessSet::from_words(usize::MAX, usize::MAX)
+}
+
 fn main() -> ! {
     use implementation::*;
     log_server::init_wait().unwrap();
@@ -388,12 +606,38 @@ fn main() -> ! {
     //let mut name_table = FnvIndexMap::<XousServerName, xous::SID, 128>::new();
     let mut name_table = CheckedHashMap::new();
 
+    // Challenges that have been issued in response to a `Lookup`, and which may be
+    // answered exactly once with an `AuthenticatedLookup` from the same process.
+    let mut challenges: HashMap<(xous::PID, XousServerName), [u32; 4]> = HashMap::new();
+
+    let policy = Policy::parse(policy::DEFAULT_POLICY)
+        .unwrap_or_else(|e| panic!("access policy has an error on line {}", e.line));
+    let mut process_names = ProcessNames::default();
+    let mut image_names = ImageNames::default();
+    let trusted = attest::trusted_names(attest::DEFAULT_TRUSTED);
+
     info!("started");
     loop {
         let mut msg = xous::receive_message(name_server).unwrap();
         log::trace!("received message: {:?}", msg);
+        if image_names.receive(&msg) {
+            continue;
+        }
+        if let Some(pid) = terminated_pid(&msg) {
+            if let Some(name) = process_names.release(pid) {
+                info!("process {:?} ('{}') has terminated", pid, name);
+            }
+            image_names.release(pid);
+            name_table.process_terminated(pid);
+            challenges.retain(|(challenged, _), _| *challenged != pid);
+            continue;
+        }
         match FromPrimitive::from_usize(msg.body.id()) {
             Some(api::Opcode::Register) => {
+                let sender_pid = msg
+                    .sender
+                    .pid()
+                    .expect("can't extract sender PID on Register");
                 let mem = msg.body.memory_message_mut().unwrap();
                 let mut buffer = unsafe { Buffer::from_memory_message_mut(mem) };
                 let registration = buffer.to_original::<Registration, _>().unwrap();
@@ -408,15 +652,48 @@ fn main() -> ! {
                 let mut should_connect = false;
 
                 log::trace!("registration request for '{}'", name);
-                if !name_table.contains_key(&name) {
+                let available = match registration.restart_token {
+                    Some(token) => name_table.can_restart(&name, token),
+                    None => name_table.is_available(&name),
+                };
+                if available {
                     let new_sid =
                         xous::create_server_id().expect("create server failed, maybe OOM?");
+                    // A restarted server keeps its token, so that it can be restarted again
+                    let restart_token = registration.restart_token.or_else(|| {
+                        if registration.restartable {
+                            Some(
+                                xous::create_server_id()
+                                    .expect("couldn't create token")
+                                    .to_array(),
+                            )
+                        } else {
+                            None
+                        }
+                    });
+                    // ...and its public key, unless it brings a new one
+                    let pubkey = match registration.restart_token {
+                        Some(_) => registration.pubkey.or(name_table.restart_pubkey(&name)),
+                        None => registration.pubkey,
+                    };
                     name_table
-                        .insert(name, new_sid, registration.conn_limit)
+                        .insert(
+                            name,
+                            new_sid,
+                            sender_pid,
+                            registration.conn_limit,
+                            pubkey,
+                            restart_token,
+                        )
                         .expect("register name failure, maybe out of HashMap capacity?");
                     log::trace!("request successful, SID is {:?}", new_sid);
                     should_connect = true;
-                    response = api::Return::SID(new_sid.into());
+                    response = match (registration.restart_token, restart_token) {
+                        (None, Some(token)) => {
+                            api::Return::RestartableSID((new_sid.to_array(), token))
+                        }
+                        _ => api::Return::SID(new_sid.into()),
+                    };
                 } else {
                     info!("request failed, waiting for deterministic timeout");
                     d11ctimeout.deterministic_busy_wait();
@@ -475,6 +752,14 @@ fn main() -> ! {
                     xous::return_scalar(msg.sender, 0).unwrap();
                     continue;
                 }
+                let sender_pid = msg.sender.pid().expect("kernel provided us a PID of None");
+                match name_from_msg(&msg) {
+                    Ok(name) if !policy_allows(&policy, &process_names, sender_pid, &name) => {
+                        respond_connect_error(msg, ConnectError::AccessDenied);
+                        continue;
+                    }
+                    _ => (),
+                }
 
                 match blocking_connect(&mut msg, &mut name_table) {
                     Err(e) => respond_connect_error(msg, e),
@@ -502,12 +787,15 @@ fn main() -> ! {
                         .expect("couldn't convert server name to string"),
                 );
                 log::trace!("Lookup request for '{}'", name);
+                let sender_pid = msg
+                    .sender
+                    .pid()
+                    .expect("can't extract sender PID on Lookup");
                 let response: api::Return;
-                if let (Some(server_sid), token) = name_table.connect(&name) {
-                    let sender_pid = msg
-                        .sender
-                        .pid()
-                        .expect("can't extract sender PID on Lookup");
+                if !policy_allows(&policy, &process_names, sender_pid, &name) {
+                    d11ctimeout.hosted_delay();
+                    response = api::Return::Failure
+                } else if let (Some(server_sid), token) = name_table.connect(&name) {
                     match xous::connect_for_process(sender_pid, server_sid)
                         .expect("can't broker connection")
                     {
@@ -524,37 +812,187 @@ fn main() -> ! {
                             response = api::Return::Failure
                         }
                     }
-                } else {
-                    log::debug!("Can't find request '{}' in table, dumping table:", name);
-                    for (_name, conn) in name_table.map.iter() {
-                        log::debug!("{:?}", conn);
-                    }
-                    // no authenticate remedy currently supported, but we'd put that code somewhere around here eventually.
+                } else if let Some(pubkey) = name_table.pubkey(&name) {
+                    // The server is out of unauthenticated connections, but it will
+                    // accept clients that can prove they hold its private key.
                     let (c1, c2, c3, c4) = xous::create_server_id().unwrap().to_u32();
+                    let challenge = [c1, c2, c3, c4];
+                    challenges.insert((sender_pid, name), challenge);
+                    log::trace!(
+                        "issuing authentication challenge for '{}' to {:?}",
+                        name,
+                        sender_pid
+                    );
                     let auth_request = AuthenticateRequest {
                         name: String::<64>::from_str(
                             name_string
                                 .as_str()
                                 .expect("couldn't convert server name to string"),
                         ),
-                        pubkey_id: [0; 20], // placeholder
-                        challenge: [c1, c2, c3, c4],
+                        pubkey_id: pubkey_id(&pubkey),
+                        challenge,
                     };
+                    response = api::Return::AuthenticateRequest(auth_request)
+                } else {
+                    log::debug!("Can't find request '{}' in table, dumping table:", name);
+                    for (_name, conn) in name_table.map.iter() {
+                        log::debug!("{:?}", conn);
+                    }
                     d11ctimeout.hosted_delay();
-                    response = api::Return::AuthenticateRequest(auth_request) // this code just exists to exercise the return path
+                    response = api::Return::Failure
                 }
                 buffer
                     .replace(response)
                     .expect("Lookup can't serialize return value");
             }
             Some(api::Opcode::AuthenticatedLookup) => {
+                let sender_pid = msg
+                    .sender
+                    .pid()
+                    .expect("can't extract sender PID on AuthenticatedLookup");
                 let mem = msg.body.memory_message_mut().unwrap();
-                let buffer = unsafe { Buffer::from_memory_message_mut(mem) };
-                let auth_lookup: AuthenticatedLookup = buffer.to_original().unwrap();
-                info!("AuthenticatedLookup request {:?}", auth_lookup);
-                error!("AuthenticatedLookup not yet implemented");
-                unimplemented!("AuthenticatedLookup not yet implemented");
+                let mut buffer = unsafe { Buffer::from_memory_message_mut(mem) };
+                let auth_lookup = buffer.to_original::<AuthenticatedLookup, _>().unwrap();
+                let name = XousServerName::from_str(
+                    auth_lookup
+                        .name
+                        .as_str()
+                        .expect("couldn't convert server name to string"),
+                );
+                log::trace!("AuthenticatedLookup request for '{}'", name);
+
+                // Each challenge may only be answered once, whether or not the
+                // answer is correct.
+                let verified = match (
+                    challenges.remove(&(sender_pid, name)),
+                    name_table.pubkey(&name),
+                ) {
+                    (Some(challenge), Some(pubkey)) => {
+                        verify_authentication(&auth_lookup, &name, &challenge, &pubkey)
+                    }
+                    _ => false,
+                };
+
+                let mut response = api::Return::Failure;
+                if verified {
+                    if let Some(server_sid) = name_table.connect_authenticated(&name) {
+                        match xous::connect_for_process(sender_pid, server_sid) {
+                            Ok(xous::Result::ConnectionID(connection_id)) => {
+                                log::trace!(
+                                    "authenticated lookup success, returning connection {}",
+                                    connection_id
+                                );
+                                response = api::Return::CID((connection_id, None))
+                            }
+                            result => {
+                                log::error!(
+                                    "error when making authenticated connection: {:?}",
+                                    result
+                                );
+                                name_table.disconnect_authenticated(&name);
+                            }
+                        }
+                    }
+                } else {
+                    info!(
+                        "authentication for '{}' failed, waiting for deterministic timeout",
+                        name
+                    );
+                    d11ctimeout.deterministic_busy_wait();
+                }
+                buffer
+                    .replace(response)
+                    .expect("AuthenticatedLookup can't serialize return value");
             }
+            Some(api::Opcode::Identify) => {
+                let sender_pid = msg
+                    .sender
+                    .pid()
+                    .expect("can't extract sender PID on Identify");
+                let mem = msg.body.memory_message_mut().unwrap();
+                let mut buffer = unsafe { Buffer::from_memory_message_mut(mem) };
+                let process_name = buffer.to_original::<String<64>, _>().unwrap();
+                let process_name = process_name.as_str().unwrap_or("");
+                let response = if image_names.may_claim(sender_pid, process_name)
+                    && process_names.claim(sender_pid, process_name, &policy)
+                {
+                    info!("process {:?} identified as '{}'", sender_pid, process_name);
+                    api::Return::Success
+                } else {
+                    log::warn!(
+                        "process {:?} was denied the identity '{}'",
+                        sender_pid,
+                        process_name
+                    );
+                    api::Return::Failure
+                };
+                buffer
+                    .replace(response)
+                    .expect("Identify can't serialize return value");
+            }
+            Some(api::Opcode::ResolveProcess) => {
+                let mem = msg.body.memory_message_mut().unwrap();
+                let mut buffer = unsafe { Buffer::from_memory_message_mut(mem) };
+                let process_name = buffer.to_original::<String<64>, _>().unwrap();
+                let response = match process_names.pid(process_name.as_str().unwrap_or("")) {
+                    Some(pid) => api::Return::PID(pid.get()),
+                    None => api::Return::Failure,
+                };
+                buffer
+                    .replace(response)
+                    .expect("ResolveProcess can't serialize return value");
+            }
+            Some(api::Opcode::ListServers) => {
+                let mem = msg.body.memory_message_mut().unwrap();
+                let mut buffer = unsafe { Buffer::from_memory_message_mut(mem) };
+                let mut list = buffer.to_original::<ServerList, _>().unwrap();
+                name_table.list(&mut list);
+                buffer
+                    .replace(list)
+                    .expect("ListServers can't serialize return value");
+            }
+            Some(api::Opcode::TrustedProcesses) => {
+                let trusted = trusted_processes(&image_names, &trusted);
+                let (lo, hi) = trusted.to_words();
+                xous::return_scalar2(msg.sender, lo, hi)
+                    .expect("couldn't return trusted processes");
+            }
+            Some(api::Opcode::ProcessName) => {
+                let mem = msg.body.memory_message_mut().unwrap();
+                let mut buffer = unsafe { Buffer::from_memory_message_mut(mem) };
+                let mut process = buffer.to_original::<api::ProcessName, _>().unwrap();
+                process.name = xous::PID::new(process.pid)
+                    .and_then(|pid| image_names.get(pid).or_else(|| process_names.get(pid)))
+                    .map(String::<64>::from_str);
+                buffer
+                    .replace(process)
+                    .expect("ProcessName can't serialize return value");
+            }
+            Some(api::Opcode::ProcessRam) => msg_blocking_scalar_unpack!(msg, pid, _, _, _, {
+                let sender_pid = msg.sender.pid();
+                let asks_about_itself = sender_pid.map(|p| p.get() as usize) == Some(pid);
+                let is_trusted = sender_pid
+                    .map(|p| trusted_processes(&image_names, &trusted).contains(p))
+                    .unwrap_or(false);
+                let response = if asks_about_itself || is_trusted {
+                    // The kernel only tells the name server about other processes
+                    match xous::rsyscall(xous::SysCall::AdjustProcessLimit(LIMIT_RAM_USED, pid, 0))
+                    {
+                        Ok(xous::Result::Scalar2(_, bytes)) => (0, bytes),
+                        Ok(_) => (xous::Error::InternalError.to_usize(), 0),
+                        Err(e) => (e.to_usize(), 0),
+                    }
+                } else {
+                    log::warn!(
+                        "process {:?} isn't trusted to ask about the RAM of PID {}",
+                        sender_pid,
+                        pid
+                    );
+                    (xous::Error::AccessDenied.to_usize(), 0)
+                };
+                xous::return_scalar2(msg.sender, response.0, response.1)
+                    .expect("couldn't return process RAM");
+            }),
             Some(api::Opcode::TrustedInitDone) => {
                 if name_table.trusted_init_done() {
                     xous::return_scalar(msg.sender, 1).expect("couldn't return trusted_init_done");
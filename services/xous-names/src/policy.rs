@@ -0,0 +1,198 @@
+//! Declarative access control for server names.
+//!
+//! The policy maps client process names to the server names that they are
+//! allowed to reach. See `policy.conf` for the file format.
+
+use std::collections::{HashMap, HashSet};
+
+/// The policy that is embedded into the image.
+pub const DEFAULT_POLICY: &str = include_str!("../policy.conf");
+
+/// The process name that matches every process.
+const EVERYONE: &str = "*";
+
+#[derive(Debug)]
+pub struct PolicyError {
+    pub line: usize,
+}
+
+#[derive(Debug, Default)]
+pub struct Policy {
+    /// Servers that each named process may reach
+    clients: HashMap<String, HashSet<String>>,
+
+    /// Servers that every process may reach
+    everyone: HashSet<String>,
+
+    /// Servers that appear in a rule, and are closed to processes that aren't listed
+    restricted: HashSet<String>,
+}
+
+impl Policy {
+    pub fn parse(text: &str) -> Result<Policy, PolicyError> {
+        let mut policy = Policy::default();
+        for (index, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            let (process, servers) = line
+                .split_once(':')
+                .ok_or(PolicyError { line: index + 1 })?;
+            let process = process.trim();
+            if process.is_empty() {
+                return Err(PolicyError { line: index + 1 });
+            }
+
+            let allowed = if process == EVERYONE {
+                &mut policy.everyone
+            } else {
+                policy.clients.entry(process.to_owned()).or_default()
+            };
+            for server in servers.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+                allowed.insert(server.to_owned());
+                policy.restricted.insert(server.to_owned());
+            }
+        }
+        Ok(policy)
+    }
+
+    /// Return `true` if `process` is named by a rule, and may therefore be claimed
+    /// as an identity.
+    pub fn is_client(&self, process: &str) -> bool {
+        self.clients.contains_key(process)
+    }
+
+    /// Return `true` if a process that has identified itself as `process` (or has
+    /// not identified itself, if `None`) may reach `server`.
+    pub fn allows(&self, process: Option<&str>, server: &str) -> bool {
+        if !self.restricted.contains(server) || self.everyone.contains(server) {
+            return true;
+        }
+        process
+            .and_then(|process| self.clients.get(process))
+            .map(|allowed| allowed.contains(server))
+            .unwrap_or(false)
+    }
+}
+
+/// The names that processes have claimed for themselves.
+#[derive(Debug, Default)]
+pub struct ProcessNames {
+    names: HashMap<xous::PID, String>,
+}
+
+impl ProcessNames {
+    /// Record that `pid` is the process called `name`. Each name in the policy may
+    /// be claimed by one process at a time, and a process may only claim one name.
+    /// The name is held until `release()` is called for the process.
+    pub fn claim(&mut self, pid: xous::PID, name: &str, policy: &Policy) -> bool {
+        if !policy.is_client(name)
+            || self.names.contains_key(&pid)
+            || self.names.values().any(|claimed| claimed == name)
+        {
+            return false;
+        }
+        self.names.insert(pid, name.to_owned());
+        true
+    }
+
+    /// Forget the name that `pid` claimed, once that process has terminated, so that
+    /// a new process that is given the same PID doesn't inherit it.
+    pub fn release(&mut self, pid: xous::PID) -> Option<String> {
+        self.names.remove(&pid)
+    }
+
+    pub fn get(&self, pid: xous::PID) -> Option<&str> {
+        self.names.get(&pid).map(|name| name.as_str())
+    }
+
+    /// The process that claimed `name`, if any
+    pub fn pid(&self, name: &str) -> Option<xous::PID> {
+        self.names
+            .iter()
+            .find(|(_, claimed)| claimed.as_str() == name)
+            .map(|(pid, _)| *pid)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pid(pid: u8) -> xous::PID {
+        xous::PID::new(pid).unwrap()
+    }
+
+    #[test]
+    fn rules() {
+        let policy = Policy::parse(
+            "# comment\n\
+             console: _mbox_, status # trailing comment\n\
+             \n\
+             shellchat:\n\
+             *: gam\n",
+        )
+        .unwrap();
+        assert!(policy.is_client("console"));
+        assert!(policy.is_client("shellchat"));
+        assert!(!policy.is_client("*"));
+        assert!(policy.allows(Some("console"), "_mbox_"));
+        assert!(policy.allows(Some("console"), "status"));
+        assert!(!policy.allows(Some("shellchat"), "_mbox_"));
+        assert!(!policy.allows(None, "_mbox_"));
+        // Rules for everyone, and servers that aren't mentioned, are open
+        assert!(policy.allows(None, "gam"));
+        assert!(policy.allows(None, "ticktimer-server"));
+    }
+
+    #[test]
+    fn malformed_rules() {
+        assert_eq!(Policy::parse("console").unwrap_err().line, 1);
+        assert_eq!(Policy::parse("console: a\n: b\n").unwrap_err().line, 2);
+        assert_eq!(
+            Policy::parse("\n\n  # only : a comment\n")
+                .unwrap()
+                .clients
+                .len(),
+            0
+        );
+    }
+
+    #[test]
+    fn duplicate_rules_are_merged() {
+        let policy = Policy::parse("console: a, a\nconsole: b\n").unwrap();
+        assert_eq!(policy.clients.len(), 1);
+        assert_eq!(policy.clients["console"].len(), 2);
+        assert!(policy.allows(Some("console"), "a"));
+        assert!(policy.allows(Some("console"), "b"));
+    }
+
+    #[test]
+    fn the_default_policy_parses() {
+        let policy = Policy::parse(DEFAULT_POLICY).unwrap();
+        assert!(policy.is_client("console"));
+    }
+
+    #[test]
+    fn names_are_claimed_once() {
+        let policy = Policy::parse("console:\nshellchat:\n").unwrap();
+        let mut names = ProcessNames::default();
+        assert!(!names.claim(pid(2), "unknown", &policy));
+        assert!(names.claim(pid(2), "console", &policy));
+        assert!(!names.claim(pid(3), "console", &policy));
+        assert!(!names.claim(pid(2), "shellchat", &policy));
+        assert_eq!(names.get(pid(2)), Some("console"));
+        assert_eq!(names.pid("console"), Some(pid(2)));
+    }
+
+    #[test]
+    fn names_are_released() {
+        let policy = Policy::parse("console:\n").unwrap();
+        let mut names = ProcessNames::default();
+        assert!(names.claim(pid(2), "console", &policy));
+        assert_eq!(names.release(pid(2)).as_deref(), Some("console"));
+        assert_eq!(names.get(pid(2)), None);
+        assert!(names.claim(pid(3), "console", &policy));
+    }
+}
@@ -0,0 +1,76 @@
+//! Connections to servers that clients ask us to send messages to.
+//!
+//! The kernel gives a process a single connection to each server, so two timers that
+//! name the same server share a CID, and that CID may also be one that the ticktimer
+//! itself relies on. Each connection is counted, and is only closed once nothing uses
+//! it. The ticktimer's own connections are pinned, and are never closed.
+
+use std::collections::{HashMap, HashSet};
+
+#[derive(Default)]
+pub struct Connections {
+    users: HashMap<xous::CID, usize>,
+    pinned: HashSet<xous::CID>,
+}
+
+impl Connections {
+    /// Mark the connection to `sid` as one that the ticktimer uses itself
+    pub fn pin(&mut self, sid: xous::SID) {
+        if let Ok(cid) = xous::try_connect(sid) {
+            self.pinned.insert(cid);
+        }
+    }
+
+    /// Connect to `sid` on behalf of a client, without waiting for it to be created
+    pub fn connect(&mut self, sid: xous::SID) -> Result<xous::CID, xous::Error> {
+        let cid = xous::try_connect(sid)?;
+        *self.users.entry(cid).or_default() += 1;
+        Ok(cid)
+    }
+
+    /// Stop using `cid` on behalf of a client, and close it if nothing else uses it
+    pub fn release(&mut self, cid: xous::CID) {
+        if !self.forget(cid) {
+            return;
+        }
+        // Safe because nothing in this process uses the connection anymore
+        unsafe { xous::disconnect(cid).ok() };
+    }
+
+    /// Count one less user of `cid`, returning `true` if it should now be closed
+    fn forget(&mut self, cid: xous::CID) -> bool {
+        match self.users.get_mut(&cid) {
+            Some(count) if *count > 1 => {
+                *count -= 1;
+                false
+            }
+            Some(_) => {
+                self.users.remove(&cid);
+                !self.pinned.contains(&cid)
+            }
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shared_connections_close_with_their_last_user() {
+        let mut conns = Connections::default();
+        *conns.users.entry(5).or_default() += 2;
+        assert!(!conns.forget(5));
+        assert!(conns.forget(5));
+        assert!(!conns.forget(5));
+    }
+
+    #[test]
+    fn pinned_connections_stay_open() {
+        let mut conns = Connections::default();
+        conns.pinned.insert(5);
+        *conns.users.entry(5).or_default() += 1;
+        assert!(!conns.forget(5));
+    }
+}
@@ -0,0 +1,390 @@
+//! Bookkeeping that explains why a thread is stuck in `LockMutex` or `WaitForCondition`.
+//!
+//! libstd only involves the ticktimer when a Mutex is contended, so the owner of a Mutex
+//! is only known from the point where a thread first has to wait for it. An owner that
+//! releases the Mutex after the last waiter has been served does so without telling us,
+//! so owners are forgotten once nobody is waiting.
+//!
+//! As a result, only deadlocks where every Mutex in the cycle was handed to its owner by
+//! the ticktimer are detected. In the common case, where two threads each take a Mutex
+//! without contention and then wait on each other's, neither owner is known. The report
+//! still shows both threads waiting, but it doesn't count a deadlock.
+//!
+//! Threads are identified by the TID that the kernel records in each message's sender,
+//! so callers can't claim to be another thread. Messages that were forwarded to us carry
+//! the proxy's PID there instead, and their threads are reported as unknown.
+
+use std::collections::HashMap;
+use std::fmt::Write;
+
+#[derive(Copy, Clone)]
+enum WaitingOn {
+    Mutex(usize),
+    Condvar(usize),
+}
+
+struct Waiting {
+    pid: Option<xous::PID>,
+    tid: Option<xous::TID>,
+    on: WaitingOn,
+    since_ms: u64,
+}
+
+struct Owner {
+    /// `None` if the Mutex was taken without contention, or by a thread that didn't
+    /// give its ID
+    tid: Option<xous::TID>,
+    since_ms: u64,
+}
+
+#[derive(Default)]
+pub struct LockDiagnostics {
+    /// Owners of contended Mutexes, indexed by process and address
+    owners: HashMap<(Option<xous::PID>, usize), Owner>,
+    /// Threads that are blocked on a Mutex or a condvar, indexed by their `MessageSender`
+    waiting: HashMap<usize, Waiting>,
+}
+
+/// The thread that sent a message, from bits 8-15 of its sender. The kernel sets the top
+/// bit of those when they hold a proxy's PID rather than a TID. This is
+/// `MessageSender::tid()` in the kernel's `xous` crate, which is newer than the one that
+/// services link.
+pub fn sender_tid(sender: xous::MessageSender) -> Option<xous::TID> {
+    let thread_or_proxy = (sender.to_usize() >> 8) & 0xff;
+    if thread_or_proxy & 0x80 != 0 || thread_or_proxy == 0 {
+        None
+    } else {
+        Some(thread_or_proxy as xous::TID)
+    }
+}
+
+impl LockDiagnostics {
+    /// A thread took a Mutex without blocking
+    pub fn mutex_granted(
+        &mut self,
+        pid: Option<xous::PID>,
+        mutex: usize,
+        tid: Option<xous::TID>,
+        now_ms: u64,
+    ) {
+        self.owners.insert(
+            (pid, mutex),
+            Owner {
+                tid,
+                since_ms: now_ms,
+            },
+        );
+    }
+
+    /// A thread is blocked waiting for a Mutex. Returns a description of the deadlock
+    /// if this completes a cycle of threads waiting on each other.
+    pub fn mutex_contended(
+        &mut self,
+        pid: Option<xous::PID>,
+        mutex: usize,
+        sender: xous::MessageSender,
+        tid: Option<xous::TID>,
+        now_ms: u64,
+    ) -> Option<String> {
+        self.owners.entry((pid, mutex)).or_insert(Owner {
+            tid: None,
+            since_ms: now_ms,
+        });
+        self.waiting.insert(
+            sender.to_usize(),
+            Waiting {
+                pid,
+                tid,
+                on: WaitingOn::Mutex(mutex),
+                since_ms: now_ms,
+            },
+        );
+        tid.and_then(|tid| self.find_cycle(pid, tid))
+            .map(|cycle| describe_cycle(pid, &cycle))
+    }
+
+    /// A Mutex was unlocked and handed to the thread that sent `sender`
+    pub fn mutex_handed_off(
+        &mut self,
+        pid: Option<xous::PID>,
+        mutex: usize,
+        sender: xous::MessageSender,
+        waiters_left: bool,
+        now_ms: u64,
+    ) {
+        let tid = self
+            .waiting
+            .remove(&sender.to_usize())
+            .and_then(|waiting| waiting.tid);
+        if waiters_left {
+            self.owners.insert(
+                (pid, mutex),
+                Owner {
+                    tid,
+                    since_ms: now_ms,
+                },
+            );
+        } else {
+            self.owners.remove(&(pid, mutex));
+        }
+    }
+
+    /// A Mutex was unlocked with nobody waiting for it
+    pub fn mutex_released(&mut self, pid: Option<xous::PID>, mutex: usize) {
+        self.owners.remove(&(pid, mutex));
+    }
+
+    /// A thread is blocked waiting for a condition
+    pub fn condvar_waiting(
+        &mut self,
+        pid: Option<xous::PID>,
+        condvar: usize,
+        sender: xous::MessageSender,
+        tid: Option<xous::TID>,
+        now_ms: u64,
+    ) {
+        self.waiting.insert(
+            sender.to_usize(),
+            Waiting {
+                pid,
+                tid,
+                on: WaitingOn::Condvar(condvar),
+                since_ms: now_ms,
+            },
+        );
+    }
+
+    /// A thread that was waiting for a condition was notified or timed out
+    pub fn woken(&mut self, sender: xous::MessageSender) {
+        self.waiting.remove(&sender.to_usize());
+    }
+
+    /// Forget the Mutexes and waiting threads of a process that has exited
+    pub fn process_terminated(&mut self, pid: xous::PID) {
+        self.owners.retain(|(owner_pid, _), _| *owner_pid != Some(pid));
+        self.waiting.retain(|_, w| w.pid != Some(pid));
+    }
+
+    /// Follow the chain of Mutex owners from thread `tid` in process `pid`. Each thread
+    /// waits on at most one Mutex, so the chain either ends or comes back around.
+    fn find_cycle(&self, pid: Option<xous::PID>, tid: xous::TID) -> Option<Vec<xous::TID>> {
+        let mut chain = vec![tid];
+        let mut current = tid;
+        while chain.len() <= self.waiting.len() {
+            let mutex = self.waiting.values().find_map(|w| match w.on {
+                WaitingOn::Mutex(mutex) if w.pid == pid && w.tid == Some(current) => Some(mutex),
+                _ => None,
+            })?;
+            current = self.owners.get(&(pid, mutex))?.tid?;
+            chain.push(current);
+            if current == tid {
+                return Some(chain);
+            }
+        }
+        None
+    }
+
+    /// Describe every contended Mutex and every waiting thread, followed by any deadlocks.
+    /// Returns the report and the number of deadlocks that were found.
+    pub fn report(&self, now_ms: u64) -> (String, u32) {
+        let mut report = String::new();
+        let thread = |tid: Option<xous::TID>| match tid {
+            Some(tid) => format!("TID {}", tid),
+            None => "an unknown thread".to_owned(),
+        };
+
+        let mut owners: Vec<_> = self.owners.iter().collect();
+        owners.sort_by_key(|((pid, mutex), _)| (pid.map(|p| p.get()), *mutex));
+        for ((pid, mutex), owner) in owners {
+            writeln!(
+                report,
+                "mutex {:08x} in PID {:?} held by {} for {} ms",
+                mutex,
+                pid.map(|p| p.get()),
+                thread(owner.tid),
+                now_ms.saturating_sub(owner.since_ms)
+            )
+            .ok();
+        }
+
+        let mut waiting: Vec<_> = self.waiting.values().collect();
+        waiting.sort_by_key(|w| (w.pid.map(|p| p.get()), w.since_ms));
+        for w in waiting.iter() {
+            let (kind, address) = match w.on {
+                WaitingOn::Mutex(address) => ("mutex", address),
+                WaitingOn::Condvar(address) => ("condvar", address),
+            };
+            writeln!(
+                report,
+                "{} in PID {:?} waiting on {} {:08x} for {} ms",
+                thread(w.tid),
+                w.pid.map(|p| p.get()),
+                kind,
+                address,
+                now_ms.saturating_sub(w.since_ms)
+            )
+            .ok();
+        }
+
+        // Every thread in a cycle finds the same cycle, so only report it from the
+        // thread with the lowest ID
+        let mut deadlocks = 0;
+        for w in waiting.iter() {
+            if let Some(tid) = w.tid {
+                if let Some(cycle) = self.find_cycle(w.pid, tid) {
+                    if cycle.iter().min() == Some(&tid) {
+                        writeln!(report, "deadlock: {}", describe_cycle(w.pid, &cycle)).ok();
+                        deadlocks += 1;
+                    }
+                }
+            }
+        }
+        (report, deadlocks)
+    }
+}
+
+/// Describe a cycle as the chain of threads that are waiting on each other, for example
+/// `PID 5: TID 2 -> TID 3 -> TID 2`
+fn describe_cycle(pid: Option<xous::PID>, cycle: &[xous::TID]) -> String {
+    let threads: Vec<String> = cycle.iter().map(|tid| format!("TID {}", tid)).collect();
+    format!("PID {:?}: {}", pid.map(|p| p.get()), threads.join(" -> "))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pid() -> Option<xous::PID> {
+        xous::PID::new(5)
+    }
+
+    /// A sender for a message from thread `tid`, where `idx` tells messages apart
+    fn sender(tid: xous::TID, idx: usize) -> xous::MessageSender {
+        xous::MessageSender::from_usize((5 << 24) | (tid << 8) | idx)
+    }
+
+    #[test]
+    fn threads_come_from_the_sender() {
+        assert_eq!(sender_tid(sender(3, 1)), Some(3));
+        // Forwarded messages carry the proxy's PID instead
+        assert_eq!(
+            sender_tid(xous::MessageSender::from_usize((5 << 24) | (0x84 << 8))),
+            None
+        );
+        assert_eq!(sender_tid(xous::MessageSender::from_usize(5 << 24)), None);
+    }
+
+    #[test]
+    fn two_threads_waiting_on_each_other() {
+        let mut diag = LockDiagnostics::default();
+        // TID 2 holds A and TID 3 holds B
+        diag.mutex_granted(pid(), 0xa, Some(2), 0);
+        diag.mutex_granted(pid(), 0xb, Some(3), 0);
+
+        assert!(diag
+            .mutex_contended(pid(), 0xb, sender(2, 2), Some(2), 0)
+            .is_none());
+        let deadlock = diag.mutex_contended(pid(), 0xa, sender(3, 3), Some(3), 0);
+        assert_eq!(
+            deadlock.as_deref(),
+            Some("PID Some(5): TID 3 -> TID 2 -> TID 3")
+        );
+
+        // The deadlock is reported once, from the thread with the lowest ID
+        let (report, deadlocks) = diag.report(10);
+        assert_eq!(deadlocks, 1);
+        assert!(report.contains("deadlock: PID Some(5): TID 2 -> TID 3 -> TID 2"));
+    }
+
+    #[test]
+    fn a_chain_that_ends_is_not_a_deadlock() {
+        let mut diag = LockDiagnostics::default();
+        diag.mutex_granted(pid(), 0xa, Some(2), 0);
+        diag.mutex_granted(pid(), 0xb, Some(3), 0);
+        // TID 2 waits on B, whose owner isn't waiting on anything
+        assert!(diag
+            .mutex_contended(pid(), 0xb, sender(2, 1), Some(2), 0)
+            .is_none());
+        // TID 4 waits on A, and so on TID 2, which waits on TID 3
+        assert!(diag
+            .mutex_contended(pid(), 0xa, sender(4, 2), Some(4), 0)
+            .is_none());
+        assert_eq!(diag.report(0).1, 0);
+    }
+
+    #[test]
+    fn unknown_owners_end_the_chain() {
+        let mut diag = LockDiagnostics::default();
+        // Nobody was waiting on A when it was taken, so its owner is unknown
+        assert!(diag
+            .mutex_contended(pid(), 0xa, sender(2, 1), Some(2), 0)
+            .is_none());
+        assert!(diag.find_cycle(pid(), 2).is_none());
+    }
+
+    #[test]
+    fn cycles_stay_within_a_process() {
+        let mut diag = LockDiagnostics::default();
+        let other = xous::PID::new(6);
+        diag.mutex_granted(pid(), 0xa, Some(2), 0);
+        diag.mutex_granted(other, 0xb, Some(3), 0);
+        // The same addresses and TIDs in another process are different Mutexes and threads
+        assert!(diag
+            .mutex_contended(pid(), 0xb, sender(2, 1), Some(2), 0)
+            .is_none());
+        assert!(diag
+            .mutex_contended(other, 0xa, sender(3, 2), Some(3), 0)
+            .is_none());
+        assert!(diag.find_cycle(pid(), 2).is_none());
+        assert!(diag.find_cycle(other, 3).is_none());
+    }
+
+    #[test]
+    fn handing_off_a_mutex_moves_its_owner() {
+        let mut diag = LockDiagnostics::default();
+        diag.mutex_granted(pid(), 0xa, Some(2), 0);
+        diag.mutex_granted(pid(), 0xb, Some(3), 0);
+        assert!(diag
+            .mutex_contended(pid(), 0xa, sender(3, 1), Some(3), 0)
+            .is_none());
+        assert!(diag
+            .mutex_contended(pid(), 0xa, sender(4, 2), Some(4), 0)
+            .is_none());
+
+        // TID 2 unlocks A and it goes to TID 3, which TID 4 is now waiting on
+        diag.mutex_handed_off(pid(), 0xa, sender(3, 1), true, 0);
+        let deadlock = diag.mutex_contended(pid(), 0xb, sender(3, 3), Some(3), 0);
+        assert!(deadlock.is_some());
+    }
+
+    #[test]
+    fn an_exited_process_is_forgotten() {
+        let mut diag = LockDiagnostics::default();
+        let other = xous::PID::new(6);
+        diag.mutex_granted(pid(), 0xa, Some(2), 0);
+        diag.mutex_granted(other, 0xa, Some(2), 0);
+        diag.condvar_waiting(pid(), 0xc, sender(3, 1), Some(3), 0);
+
+        diag.process_terminated(pid().unwrap());
+        let (report, _) = diag.report(0);
+        assert_eq!(report, "mutex 0000000a in PID Some(6) held by TID 2 for 0 ms\n");
+    }
+
+    #[test]
+    fn deadlocks_on_uncontended_mutexes_are_not_detected() {
+        let mut diag = LockDiagnostics::default();
+        // TID 2 took A and TID 3 took B without contention, so we never heard of it
+        assert!(diag
+            .mutex_contended(pid(), 0xb, sender(2, 1), Some(2), 0)
+            .is_none());
+        assert!(diag
+            .mutex_contended(pid(), 0xa, sender(3, 2), Some(3), 0)
+            .is_none());
+
+        let (report, deadlocks) = diag.report(0);
+        assert_eq!(deadlocks, 0);
+        assert!(report.contains("mutex 0000000a in PID Some(5) held by an unknown thread"));
+        assert!(report.contains("TID 2 in PID Some(5) waiting on mutex 0000000b"));
+        assert!(report.contains("TID 3 in PID Some(5) waiting on mutex 0000000a"));
+    }
+}
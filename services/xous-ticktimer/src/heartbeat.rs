@@ -0,0 +1,298 @@
+//! Heartbeats of critical services, which decide whether the watchdog is pet.
+//!
+//! Each critical service must send a heartbeat within its deadline. The deadline is
+//! checked lazily: a single check per service is scheduled in the sleep heap, and when
+//! it fires it is moved out to the latest heartbeat plus the deadline.
+//!
+//! A service that misses its deadline stops the watchdog from being pet. If a supervisor
+//! is registered, it is told first, and has `SUPERVISOR_GRACE_US` to recover the service
+//! before the watchdog is stopped.
+//!
+//! Since a critical service can reset the system, only trusted processes may register
+//! one or become the supervisor. That is checked by the main loop.
+
+use std::collections::HashMap;
+
+/// How long a supervisor has to deal with a missed deadline
+pub const SUPERVISOR_GRACE_US: i64 = 5_000_000;
+
+struct Critical {
+    pid: Option<xous::PID>,
+    name: String,
+    deadline_us: i64,
+    last_beat_us: i64,
+    /// The time at which the deadline was found to be missed. No check is scheduled
+    /// while this is set.
+    missed_at_us: Option<i64>,
+}
+
+pub struct Supervisor {
+    pub pid: Option<xous::PID>,
+    pub cid: xous::CID,
+    pub opcode: usize,
+}
+
+/// The outcome of a scheduled check
+pub enum Check {
+    /// The service is no longer registered
+    Gone,
+    /// The service is alive, and should be checked again at this time
+    Alive(i64),
+    /// The service just missed its deadline
+    Missed {
+        name: String,
+        pid: Option<xous::PID>,
+    },
+}
+
+#[derive(Default)]
+pub struct Heartbeats {
+    services: HashMap<u32, Critical>,
+    last_id: u32,
+    supervisor: Option<Supervisor>,
+}
+
+impl Heartbeats {
+    /// Register a critical service, returning its ID. The first check is due at
+    /// `now_us + deadline_us`.
+    pub fn register(
+        &mut self,
+        pid: Option<xous::PID>,
+        name: String,
+        deadline_us: i64,
+        now_us: i64,
+    ) -> u32 {
+        // IDs are never reused, so a check that is still scheduled for an unregistered
+        // service can't be mistaken for a new one
+        self.last_id += 1;
+        self.services.insert(
+            self.last_id,
+            Critical {
+                pid,
+                name,
+                deadline_us,
+                last_beat_us: now_us,
+                missed_at_us: None,
+            },
+        );
+        self.last_id
+    }
+
+    /// Record a heartbeat from the service `id`, which must belong to `pid`. Returns the
+    /// time of the next check if the service had missed its deadline, since it then has
+    /// no check scheduled.
+    pub fn beat(&mut self, id: u32, pid: Option<xous::PID>, now_us: i64) -> Option<i64> {
+        let service = self.services.get_mut(&id).filter(|s| s.pid == pid)?;
+        service.last_beat_us = now_us;
+        service.missed_at_us.take().map(|_| {
+            log::info!("{} recovered and is sending heartbeats again", service.name);
+            now_us + service.deadline_us
+        })
+    }
+
+    /// Remove the service `id`, which must belong to `pid`. The supervisor may also
+    /// remove a service that has missed its deadline, to recover from it.
+    pub fn unregister(&mut self, id: u32, pid: Option<xous::PID>) -> bool {
+        let supervisor = self
+            .supervisor
+            .as_ref()
+            .map(|s| s.pid == pid)
+            .unwrap_or(false);
+        if self
+            .services
+            .get(&id)
+            .map(|s| s.pid == pid || (supervisor && s.missed_at_us.is_some()))
+            .unwrap_or(false)
+        {
+            self.services.remove(&id);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Run the check that was scheduled for the service `id`
+    pub fn check(&mut self, id: u32, now_us: i64) -> Check {
+        let service = match self.services.get_mut(&id) {
+            Some(service) if service.missed_at_us.is_none() => service,
+            _ => return Check::Gone,
+        };
+        let due = service.last_beat_us + service.deadline_us;
+        if due > now_us {
+            Check::Alive(due)
+        } else {
+            service.missed_at_us = Some(now_us);
+            Check::Missed {
+                name: service.name.clone(),
+                pid: service.pid,
+            }
+        }
+    }
+
+    /// `true` if `pid` may become the supervisor: there is none, or it is `pid`
+    /// replacing its own registration
+    pub fn may_supervise(&self, pid: Option<xous::PID>) -> bool {
+        self.supervisor
+            .as_ref()
+            .map(|current| current.pid == pid)
+            .unwrap_or(true)
+    }
+
+    /// Make `supervisor` the process that is told about missed deadlines, returning the
+    /// registration that it replaces. Check `may_supervise()` first.
+    pub fn set_supervisor(&mut self, supervisor: Supervisor) -> Option<Supervisor> {
+        self.supervisor.replace(supervisor)
+    }
+
+    pub fn supervisor(&self) -> Option<&Supervisor> {
+        self.supervisor.as_ref()
+    }
+
+    /// Forget a supervisor that can no longer be reached
+    pub fn remove_supervisor(&mut self) -> Option<Supervisor> {
+        self.supervisor.take()
+    }
+
+    /// Forget the services of `pid` once it has terminated, returning its supervisor
+    /// registration if it was the supervisor. Checks that are still scheduled for its
+    /// services find nothing when they fire.
+    pub fn remove_process(&mut self, pid: xous::PID) -> Option<Supervisor> {
+        self.services.retain(|_, s| s.pid != Some(pid));
+        if self.may_supervise(Some(pid)) {
+            self.supervisor.take()
+        } else {
+            None
+        }
+    }
+
+    /// The number of critical services
+    pub fn count(&self) -> usize {
+        self.services.len()
+    }
+
+    /// `true` if the watchdog should still be pet: no service has missed its deadline,
+    /// or the supervisor is still within its grace period for all of them. A service
+    /// counts as having missed its deadline once it has passed, even if the check hasn't
+    /// run yet, so that a check that is held up doesn't keep the watchdog going.
+    pub fn healthy(&self, now_us: i64) -> bool {
+        self.services.values().all(|s| {
+            let due = s.last_beat_us + s.deadline_us;
+            let missed_at_us = s.missed_at_us.or(if due <= now_us { Some(due) } else { None });
+            match missed_at_us {
+                None => true,
+                Some(missed_at_us) => {
+                    self.supervisor.is_some() && now_us - missed_at_us < SUPERVISOR_GRACE_US
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pid(pid: u8) -> Option<xous::PID> {
+        xous::PID::new(pid)
+    }
+
+    fn supervisor(pid: Option<xous::PID>) -> Supervisor {
+        Supervisor {
+            pid,
+            cid: 9,
+            opcode: 1,
+        }
+    }
+
+    #[test]
+    fn heartbeats_keep_a_service_alive() {
+        let mut heartbeats = Heartbeats::default();
+        let id = heartbeats.register(pid(2), "gam".to_owned(), 1_000, 0);
+        assert!(matches!(heartbeats.check(id, 1_000), Check::Missed { .. }));
+
+        let id = heartbeats.register(pid(2), "gam".to_owned(), 1_000, 0);
+        assert_eq!(heartbeats.beat(id, pid(2), 600), None);
+        assert!(matches!(heartbeats.check(id, 1_000), Check::Alive(1_600)));
+        // Only the service itself can send its heartbeat
+        heartbeats.beat(id, pid(3), 1_500);
+        assert!(matches!(heartbeats.check(id, 1_600), Check::Missed { .. }));
+    }
+
+    #[test]
+    fn a_missed_deadline_stops_the_watchdog() {
+        let mut heartbeats = Heartbeats::default();
+        let id = heartbeats.register(pid(2), "gam".to_owned(), 1_000, 0);
+        assert!(heartbeats.healthy(900));
+        assert!(matches!(heartbeats.check(id, 1_000), Check::Missed { .. }));
+        assert!(!heartbeats.healthy(1_000));
+        // A missed service has no check scheduled, so the heartbeat schedules one
+        assert_eq!(heartbeats.beat(id, pid(2), 2_000), Some(3_000));
+        assert!(heartbeats.healthy(2_000));
+    }
+
+    #[test]
+    fn a_late_check_doesnt_keep_the_watchdog_going() {
+        let mut heartbeats = Heartbeats::default();
+        heartbeats.register(pid(2), "gam".to_owned(), 1_000, 0);
+        assert!(heartbeats.healthy(999));
+        // The check that was due at 1_000 hasn't run
+        assert!(!heartbeats.healthy(1_000));
+
+        heartbeats.set_supervisor(supervisor(pid(5)));
+        assert!(heartbeats.healthy(1_000 + SUPERVISOR_GRACE_US - 1));
+        assert!(!heartbeats.healthy(1_000 + SUPERVISOR_GRACE_US));
+    }
+
+    #[test]
+    fn the_supervisor_gets_a_grace_period() {
+        let mut heartbeats = Heartbeats::default();
+        heartbeats.set_supervisor(supervisor(pid(5)));
+        let id = heartbeats.register(pid(2), "gam".to_owned(), 1_000, 0);
+        assert!(matches!(heartbeats.check(id, 1_000), Check::Missed { .. }));
+        assert!(heartbeats.healthy(1_000 + SUPERVISOR_GRACE_US - 1));
+        assert!(!heartbeats.healthy(1_000 + SUPERVISOR_GRACE_US));
+    }
+
+    #[test]
+    fn only_the_first_supervisor_may_supervise() {
+        let mut heartbeats = Heartbeats::default();
+        assert!(heartbeats.may_supervise(pid(5)));
+        assert!(heartbeats.set_supervisor(supervisor(pid(5))).is_none());
+        assert!(!heartbeats.may_supervise(pid(6)));
+        assert!(heartbeats.may_supervise(pid(5)));
+        assert_eq!(heartbeats.remove_supervisor().map(|s| s.pid), Some(pid(5)));
+        assert!(heartbeats.may_supervise(pid(6)));
+    }
+
+    #[test]
+    fn the_supervisor_only_unregisters_missed_services() {
+        let mut heartbeats = Heartbeats::default();
+        heartbeats.set_supervisor(supervisor(pid(5)));
+        let id = heartbeats.register(pid(2), "gam".to_owned(), 1_000, 0);
+        assert!(!heartbeats.unregister(id, pid(5)));
+        assert!(!heartbeats.unregister(id, pid(3)));
+        assert!(matches!(heartbeats.check(id, 1_000), Check::Missed { .. }));
+        assert!(heartbeats.unregister(id, pid(5)));
+        assert!(heartbeats.healthy(1_000));
+
+        let id = heartbeats.register(pid(2), "gam".to_owned(), 1_000, 0);
+        assert!(heartbeats.unregister(id, pid(2)));
+        assert_eq!(heartbeats.count(), 0);
+    }
+
+    #[test]
+    fn services_go_away_with_their_process() {
+        let mut heartbeats = Heartbeats::default();
+        heartbeats.set_supervisor(supervisor(pid(5)));
+        let a = heartbeats.register(pid(2), "gam".to_owned(), 1_000, 0);
+        let b = heartbeats.register(pid(3), "net".to_owned(), 1_000, 0);
+        assert!(heartbeats.remove_process(pid(2).unwrap()).is_none());
+        assert!(matches!(heartbeats.check(a, 1_000), Check::Gone));
+        assert!(matches!(heartbeats.check(b, 1_000), Check::Missed { .. }));
+        assert_eq!(
+            heartbeats.remove_process(pid(5).unwrap()).map(|s| s.cid),
+            Some(9)
+        );
+        assert!(heartbeats.supervisor().is_none());
+    }
+}
@@ -3,6 +3,12 @@
 #![cfg_attr(target_os = "none", no_main)]
 
 use xous_api_ticktimer::*;
+mod conns;
+mod diag;
+mod heartbeat;
+mod sync;
+mod timers;
+mod wallclock;
 #[cfg(feature = "timestamp")]
 mod version;
 
@@ -10,17 +16,30 @@ use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
 
 use log::{error, info};
 
+/// A time since boot, in microseconds
 type TimeoutExpiry = i64;
 
 #[derive(PartialEq, Eq, PartialOrd, Ord, Copy, Clone)]
 pub enum RequestKind {
     Sleep = 0,
     Timeout = 1,
+    /// A timer subscription, which has no blocked sender to respond to
+    Subscription = 2,
+    /// A check that a critical service has sent a heartbeat in time, which also has no
+    /// blocked sender
+    Heartbeat = 3,
+}
+
+impl RequestKind {
+    /// Whether a thread is blocked until this request expires
+    pub fn has_sender(&self) -> bool {
+        matches!(self, RequestKind::Sleep | RequestKind::Timeout)
+    }
 }
 
 #[derive(Eq)]
 pub struct TimerRequest {
-    msec: TimeoutExpiry,
+    usec: TimeoutExpiry,
     sender: xous::MessageSender,
     kind: RequestKind,
     data: usize,
@@ -28,21 +47,21 @@ pub struct TimerRequest {
 
 impl core::fmt::Display for TimerRequest {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
-        write!(f, "TimerRequest {{ msec: {}, {} }}", self.msec, self.sender)
+        write!(f, "TimerRequest {{ usec: {}, {} }}", self.usec, self.sender)
     }
 }
 
 impl core::fmt::Debug for TimerRequest {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
-        write!(f, "TimerRequest {{ msec: {}, {} }}", self.msec, self.sender)
+        write!(f, "TimerRequest {{ usec: {}, {} }}", self.usec, self.sender)
     }
 }
 
 impl core::cmp::Ord for TimerRequest {
     fn cmp(&self, other: &Self) -> core::cmp::Ordering {
-        if self.msec < other.msec {
+        if self.usec < other.usec {
             core::cmp::Ordering::Less
-        } else if self.msec > other.msec {
+        } else if self.usec > other.usec {
             core::cmp::Ordering::Greater
         } else {
             self.sender.cmp(&other.sender)
@@ -58,16 +77,45 @@ impl core::cmp::PartialOrd for TimerRequest {
 
 impl core::cmp::PartialEq for TimerRequest {
     fn eq(&self, other: &Self) -> bool {
-        self.msec == other.msec && self.sender == other.sender
+        self.usec == other.usec && self.sender == other.sender
+    }
+}
+
+/// Tell the main loop that `request` has expired by sending it a `RecalculateSleep` message
+/// with `notify`, then respond to the request's sender. This runs in the interrupt handler,
+/// or in the timer thread when hosted, and our own queue may be full. If the message can't
+/// be sent, nobody is responded to and the request is handed back, so that it can be kept
+/// until `stop_sleep()` puts it back in the sleep heap.
+fn fire(
+    request: TimerRequest,
+    notify: impl FnOnce(xous::Message) -> Result<xous::Result, xous::Error>,
+) -> Option<TimerRequest> {
+    use num_traits::ToPrimitive;
+    let message = xous::Message::Scalar(xous::ScalarMessage {
+        id: api::Opcode::RecalculateSleep.to_usize().unwrap(),
+        arg1: request.sender.to_usize(),
+        arg2: request.kind as usize,
+        arg3: request.data,
+        arg4: 0,
+    });
+    if notify(message).is_err() {
+        return Some(request);
+    }
+    if request.kind.has_sender() {
+        xous::return_scalar(request.sender, request.kind as usize)
+            .expect("couldn't send response");
     }
+    None
 }
 
 #[cfg(any(feature = "precursor", feature = "renode", feature="cramium-fpga", feature="cramium-soc"))]
 mod implementation {
+    // The Precursor gateware divides the system clock down to a fixed 1 ms tick
     #[cfg(feature="precursor")]
     const TICKS_PER_MS: u64 = 1;
+    // Cramium has a programmable divider, which `set_tick_rate()` sets to a 1 us tick
     #[cfg(any(feature="cramium-fpga", feature="cramium-soc"))]
-    const TICKS_PER_MS: u64 = 1;
+    const TICKS_PER_MS: u64 = 1000;
     use super::TimerRequest;
     #[cfg(features="susres")]
     use susres::{RegManager, RegOrField, SuspendResume};
@@ -92,27 +140,16 @@ mod implementation {
         // Safe because we're in an interrupt, and this interrupt is only
         // enabled when this value is not None.
         let response = xtt.current_response.take().unwrap();
-        xous::return_scalar(response.sender, response.kind as usize)
-            .expect("couldn't send response");
 
         // Disable the timer
         xtt.csr.wfo(utra::ticktimer::EV_ENABLE_ALARM, 0);
         xtt.csr.wfo(utra::ticktimer::EV_PENDING_ALARM, 1);
 
-        // This is dangerous and may return an error if the queue is full.
-        // Which is fine, because the queue is always recalculated any time a message arrives.
-        use num_traits::ToPrimitive;
-        xous::try_send_message(
-            xtt.connection,
-            xous::Message::Scalar(xous::ScalarMessage {
-                id: crate::api::Opcode::RecalculateSleep.to_usize().unwrap(),
-                arg1: response.sender.to_usize(),
-                arg2: response.kind as usize,
-                arg3: response.data,
-                arg4: 0,
-            }),
-        )
-        .ok();
+        // If our queue is full, hold on to the request with the timer disabled. The main
+        // loop notices with `missed()` and schedules it again.
+        let connection = xtt.connection;
+        xtt.current_response =
+            super::fire(response, |message| xous::try_send_message(connection, message));
     }
 
     impl XousTickTimer {
@@ -181,9 +218,23 @@ mod implementation {
 
         pub fn reset(&mut self) {
             self.csr.wfo(utra::ticktimer::CONTROL_RESET, 0b1);
+            self.set_tick_rate();
             self.csr.wo(utra::ticktimer::CONTROL, 0); // not paused, not reset -> free-run
         }
 
+        /// The divider resets to a 1 ms tick, which is too coarse for `elapsed_us()` and
+        /// `SleepUs`. Program it for `TICKS_PER_MS` ticks per millisecond instead.
+        #[cfg(any(feature="cramium-fpga", feature="cramium-soc"))]
+        fn set_tick_rate(&mut self) {
+            self.csr.wo(
+                utra::ticktimer::CLOCKS_PER_TICK,
+                (LITEX_CONFIG_CLOCK_FREQUENCY as u64 / (TICKS_PER_MS * 1000)) as u32,
+            );
+        }
+
+        #[cfg(not(any(feature="cramium-fpga", feature="cramium-soc")))]
+        fn set_tick_rate(&mut self) {}
+
         pub fn raw_ticktime(&self) -> u64 {
             let mut time: u64 = self.csr.r(utra::ticktimer::TIME0) as u64;
             time |= (self.csr.r(utra::ticktimer::TIME1) as u64) << 32;
@@ -195,6 +246,18 @@ mod implementation {
             self.raw_ticktime() / TICKS_PER_MS
         }
 
+        /// The resolution of this is one tick of the hardware timer: a microsecond on
+        /// Cramium, but a millisecond on Precursor, where this is always a multiple of 1000.
+        pub fn elapsed_us(&self) -> u64 {
+            self.raw_ticktime() * 1000 / TICKS_PER_MS
+        }
+
+        /// Whether a request expired while our queue was full, and is waiting to be
+        /// put back in the sleep heap by `stop_interrupt()`
+        pub fn missed(&self) -> bool {
+            self.csr.rf(utra::ticktimer::EV_ENABLE_ALARM) == 0 && self.current_response.is_some()
+        }
+
         pub fn stop_interrupt(&mut self) -> Option<TimerRequest> {
             // Disable the timer
             self.csr.wfo(utra::ticktimer::EV_ENABLE_ALARM, 0);
@@ -206,9 +269,9 @@ mod implementation {
                 #[cfg(feature = "debug-print")]
                 {
                     log::info!(
-                        "Stopping currently-running timer sr.msec: {}  elapsed_ms: {}",
-                        sr.msec,
-                        self.elapsed_ms()
+                        "Stopping currently-running timer sr.usec: {}  elapsed_us: {}",
+                        sr.usec,
+                        self.elapsed_us()
                     );
                 }
                 Some(sr)
@@ -218,11 +281,12 @@ mod implementation {
         }
 
         pub fn schedule_response(&mut self, request: TimerRequest) {
-            let irq_target = request.msec;
+            // Round up to the next tick, so that the response is never early
+            let irq_target = (request.usec as u64 * TICKS_PER_MS + 999) / 1000;
             log::trace!(
-                "setting a response at {} ms (current time: {} ms)",
-                irq_target,
-                self.elapsed_ms()
+                "setting a response at {} us (current time: {} us)",
+                request.usec,
+                self.elapsed_us()
             );
 
             // Disable the timer interrupt
@@ -284,6 +348,9 @@ mod implementation {
                 self.wdt.wfo(utra::wdt::WATCHDOG_ENABLE, 1);
             }
 
+            // the divider isn't saved by the susres manager, so program it again
+            self.set_tick_rate();
+
             // manually clear any pending ticktimer events. This is mainly releveant for a "touch-and-go" simulated suspend.
             self.csr.wfo(utra::ticktimer::EV_PENDING_ALARM, 1);
 
@@ -310,31 +377,29 @@ mod implementation {
     not(any(feature = "precursor", feature = "renode", feature="cramium-fpga", feature="cramium-soc", not(target_os = "xous")))
 ))]
 mod implementation {
-    use crate::RequestKind;
-
     use super::TimerRequest;
-    use num_traits::ToPrimitive;
     use std::convert::TryInto;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
 
     #[derive(Debug)]
     enum SleepComms {
         InterruptSleep,
-        StartSleep(
-            xous::MessageSender,
-            i64, /* ms */
-            u64, /* elapsed */
-        ),
+        StartSleep(TimerRequest, u64 /* elapsed */),
     }
     pub struct XousTickTimer {
         start: std::time::Instant,
         sleep_comms: std::sync::mpsc::Sender<SleepComms>,
         time_remaining_receiver: std::sync::mpsc::Receiver<Option<TimerRequest>>,
+        missed: Arc<AtomicBool>,
     }
 
     impl XousTickTimer {
         pub fn new(cid: xous::CID) -> XousTickTimer {
             let (sleep_sender, sleep_receiver) = std::sync::mpsc::channel();
             let (time_remaining_sender, time_remaining_receiver) = std::sync::mpsc::channel();
+            let missed = Arc::new(AtomicBool::new(false));
+            let thread_missed = missed.clone();
             xous::create_thread(move || {
                 let mut timeout = None;
                 let mut current_response: Option<TimerRequest> = None;
@@ -349,22 +414,14 @@ mod implementation {
                         Err(std::sync::mpsc::RecvTimeoutError::Timeout) => {
                             let response = current_response.take().unwrap();
                             #[cfg(feature = "debug-print")]
-                            log::info!("Returning scalar to {}", response.sender);
-                            xous::return_scalar(response.sender, response.kind as usize)
-                                .expect("couldn't send response");
-
-                            // This is dangerous and may panic if the queue is full.
-                            xous::try_send_message(
-                                cid,
-                                xous::Message::Scalar(xous::ScalarMessage {
-                                    id: crate::api::Opcode::RecalculateSleep.to_usize().unwrap(),
-                                    arg1: response.sender.to_usize(),
-                                    arg2: response.kind as usize,
-                                    arg3: response.data,
-                                    arg4: 0,
-                                }),
-                            )
-                            .unwrap();
+                            log::info!("Request for {} expired", response.sender);
+
+                            // If our queue is full, hold on to the request until the main
+                            // loop notices with `missed()` and schedules it again.
+                            current_response = crate::fire(response, |message| {
+                                xous::try_send_message(cid, message)
+                            });
+                            thread_missed.store(current_response.is_some(), Ordering::Relaxed);
                             timeout = None;
                         }
                         Err(std::sync::mpsc::RecvTimeoutError::Disconnected) => {
@@ -372,35 +429,31 @@ mod implementation {
                         }
                         Ok(SleepComms::InterruptSleep) => {
                             timeout = None;
+                            thread_missed.store(false, Ordering::Relaxed);
                             time_remaining_sender.send(current_response.take()).unwrap()
                         }
-                        Ok(SleepComms::StartSleep(new_sender, expiry, elapsed)) => {
-                            let mut duration = expiry - (elapsed as i64);
+                        Ok(SleepComms::StartSleep(request, elapsed)) => {
+                            let mut duration = request.usec - (elapsed as i64);
                             if duration > 0 {
                                 #[cfg(feature = "debug-print")]
                                 log::info!(
-                                    "Starting sleep for {} ms, returning to {}",
+                                    "Starting sleep for {} us, returning to {}",
                                     duration,
-                                    new_sender
+                                    request.sender
                                 );
                             } else {
                                 #[cfg(feature = "debug-print")]
                                 log::info!(
-                                    "Clamping duration to 0 (was: {})m returning to {}",
+                                    "Clamping duration to 0 (was: {})us returning to {}",
                                     duration,
-                                    new_sender
+                                    request.sender
                                 );
                                 duration = 0;
                             }
-                            timeout = Some(std::time::Duration::from_millis(
+                            timeout = Some(std::time::Duration::from_micros(
                                 duration.try_into().unwrap(),
                             ));
-                            current_response = Some(TimerRequest {
-                                sender: new_sender,
-                                msec: expiry,
-                                kind: RequestKind::Sleep,
-                                data: 0,
-                            });
+                            current_response = Some(request);
                         }
                     }
                 }
@@ -411,6 +464,7 @@ mod implementation {
                 start: std::time::Instant::now(),
                 time_remaining_receiver,
                 sleep_comms: sleep_sender,
+                missed,
             }
         }
 
@@ -422,6 +476,16 @@ mod implementation {
             self.start.elapsed().as_millis().try_into().unwrap()
         }
 
+        pub fn elapsed_us(&self) -> u64 {
+            self.start.elapsed().as_micros().try_into().unwrap()
+        }
+
+        /// Whether a request expired while our queue was full, and is waiting to be
+        /// put back in the sleep heap by `stop_interrupt()`
+        pub fn missed(&self) -> bool {
+            self.missed.load(Ordering::Relaxed)
+        }
+
         pub fn stop_interrupt(&mut self) -> Option<TimerRequest> {
             self.sleep_comms.send(SleepComms::InterruptSleep).unwrap();
             self.time_remaining_receiver.recv().unwrap()
@@ -430,17 +494,14 @@ mod implementation {
         pub fn schedule_response(&mut self, request: TimerRequest) {
             #[cfg(feature = "debug-print")]
             log::info!(
-                "request.msec: {}  self.elapsed_ms: {}  returning to: {}",
-                request.msec,
-                self.elapsed_ms(),
+                "request.usec: {}  self.elapsed_us: {}  returning to: {}",
+                request.usec,
+                self.elapsed_us(),
                 request.sender
             );
+            let elapsed = self.elapsed_us();
             self.sleep_comms
-                .send(SleepComms::StartSleep(
-                    request.sender,
-                    request.msec as i64,
-                    self.elapsed_ms(),
-                ))
+                .send(SleepComms::StartSleep(request, elapsed))
                 .unwrap();
         }
 
@@ -469,31 +530,55 @@ fn stop_sleep(
     ticktimer: &mut XousTickTimer,
     sleep_heap: &mut BTreeMap<TimeoutExpiry, TimerRequest>, // min-heap with Reverse
 ) {
-    // If there's a sleep request ongoing now, grab it.
+    // If there's a sleep request ongoing now, or one that expired while our queue was
+    // full, grab it.
     if let Some(current) = ticktimer.stop_interrupt() {
         #[cfg(feature = "debug-print")]
         info!("Existing request was {:?}", current);
-        sleep_heap.insert(current.msec, current);
+        requeue(sleep_heap, current);
     } else {
         #[cfg(feature = "debug-print")]
         info!("There was no existing sleep() request");
     }
 }
 
+/// Put a request that was taken out of the sleep heap back in. A request with the same
+/// expiry may have been added in the meantime, so move this one along until it fits.
+fn requeue(sleep_heap: &mut BTreeMap<TimeoutExpiry, TimerRequest>, mut request: TimerRequest) {
+    while sleep_heap.contains_key(&request.usec) {
+        request.usec += 1;
+    }
+    sleep_heap.insert(request.usec, request);
+}
+
 fn start_sleep(
     ticktimer: &mut XousTickTimer,
     sleep_heap: &mut BTreeMap<TimeoutExpiry, TimerRequest>, // min-heap with Reverse
 ) {
+    // Wake every sleeper that is already due in one pass. Otherwise each of them costs
+    // an interrupt and a `RecalculateSleep` message to ourselves, which adds up quickly
+    // when many short sleeps expire within the same tick.
+    let now = ticktimer.elapsed_us() as i64;
+    let due: Vec<TimeoutExpiry> = sleep_heap
+        .range(..=now)
+        .filter(|(_, request)| request.kind == RequestKind::Sleep)
+        .map(|(usec, _)| *usec)
+        .collect();
+    for usec in due {
+        let request = sleep_heap.remove(&usec).unwrap();
+        xous::return_scalar(request.sender, request.kind as usize).expect("couldn't send response");
+    }
+
     // If there are items in the sleep heap, take the next item that will expire.
     // TODO: Replace this with `.min()` when it's stabilized:
     // https://github.com/rust-lang/rust/issues/62924
-    let next_timeout_msec = sleep_heap.iter().min().map(|(msec, _)| *msec);
-    if let Some(msec) = next_timeout_msec {
-        let next_response = sleep_heap.remove(&msec).unwrap();
+    let next_timeout_usec = sleep_heap.iter().min().map(|(usec, _)| *usec);
+    if let Some(usec) = next_timeout_usec {
+        let next_response = sleep_heap.remove(&usec).unwrap();
         #[cfg(feature = "debug-print")]
         info!(
             "scheduling a response at {} to {} (heap: {:?})",
-            next_response.msec, next_response.sender, sleep_heap
+            next_response.usec, next_response.sender, sleep_heap
         );
         ticktimer.schedule_response(next_response);
     } else {
@@ -505,6 +590,24 @@ fn start_sleep(
     }
 }
 
+/// Respond to a thread that was waiting on a semaphore or a lock, and has now been
+/// granted it. Returns `false` if the thread's timeout has already fired, in which case
+/// it has been responded to already. The sleep timer must be stopped.
+fn wake_waiter(
+    sleep_heap: &mut BTreeMap<TimeoutExpiry, TimerRequest>,
+    waiter: &sync::Waiter,
+) -> bool {
+    if waiter.timeout {
+        let pending = sleep_heap.len();
+        sleep_heap.retain(|_, v| v.sender != waiter.sender);
+        if sleep_heap.len() == pending {
+            return false;
+        }
+    }
+    xous::return_scalar(waiter.sender, 0).expect("couldn't send response");
+    true
+}
+
 /// Recalculate the sleep timer, optionally adding a new Request to the list of available
 /// sleep events. This involves stopping the timer, recalculating the newest item, then
 /// restarting the timer.
@@ -524,14 +627,14 @@ fn recalculate_sleep(
         info!("New sleep request was: {:?}", request);
 
         // Ensure that each timeout only exists once inside the tree
-        request.msec += ticktimer.elapsed_ms() as i64;
-        while sleep_heap.contains_key(&request.msec) {
-            request.msec += 1;
+        request.usec += ticktimer.elapsed_us() as i64;
+        while sleep_heap.contains_key(&request.usec) {
+            request.usec += 1;
         }
 
         #[cfg(feature = "debug-print")]
         info!("Modified, the request was: {:?}", request);
-        sleep_heap.insert(request.msec, request);
+        sleep_heap.insert(request.usec, request);
     } else {
         #[cfg(feature = "debug-print")]
         info!("No new sleep request");
@@ -540,6 +643,960 @@ fn recalculate_sleep(
     start_sleep(ticktimer, sleep_heap);
 }
 
+/// The number of threads waiting in `mutex_hash` or `notify_hash`
+fn count_waiters(
+    hash: &HashMap<Option<xous::PID>, HashMap<usize, VecDeque<xous::MessageSender>>>,
+) -> u32 {
+    hash.values()
+        .flat_map(|queues| queues.values())
+        .map(|queue| queue.len() as u32)
+        .sum()
+}
+
+/// Ask the name server which processes are trusted, and hand the answer to the main
+/// loop. This runs on its own thread, because the name server may not be running yet,
+/// and the ticktimer has to keep answering requests in the meantime.
+fn fetch_trusted_processes(ticktimer_client: usize) {
+    use num_traits::ToPrimitive;
+    let xns = xous_names::XousNames::new().unwrap();
+    let (lo, hi) = xns
+        .trusted_processes()
+        .expect("couldn't get the trusted processes")
+        .to_words();
+    xous::send_message(
+        ticktimer_client as xous::CID,
+        xous::Message::new_scalar(
+            api::Opcode::TrustedProcesses.to_usize().unwrap(),
+            lo,
+            hi,
+            0,
+            0,
+        ),
+    )
+    .expect("couldn't hand over the trusted processes");
+}
+
+/// Everything that the Ticktimer keeps track of. The messages that
+/// `api::dispatch_ticktimer_api()` decodes end up in the `api::TicktimerApi` methods below,
+/// and the remaining opcodes are handled in the main loop.
+struct Server {
+    ticktimer: XousTickTimer,
+
+    // A list of all sleep requests in the system, sorted by the time at which it
+    // expires. That is, if a request comes in to sleep for 1000 ms, and the ticktimer
+    // is currently at 900, the Request will be `1900`.
+    sleep_heap: BTreeMap<TimeoutExpiry, TimerRequest>,
+
+    // A list of message IDs that are waiting to receive a Notification. This queue is drained
+    // by threads sending `NotifyCondition` to us, or by a condvar timing out.
+    notify_hash: HashMap<Option<xous::PID>, HashMap<usize, VecDeque<xous::MessageSender>>>,
+
+    // There is a small chance that a client sends a `notify_one()` or `notify_all()` before
+    // the other threads have a chance to recover. This is due to a non-threadsafe use of
+    // Mutex<T> within the standard library. Keep track of any excess `notify_one()` or
+    // `notify_all()` messages for when the Mutex<T> is successfully locked.
+    immedaite_notifications: HashMap<Option<xous::PID>, HashMap<usize, usize>>,
+
+    // A list of mutexes that should be allowed to run immediately, because the
+    // thread they were waiting on has already unlocked the mutex. This occurs
+    // when a thread attempts to Lock a Mutex and fails, then gets preempted
+    // before it has a chance to send the `LockMutex` message to us.
+    mutex_ready_hash: HashMap<Option<xous::PID>, HashSet<usize>>,
+
+    // A list of message IDs that are waiting to lock a Mutex. These are processes
+    // that have attempted to lock a Mutex and failed, and have sent us the `LockMutex`
+    // message. This queue is drained by threads sending `UnlockMutex` to us.
+    mutex_hash: HashMap<Option<xous::PID>, HashMap<usize, VecDeque<xous::MessageSender>>>,
+
+    // Owners of contended Mutexes and the threads waiting on Mutexes and condvars, which
+    // are reported by `LockDiagnostics`.
+    lock_diagnostics: diag::LockDiagnostics,
+
+    // Counting semaphores and reader-writer locks, indexed by process and address. Their
+    // timeouts are scheduled in `sleep_heap` as `Timeout` requests, with the address as data.
+    semaphores: HashMap<Option<xous::PID>, HashMap<usize, sync::Semaphore>>,
+    rwlocks: HashMap<Option<xous::PID>, HashMap<usize, sync::RwLock>>,
+
+    // Timers that send a message to a server when they expire, indexed by timer ID.
+    // Their expiries are scheduled in `sleep_heap` alongside the sleepers.
+    timers: timers::Timers,
+
+    // Connections to the servers that timers send their messages to
+    conns: conns::Connections,
+
+    // Wall-clock time and the system time zone
+    wall_clock: wallclock::WallClock,
+
+    // The processes that may set the time, which nobody may do until the name server
+    // has told us who they are
+    trusted: xous_names::ProcessSet,
+
+    // Critical services, which must keep sending heartbeats for the watchdog to be pet.
+    // Their deadlines are checked by `Heartbeat` requests in `sleep_heap`.
+    heartbeats: heartbeat::Heartbeats,
+
+    // The number of messages handled, which is reported by `Stats`
+    messages: u64,
+}
+
+impl Server {
+    fn new(ticktimer: XousTickTimer) -> Server {
+        // Don't let a timer close one of the connections that we need ourselves.
+        let mut conns = conns::Connections::default();
+        conns.pin(xous::SID::from_bytes(b"ticktimer-server").unwrap());
+        conns.pin(xous::SID::from_bytes(b"xous-log-server ").unwrap());
+        conns.pin(xous::SID::from_bytes(b"xous-name-server").unwrap());
+
+        Server {
+            ticktimer,
+            sleep_heap: BTreeMap::new(),
+            notify_hash: HashMap::new(),
+            immedaite_notifications: HashMap::new(),
+            mutex_ready_hash: HashMap::new(),
+            mutex_hash: HashMap::new(),
+            lock_diagnostics: diag::LockDiagnostics::default(),
+            semaphores: HashMap::new(),
+            rwlocks: HashMap::new(),
+            timers: timers::Timers::default(),
+            conns,
+            wall_clock: wallclock::WallClock::default(),
+            trusted: xous_names::ProcessSet::default(),
+            heartbeats: heartbeat::Heartbeats::default(),
+            messages: 0,
+        }
+    }
+
+    fn is_trusted(&self, pid: Option<xous::PID>) -> bool {
+        pid.map(|pid| self.trusted.contains(pid)).unwrap_or(false)
+    }
+
+    /// Handle a `RecalculateSleep` message from our own interrupt handler, which says that
+    /// the request in `args` has expired.
+    fn expired(&mut self, args: &xous::ScalarMessage) {
+        // If this is a Timeout message that fired, remove it from the Notification list
+        let sender = args.arg1;
+        let request_kind = args.arg2;
+        let condvar = args.arg3;
+        let sender_pid = xous::MessageSender::from_usize(sender).pid();
+
+        // If we're being asked to recalculate due to a timeout expiring, drop the sent
+        // message from the `entries` list.
+        if (request_kind == RequestKind::Timeout as usize) && (sender > 0) {
+            let entries = self
+                .notify_hash
+                .entry(sender_pid)
+                .or_default()
+                .entry(condvar)
+                .or_default();
+            let mut idx = None;
+            for (i, val) in entries.iter().enumerate() {
+                if val.to_usize() == sender {
+                    idx = Some(i);
+                    break;
+                }
+            }
+            if let Some(idx) = idx {
+                entries.remove(idx);
+                self.lock_diagnostics
+                    .woken(xous::MessageSender::from_usize(sender));
+            }
+            // log::trace!("new entries for PID {:?}/condvar {:08x}: {:?}", sender_pid, condvar, notify_hash.get(&sender_pid).unwrap().get(&condvar));
+
+            // The timeout may instead belong to a semaphore or a reader-writer lock
+            let waiter = xous::MessageSender::from_usize(sender);
+            if let Some(semaphore) = self
+                .semaphores
+                .get_mut(&sender_pid)
+                .and_then(|s| s.get_mut(&condvar))
+            {
+                semaphore.remove(waiter);
+            }
+            if let Some(lock) = self
+                .rwlocks
+                .get_mut(&sender_pid)
+                .and_then(|l| l.get_mut(&condvar))
+            {
+                stop_sleep(&mut self.ticktimer, &mut self.sleep_heap);
+                let sleep_heap = &mut self.sleep_heap;
+                lock.remove(waiter, |w| wake_waiter(sleep_heap, w));
+            }
+        }
+
+        // If a timer subscription expired, send its message and schedule the next expiry.
+        if request_kind == RequestKind::Subscription as usize {
+            let id = args.arg3 as u32;
+            let now = self.ticktimer.elapsed_us() as i64;
+            // A timer that was rescheduled after this expiry was queued is not due yet
+            if let Some(timer) = self.timers.due(id, now) {
+                let expiry_ms = timer.expiry as u64 / 1000;
+                let delivered = xous::try_send_message(
+                    timer.cid,
+                    xous::Message::new_scalar(
+                        timer.opcode,
+                        id as usize,
+                        (expiry_ms & 0xFFFF_FFFF) as usize,
+                        (expiry_ms >> 32) as usize,
+                        0,
+                    ),
+                );
+                let next = match delivered {
+                    Err(xous::Error::ServerQueueFull) => {
+                        log::trace!("queue for timer {} is full, dropping expiry", id);
+                        self.timers.expired(id, now)
+                    }
+                    Err(e) => {
+                        log::warn!("timer {} can't reach its server ({:?}), cancelling", id, e);
+                        self.timers.remove(id).map(timers::Expired::Done)
+                    }
+                    Ok(_) => self.timers.expired(id, now),
+                };
+                match next {
+                    Some(timers::Expired::Next(expiry)) => {
+                        recalculate_sleep(
+                            &mut self.ticktimer,
+                            &mut self.sleep_heap,
+                            Some(TimerRequest {
+                                usec: expiry - now,
+                                sender: xous::MessageSender::from_usize(0),
+                                kind: RequestKind::Subscription,
+                                data: id as usize,
+                            }),
+                        );
+                        return;
+                    }
+                    Some(timers::Expired::Done(timer)) => self.conns.release(timer.cid),
+                    None => (),
+                }
+            }
+        }
+
+        // If a critical service is due, make sure that it sent a heartbeat in time
+        if request_kind == RequestKind::Heartbeat as usize {
+            let id = args.arg3 as u32;
+            let now = self.ticktimer.elapsed_us() as i64;
+            match self.heartbeats.check(id, now) {
+                heartbeat::Check::Gone => (),
+                heartbeat::Check::Alive(due) => {
+                    recalculate_sleep(
+                        &mut self.ticktimer,
+                        &mut self.sleep_heap,
+                        Some(TimerRequest {
+                            usec: due - now,
+                            sender: xous::MessageSender::from_usize(0),
+                            kind: RequestKind::Heartbeat,
+                            data: id as usize,
+                        }),
+                    );
+                    return;
+                }
+                heartbeat::Check::Missed { name, pid } => {
+                    let grace_ms = heartbeat::SUPERVISOR_GRACE_US as usize / 1000;
+                    let notified = self.heartbeats.supervisor().map(|supervisor| {
+                        xous::try_send_message(
+                            supervisor.cid,
+                            xous::Message::new_scalar(
+                                supervisor.opcode,
+                                id as usize,
+                                pid.map(|p| p.get() as usize).unwrap_or(0),
+                                grace_ms,
+                                0,
+                            ),
+                        )
+                    });
+                    match notified {
+                        Some(Ok(_)) => error!(
+                            "{} missed its heartbeat deadline, the watchdog stops in {} ms",
+                            name, grace_ms
+                        ),
+                        Some(Err(e)) => {
+                            error!(
+                                "{} missed its heartbeat deadline, and the supervisor can't be reached ({:?})",
+                                name, e
+                            );
+                            if let Some(supervisor) = self.heartbeats.remove_supervisor() {
+                                self.conns.release(supervisor.cid);
+                            }
+                        }
+                        None => error!(
+                            "{} missed its heartbeat deadline, the watchdog is stopped",
+                            name
+                        ),
+                    }
+                }
+            }
+        }
+        recalculate_sleep(&mut self.ticktimer, &mut self.sleep_heap, None);
+    }
+
+    /// Forget what a process that has terminated left behind
+    fn process_terminated(&mut self, pid: xous::PID) {
+        // The PID may be reused by a process that isn't trusted
+        self.trusted.remove(pid);
+        // Nobody is left to send the process's heartbeats
+        if let Some(supervisor) = self.heartbeats.remove_process(pid) {
+            self.conns.release(supervisor.cid);
+        }
+        // Nobody is left to cancel the process's timers, so drop them here
+        let removed = self.timers.remove_process(pid);
+        if !removed.is_empty() {
+            stop_sleep(&mut self.ticktimer, &mut self.sleep_heap);
+            self.sleep_heap.retain(|_, v| {
+                !(v.kind == RequestKind::Subscription
+                    && removed.iter().any(|(id, _)| v.data == *id as usize))
+            });
+            for (_, timer) in removed {
+                self.conns.release(timer.cid);
+            }
+            start_sleep(&mut self.ticktimer, &mut self.sleep_heap);
+        }
+        // The process's semaphores and reader-writer locks went with its memory, and its
+        // waiters can't be answered, so drop them along with the timeouts of those waiters
+        self.semaphores.remove(&Some(pid));
+        self.rwlocks.remove(&Some(pid));
+        if self
+            .sleep_heap
+            .values()
+            .any(|v| v.kind == RequestKind::Timeout && v.sender.pid() == Some(pid))
+        {
+            stop_sleep(&mut self.ticktimer, &mut self.sleep_heap);
+            self.sleep_heap
+                .retain(|_, v| !(v.kind == RequestKind::Timeout && v.sender.pid() == Some(pid)));
+            start_sleep(&mut self.ticktimer, &mut self.sleep_heap);
+        }
+        self.lock_diagnostics.process_terminated(pid);
+    }
+
+    /// Take a semaphore unit, a read lock or the write lock, or queue the sender until it
+    /// is granted or `timeout` milliseconds have passed
+    fn acquire(
+        &mut self,
+        opcode: api::Opcode,
+        sender: xous::MessageSender,
+        address: usize,
+        timeout: usize,
+    ) -> Option<usize> {
+        let pid = sender.pid();
+        let waiter = sync::Waiter {
+            sender,
+            timeout: timeout != 0,
+        };
+
+        let acquired = if opcode == api::Opcode::AcquireSemaphore {
+            let semaphore = self
+                .semaphores
+                .entry(pid)
+                .or_default()
+                .entry(address)
+                .or_default();
+            let acquired = semaphore.try_acquire();
+            if !acquired {
+                semaphore.wait(waiter);
+            }
+            acquired
+        } else {
+            let write = opcode == api::Opcode::WriteLock;
+            let lock = self
+                .rwlocks
+                .entry(pid)
+                .or_default()
+                .entry(address)
+                .or_default();
+            let acquired = if write {
+                lock.try_write()
+            } else {
+                lock.try_read()
+            };
+            if !acquired {
+                lock.wait(waiter, write);
+            }
+            acquired
+        };
+
+        if acquired {
+            return Some(0);
+        }
+
+        if timeout != 0 {
+            recalculate_sleep(
+                &mut self.ticktimer,
+                &mut self.sleep_heap,
+                Some(TimerRequest {
+                    usec: timeout as i64 * 1000,
+                    sender,
+                    kind: RequestKind::Timeout,
+                    data: address,
+                }),
+            )
+        }
+
+        // The sender is responded to when the semaphore or lock is granted, or when it
+        // times out.
+        None
+    }
+
+    /// Return semaphore units, or release a read lock or the write lock, and wake the
+    /// waiters that can now go ahead
+    fn release(
+        &mut self,
+        opcode: api::Opcode,
+        sender: xous::MessageSender,
+        address: usize,
+        count: usize,
+    ) {
+        let pid = sender.pid();
+
+        // Stop the timer so that waiters whose timeout has fired can be told apart
+        stop_sleep(&mut self.ticktimer, &mut self.sleep_heap);
+        let sleep_heap = &mut self.sleep_heap;
+        let wake = |w: &sync::Waiter| wake_waiter(sleep_heap, w);
+        match opcode {
+            api::Opcode::ReleaseSemaphore => self
+                .semaphores
+                .entry(pid)
+                .or_default()
+                .entry(address)
+                .or_default()
+                .release(count, wake),
+            api::Opcode::ReadUnlock => self
+                .rwlocks
+                .entry(pid)
+                .or_default()
+                .entry(address)
+                .or_default()
+                .unlock_read(wake),
+            _ => self
+                .rwlocks
+                .entry(pid)
+                .or_default()
+                .entry(address)
+                .or_default()
+                .unlock_write(wake),
+        }
+        start_sleep(&mut self.ticktimer, &mut self.sleep_heap);
+    }
+
+    /// Set the wall clock or the time zone, which only trusted processes may do
+    fn set_time(&mut self, opcode: api::Opcode, pid: Option<xous::PID>, value: u64) -> usize {
+        let result = if !self.is_trusted(pid) {
+            log::warn!(
+                "process {:?} isn't trusted to make a {:?} request",
+                pid,
+                opcode
+            );
+            xous::Error::AccessDenied
+        } else if opcode == api::Opcode::SetUtcMs {
+            self.wall_clock
+                .set_utc_ms(value, self.ticktimer.elapsed_ms());
+            info!("wall clock set to {} ms", value);
+            xous::Error::NoError
+        } else if self.wall_clock.set_zone(value as usize) {
+            xous::Error::NoError
+        } else {
+            xous::Error::InvalidString
+        };
+        result.to_usize()
+    }
+
+    /// Cancel a timer, or reschedule it to expire after `ms` milliseconds. Returns
+    /// `false` if the timer doesn't belong to `pid`.
+    fn change_timer(&mut self, pid: Option<xous::PID>, id: u32, ms: Option<i64>) -> bool {
+        if !self.timers.owned_by(id, pid) {
+            return false;
+        }
+
+        // Pull the pending expiry out of the schedule
+        stop_sleep(&mut self.ticktimer, &mut self.sleep_heap);
+        self.sleep_heap
+            .retain(|_, v| !(v.kind == RequestKind::Subscription && v.data == id as usize));
+        match ms {
+            None => {
+                if let Some(timer) = self.timers.remove(id) {
+                    self.conns.release(timer.cid);
+                }
+                start_sleep(&mut self.ticktimer, &mut self.sleep_heap);
+            }
+            Some(ms) => {
+                let now = self.ticktimer.elapsed_us() as i64;
+                let expiry = self.timers.reschedule(id, ms * 1000, now).unwrap();
+                recalculate_sleep(
+                    &mut self.ticktimer,
+                    &mut self.sleep_heap,
+                    Some(TimerRequest {
+                        usec: expiry - now,
+                        sender: xous::MessageSender::from_usize(0),
+                        kind: RequestKind::Subscription,
+                        data: id as usize,
+                    }),
+                );
+            }
+        }
+        true
+    }
+}
+
+impl api::TicktimerApi for Server {
+    fn elapsed_ms(&mut self, _sender: xous::MessageSender) -> u64 {
+        self.ticktimer.elapsed_ms()
+    }
+
+    fn sleep_ms(&mut self, sender: xous::MessageSender, ms: usize) -> Option<()> {
+        recalculate_sleep(
+            &mut self.ticktimer,
+            &mut self.sleep_heap,
+            Some(TimerRequest {
+                usec: ms as i64 * 1000,
+                sender,
+                kind: RequestKind::Sleep,
+                data: 0,
+            }),
+        );
+
+        // The sender is responded to when the sleep expires
+        None
+    }
+
+    fn ping_wdt(&mut self, _sender: xous::MessageSender) {
+        #[cfg(feature = "watchdog")]
+        if self.heartbeats.healthy(self.ticktimer.elapsed_us() as i64) {
+            self.ticktimer.reset_wdt();
+        }
+    }
+
+    fn lock_mutex(&mut self, sender: xous::MessageSender, mtx: usize) -> Option<()> {
+        let pid = sender.pid();
+        let ready = self.mutex_ready_hash.entry(pid).or_default();
+
+        // If this item is in the Ready list, return right away without blocking
+        if ready.remove(&mtx) {
+            // The owner is only tracked while someone is waiting on it
+            let contended = self
+                .mutex_hash
+                .get(&pid)
+                .and_then(|awaiting| awaiting.get(&mtx))
+                .map(|mutex_entry| !mutex_entry.is_empty())
+                .unwrap_or(false);
+            if contended {
+                self.lock_diagnostics.mutex_granted(
+                    pid,
+                    mtx,
+                    diag::sender_tid(sender),
+                    self.ticktimer.elapsed_ms(),
+                );
+            }
+            return Some(());
+        }
+
+        // This item is not in the Ready list, so add our sender to the list of processes
+        // to get called when UnlockMutex is invoked
+        let awaiting = self.mutex_hash.entry(pid).or_default();
+
+        // Add this to the end of the list of entries to call so that when `UnlockMutex` is sent
+        // the message will get a response.
+        let mutex_entry = awaiting.entry(mtx).or_default();
+        mutex_entry.push_back(sender);
+
+        if let Some(deadlock) = self.lock_diagnostics.mutex_contended(
+            pid,
+            mtx,
+            sender,
+            diag::sender_tid(sender),
+            self.ticktimer.elapsed_ms(),
+        ) {
+            error!("deadlock: {}", deadlock);
+        }
+
+        // We've saved the sender above and will return the lock when `UnlockMutex` is sent
+        None
+    }
+
+    fn unlock_mutex(&mut self, sender: xous::MessageSender, mtx: usize) {
+        let pid = sender.pid();
+
+        // Get a list of awaiting mutexes for this process
+        let awaiting = self.mutex_hash.entry(pid).or_default();
+
+        // Get the vector of awaiting mutex entries.
+        let mutex_entry = awaiting.entry(mtx).or_default();
+
+        // If there's something waiting in the queue, respond to that message
+        if let Some(waiter) = mutex_entry.pop_front() {
+            xous::return_scalar(waiter, 0).unwrap();
+            self.lock_diagnostics.mutex_handed_off(
+                pid,
+                mtx,
+                waiter,
+                !mutex_entry.is_empty(),
+                self.ticktimer.elapsed_ms(),
+            );
+        } else {
+            // Otherwise, mark this scalar as being ready to run
+            self.mutex_ready_hash.entry(pid).or_default().insert(mtx);
+            self.lock_diagnostics.mutex_released(pid, mtx);
+        }
+    }
+
+    fn wait_for_condition(
+        &mut self,
+        sender: xous::MessageSender,
+        condvar: usize,
+        timeout: usize,
+    ) -> Option<usize> {
+        let pid = sender.pid();
+
+        log::trace!(
+            "sender in pid {:?} is waiting on a condition {:08x} with a timeout of {}",
+            pid,
+            condvar,
+            timeout
+        );
+
+        // If there's a condition waiting already, decrement the total list
+        // and return immediately.
+        if let Some(excess) = self
+            .immedaite_notifications
+            .entry(pid)
+            .or_default()
+            .get_mut(&condvar)
+        {
+            if *excess > 0 {
+                *excess -= 1;
+                return Some(0);
+            }
+        }
+
+        // If there's a `timeout` argument, schedule a response.
+        if timeout != 0 {
+            recalculate_sleep(
+                &mut self.ticktimer,
+                &mut self.sleep_heap,
+                Some(TimerRequest {
+                    usec: timeout as i64 * 1000,
+                    sender,
+                    kind: RequestKind::Timeout,
+                    data: condvar,
+                }),
+            )
+        }
+
+        self.lock_diagnostics.condvar_waiting(
+            pid,
+            condvar,
+            sender,
+            diag::sender_tid(sender),
+            self.ticktimer.elapsed_ms(),
+        );
+
+        // Add this to the list of entries waiting for a response.
+        self.notify_hash
+            .entry(pid)
+            .or_default()
+            .entry(condvar)
+            .or_default()
+            .push_back(sender);
+
+        // log::trace!("New waiting senders: {:?}", notify_hash.get(&pid).unwrap().get(&condvar));
+
+        // The sender will be responded to as part of the notification hash when the
+        // condvar is unlocked.
+        None
+    }
+
+    fn notify_condition(&mut self, sender: xous::MessageSender, condvar: usize, count: usize) {
+        let pid = sender.pid();
+
+        log::trace!(
+            "sender in pid {:?} is notifying {} entries for condition {:08x}",
+            pid,
+            count,
+            condvar,
+        );
+
+        let awaiting = self
+            .notify_hash
+            .entry(pid)
+            .or_default()
+            .entry(condvar)
+            .or_default();
+
+        // Wake threads, ensuring we don't run off the end.
+        let requested_count = count;
+        let available_count = core::cmp::min(requested_count, awaiting.len());
+
+        stop_sleep(&mut self.ticktimer, &mut self.sleep_heap);
+        for entry in awaiting.drain(..available_count) {
+            // Remove each entry in the timeout set
+            self.sleep_heap
+                .retain(|_, v| if v.sender == entry { false } else { true });
+            xous::return_scalar(entry, 0).expect("couldn't send response");
+            self.lock_diagnostics.woken(entry);
+        }
+
+        // If there are leftover requested, add them to the list of
+        // notofications that will be responded to immediately.
+        if available_count - requested_count > 0 {
+            #[cfg(feature = "debug-print")]
+            log::trace!(
+                "Adding {} spare sleep requests to immediate_notifications list",
+                available_count - requested_count
+            );
+            *self
+                .immedaite_notifications
+                .entry(pid)
+                .or_default()
+                .entry(condvar)
+                .or_default() += available_count - requested_count;
+        }
+
+        // Resume sleeping, which re-enables interrupts and queues the
+        // next timer event to fire.
+        start_sleep(&mut self.ticktimer, &mut self.sleep_heap);
+    }
+
+    fn subscribe_timer(
+        &mut self,
+        sender: xous::MessageSender,
+        subscription: &mut api::TimerSubscription,
+    ) {
+        subscription.id = 0;
+
+        // The callback goes out on our own connection to the server, since the
+        // caller's connection IDs mean nothing in this process.
+        let sid = xous::SID::from_array(subscription.sid);
+        match self.conns.connect(sid) {
+            Ok(cid) => {
+                let now = self.ticktimer.elapsed_us() as i64;
+                let timer = timers::Timer::new(
+                    sender.pid(),
+                    cid,
+                    subscription.opcode as usize,
+                    subscription.ms as i64 * 1000,
+                    subscription.periodic,
+                    now,
+                );
+                let expiry = timer.expiry;
+                let id = self.timers.add(timer);
+                recalculate_sleep(
+                    &mut self.ticktimer,
+                    &mut self.sleep_heap,
+                    Some(TimerRequest {
+                        usec: expiry - now,
+                        sender: xous::MessageSender::from_usize(0),
+                        kind: RequestKind::Subscription,
+                        data: id as usize,
+                    }),
+                );
+                subscription.id = id;
+            }
+            Err(e) => {
+                info!("couldn't connect to timer subscriber: {:?}", e);
+            }
+        }
+    }
+
+    fn cancel_timer(&mut self, sender: xous::MessageSender, id: u32) -> bool {
+        self.change_timer(sender.pid(), id, None)
+    }
+
+    fn reschedule_timer(&mut self, sender: xous::MessageSender, id: u32, ms: u64) -> bool {
+        self.change_timer(sender.pid(), id, Some(ms as i64))
+    }
+
+    fn elapsed_us(&mut self, _sender: xous::MessageSender) -> u64 {
+        self.ticktimer.elapsed_us()
+    }
+
+    fn sleep_us(&mut self, sender: xous::MessageSender, us: usize) -> Option<()> {
+        // Sleeps that are shorter than a tick of the hardware timer wake on the next
+        // tick, along with any other sleepers that are due by then.
+        recalculate_sleep(
+            &mut self.ticktimer,
+            &mut self.sleep_heap,
+            Some(TimerRequest {
+                usec: us as i64,
+                sender,
+                kind: RequestKind::Sleep,
+                data: 0,
+            }),
+        );
+        None
+    }
+
+    fn acquire_semaphore(
+        &mut self,
+        sender: xous::MessageSender,
+        sem: usize,
+        ms: usize,
+    ) -> Option<usize> {
+        self.acquire(api::Opcode::AcquireSemaphore, sender, sem, ms)
+    }
+
+    fn release_semaphore(&mut self, sender: xous::MessageSender, sem: usize, count: usize) {
+        self.release(api::Opcode::ReleaseSemaphore, sender, sem, count)
+    }
+
+    fn read_lock(&mut self, sender: xous::MessageSender, lock: usize, ms: usize) -> Option<usize> {
+        self.acquire(api::Opcode::ReadLock, sender, lock, ms)
+    }
+
+    fn write_lock(&mut self, sender: xous::MessageSender, lock: usize, ms: usize) -> Option<usize> {
+        self.acquire(api::Opcode::WriteLock, sender, lock, ms)
+    }
+
+    fn read_unlock(&mut self, sender: xous::MessageSender, lock: usize) {
+        self.release(api::Opcode::ReadUnlock, sender, lock, 0)
+    }
+
+    fn write_unlock(&mut self, sender: xous::MessageSender, lock: usize) {
+        self.release(api::Opcode::WriteUnlock, sender, lock, 0)
+    }
+
+    fn lock_diagnostics(&mut self, _sender: xous::MessageSender, report: &mut api::LockReport) {
+        let (mut text, deadlocks) = self.lock_diagnostics.report(self.ticktimer.elapsed_ms());
+
+        // Truncate on a line boundary if the report doesn't fit
+        if text.len() > api::LOCK_REPORT_LEN {
+            let end = text[..api::LOCK_REPORT_LEN]
+                .rfind('\n')
+                .map(|i| i + 1)
+                .unwrap_or(0);
+            text.truncate(end);
+        }
+        report.text = xous_ipc::String::from_str(&text);
+        report.deadlocks = deadlocks;
+    }
+
+    fn utc_ms(&mut self, _sender: xous::MessageSender) -> u64 {
+        self.wall_clock.utc_ms(self.ticktimer.elapsed_ms())
+    }
+
+    fn set_utc_ms(&mut self, sender: xous::MessageSender, utc_ms: u64) -> usize {
+        self.set_time(api::Opcode::SetUtcMs, sender.pid(), utc_ms)
+    }
+
+    fn wall_clock_synchronized(&mut self, _sender: xous::MessageSender) -> bool {
+        self.wall_clock.synchronized()
+    }
+
+    fn time_zone(&mut self, _sender: xous::MessageSender) -> usize {
+        self.wall_clock.zone()
+    }
+
+    fn set_time_zone(&mut self, sender: xous::MessageSender, zone: usize) -> usize {
+        self.set_time(api::Opcode::SetTimeZone, sender.pid(), zone as u64)
+    }
+
+    fn register_heartbeat(
+        &mut self,
+        sender: xous::MessageSender,
+        registration: &mut api::HeartbeatRegistration,
+    ) {
+        let pid = sender.pid();
+        // A critical service can reset the system by going quiet
+        if !self.is_trusted(pid) {
+            log::warn!(
+                "process {:?} isn't trusted to register {} as critical",
+                pid,
+                registration.name
+            );
+            registration.id = 0;
+            return;
+        }
+        let deadline_us = registration.deadline_ms.max(1) as i64 * 1000;
+        let id = self.heartbeats.register(
+            pid,
+            registration.name.as_str().unwrap_or("").to_owned(),
+            deadline_us,
+            self.ticktimer.elapsed_us() as i64,
+        );
+        info!(
+            "{} is critical, with a heartbeat deadline of {} ms",
+            registration.name, registration.deadline_ms
+        );
+        recalculate_sleep(
+            &mut self.ticktimer,
+            &mut self.sleep_heap,
+            Some(TimerRequest {
+                usec: deadline_us,
+                sender: xous::MessageSender::from_usize(0),
+                kind: RequestKind::Heartbeat,
+                data: id as usize,
+            }),
+        );
+        registration.id = id;
+    }
+
+    fn heartbeat(&mut self, sender: xous::MessageSender, id: u32) {
+        let now = self.ticktimer.elapsed_us() as i64;
+        if let Some(due) = self.heartbeats.beat(id, sender.pid(), now) {
+            recalculate_sleep(
+                &mut self.ticktimer,
+                &mut self.sleep_heap,
+                Some(TimerRequest {
+                    usec: due - now,
+                    sender: xous::MessageSender::from_usize(0),
+                    kind: RequestKind::Heartbeat,
+                    data: id as usize,
+                }),
+            );
+        }
+    }
+
+    fn unregister_heartbeat(&mut self, sender: xous::MessageSender, id: u32) -> bool {
+        // A check that is still scheduled finds nothing when it fires
+        self.heartbeats.unregister(id, sender.pid())
+    }
+
+    fn supervise_watchdog(
+        &mut self,
+        sender: xous::MessageSender,
+        request: &mut api::WatchdogSupervisor,
+    ) {
+        let pid = sender.pid();
+        request.accepted = if !self.is_trusted(pid) {
+            log::warn!("process {:?} isn't trusted to supervise the watchdog", pid);
+            false
+        } else if !self.heartbeats.may_supervise(pid) {
+            false
+        } else {
+            match self.conns.connect(xous::SID::from_array(request.sid)) {
+                Ok(cid) => {
+                    let supervisor = heartbeat::Supervisor {
+                        pid,
+                        cid,
+                        opcode: request.opcode as usize,
+                    };
+                    if let Some(replaced) = self.heartbeats.set_supervisor(supervisor) {
+                        self.conns.release(replaced.cid);
+                    }
+                    true
+                }
+                Err(e) => {
+                    info!("couldn't connect to watchdog supervisor: {:?}", e);
+                    false
+                }
+            }
+        };
+    }
+
+    fn stats(&mut self, _sender: xous::MessageSender, stats: &mut api::TicktimerStats) {
+        let sleep_heap = &self.sleep_heap;
+        let count_kind =
+            |kind: RequestKind| sleep_heap.values().filter(|r| r.kind == kind).count() as u32;
+        *stats = api::TicktimerStats {
+            elapsed_ms: self.ticktimer.elapsed_ms(),
+            messages: self.messages,
+            sleepers: count_kind(RequestKind::Sleep),
+            timeouts: count_kind(RequestKind::Timeout),
+            mutex_waiters: count_waiters(&self.mutex_hash),
+            condvar_waiters: count_waiters(&self.notify_hash),
+            semaphores: self.semaphores.values().map(|s| s.len() as u32).sum(),
+            rwlocks: self.rwlocks.values().map(|l| l.len() as u32).sum(),
+            timers: self.timers.len() as u32,
+            heartbeats: self.heartbeats.count() as u32,
+        };
+    }
+}
+
 fn main() -> ! {
     log_server::init_wait().unwrap();
     log::set_max_level(log::LevelFilter::Info);
@@ -579,151 +1636,66 @@ fn main() -> ! {
     let sr_cid =
         xous::connect(ticktimer_server).expect("couldn't create suspend callback connection");
 
-    // A list of all sleep requests in the system, sorted by the time at which it
-    // expires. That is, if a request comes in to sleep for 1000 ms, and the ticktimer
-    // is currently at 900, the Request will be `1900`.
-    let mut sleep_heap: BTreeMap<TimeoutExpiry, TimerRequest> = BTreeMap::new();
-
-    // A list of message IDs that are waiting to receive a Notification. This queue is drained
-    // by threads sending `NotifyCondition` to us, or by a condvar timing out.
-    let mut notify_hash: HashMap<Option<xous::PID>, HashMap<usize, VecDeque<xous::MessageSender>>> =
-        HashMap::new();
-
-    // There is a small chance that a client sends a `notify_one()` or `notify_all()` before
-    // the other threads have a chance to recover. This is due to a non-threadsafe use of
-    // Mutex<T> within the standard library. Keep track of any excess `notify_one()` or
-    // `notify_all()` messages for when the Mutex<T> is successfully locked.
-    let mut immedaite_notifications: HashMap<Option<xous::PID>, HashMap<usize, usize>> =
-        HashMap::new();
-
-    // // A list of timeouts for a given condvar. This list serves two purposes:
-    // //      1. When a Notification is sent to a condvar, it is removed from this hash (based on the Message ID)
-    // //      2. When the timer event hits, a response is sent to this condvar
-    // // Therefore, this must be indexable by two keys: Message ID and
-    // let mut timeout_heap: std::collections::VecDeque<TimerRequest> = VecDeque::new();
-
-    // A list of mutexes that should be allowed to run immediately, because the
-    // thread they were waiting on has already unlocked the mutex. This occurs
-    // when a thread attempts to Lock a Mutex and fails, then gets preempted
-    // before it has a chance to send the `LockMutex` message to us.
-    let mut mutex_ready_hash: HashMap<Option<xous::PID>, HashSet<usize>> = HashMap::new();
+    let mut server = Server::new(ticktimer);
 
-    // A list of message IDs that are waiting to lock a Mutex. These are processes
-    // that have attempted to lock a Mutex and failed, and have sent us the `LockMutex`
-    // message. This queue is drained by threads sending `UnlockMutex` to us.
-    let mut mutex_hash: HashMap<Option<xous::PID>, HashMap<usize, VecDeque<xous::MessageSender>>> =
-        HashMap::new();
+    // Nobody may set the time until the name server has told us who is trusted
+    xous::create_thread_1(fetch_trusted_processes, ticktimer_client as usize)
+        .expect("couldn't start the thread that fetches the trusted processes");
 
     let mut msg_opt = None;
     let mut return_type = 0;
     loop {
         #[cfg(feature = "watchdog")]
-        ticktimer.reset_wdt();
+        if server
+            .heartbeats
+            .healthy(server.ticktimer.elapsed_us() as i64)
+        {
+            server.ticktimer.reset_wdt();
+        }
         //#[cfg(feature = "watchdog")] // for debugging the watchdog
-        //ticktimer.check_wdt();
+        //server.ticktimer.check_wdt();
 
         xous::reply_and_receive_next_legacy(ticktimer_server, &mut msg_opt, &mut return_type)
             .unwrap();
         let msg = msg_opt.as_mut().unwrap();
         log::trace!("msg: {:x?}", msg);
-        match num_traits::FromPrimitive::from_usize(msg.body.id())
-            .unwrap_or(api::Opcode::InvalidCall)
+        server.messages += 1;
+        // A request that expired while our queue was full never made it here as a
+        // `RecalculateSleep`. Schedule it again, so that it fires as soon as there's room.
+        if server.ticktimer.missed() {
+            recalculate_sleep(&mut server.ticktimer, &mut server.sleep_heap, None);
+        }
+        if let Some(pid) = xous_names::terminated_pid(msg) {
+            server.process_terminated(pid);
+            continue;
+        }
+
+        // Everything but the opcodes below is answered by the `TicktimerApi` methods
+        let opcode = match api::dispatch_ticktimer_api(&mut server, &mut msg_opt, &mut return_type)
         {
-            api::Opcode::ElapsedMs => {
-                if let Some(scalar) = msg.body.scalar_message_mut() {
-                    let time = ticktimer.elapsed_ms() as i64;
-                    scalar.arg1 = (time & 0xFFFF_FFFFi64) as usize;
-                    scalar.arg2 = ((time >> 32) & 0xFFF_FFFFi64) as usize;
-                    scalar.id = 0;
-
-                    // API calls expect a `Scalar2` value in response
-                    return_type = 2;
-                }
-            }
-            api::Opcode::SleepMs => {
-                if let Some(scalar) = msg.body.scalar_message_mut() {
-                    let ms = scalar.arg1 as i64;
-                    let sender = msg.sender;
-
-                    // Forget the contents of the message, replacing it with `None`. This
-                    // will prevent it from getting dropped, which (under normal circumstances)
-                    // would cause it to be responded to automatically.
-                    //
-                    // The ideal approach here would be to have the struct -- in this case the
-                    // TimerRequest -- keep track of the element itself. That way, it could
-                    // return the value as part of its response routine. However, due to the
-                    // legacy split of `BlockingScalars`, these are allowed to have an explicit
-                    // return path, so we need to forget the original message here to prevent
-                    // it from getting returned early.
-                    //
-                    // (n.b. this auto-response hasn't been enabled yet for Scalar messages,
-                    // but this definitely would need to be done for e.g. Memory messages).
-                    core::mem::forget(msg_opt.take());
-
-                    // let timeout_queue = timeout_heap.entry(msg.sender.pid()).or_default();
-                    recalculate_sleep(
-                        &mut ticktimer,
-                        &mut sleep_heap,
-                        Some(TimerRequest {
-                            msec: ms,
-                            sender: sender,
-                            kind: RequestKind::Sleep,
-                            data: 0,
-                        }),
-                    );
-                }
-            }
-            api::Opcode::RecalculateSleep => {
-                // let timeout_queue = timeout_heap.entry(msg.sender.pid()).or_default();
-                if let Some(args) = msg.body.scalar_message() {
-                    // If this is a Timeout message that fired, remove it from the Notification list
-                    let sender = args.arg1;
-                    let request_kind = args.arg2;
-                    let condvar = args.arg3;
-                    let sender_pid = xous::MessageSender::from_usize(sender).pid();
-
-                    // If we're being asked to recalculate due to a timeout expiring, drop the sent
-                    // message from the `entries` list.
-                    // the first check confirms that the origin of the RecalculateSleep message is the Ticktimer,
-                    // to prevent third-party servers from issuing the command and thus distorting the sleep
-                    // calculations (since this is a public API, anything could happen).
-                    if (msg.sender.pid().map(|p| p.get()).unwrap_or_default() as u32)
-                        == xous::process::id()
-                        && (request_kind == RequestKind::Timeout as usize)
-                        && (sender > 0)
-                    {
-                        let entries = notify_hash
-                            .entry(sender_pid)
-                            .or_default()
-                            .entry(condvar)
-                            .or_default();
-                        let mut idx = None;
-                        for (i, val) in entries.iter().enumerate() {
-                            if val.to_usize() == sender {
-                                idx = Some(i);
-                                break;
-                            }
-                        }
-                        if let Some(idx) = idx {
-                            entries.remove(idx);
-                        }
-                        // log::trace!("new entries for PID {:?}/condvar {:08x}: {:?}", sender_pid, condvar, notify_hash.get(&sender_pid).unwrap().get(&condvar));
-                    }
-                }
-                recalculate_sleep(&mut ticktimer, &mut sleep_heap, None);
-            }
+            None => continue,
+            Some(opcode) => opcode,
+        };
+        let msg = msg_opt.as_mut().unwrap();
+        let from_self = msg.sender.pid().map(|p| p.get() as u32) == Some(xous::process::id());
+        match opcode {
+            api::Opcode::RecalculateSleep => match msg.body.scalar_message() {
+                // Only our own interrupt handler says what expired, to prevent third-party
+                // servers from distorting the sleep calculations (since this is a public
+                // API, anything could happen).
+                Some(args) if from_self => server.expired(args),
+                _ => recalculate_sleep(&mut server.ticktimer, &mut server.sleep_heap, None),
+            },
             api::Opcode::SuspendResume => xous::msg_scalar_unpack!(msg, _token, _, _, _, {
-                ticktimer.suspend();
+                server.wall_clock.suspend(server.ticktimer.elapsed_ms());
+                server.ticktimer.suspend();
                 #[cfg(feature="susres")]
                 susres
                     .suspend_until_resume(_token)
                     .expect("couldn't execute suspend/resume");
-                ticktimer.resume();
+                server.ticktimer.resume();
+                server.wall_clock.resume(server.ticktimer.elapsed_ms());
             }),
-            api::Opcode::PingWdt => {
-                #[cfg(feature="watchdog")]
-                ticktimer.reset_wdt();
-            }
             /*
             api::Opcode::GetVersion => {
                 let raw_msg = msg.body.memory_message_mut().unwrap();
@@ -747,179 +1719,69 @@ fn main() -> ! {
                     *dest = *src;
                 }
             }
-            api::Opcode::LockMutex => {
-                let pid = msg.sender.pid();
-                if let Some(scalar) = msg.body.scalar_message_mut() {
-                    let ready = mutex_ready_hash.entry(pid).or_default();
-
-                    // If this item is in the Ready list, return right away without blocking
-                    if ready.remove(&scalar.arg1) {
-                        scalar.id = 0;
-                        continue;
+            api::Opcode::TrustedProcesses => {
+                // Only our own thread knows the answer, anyone else could be lying
+                if from_self {
+                    if let Some(scalar) = msg.body.scalar_message() {
+                        server.trusted =
+                            xous_names::ProcessSet::from_words(scalar.arg1, scalar.arg2);
+                        info!("{} processes are trusted", server.trusted.iter().count());
                     }
-
-                    // This item is not in the Ready list, so add our sender to the list of processes
-                    // to get called when UnlockMutex is invoked
-                    let awaiting = mutex_hash.entry(pid).or_default();
-
-                    // Add this to the end of the list of entries to call so that when `UnlockMutex` is sent
-                    // the message will get a response.
-                    let mutex_entry = awaiting.entry(scalar.arg1).or_default();
-                    mutex_entry.push_back(msg.sender);
-
-                    // We've saved the `msg.sender` above and will return the lock as part of the mutex
-                    // work thread. Forget the contents of `msg_opt` without running its destructor.
-                    core::mem::forget(msg_opt.take());
-                } else {
-                    info!("sender made LockMutex request that was not blocking");
                 }
             }
-            api::Opcode::UnlockMutex => {
-                let pid = msg.sender.pid();
-                if msg.body.is_blocking() {
-                    info!("sender made UnlockMutex request that was blocking");
-                    continue;
-                }
-                if let Some(scalar) = msg.body.scalar_message() {
-                    // Get a list of awaiting mutexes for this process
-                    let awaiting = mutex_hash.entry(pid).or_default();
-
-                    // Get the vector of awaiting mutex entries.
-                    let mutex_entry = awaiting.entry(scalar.arg1).or_default();
-
-                    // If there's something waiting in the queue, respond to that message
-                    if let Some(sender) = mutex_entry.pop_front() {
-                        xous::return_scalar(sender, 0).unwrap();
-                    } else {
-                        // Otherwise, mark this scalar as being ready to run
-                        mutex_ready_hash.entry(pid).or_default().insert(scalar.arg1);
-                    }
-                }
+            api::Opcode::InvalidCall => {
+                error!("couldn't convert opcode");
             }
-            api::Opcode::WaitForCondition => {
-                let pid = msg.sender.pid();
-                if let Some(scalar) = msg.body.scalar_message_mut() {
-                    let condvar = scalar.arg1;
-                    let timeout = scalar.arg2;
-
-                    log::trace!(
-                        "sender in pid {:?} is waiting on a condition {:08x} with a timeout of {}",
-                        pid,
-                        condvar,
-                        timeout
-                    );
-
-                    // If there's a condition waiting already, decrement the total list
-                    // and return immediately.
-                    if let Some(excess) = immedaite_notifications
-                        .entry(pid)
-                        .or_default()
-                        .get_mut(&condvar)
-                    {
-                        if *excess > 0 {
-                            *excess -= 1;
-                            scalar.id = 0;
-
-                            // Rust libstd expects a `Scalar1` return type for this call
-                            return_type = 1;
-                            continue;
-                        }
-                    }
-
-                    // If there's a `timeout` argument, schedule a response.
-                    if timeout != 0 {
-                        recalculate_sleep(
-                            &mut ticktimer,
-                            &mut sleep_heap,
-                            Some(TimerRequest {
-                                msec: timeout as i64,
-                                sender: msg.sender,
-                                kind: RequestKind::Timeout,
-                                data: condvar,
-                            }),
-                        )
-                    }
-
-                    // Add this to the list of entries waiting for a response.
-                    notify_hash
-                        .entry(pid)
-                        .or_default()
-                        .entry(condvar)
-                        .or_default()
-                        .push_back(msg.sender);
-
-                    // log::trace!("New waiting senders: {:?}", notify_hash.get(&pid).unwrap().get(&condvar));
-
-                    // The message will be responded to as part of the notification hash
-                    // when the condvar is unlocked. Forget the contents of the message
-                    // in order to prevent it from being responded to early.
-                    core::mem::forget(msg_opt.take());
-                } else {
-                    info!(
-                        "sender made WaitForCondition request that wasn't a BlockingScalar Message"
-                    );
-                }
+            opcode => {
+                info!(
+                    "sender made a {:?} request of the wrong message type",
+                    opcode
+                );
             }
-            api::Opcode::NotifyCondition => {
-                let pid = msg.sender.pid();
-                if msg.body.is_blocking() {
-                    info!("sender made NotifyCondition request that was blocking");
-                    continue;
-                }
-                if let Some(scalar) = msg.body.scalar_message() {
-                    let condvar = scalar.arg1;
+        }
+    }
+}
 
-                    log::trace!(
-                        "sender in pid {:?} is notifying {} entries for condition {:08x}",
-                        pid,
-                        scalar.arg2,
-                        condvar,
-                    );
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-                    let awaiting = notify_hash
-                        .entry(pid)
-                        .or_default()
-                        .entry(condvar)
-                        .or_default();
-
-                    // Wake threads, ensuring we don't run off the end.
-                    let requested_count = scalar.arg2;
-                    let available_count = core::cmp::min(requested_count, awaiting.len());
-
-                    stop_sleep(&mut ticktimer, &mut sleep_heap);
-                    for entry in awaiting.drain(..available_count) {
-                        // Remove each entry in the timeout set
-                        sleep_heap.retain(|_, v| if v.sender == entry { false } else { true });
-                        xous::return_scalar(entry, 0).expect("couldn't send response");
-                    }
+    fn subscription(usec: TimeoutExpiry, id: usize) -> TimerRequest {
+        TimerRequest {
+            usec,
+            sender: xous::MessageSender::from_usize(0),
+            kind: RequestKind::Subscription,
+            data: id,
+        }
+    }
 
-                    // If there are leftover requested, add them to the list of
-                    // notofications that will be responded to immediately.
-                    if available_count - requested_count > 0 {
-                        #[cfg(feature = "debug-print")]
-                        log::trace!(
-                            "Adding {} spare sleep requests to immediate_notifications list",
-                            available_count - requested_count
-                        );
-                        *immedaite_notifications
-                            .entry(pid)
-                            .or_default()
-                            .entry(condvar)
-                            .or_default() += available_count - requested_count;
-                    }
+    #[test]
+    fn expiry_is_kept_when_the_irq_message_is_dropped() {
+        let kept = fire(subscription(1000, 7), |_| Err(xous::Error::ServerQueueFull))
+            .expect("the expired request was lost");
+        assert_eq!(kept.data, 7);
+
+        // Another request may have taken its place in the meantime
+        let mut sleep_heap = BTreeMap::new();
+        sleep_heap.insert(1000, subscription(1000, 8));
+        requeue(&mut sleep_heap, kept);
+        let ids: Vec<usize> = sleep_heap.values().map(|r| r.data).collect();
+        assert_eq!(ids, [8, 7]);
+        assert_eq!(sleep_heap.keys().copied().collect::<Vec<_>>(), [1000, 1001]);
+    }
 
-                    // Resume sleeping, which re-enables interrupts and queues the
-                    // next timer event to fire.
-                    start_sleep(&mut ticktimer, &mut sleep_heap);
-                } else {
-                    info!(
-                        "sender made WaitForCondition request that wasn't a BlockingScalar Message"
-                    );
-                }
-            }
-            api::Opcode::InvalidCall => {
-                error!("couldn't convert opcode");
-            }
-        }
+    #[test]
+    fn expiry_is_reported_to_the_main_loop() {
+        let mut sent = None;
+        let kept = fire(subscription(1000, 7), |message| {
+            sent = Some(message);
+            Ok(xous::Result::Ok)
+        });
+        assert!(kept.is_none());
+        let sent = sent.expect("nothing was sent");
+        let scalar = sent.scalar_message().unwrap();
+        assert_eq!(scalar.id, api::Opcode::RecalculateSleep as usize);
+        assert_eq!(scalar.arg2, RequestKind::Subscription as usize);
+        assert_eq!(scalar.arg3, 7);
     }
 }
@@ -0,0 +1,256 @@
+//! Counting semaphores and reader-writer locks.
+//!
+//! These only track who holds what and who is waiting, in the order that they
+//! arrived. Responding to the waiters is left to the main loop, which also owns
+//! their timeouts.
+
+use std::collections::VecDeque;
+
+/// A thread that is blocked on a semaphore or a lock
+pub struct Waiter {
+    pub sender: xous::MessageSender,
+    /// `true` if a timeout is scheduled for this waiter in the sleep heap
+    pub timeout: bool,
+}
+
+/// A counting semaphore. Like Mutexes, semaphores start out empty, and are made
+/// available by releasing them.
+#[derive(Default)]
+pub struct Semaphore {
+    count: usize,
+    waiters: VecDeque<Waiter>,
+}
+
+impl Semaphore {
+    /// Take a unit without blocking. This fails if there are threads already
+    /// waiting, so that they are served first.
+    pub fn try_acquire(&mut self) -> bool {
+        if self.count > 0 && self.waiters.is_empty() {
+            self.count -= 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn wait(&mut self, waiter: Waiter) {
+        self.waiters.push_back(waiter);
+    }
+
+    /// Return `count` units, handing them to waiters in the order that they arrived.
+    /// `wake` responds to a waiter, and returns `false` if the waiter has already
+    /// timed out, in which case the unit goes to the next one instead.
+    pub fn release(&mut self, mut count: usize, mut wake: impl FnMut(&Waiter) -> bool) {
+        while count > 0 {
+            match self.waiters.pop_front() {
+                Some(waiter) => {
+                    if wake(&waiter) {
+                        count -= 1;
+                    }
+                }
+                None => break,
+            }
+        }
+        self.count += count;
+    }
+
+    /// Forget a waiter whose timeout has expired
+    pub fn remove(&mut self, sender: xous::MessageSender) {
+        self.waiters.retain(|w| w.sender != sender);
+    }
+}
+
+/// A reader-writer lock. Locks start out unlocked.
+///
+/// Waiters are served strictly in order, so a reader that arrives while a writer
+/// is waiting queues up behind it rather than starving it.
+#[derive(Default)]
+pub struct RwLock {
+    readers: usize,
+    writer: bool,
+    /// Waiting threads, and whether each one wants to write
+    waiters: VecDeque<(Waiter, bool)>,
+}
+
+impl RwLock {
+    /// Take a read lock without blocking
+    pub fn try_read(&mut self) -> bool {
+        if !self.writer && self.waiters.is_empty() {
+            self.readers += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Take the write lock without blocking
+    pub fn try_write(&mut self) -> bool {
+        if !self.writer && self.readers == 0 && self.waiters.is_empty() {
+            self.writer = true;
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn wait(&mut self, waiter: Waiter, write: bool) {
+        self.waiters.push_back((waiter, write));
+    }
+
+    pub fn unlock_read(&mut self, wake: impl FnMut(&Waiter) -> bool) {
+        self.readers = self.readers.saturating_sub(1);
+        self.wake(wake);
+    }
+
+    pub fn unlock_write(&mut self, wake: impl FnMut(&Waiter) -> bool) {
+        self.writer = false;
+        self.wake(wake);
+    }
+
+    /// Forget a waiter whose timeout has expired. The waiters behind it may now be
+    /// able to take the lock.
+    pub fn remove(&mut self, sender: xous::MessageSender, wake: impl FnMut(&Waiter) -> bool) {
+        self.waiters.retain(|(w, _)| w.sender != sender);
+        self.wake(wake);
+    }
+
+    /// Grant the lock to as many waiters at the front of the queue as possible: either
+    /// one writer, or a run of readers. `wake` has the same meaning as in
+    /// `Semaphore::release()`.
+    fn wake(&mut self, mut wake: impl FnMut(&Waiter) -> bool) {
+        while let Some((_, write)) = self.waiters.front() {
+            if self.writer || (*write && self.readers > 0) {
+                break;
+            }
+            let (waiter, write) = self.waiters.pop_front().unwrap();
+            if wake(&waiter) {
+                if write {
+                    self.writer = true;
+                } else {
+                    self.readers += 1;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn waiter(id: usize, timeout: bool) -> Waiter {
+        Waiter {
+            sender: xous::MessageSender::from_usize(id),
+            timeout,
+        }
+    }
+
+    /// Wake every waiter, recording the order that they were woken in
+    fn record(woken: &mut Vec<usize>) -> impl FnMut(&Waiter) -> bool + '_ {
+        move |w| {
+            woken.push(w.sender.to_usize());
+            true
+        }
+    }
+
+    #[test]
+    fn semaphores_start_out_empty() {
+        let mut semaphore = Semaphore::default();
+        assert!(!semaphore.try_acquire());
+        semaphore.release(2, |_| unreachable!());
+        assert!(semaphore.try_acquire());
+        assert!(semaphore.try_acquire());
+        assert!(!semaphore.try_acquire());
+    }
+
+    #[test]
+    fn semaphores_serve_waiters_in_order() {
+        let mut semaphore = Semaphore::default();
+        for id in 1..=3 {
+            semaphore.wait(waiter(id, false));
+        }
+        let mut woken = vec![];
+        semaphore.release(2, record(&mut woken));
+        assert_eq!(woken, vec![1, 2]);
+        assert!(!semaphore.try_acquire());
+
+        // Spare units are kept once everyone has been served
+        woken.clear();
+        semaphore.release(2, record(&mut woken));
+        assert_eq!(woken, vec![3]);
+        assert!(semaphore.try_acquire());
+        assert!(!semaphore.try_acquire());
+    }
+
+    #[test]
+    fn semaphores_skip_waiters_that_timed_out() {
+        let mut semaphore = Semaphore::default();
+        semaphore.wait(waiter(1, true));
+        semaphore.wait(waiter(2, true));
+        semaphore.wait(waiter(3, true));
+        semaphore.remove(xous::MessageSender::from_usize(2));
+
+        // Waiter 1's timeout has fired but it hasn't been removed yet
+        let mut woken = vec![];
+        semaphore.release(1, |w| {
+            woken.push(w.sender.to_usize());
+            w.sender.to_usize() != 1
+        });
+        assert_eq!(woken, vec![1, 3]);
+        assert!(!semaphore.try_acquire());
+    }
+
+    #[test]
+    fn readers_share_the_lock() {
+        let mut lock = RwLock::default();
+        assert!(lock.try_read());
+        assert!(lock.try_read());
+        assert!(!lock.try_write());
+        lock.unlock_read(|_| unreachable!());
+        lock.unlock_read(|_| unreachable!());
+        assert!(lock.try_write());
+        assert!(!lock.try_read());
+        assert!(!lock.try_write());
+    }
+
+    #[test]
+    fn waiting_writers_are_not_starved() {
+        let mut lock = RwLock::default();
+        assert!(lock.try_read());
+        lock.wait(waiter(1, false), true);
+        // A new reader queues up behind the writer
+        assert!(!lock.try_read());
+        lock.wait(waiter(2, false), false);
+        lock.wait(waiter(3, false), false);
+        lock.wait(waiter(4, false), true);
+
+        let mut woken = vec![];
+        lock.unlock_read(record(&mut woken));
+        assert_eq!(woken, vec![1]);
+
+        // The run of readers is woken together, but not the writer behind them
+        woken.clear();
+        lock.unlock_write(record(&mut woken));
+        assert_eq!(woken, vec![2, 3]);
+
+        woken.clear();
+        lock.unlock_read(record(&mut woken));
+        assert!(woken.is_empty());
+        lock.unlock_read(record(&mut woken));
+        assert_eq!(woken, vec![4]);
+    }
+
+    #[test]
+    fn removing_a_waiter_lets_the_next_one_in() {
+        let mut lock = RwLock::default();
+        assert!(lock.try_read());
+        lock.wait(waiter(1, true), true);
+        lock.wait(waiter(2, false), false);
+
+        // The writer times out, so the reader behind it can share the lock
+        let mut woken = vec![];
+        lock.remove(xous::MessageSender::from_usize(1), record(&mut woken));
+        assert_eq!(woken, vec![2]);
+        assert!(lock.try_read());
+    }
+}
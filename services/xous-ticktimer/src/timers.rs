@@ -0,0 +1,227 @@
+//! Timers created by `SubscribeTimer`, which send a message to a server each time
+//! they expire.
+//!
+//! Expiries are scheduled in the sleep heap by the main loop, which looks the timer
+//! up here when one fires. A timer may have been rescheduled or cancelled since its
+//! expiry was queued, so stale expiries find nothing to do.
+
+use std::collections::HashMap;
+
+pub struct Timer {
+    /// The process that created the timer, which is the only one that may change it
+    pub pid: Option<xous::PID>,
+    /// Our own connection to the server that the timer sends its message to
+    pub cid: xous::CID,
+    pub opcode: usize,
+    /// The time at which the timer next expires
+    pub expiry: i64,
+    /// The period of a periodic timer
+    pub period: Option<i64>,
+}
+
+impl Timer {
+    /// A timer that first expires `us` from `now_us`, and then every `us` after that if
+    /// it is `periodic`
+    pub fn new(
+        pid: Option<xous::PID>,
+        cid: xous::CID,
+        opcode: usize,
+        us: i64,
+        periodic: bool,
+        now_us: i64,
+    ) -> Self {
+        // A period of 0 would expire continuously
+        let us = if periodic { us.max(1000) } else { us.max(0) };
+        Timer {
+            pid,
+            cid,
+            opcode,
+            expiry: now_us + us,
+            period: periodic.then_some(us),
+        }
+    }
+}
+
+/// What became of a timer once its expiry was delivered
+pub enum Expired {
+    /// The periodic timer expires again at this time
+    Next(i64),
+    /// The one-shot timer is done, and has been removed
+    Done(Timer),
+}
+
+#[derive(Default)]
+pub struct Timers {
+    timers: HashMap<u32, Timer>,
+    last_id: u32,
+}
+
+impl Timers {
+    /// Add a timer, returning its ID, which is never 0
+    pub fn add(&mut self, timer: Timer) -> u32 {
+        loop {
+            self.last_id = self.last_id.wrapping_add(1).max(1);
+            if !self.timers.contains_key(&self.last_id) {
+                break;
+            }
+        }
+        self.timers.insert(self.last_id, timer);
+        self.last_id
+    }
+
+    /// The timer `id`, if it is due at `now_us`
+    pub fn due(&self, id: u32, now_us: i64) -> Option<&Timer> {
+        self.timers.get(&id).filter(|t| t.expiry <= now_us)
+    }
+
+    /// Move the timer `id` on once its expiry has been delivered. Periodic timers are
+    /// scheduled from their previous expiry, so that they don't drift, but never for a
+    /// time that has already passed.
+    pub fn expired(&mut self, id: u32, now_us: i64) -> Option<Expired> {
+        let timer = self.timers.get_mut(&id)?;
+        match timer.period {
+            Some(period) => {
+                timer.expiry = (timer.expiry + period).max(now_us);
+                Some(Expired::Next(timer.expiry))
+            }
+            None => self.timers.remove(&id).map(Expired::Done),
+        }
+    }
+
+    /// `true` if the timer `id` exists and was created by `pid`
+    pub fn owned_by(&self, id: u32, pid: Option<xous::PID>) -> bool {
+        self.timers.get(&id).map(|t| t.pid == pid).unwrap_or(false)
+    }
+
+    /// Have the timer `id` expire `us` from `now_us`. A periodic timer keeps that as
+    /// its new period. Returns the new expiry.
+    pub fn reschedule(&mut self, id: u32, us: i64, now_us: i64) -> Option<i64> {
+        let timer = self.timers.get_mut(&id)?;
+        let periodic = timer.period.is_some();
+        *timer = Timer::new(timer.pid, timer.cid, timer.opcode, us, periodic, now_us);
+        Some(timer.expiry)
+    }
+
+    pub fn remove(&mut self, id: u32) -> Option<Timer> {
+        self.timers.remove(&id)
+    }
+
+    /// Remove the timers created by `pid`, returning their IDs and the timers
+    pub fn remove_process(&mut self, pid: xous::PID) -> Vec<(u32, Timer)> {
+        let ids: Vec<u32> = self
+            .timers
+            .iter()
+            .filter(|(_, t)| t.pid == Some(pid))
+            .map(|(id, _)| *id)
+            .collect();
+        ids.into_iter()
+            .filter_map(|id| self.timers.remove(&id).map(|t| (id, t)))
+            .collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.timers.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pid(pid: u8) -> Option<xous::PID> {
+        xous::PID::new(pid)
+    }
+
+    #[test]
+    fn one_shot_timers_expire_once() {
+        let mut timers = Timers::default();
+        let id = timers.add(Timer::new(pid(2), 5, 9, 10_000, false, 1_000));
+        assert!(timers.due(id, 10_999).is_none());
+        assert_eq!(
+            timers.due(id, 11_000).map(|t| (t.cid, t.opcode)),
+            Some((5, 9))
+        );
+        assert!(matches!(timers.expired(id, 11_000), Some(Expired::Done(t)) if t.cid == 5));
+        assert!(timers.due(id, 11_000).is_none());
+        assert_eq!(timers.len(), 0);
+    }
+
+    #[test]
+    fn periodic_timers_do_not_drift() {
+        let mut timers = Timers::default();
+        let id = timers.add(Timer::new(pid(2), 5, 9, 10_000, true, 0));
+        // Delivered late, but the next expiry is still on the original schedule
+        assert!(matches!(
+            timers.expired(id, 12_000),
+            Some(Expired::Next(20_000))
+        ));
+        // So late that the next expiry has already passed
+        assert!(matches!(
+            timers.expired(id, 45_000),
+            Some(Expired::Next(45_000))
+        ));
+        assert_eq!(timers.len(), 1);
+    }
+
+    #[test]
+    fn periodic_timers_have_a_minimum_period() {
+        let mut timers = Timers::default();
+        let id = timers.add(Timer::new(pid(2), 5, 9, 0, true, 0));
+        assert!(timers.due(id, 999).is_none());
+        assert!(matches!(
+            timers.expired(id, 1_000),
+            Some(Expired::Next(2_000))
+        ));
+    }
+
+    #[test]
+    fn rescheduled_timers_leave_stale_expiries_behind() {
+        let mut timers = Timers::default();
+        let id = timers.add(Timer::new(pid(2), 5, 9, 10_000, true, 0));
+        assert_eq!(timers.reschedule(id, 50_000, 5_000), Some(55_000));
+        assert!(timers.due(id, 10_000).is_none());
+        assert!(matches!(
+            timers.expired(id, 55_000),
+            Some(Expired::Next(105_000))
+        ));
+    }
+
+    #[test]
+    fn only_the_owner_may_change_a_timer() {
+        let mut timers = Timers::default();
+        let id = timers.add(Timer::new(pid(2), 5, 9, 10_000, false, 0));
+        assert!(timers.owned_by(id, pid(2)));
+        assert!(!timers.owned_by(id, pid(3)));
+        assert!(!timers.owned_by(id + 1, pid(2)));
+    }
+
+    #[test]
+    fn timers_go_away_with_their_process() {
+        let mut timers = Timers::default();
+        let a = timers.add(Timer::new(pid(2), 5, 9, 10_000, false, 0));
+        let b = timers.add(Timer::new(pid(3), 6, 9, 10_000, true, 0));
+        let c = timers.add(Timer::new(pid(2), 7, 9, 10_000, true, 0));
+        let mut removed: Vec<u32> = timers
+            .remove_process(xous::PID::new(2).unwrap())
+            .into_iter()
+            .map(|(id, _)| id)
+            .collect();
+        removed.sort();
+        assert_eq!(removed, vec![a, c]);
+        assert!(timers.owned_by(b, pid(3)));
+        assert_eq!(timers.len(), 1);
+    }
+
+    #[test]
+    fn ids_are_never_zero_or_reused() {
+        let mut timers = Timers {
+            last_id: u32::MAX - 1,
+            ..Default::default()
+        };
+        let a = timers.add(Timer::new(pid(2), 5, 9, 0, false, 0));
+        let b = timers.add(Timer::new(pid(2), 5, 9, 0, false, 0));
+        assert_eq!((a, b), (u32::MAX, 1));
+        timers.last_id = u32::MAX - 1;
+        assert_eq!(timers.add(Timer::new(pid(2), 5, 9, 0, false, 0)), 2);
+    }
+}
@@ -0,0 +1,132 @@
+//! Wall-clock time, kept as an offset from the ticktimer's elapsed time.
+//!
+//! The ticktimer stops counting while the system is suspended, and may come back from a
+//! suspend with a count that is behind the one it went into it with. The offset is moved
+//! on by any ticks that went missing, so that the wall clock carries on from where it
+//! stopped rather than going backwards. The time spent in suspend is still missing, so
+//! the clock is marked unsynchronized until it is set again, for example from the RTC.
+
+pub struct WallClock {
+    /// UTC in milliseconds since the Unix epoch at the moment `elapsed_ms` was 0, or
+    /// `None` if the time has never been set
+    offset_ms: Option<i64>,
+    synchronized: bool,
+    /// An index into `tz::ZONES`
+    zone: usize,
+    /// The elapsed time when the system was suspended
+    suspended_ms: Option<u64>,
+}
+
+impl Default for WallClock {
+    fn default() -> Self {
+        WallClock {
+            offset_ms: None,
+            synchronized: false,
+            zone: xous_api_ticktimer::tz::UTC,
+            suspended_ms: None,
+        }
+    }
+}
+
+impl WallClock {
+    /// The current time in milliseconds since the Unix epoch, or 0 if it was never set
+    pub fn utc_ms(&self, elapsed_ms: u64) -> u64 {
+        self.offset_ms
+            .map(|offset| (offset + elapsed_ms as i64).max(0) as u64)
+            .unwrap_or(0)
+    }
+
+    pub fn set_utc_ms(&mut self, utc_ms: u64, elapsed_ms: u64) {
+        self.offset_ms = Some(utc_ms as i64 - elapsed_ms as i64);
+        self.synchronized = true;
+    }
+
+    /// `true` if the time was set, and the system hasn't been suspended since
+    pub fn synchronized(&self) -> bool {
+        self.synchronized
+    }
+
+    pub fn zone(&self) -> usize {
+        self.zone
+    }
+
+    pub fn set_zone(&mut self, zone: usize) -> bool {
+        if zone < xous_api_ticktimer::tz::ZONES.len() {
+            self.zone = zone;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Called before suspending, with the elapsed time at that point
+    pub fn suspend(&mut self, elapsed_ms: u64) {
+        self.suspended_ms = Some(elapsed_ms);
+    }
+
+    /// Called on resume, once the ticktimer is counting again
+    pub fn resume(&mut self, elapsed_ms: u64) {
+        if let (Some(suspended_ms), Some(offset)) = (self.suspended_ms.take(), self.offset_ms) {
+            if elapsed_ms < suspended_ms {
+                self.offset_ms = Some(offset + (suspended_ms - elapsed_ms) as i64);
+            }
+        }
+        self.synchronized = false;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_clock_counts_from_when_it_was_set() {
+        let mut clock = WallClock::default();
+        assert_eq!(clock.utc_ms(5_000), 0);
+        assert!(!clock.synchronized());
+        clock.set_utc_ms(1_700_000_000_000, 5_000);
+        assert_eq!(clock.utc_ms(6_500), 1_700_000_001_500);
+        assert!(clock.synchronized());
+    }
+
+    #[test]
+    fn a_resume_carries_on_from_the_suspend() {
+        let mut clock = WallClock::default();
+        clock.set_utc_ms(1_700_000_000_000, 5_000);
+        clock.suspend(10_000);
+        // The ticktimer came back from the suspend counting from an earlier value
+        clock.resume(2_000);
+        assert_eq!(clock.utc_ms(2_000), 1_700_000_005_000);
+        assert_eq!(clock.utc_ms(3_000), 1_700_000_006_000);
+        assert!(!clock.synchronized());
+    }
+
+    #[test]
+    fn a_resume_keeps_ticks_that_were_counted() {
+        let mut clock = WallClock::default();
+        clock.set_utc_ms(1_700_000_000_000, 5_000);
+        clock.suspend(10_000);
+        clock.resume(10_020);
+        assert_eq!(clock.utc_ms(10_020), 1_700_000_005_020);
+        assert!(!clock.synchronized());
+        clock.set_utc_ms(1_700_000_100_000, 10_020);
+        assert!(clock.synchronized());
+    }
+
+    #[test]
+    fn a_clock_that_was_never_set_stays_unset() {
+        let mut clock = WallClock::default();
+        clock.suspend(10_000);
+        clock.resume(0);
+        assert_eq!(clock.utc_ms(1_000), 0);
+    }
+
+    #[test]
+    fn only_known_zones_are_set() {
+        let mut clock = WallClock::default();
+        assert!(clock.set_zone(1));
+        assert_eq!(clock.zone(), 1);
+        assert!(!clock.set_zone(xous_api_ticktimer::tz::ZONES.len()));
+        assert_eq!(clock.zone(), 1);
+    }
+}
@@ -628,6 +628,7 @@ impl Field {
     }
     pub const fn offset(&self) -> usize { self.offset }
     pub const fn mask(&self) -> usize { self.mask }
+    pub const fn register(&self) -> Register { self.register }
 }
 #[derive(Debug, Copy, Clone)]
 pub struct CSR<T> {
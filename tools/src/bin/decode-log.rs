@@ -0,0 +1,72 @@
+use clap::{crate_version, App, Arg};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Write};
+use std::process;
+use tools::log_decode::{decode_line, read_entries, EntryTable};
+
+fn main() {
+    let matches = App::new("decode-log")
+        .version(crate_version!())
+        .about("Turn compact log records in a console capture back into text")
+        .arg(
+            Arg::with_name("elf")
+                .long("elf")
+                .help("ELF file of a process that logs compact records, as PID=FILE, or just FILE to use it for every PID without one")
+                .value_name("[PID=]FILE")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .required(true),
+        )
+        .arg(
+            Arg::with_name("capture")
+                .help("Console capture to decode, or standard input if omitted")
+                .value_name("CAPTURE"),
+        )
+        .get_matches();
+
+    let mut tables: HashMap<u32, EntryTable> = HashMap::new();
+    let mut default_table = None;
+    for spec in matches.values_of("elf").unwrap() {
+        let (pid, filename) = match spec.split_once('=') {
+            Some((pid, filename)) => match pid.parse::<u32>() {
+                Ok(pid) => (Some(pid), filename),
+                Err(_) => (None, spec),
+            },
+            None => (None, spec),
+        };
+        let table = read_entries(filename).unwrap_or_else(|e| {
+            eprintln!("Unable to read {}: {}", filename, e);
+            process::exit(1);
+        });
+        match pid {
+            Some(pid) => {
+                tables.insert(pid, table);
+            }
+            None => default_table = Some(table),
+        }
+    }
+
+    let input: Box<dyn BufRead> = match matches.value_of("capture") {
+        Some(filename) => Box::new(BufReader::new(File::open(filename).unwrap_or_else(|e| {
+            eprintln!("Unable to open {}: {}", filename, e);
+            process::exit(1);
+        }))),
+        None => Box::new(BufReader::new(io::stdin())),
+    };
+
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    for line in input.split(b'\n') {
+        let line = line.unwrap_or_else(|e| {
+            eprintln!("Unable to read the capture: {}", e);
+            process::exit(1);
+        });
+        // Captures may hold binary noise, so don't insist on UTF-8
+        let line = String::from_utf8_lossy(&line);
+        let line = line.trim_end_matches('\r');
+        let decoded = decode_line(line, |pid| tables.get(&pid).or(default_table.as_ref()));
+        writeln!(out, "{}", decoded.as_deref().unwrap_or(line)).ok();
+    }
+}
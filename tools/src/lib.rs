@@ -3,6 +3,7 @@ extern crate log;
 #[macro_use]
 pub mod xous_arguments;
 pub mod elf;
+pub mod log_decode;
 pub mod sign_image;
 pub mod tags;
 pub mod utils;
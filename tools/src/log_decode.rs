@@ -0,0 +1,432 @@
+//! Turn the compact log records that `xous_api_log::compact` sends back into text.
+//!
+//! The log server writes each compact record as a line of the form
+//! `XLOG <pid> <entry address> <encoded arguments>`. The entry address is that of a
+//! static whose symbol name holds the level, module, file, line and format string, so
+//! the ELF file of the process is all that's needed to rebuild the text.
+
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use xmas_elf::sections::SectionData;
+use xmas_elf::symbol_table::Entry;
+use xmas_elf::ElfFile;
+
+use crate::elf::ElfReadError;
+
+/// The prefix of the names of interned entries
+const SYMBOL_PREFIX: &str = "xous_log;";
+
+/// The prefix of compact records in the console output
+const LINE_PREFIX: &str = "XLOG ";
+
+/// An interned log statement
+#[derive(Debug, Clone)]
+pub struct LogEntry {
+    pub level: u8,
+    pub module: String,
+    pub file: String,
+    pub line: u32,
+    /// The names of the named arguments, which are sent before the positional ones
+    pub names: Vec<String>,
+    pub format: String,
+}
+
+impl LogEntry {
+    fn from_symbol(name: &str) -> Option<LogEntry> {
+        let mut fields = name.strip_prefix(SYMBOL_PREFIX)?.splitn(7, ';');
+        let level = fields.next()?.parse().ok()?;
+        let module = fields.next()?.to_owned();
+        let file = fields.next()?.to_owned();
+        let line = fields.next()?.parse().ok()?;
+        let _column = fields.next()?;
+        let names = fields
+            .next()?
+            .split_terminator(',')
+            .map(str::to_owned)
+            .collect();
+        let format = fields.next()?.to_owned();
+        Some(LogEntry {
+            level,
+            module,
+            file,
+            line,
+            names,
+            format,
+        })
+    }
+}
+
+/// The interned entries of one ELF file, by address
+pub type EntryTable = HashMap<u64, LogEntry>;
+
+/// Read the interned entries out of the symbol table of an ELF file
+pub fn read_entries<P: AsRef<Path>>(filename: P) -> Result<EntryTable, ElfReadError> {
+    let mut b = Vec::new();
+    File::open(filename)
+        .map_err(ElfReadError::OpenElfError)?
+        .read_to_end(&mut b)
+        .map_err(ElfReadError::ReadFileError)?;
+    let elf = ElfFile::new(&b).map_err(ElfReadError::ParseElfError)?;
+
+    let mut entries = EntryTable::new();
+    let mut add = |name: Result<&str, &'static str>, address: u64| {
+        if let Some(entry) = name.ok().and_then(LogEntry::from_symbol) {
+            entries.insert(address, entry);
+        }
+    };
+    for section in elf.section_iter() {
+        match section.get_data(&elf) {
+            Ok(SectionData::SymbolTable32(symbols)) => {
+                for symbol in symbols {
+                    add(symbol.get_name(&elf), symbol.value());
+                }
+            }
+            Ok(SectionData::SymbolTable64(symbols)) => {
+                for symbol in symbols {
+                    add(symbol.get_name(&elf), symbol.value());
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(entries)
+}
+
+/// A decoded argument
+#[derive(Debug, Clone, PartialEq)]
+pub enum Arg {
+    Unsigned(u64),
+    Signed(i64),
+    Bool(bool),
+    Char(char),
+    Str(String),
+}
+
+fn read_leb128(buf: &[u8], offset: &mut usize) -> Option<u64> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let byte = *buf.get(*offset)?;
+        *offset += 1;
+        value |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+/// Decode the arguments of a compact record. Decoding stops at the first argument that
+/// is malformed.
+pub fn decode_args(buf: &[u8]) -> Vec<Arg> {
+    let mut args = vec![];
+    let mut offset = 0;
+    while offset < buf.len() {
+        let tag = buf[offset];
+        offset += 1;
+        let arg = match tag {
+            0 => read_leb128(buf, &mut offset).map(Arg::Unsigned),
+            1 => read_leb128(buf, &mut offset)
+                .map(|v| Arg::Signed((v >> 1) as i64 ^ -((v & 1) as i64))),
+            2 => Some(Arg::Bool(false)),
+            3 => Some(Arg::Bool(true)),
+            4 => read_leb128(buf, &mut offset)
+                .and_then(|v| std::char::from_u32(v as u32))
+                .map(Arg::Char),
+            5 => read_leb128(buf, &mut offset).and_then(|len| {
+                let end = offset.checked_add(usize::try_from(len).ok()?)?;
+                let s = buf.get(offset..end)?;
+                offset = end;
+                Some(Arg::Str(String::from_utf8_lossy(s).into_owned()))
+            }),
+            _ => None,
+        };
+        match arg {
+            Some(arg) => args.push(arg),
+            None => break,
+        }
+    }
+    args
+}
+
+/// Format `arg` according to the part of a placeholder after the `:`. Fill, alignment,
+/// sign and precision are ignored.
+fn format_arg(arg: &Arg, spec: &str) -> String {
+    let alternate = spec.contains('#');
+    let digits = spec.trim_start_matches(|c| "<^>#+-".contains(c));
+    let zero_pad = digits.starts_with('0');
+    let width: usize = digits
+        .chars()
+        .take_while(|c| c.is_ascii_digit())
+        .collect::<String>()
+        .parse()
+        .unwrap_or(0);
+    let kind = spec
+        .chars()
+        .last()
+        .filter(|c| c.is_ascii_alphabetic() || *c == '?');
+
+    let text = match (arg, kind) {
+        (Arg::Unsigned(v), Some('x')) if alternate => format!("{:#x}", v),
+        (Arg::Unsigned(v), Some('x')) => format!("{:x}", v),
+        (Arg::Unsigned(v), Some('X')) if alternate => format!("{:#X}", v),
+        (Arg::Unsigned(v), Some('X')) => format!("{:X}", v),
+        (Arg::Unsigned(v), Some('b')) if alternate => format!("{:#b}", v),
+        (Arg::Unsigned(v), Some('b')) => format!("{:b}", v),
+        (Arg::Unsigned(v), Some('o')) => format!("{:o}", v),
+        (Arg::Unsigned(v), _) => v.to_string(),
+        // Hex of a negative number shows its bits, as for the original type. The width of
+        // that type is lost, so assume 32 bits if it fits.
+        (Arg::Signed(v), Some('x' | 'X' | 'b' | 'o')) if *v < 0 => {
+            let bits = if *v >= i32::MIN as i64 {
+                *v as u32 as u64
+            } else {
+                *v as u64
+            };
+            return format_arg(&Arg::Unsigned(bits), spec);
+        }
+        (Arg::Signed(v), Some('x' | 'X' | 'b' | 'o')) => {
+            return format_arg(&Arg::Unsigned(*v as u64), spec)
+        }
+        (Arg::Signed(v), _) => v.to_string(),
+        (Arg::Bool(v), _) => v.to_string(),
+        (Arg::Char(v), Some('?')) => format!("{:?}", v),
+        (Arg::Char(v), _) => v.to_string(),
+        (Arg::Str(v), Some('?')) => format!("{:?}", v),
+        (Arg::Str(v), _) => v.clone(),
+    };
+
+    if text.len() >= width {
+        text
+    } else if zero_pad {
+        // Zeroes go after the sign and a `0x` prefix
+        let sign = text.starts_with('-') as usize;
+        let prefix = ["0x", "0b"].iter().any(|p| text[sign..].starts_with(p));
+        let split = sign + if alternate && prefix { 2 } else { 0 };
+        format!(
+            "{}{}{}",
+            &text[..split],
+            "0".repeat(width - text.len()),
+            &text[split..]
+        )
+    } else if matches!(arg, Arg::Str(_) | Arg::Char(_) | Arg::Bool(_)) {
+        format!("{:<width$}", text, width = width)
+    } else {
+        format!("{:>width$}", text, width = width)
+    }
+}
+
+/// Substitute `args` into `format` the way `format!()` would. The arguments called
+/// `names` come first in `args`, followed by the positional ones. Arguments that were
+/// dropped because they didn't fit, or that were never sent, are shown as `{?}`.
+pub fn format_message(format: &str, names: &[String], args: &[Arg]) -> String {
+    let mut out = String::new();
+    // The next argument for a placeholder that doesn't say which one it takes
+    let mut next = 0;
+    let mut chars = format.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '{' if chars.peek() == Some(&'{') => {
+                chars.next();
+                out.push('{');
+            }
+            '}' if chars.peek() == Some(&'}') => {
+                chars.next();
+                out.push('}');
+            }
+            '{' => {
+                let placeholder: String = chars.by_ref().take_while(|c| *c != '}').collect();
+                let (which, spec) = placeholder
+                    .split_once(':')
+                    .unwrap_or((placeholder.as_str(), ""));
+                let index = if which.is_empty() {
+                    next += 1;
+                    Some(names.len() + next - 1)
+                } else if let Ok(position) = which.parse::<usize>() {
+                    Some(names.len() + position)
+                } else {
+                    names.iter().position(|name| name == which)
+                };
+                match index.and_then(|index| args.get(index)) {
+                    Some(arg) => out.push_str(&format_arg(arg, spec)),
+                    None => out.push_str("{?}"),
+                }
+            }
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+fn level_name(level: u8) -> &'static str {
+    match level {
+        1 => "ERR ",
+        2 => "WARN",
+        3 => "INFO",
+        4 => "DBG ",
+        5 => "TRCE",
+        _ => "UNKNOWN",
+    }
+}
+
+fn parse_hex(s: &str) -> Option<Vec<u8>> {
+    if s.len() % 2 != 0 {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// Decode the compact record in `line`, if there is one. `table_for` returns the entries
+/// of the process with the given PID.
+///
+/// The result is formatted as the log server formats text records, or is a note saying
+/// why the record couldn't be decoded.
+pub fn decode_line<'a, F>(line: &str, table_for: F) -> Option<String>
+where
+    F: Fn(u32) -> Option<&'a EntryTable>,
+{
+    let start = line.find(LINE_PREFIX)?;
+    let mut fields = line[start + LINE_PREFIX.len()..].trim_end().split(' ');
+    let pid: u32 = fields.next()?.parse().ok()?;
+    let address = u64::from_str_radix(fields.next()?, 16).ok()?;
+    let args = parse_hex(fields.next().unwrap_or(""))?;
+
+    let entry = match table_for(pid).and_then(|table| table.get(&address)) {
+        Some(entry) => entry,
+        None => {
+            return Some(format!(
+                "{}<no entry at {:08x} for PID {}>",
+                &line[..start],
+                address,
+                pid
+            ))
+        }
+    };
+    Some(format!(
+        "{}{}:{}: {} ({}:{})",
+        &line[..start],
+        level_name(entry.level),
+        entry.module,
+        format_message(&entry.format, &entry.names, &decode_args(&args)),
+        entry.file,
+        entry.line
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn symbols_are_parsed() {
+        let entry =
+            LogEntry::from_symbol("xous_log;3;app::net;src/net.rs;42;9;n,m,;{n} {m}; {}").unwrap();
+        assert_eq!(entry.level, 3);
+        assert_eq!(entry.module, "app::net");
+        assert_eq!(entry.file, "src/net.rs");
+        assert_eq!(entry.line, 42);
+        assert_eq!(entry.names, names(&["n", "m"]));
+        assert_eq!(entry.format, "{n} {m}; {}");
+        let entry = LogEntry::from_symbol("xous_log;1;app;src/main.rs;7;5;;plain").unwrap();
+        assert!(entry.names.is_empty());
+        assert!(LogEntry::from_symbol("something_else").is_none());
+    }
+
+    #[test]
+    fn arguments_are_decoded() {
+        let buf = [0, 0xe5, 0x8e, 0x26, 1, 5, 2, 3, 4, 0x41, 5, 2, b'h', b'i'];
+        assert_eq!(
+            decode_args(&buf),
+            [
+                Arg::Unsigned(624485),
+                Arg::Signed(-3),
+                Arg::Bool(false),
+                Arg::Bool(true),
+                Arg::Char('A'),
+                Arg::Str("hi".to_owned()),
+            ]
+        );
+    }
+
+    #[test]
+    fn malformed_arguments_stop_decoding() {
+        // A string that is longer than what's left
+        assert_eq!(decode_args(&[0, 1, 5, 9, b'a']), [Arg::Unsigned(1)]);
+        // A string length that would overflow the offset
+        let mut buf = vec![5];
+        buf.extend_from_slice(&[0xff; 9]);
+        buf.push(0x01);
+        assert_eq!(decode_args(&buf), []);
+        // An unknown tag
+        assert_eq!(decode_args(&[3, 9, 2]), [Arg::Bool(true)]);
+    }
+
+    #[test]
+    fn numbers_are_formatted() {
+        assert_eq!(format_arg(&Arg::Unsigned(255), "x"), "ff");
+        assert_eq!(format_arg(&Arg::Unsigned(255), "#06x"), "0x00ff");
+        assert_eq!(format_arg(&Arg::Unsigned(5), "08b"), "00000101");
+        assert_eq!(format_arg(&Arg::Unsigned(7), "4"), "   7");
+        assert_eq!(format_arg(&Arg::Signed(-3), ""), "-3");
+        assert_eq!(format_arg(&Arg::Signed(-3), "04"), "-003");
+        assert_eq!(format_arg(&Arg::Signed(-3), "x"), "fffffffd");
+        assert_eq!(format_arg(&Arg::Signed(12), "05"), "00012");
+        assert_eq!(format_arg(&Arg::Unsigned(1234), "#06"), "001234");
+        assert_eq!(format_arg(&Arg::Str("ab".to_owned()), "4"), "ab  ");
+        assert_eq!(format_arg(&Arg::Char('a'), "?"), "'a'");
+    }
+
+    #[test]
+    fn placeholders_take_the_right_arguments() {
+        let args = [Arg::Unsigned(1), Arg::Unsigned(2), Arg::Str("x".to_owned())];
+        assert_eq!(format_message("{} {} {}", &[], &args), "1 2 x");
+        assert_eq!(format_message("{2} {0} {1:02}", &[], &args), "x 1 02");
+        assert_eq!(format_message("{{{}}} }}", &[], &args), "{1} }");
+        assert_eq!(format_message("{} {} {} {}", &[], &args), "1 2 x {?}");
+        // Named arguments come first
+        let named = names(&["name", "n"]);
+        let args = [
+            Arg::Str("x".to_owned()),
+            Arg::Unsigned(10),
+            Arg::Unsigned(1),
+        ];
+        assert_eq!(
+            format_message("{name} {n:#x} {} {0} {missing}", &named, &args),
+            "x 0xa 1 1 {?}"
+        );
+    }
+
+    #[test]
+    fn lines_are_decoded() {
+        let mut table = EntryTable::new();
+        table.insert(
+            0x1234,
+            LogEntry::from_symbol("xous_log;2;app;src/main.rs;7;5;;{} left").unwrap(),
+        );
+        let decode = |line: &str| decode_line(line, |pid| (pid == 5).then_some(&table));
+        assert_eq!(
+            decode("12.5: XLOG 5 00001234 0003").unwrap(),
+            "12.5: WARN:app: 3 left (src/main.rs:7)"
+        );
+        assert_eq!(
+            decode("XLOG 5 00004321 ").unwrap(),
+            "<no entry at 00004321 for PID 5>"
+        );
+        assert_eq!(
+            decode("XLOG 6 00001234 0003").unwrap(),
+            "<no entry at 00001234 for PID 6>"
+        );
+        assert!(decode("INFO:app: not compact").is_none());
+        assert!(decode("XLOG 5 00001234 003").is_none());
+    }
+}
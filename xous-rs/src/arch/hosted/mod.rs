@@ -120,6 +120,7 @@ enum CallMemoryKind {
     MutableBorrow,
     Move,
     ReturnMemory,
+    Forward,
 }
 
 #[derive(Clone)]
@@ -147,6 +148,8 @@ pub fn syscall(call: SysCall) -> SysCallResult {
             CallMemoryKind::Move
         } else if call.is_return_memory() {
             CallMemoryKind::ReturnMemory
+        } else if call.is_forward_memory() {
+            CallMemoryKind::Forward
         } else {
             panic!("call had memory, but was unrecognized")
         };
@@ -305,6 +308,12 @@ fn read_next_syscall_result(
             if kind == CallMemoryKind::ReturnMemory {
                 mem::unmap_memory_post(mem).unwrap();
             }
+
+            // A forwarded message now belongs to the target server. If the forward
+            // failed, the message is still ours and must be kept.
+            if kind == CallMemoryKind::Forward && !matches!(response, Result::Error(_)) {
+                mem::unmap_memory_post(mem).unwrap();
+            }
         }
         return (msg_thread_id, response);
     }
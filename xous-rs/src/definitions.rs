@@ -10,6 +10,19 @@ pub type Connection = usize;
 
 pub const MAX_CID: usize = 34;
 
+/// The ID of the `Scalar` message that the kernel sends to each server that a process
+/// was connected to when that process terminates. The sender is the kernel, PID 1, and
+/// `arg1` holds the PID of the process that terminated.
+pub const PROCESS_TERMINATED_ID: usize = 0xffff_fffe;
+
+/// The ID of the `Scalar` messages that the kernel sends to the name server when it is
+/// created, describing the processes in the boot image. The names come from the loader,
+/// so unlike the names that processes claim for themselves, they can be trusted. The
+/// sender is the kernel, PID 1. `arg1` holds the PID in bits 0-7, the offset of this
+/// part of the name in bits 8-15 and the length of the whole name in bits 16-23.
+/// `arg2` to `arg4` hold up to 12 bytes of the name, little-endian.
+pub const PROCESS_NAME_ID: usize = 0xffff_fffd;
+
 pub const FLASH_PHYS_BASE: u32 = 0x6000_0000;
 pub const SOC_REGION_LOC: u32 = 0x0000_0000;
 pub const SOC_REGION_LEN: u32 = 0x00D0_0000; // gw + staging + loader + kernel
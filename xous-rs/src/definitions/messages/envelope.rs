@@ -45,9 +45,10 @@ impl Envelope {
 
     /// Take this message and forward it to another server.
     ///
-    /// **Note**: For blocking messages, this will block until the other server responds to
-    /// the message. In the future, this may be turned into a nonblocking operation where
-    /// this will return immediately and allow the target Server to respond directly.
+    /// Blocking messages are handed over by the kernel along with the obligation to
+    /// respond, so this returns as soon as the other server has the message. The
+    /// other server replies directly to the original sender, and sees this process
+    /// as the message's proxy.
     ///
     /// ## Result
     ///
@@ -71,7 +72,7 @@ impl Envelope {
         // Unsafe because there are now two things that are pointing at "self.body". However,
         // this is fine since these two pointers are never used at the same time.
         let body = unsafe { core::ptr::read(&manual_self.body) };
-        let sender = unsafe { core::ptr::read(&manual_self.sender) };
+        let sender = manual_self.sender;
 
         // Different messages have different kinds of lifetimes, so they must all be
         // handled differently.
@@ -90,46 +91,23 @@ impl Envelope {
                     return Err((ManuallyDrop::into_inner(manual_self), e));
                 }
 
-                return Err((
+                Err((
                     ManuallyDrop::into_inner(manual_self),
                     crate::Error::MemoryInUse,
-                ));
+                ))
             }
-            Message::BlockingScalar(_) => {
-                let result = crate::send_message(connection, body);
-
-                // If there's an error, reconstitute ourselves and return.
-                if let Err(e) = result {
-                    return Err((ManuallyDrop::into_inner(manual_self), e));
-                } else if let Ok(crate::Result::Scalar1(v)) = result {
-                    if let Err(e) = crate::return_scalar(sender, v) {
-                        return Err((ManuallyDrop::into_inner(manual_self), e));
-                    }
-                    return Ok(());
-                } else if let Ok(crate::Result::Scalar2(v1, v2)) = result {
-                    if let Err(e) = crate::return_scalar2(sender, v1, v2) {
-                        return Err((ManuallyDrop::into_inner(manual_self), e));
-                    }
-                    return Ok(());
-                }
-                return Err((
-                    ManuallyDrop::into_inner(manual_self),
-                    crate::Error::MemoryInUse,
-                ));
-            }
-
-            Message::Borrow(_) | Message::MutableBorrow(_) | Message::Scalar(_) => {
-                let result = crate::send_message(connection, body);
-                let new_self = ManuallyDrop::into_inner(manual_self);
-                match result {
-                    // `new_self` will have its Drop() called
-                    Ok(crate::Result::Ok) | Ok(crate::Result::MemoryReturned(_, _)) => Ok(()),
-
-                    // If there's an error, reconstitute ourselves and return.
-                    Err(e) => Err((new_self, e)),
-
-                    // Any other value is a bug in the kernel
-                    o => panic!("unrecognized return from send_message: {:?}", o),
+            Message::Scalar(_) => match crate::send_message(connection, body) {
+                Ok(_) => Ok(()),
+                Err(e) => Err((ManuallyDrop::into_inner(manual_self), e)),
+            },
+            Message::BlockingScalar(_) | Message::Borrow(_) | Message::MutableBorrow(_) => {
+                match crate::forward_message(sender, connection, body) {
+                    // The other server now owns the message and will respond to it,
+                    // so `self` goes out of scope without having `Drop` called on it
+                    Ok(()) => Ok(()),
+
+                    // If there's an error, the message is still ours
+                    Err(e) => Err((ManuallyDrop::into_inner(manual_self), e)),
                 }
             }
         }
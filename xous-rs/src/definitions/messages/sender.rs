@@ -1,4 +1,8 @@
-use crate::PID;
+use crate::{PID, TID};
+
+/// Set in bits 8-15 of the sender when they hold a proxy's PID rather than the
+/// sending thread's TID
+const PROXY_FLAG: usize = 0x80;
 
 #[derive(Debug, PartialEq, Eq, Ord, PartialOrd, Copy, Clone, Default)]
 pub struct Sender {
@@ -18,6 +22,27 @@ impl Sender {
         let pid_u8 = ((self.data >> 24) & 0xff) as u8;
         PID::new(pid_u8)
     }
+
+    /// If this message was forwarded to us by another server, return the PID
+    /// of the process that forwarded it. `pid()` is still the process that
+    /// originally sent the message.
+    pub fn proxy_pid(&self) -> Option<PID> {
+        let thread_or_proxy = (self.data >> 8) & 0xff;
+        if thread_or_proxy & PROXY_FLAG == 0 {
+            return None;
+        }
+        PID::new((thread_or_proxy & !PROXY_FLAG) as u8)
+    }
+
+    /// The thread that sent this message, as recorded by the kernel. This is `None`
+    /// for messages that were forwarded to us, and for messages from the kernel.
+    pub fn tid(&self) -> Option<TID> {
+        let thread_or_proxy = (self.data >> 8) & 0xff;
+        if thread_or_proxy & PROXY_FLAG != 0 || thread_or_proxy == 0 {
+            return None;
+        }
+        Some(thread_or_proxy as TID)
+    }
 }
 
 impl core::fmt::Display for Sender {
@@ -3,6 +3,21 @@ use crate::{
     Result, CID,
 };
 
+/// The largest amount of string data sent in a single chunk by
+/// [`StringBuffer::lend_streamed`]. This is one page, so every chunk
+/// can be lent in place without copying.
+pub const STRING_STREAM_CHUNK_SIZE: usize = 0x1000;
+
+/// Value placed in the `offset` field of a streamed chunk when more chunks
+/// follow. The final chunk of a stream has an `offset` of `None`.
+pub const STRING_STREAM_CONTINUES: usize = 1;
+
+/// Returns `true` if the given message is a chunk of a streamed string and
+/// more chunks will follow it.
+pub fn is_continued(mem: &MemoryMessage) -> bool {
+    mem.offset.map(|o| o.get()) == Some(STRING_STREAM_CONTINUES)
+}
+
 /// A buffered String suitable for sending across as a message
 pub struct StringBuffer<'a> {
     /// The backing store for this string, as a mutable pointer to
@@ -245,6 +260,80 @@ impl<'a> StringBuffer<'a> {
         self.should_free = false;
         Ok(result)
     }
+
+    /// Lend this String to the server as a sequence of page-sized chunks, so
+    /// that strings of any length can be sent to a server that only accepts a
+    /// single page at a time. Each chunk is lent in place. Every chunk but the
+    /// last has its `offset` set to [`STRING_STREAM_CONTINUES`], and the server
+    /// reassembles the string with [`StringBuffer::append_chunk`].
+    pub fn lend_streamed(&self, connection: CID, id: u32) -> core::result::Result<(), Error> {
+        let bytes = match &self.bytes {
+            Some(bytes) => bytes,
+            None => return Ok(()),
+        };
+
+        let mut start = 0;
+        loop {
+            let remaining = self.len() - start;
+            let more = remaining > STRING_STREAM_CHUNK_SIZE;
+            // The backing store is page-aligned and a multiple of the page size,
+            // so each chunk is a whole page within the backing store.
+            let msg = MemoryMessage {
+                id: id as usize,
+                buf: unsafe {
+                    MemoryRange::new(bytes.as_ptr() as usize + start, STRING_STREAM_CHUNK_SIZE)?
+                },
+                offset: if more {
+                    MemorySize::new(STRING_STREAM_CONTINUES)
+                } else {
+                    None
+                },
+                valid: MemorySize::new(remaining.min(STRING_STREAM_CHUNK_SIZE)),
+            };
+            send_message(connection, Message::Borrow(msg))?;
+            if !more {
+                return Ok(());
+            }
+            start += STRING_STREAM_CHUNK_SIZE;
+        }
+    }
+
+    /// Append one chunk of a string sent with [`StringBuffer::lend_streamed`],
+    /// growing this String as needed. Returns `true` once the final chunk has
+    /// been appended. A multi-byte character may be split across two chunks,
+    /// so the contents are only checked for valid UTF-8 when the stream is
+    /// complete, at which point any invalid tail is dropped.
+    pub fn append_chunk(&mut self, mem: &MemoryMessage) -> bool {
+        let valid = mem.valid.map(|v| v.get()).unwrap_or(0).min(mem.buf.len());
+        self.push_bytes(&mem.buf.as_slice::<u8>()[..valid]);
+
+        if is_continued(mem) {
+            return false;
+        }
+        if let Err(e) = core::str::from_utf8(&self.as_bytes()[..self.len()]) {
+            self.length = e.valid_up_to() as u32;
+        }
+        true
+    }
+
+    /// Append raw bytes to the end of the string, growing the backing store if
+    /// necessary. A loaned String cannot grow, so the data is truncated to fit.
+    fn push_bytes(&mut self, data: &[u8]) {
+        // Ensure the string can hold the new data
+        self.resize(self.len() + data.len());
+
+        // Copy the data over
+        let length = self.len();
+        let mut copied = 0;
+        for (dest, src) in self.as_bytes_mut()[length..].iter_mut().zip(data) {
+            *dest = *src;
+            copied += 1;
+        }
+        self.length += copied as u32;
+        if let Some(mm) = self.memory_message.as_mut() {
+            mm.valid = MemorySize::new(self.length as _).or(None);
+        }
+    }
 }
 
 impl<'a> core::str::FromStr for StringBuffer<'a> {
@@ -282,18 +371,7 @@ impl<'a> core::fmt::Display for StringBuffer<'a> {
 
 impl<'a> core::fmt::Write for StringBuffer<'a> {
     fn write_str(&mut self, s: &str) -> core::result::Result<(), core::fmt::Error> {
-        // Ensure the string can hold the new data
-        self.resize(self.len() + s.len());
-
-        // Copy the data over
-        let length = self.len();
-        for (dest, src) in self.as_bytes_mut()[length..].iter_mut().zip(s.as_bytes()) {
-            *dest = *src;
-        }
-        self.length += s.len() as u32;
-        if let Some(mm) = self.memory_message.as_mut() {
-            mm.valid = MemorySize::new(self.length as _).or(None);
-        }
+        self.push_bytes(s.as_bytes());
         Ok(())
     }
 }
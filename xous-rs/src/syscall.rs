@@ -443,6 +443,10 @@ pub enum SysCall {
     ///                  are supported:
     ///                         1: Maximum heap size
     ///                         2: Current heap size
+    ///                         3: RAM owned by a process, which is read-only.
+    ///                            Pass that process' PID as the current limit.
+    ///                            Only the name server may pass a PID other
+    ///                            than its own.
     ///     * **Current Limit**: Pass the current limit value here. The current
     ///                 limit must match in order for the new limit to take
     ///                 effect. This is used to avoid a race condition if two
@@ -456,6 +460,9 @@ pub enum SysCall {
     /// ## Errors
     ///
     ///     * **InvalidLimit**: The specified index was not valid
+    ///     * **ProcessNotFound**: Index 3 was given a PID that isn't running
+    ///     * **AccessDenied**: Index 3 was given another process' PID by a
+    ///                         process other than the name server
     AdjustProcessLimit(
         usize, /* process limit index */
         usize, /* expected current limit */
@@ -517,6 +524,39 @@ pub enum SysCall {
         usize,         /* for BlockingScalars, indicates how many args are valid */
     ),
 
+    /// Forward a blocking message that this server received to another server.
+    /// The obligation to reply moves to the target server, whose reply goes
+    /// directly to the process that originally sent the message. The target
+    /// sees the original sender's PID, and can get the PID of the forwarding
+    /// process from `MessageSender::proxy_pid()`.
+    ///
+    /// The message must be the same kind as the one that was received, except
+    /// that a `MutableBorrow` may be forwarded as a `Borrow`. Non-blocking
+    /// messages have no reply to forward, and should be sent normally.
+    ///
+    /// ## Arguments
+    ///
+    /// * **MessageSender**: The `sender` from the received message envelope
+    /// * **CID**: The connection to forward the message to
+    /// * **Message**: The message to deliver to the target server
+    ///
+    /// ## Returns
+    ///
+    /// * **Ok**: The message was handed to the target server. The caller no
+    ///   longer owns it, and must not respond to it.
+    ///
+    /// # Errors
+    ///
+    /// * **ServerNotFound**: The connection or the sender is not valid
+    /// * **ServerQueueFull**: The target server's queue is full
+    /// * **ShareViolation**: Tried to forward a `Borrow` as a `MutableBorrow`
+    /// * **InvalidSyscall**: The message kind doesn't match the received message
+    ///
+    /// These leave the caller holding the message. If it can't be delivered
+    /// after the caller has given it up, the original sender gets the error as
+    /// its reply, and the caller gets it too but must not respond.
+    ForwardMessage(MessageSender, CID, Message),
+
     /// This syscall does not exist. It captures all possible
     /// arguments so detailed analysis can be performed.
     Invalid(usize, usize, usize, usize, usize, usize, usize),
@@ -565,6 +605,7 @@ pub enum SysCallNumber {
     VirtToPhys = 39,
     ReturnScalar5 = 40,
     ReplyAndReceiveNext = 41,
+    ForwardMessage = 42,
     Invalid,
 }
 
@@ -613,6 +654,7 @@ impl SysCallNumber {
             39 => VirtToPhys,
             40 => ReturnScalar5,
             41 => ReplyAndReceiveNext,
+            42 => ForwardMessage,
             _ => Invalid,
         }
     }
@@ -1002,6 +1044,31 @@ impl SysCall {
                 *arg5,
                 0,
             ],
+            // There are not enough registers for the sender, connection, message
+            // type, ID and four arguments, so the message type shares a register
+            // with the connection ID.
+            SysCall::ForwardMessage(sender, cid, ref msg) => match msg {
+                Message::MutableBorrow(mm) | Message::Borrow(mm) | Message::Move(mm) => [
+                    SysCallNumber::ForwardMessage as usize,
+                    sender.to_usize(),
+                    (msg.message_type() << 16) | (*cid as usize & 0xffff),
+                    mm.id,
+                    mm.buf.as_ptr() as usize,
+                    mm.buf.len(),
+                    mm.offset.map(|x| x.get()).unwrap_or(0),
+                    mm.valid.map(|x| x.get()).unwrap_or(0),
+                ],
+                Message::Scalar(sc) | Message::BlockingScalar(sc) => [
+                    SysCallNumber::ForwardMessage as usize,
+                    sender.to_usize(),
+                    (msg.message_type() << 16) | (*cid as usize & 0xffff),
+                    sc.id,
+                    sc.arg1,
+                    sc.arg2,
+                    sc.arg3,
+                    sc.arg4,
+                ],
+            },
             SysCall::Invalid(a1, a2, a3, a4, a5, a6, a7) => [
                 SysCallNumber::Invalid as usize,
                 *a1,
@@ -1174,6 +1241,11 @@ impl SysCall {
             SysCallNumber::ReturnScalar5 => {
                 SysCall::ReturnScalar5(MessageSender::from_usize(a1), a2, a3, a4, a5, a6)
             }
+            SysCallNumber::ForwardMessage => Message::try_from((a2 >> 16, a3, a4, a5, a6, a7))
+                .map(|m| {
+                    SysCall::ForwardMessage(MessageSender::from_usize(a1), (a2 & 0xffff) as _, m)
+                })
+                .unwrap_or_else(|_| SysCall::Invalid(a1, a2, a3, a4, a5, a6, a7)),
             SysCallNumber::Invalid => SysCall::Invalid(a1, a2, a3, a4, a5, a6, a7),
         })
     }
@@ -1188,6 +1260,7 @@ impl SysCall {
                 )
             }
             SysCall::ReturnMemory(_, _, _, _) => true,
+            SysCall::ForwardMessage(_, _, msg) => msg.memory_message().is_some(),
             _ => false,
         }
     }
@@ -1227,6 +1300,11 @@ impl SysCall {
         matches!(self, SysCall::ReturnMemory(_, _, _, _))
     }
 
+    /// Returns `true` if the associated syscall is forwarding a message with memory
+    pub fn is_forward_memory(&self) -> bool {
+        matches!(self, SysCall::ForwardMessage(_, _, msg) if msg.memory_message().is_some())
+    }
+
     /// If the syscall has memory attached to it, return the memory
     pub fn memory(&self) -> Option<MemoryRange> {
         match self {
@@ -1237,6 +1315,7 @@ impl SysCall {
                 _ => None,
             },
             SysCall::ReturnMemory(_, range, _, _) => Some(*range),
+            SysCall::ForwardMessage(_, _, msg) => msg.memory_message().map(|m| m.buf),
             _ => None,
         }
     }
@@ -1256,6 +1335,12 @@ impl SysCall {
                 _ => None,
             },
             SysCall::ReturnMemory(_, range, _, _) => Some(range),
+            SysCall::ForwardMessage(_, _, msg) => match msg {
+                Message::Move(memory_message)
+                | Message::Borrow(memory_message)
+                | Message::MutableBorrow(memory_message) => Some(&mut memory_message.buf),
+                _ => None,
+            },
             _ => None,
         }
     }
@@ -1670,6 +1755,31 @@ pub fn send_message(connection: CID, message: Message) -> core::result::Result<R
     }
 }
 
+/// Forward a blocking message that this server received to another server,
+/// handing it the obligation to reply. The reply goes straight to the process
+/// that originally sent the message, and the target can see both that process'
+/// PID and the PID of the forwarding process.
+///
+/// Most servers should use `MessageEnvelope::forward()` instead.
+///
+/// # Errors
+///
+/// * **ServerNotFound**: The connection or the sender is not valid
+/// * **ServerQueueFull**: The target server's queue is full
+/// * **ShareViolation**: Tried to forward a `Borrow` as a `MutableBorrow`
+/// * **InvalidSyscall**: The message kind doesn't match the received message
+pub fn forward_message(
+    sender: MessageSender,
+    connection: CID,
+    message: Message,
+) -> core::result::Result<(), Error> {
+    match rsyscall(SysCall::ForwardMessage(sender, connection, message)) {
+        Ok(Result::Ok) => Ok(()),
+        Err(e) => Err(e),
+        _ => Err(Error::InternalError),
+    }
+}
+
 pub fn terminate_process(exit_code: u32) -> ! {
     rsyscall(SysCall::TerminateProcess(exit_code)).expect("terminate_process returned an error");
     panic!("process didn't terminate");
@@ -34,6 +34,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     let user_pkgs = [
         &base_pkgs_remote[..],
         &[
+            "coreuser",
             "console",
         ]
     ].concat();